@@ -0,0 +1,256 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{anyhow, Error, Result};
+
+use ipnet::IpNet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::proto::Address;
+
+/// What a [`DestinationPattern`] matches the host against
+#[derive(Clone, Debug)]
+enum Host {
+    Any,
+    /// The domain and its subdomains
+    Domain(String),
+    /// `*` stands for any run of characters, like `ads*.example.com`
+    Wildcard(String),
+    Net(IpNet),
+}
+
+/// A destination refused by `blocked_destinations`: a host and optionally the
+/// ports, such as `*.example.com`, `10.0.0.0/8:22`, `[2001:db8::/32]:443` or
+/// `*:6000-7000`. IP ranges also match domains once they resolve into them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct DestinationPattern {
+    text: String,
+    host: Host,
+    ports: Option<(u16, u16)>,
+}
+
+impl TryFrom<String> for DestinationPattern {
+    type Error = Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        let (host, ports) = match text.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("Missing ] in {}", text))?;
+                match rest {
+                    "" => (host, None),
+                    _ => (
+                        host,
+                        Some(
+                            rest.strip_prefix(':')
+                                .ok_or_else(|| anyhow!("Expected :port after ] in {}", text))?,
+                        ),
+                    ),
+                }
+            }
+            // More than one colon is a bare IPv6 address or range
+            None => match text.matches(':').count() {
+                1 => text
+                    .split_once(':')
+                    .map(|(host, ports)| (host, Some(ports)))
+                    .unwrap(),
+                _ => (text.as_str(), None),
+            },
+        };
+
+        let ports = match ports {
+            Some(ports) => Some(
+                parse_ports(ports)
+                    .ok_or_else(|| anyhow!("Invalid port or port range {} in {}", ports, text))?,
+            ),
+            None => None,
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let host = match host.as_str() {
+            "" => return Err(anyhow!("Missing host in {}", text)),
+            "*" => Host::Any,
+            _ => match (host.parse::<IpNet>(), host.parse::<IpAddr>()) {
+                (Ok(net), _) => Host::Net(net),
+                (_, Ok(ip)) => Host::Net(ip.into()),
+                _ if host.contains('*') => Host::Wildcard(host),
+                _ => Host::Domain(host),
+            },
+        };
+        Ok(DestinationPattern { text, host, ports })
+    }
+}
+
+impl From<DestinationPattern> for String {
+    fn from(pattern: DestinationPattern) -> Self {
+        pattern.text
+    }
+}
+
+/// `25` or `6000-7000`
+fn parse_ports(ports: &str) -> Option<(u16, u16)> {
+    let (low, high) = ports.split_once('-').unwrap_or((ports, ports));
+    let (low, high) = (low.trim().parse().ok()?, high.trim().parse().ok()?);
+    (low <= high).then_some((low, high))
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    // Where the last `*` was in the pattern and what it has swallowed so far
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn normalize(domain: &[u8]) -> String {
+    String::from_utf8_lossy(domain)
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+impl DestinationPattern {
+    pub fn matches(&self, addr: &Address) -> bool {
+        let port = match addr {
+            Address::SocketAddress(addr) => addr.port(),
+            Address::DomainAddress(_, port) => *port,
+        };
+        if let Some((low, high)) = self.ports {
+            if port < low || port > high {
+                return false;
+            }
+        }
+
+        match (&self.host, addr) {
+            (Host::Any, _) => true,
+            (Host::Net(net), Address::SocketAddress(addr)) => {
+                net.contains(&addr.ip().to_canonical())
+            }
+            (Host::Net(_), Address::DomainAddress(..)) => false,
+            (Host::Domain(domain), Address::DomainAddress(host, _)) => {
+                let host = normalize(host);
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            }
+            (Host::Wildcard(pattern), Address::DomainAddress(host, _)) => {
+                let host = normalize(host);
+                wildcard(pattern.as_bytes(), host.as_bytes())
+            }
+            (Host::Domain(_) | Host::Wildcard(_), Address::SocketAddress(_)) => false,
+        }
+    }
+}
+
+/// Whether any of `patterns` refuses a connection to `addr`
+pub fn is_blocked(patterns: &[DestinationPattern], addr: &Address) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(addr))
+}
+
+/// Whether any of `patterns` is an IP range, which a domain only matches
+/// once resolved
+pub fn has_ranges(patterns: &[DestinationPattern]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| matches!(pattern.host, Host::Net(_)))
+}
+
+/// Why a domain that resolved into a blocked range wasn't connected to
+#[derive(Debug)]
+pub struct BlockedAddress(pub SocketAddr);
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Destination resolved to blocked address {}", self.0)
+    }
+}
+
+impl std::error::Error for BlockedAddress {}
+
+/// Fails with [`BlockedAddress`] when any of `patterns` refuses one of the
+/// addresses a domain resolved to
+pub fn check_resolved(patterns: &[DestinationPattern], addrs: &[SocketAddr]) -> io::Result<()> {
+    match addrs
+        .iter()
+        .find(|addr| is_blocked(patterns, &Address::SocketAddress(**addr)))
+    {
+        Some(addr) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            BlockedAddress(*addr),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether connecting failed because of [`check_resolved`]
+pub fn is_blocked_address(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<BlockedAddress>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(texts: &[&str]) -> Vec<DestinationPattern> {
+        texts
+            .iter()
+            .map(|text| text.to_string().try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ranges_only_match_addresses() {
+        let blocked = patterns(&["10.0.0.0/8", "[fd00::/8]:443"]);
+        let domain = Address::DomainAddress(b"intranet.example".to_vec(), 443);
+        assert!(!is_blocked(&blocked, &domain));
+        assert!(is_blocked(
+            &blocked,
+            &Address::SocketAddress(([10, 1, 2, 3], 80).into())
+        ));
+        assert!(has_ranges(&blocked));
+        assert!(!has_ranges(&patterns(&["*.example.com"])));
+    }
+
+    #[test]
+    fn resolved_addresses_are_checked() {
+        let blocked = patterns(&["10.0.0.0/8", "[fd00::/8]:443"]);
+        let public = SocketAddr::from(([192, 0, 2, 1], 443));
+        let private = SocketAddr::from(([10, 1, 2, 3], 443));
+        let unique_local: SocketAddr = "[fd00::1]:443".parse().unwrap();
+
+        assert!(check_resolved(&blocked, &[public]).is_ok());
+        let err = check_resolved(&blocked, &[public, private]).unwrap_err();
+        assert!(is_blocked_address(&err));
+        assert!(check_resolved(&blocked, &[unique_local]).is_err());
+        // The range only covers port 443
+        let other_port = SocketAddr::new(unique_local.ip(), 80);
+        assert!(check_resolved(&blocked, &[other_port]).is_ok());
+        assert!(!is_blocked_address(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+}
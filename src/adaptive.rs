@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+/// Connects a path needs before its average is trusted
+const MIN_SAMPLES: u32 = 3;
+
+/// Share of connections sent the slower way, so a path that got faster is
+/// noticed
+const EXPLORE: f64 = 0.05;
+
+/// Weight of the newest connect time in the average
+const ALPHA: f64 = 0.3;
+
+/// What a failed connect counts as
+const FAILURE: Duration = Duration::from_secs(10);
+
+/// Destinations remembered, the least recently used is forgotten first
+const MAX_HOSTS: usize = 4096;
+
+/// Which way adaptive routing sent a connection
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Path {
+    Direct,
+    Proxy,
+}
+
+impl Path {
+    /// Added to the connection's tags, so the access log shows the decision
+    pub fn tag(self) -> &'static str {
+        match self {
+            Path::Direct => "adaptive:direct",
+            Path::Proxy => "adaptive:proxy",
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct PathStats {
+    samples: u32,
+    /// Moving average of connect times in milliseconds
+    average_ms: f64,
+}
+
+impl PathStats {
+    fn record(&mut self, ms: f64) {
+        self.average_ms = match self.samples {
+            0 => ms,
+            _ => ALPHA * ms + (1.0 - ALPHA) * self.average_ms,
+        };
+        self.samples = self.samples.saturating_add(1);
+    }
+}
+
+struct HostStats {
+    direct: PathStats,
+    proxy: PathStats,
+    used: Instant,
+}
+
+lazy_static! {
+    static ref HOSTS: Mutex<HashMap<String, HostStats>> = Mutex::new(HashMap::new());
+}
+
+/// The way to send a connection to `host`: whichever connected faster so
+/// far, once both have been tried enough
+pub fn choose(host: &str) -> Path {
+    let hosts = HOSTS.lock().unwrap();
+    let (direct, proxy) = match hosts.get(host) {
+        Some(stats) => (stats.direct, stats.proxy),
+        None => return Path::Proxy,
+    };
+    // Learn both first, the proxy wins ties as that is what was asked for
+    if proxy.samples < MIN_SAMPLES || direct.samples < MIN_SAMPLES {
+        return match direct.samples < proxy.samples {
+            true => Path::Direct,
+            false => Path::Proxy,
+        };
+    }
+
+    let faster = match direct.average_ms < proxy.average_ms {
+        true => Path::Direct,
+        false => Path::Proxy,
+    };
+    match rand::random::<f64>() < EXPLORE {
+        true if faster == Path::Direct => Path::Proxy,
+        true => Path::Direct,
+        false => faster,
+    }
+}
+
+/// Notes how long connecting to `host` over `path` took, `None` when it
+/// failed
+pub fn record(host: &str, path: Path, connect_time: Option<Duration>) {
+    let ms = connect_time.unwrap_or(FAILURE).as_secs_f64() * 1000.0;
+    let mut hosts = HOSTS.lock().unwrap();
+    if hosts.len() >= MAX_HOSTS && !hosts.contains_key(host) {
+        let oldest = hosts
+            .iter()
+            .min_by_key(|(_, stats)| stats.used)
+            .map(|(host, _)| host.clone());
+        if let Some(oldest) = oldest {
+            hosts.remove(&oldest);
+        }
+    }
+
+    let stats = hosts.entry(host.to_string()).or_insert(HostStats {
+        direct: PathStats::default(),
+        proxy: PathStats::default(),
+        used: Instant::now(),
+    });
+    stats.used = Instant::now();
+    match path {
+        Path::Direct => stats.direct.record(ms),
+        Path::Proxy => stats.proxy.record(ms),
+    }
+}
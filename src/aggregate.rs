@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+use crate::proto::Address;
+
+use tokio::time::sleep;
+
+use crate::{
+    events::{emit, EventKind},
+    stats::destination_addr,
+};
+
+const HOUR: u64 = 3600;
+
+/// What `aggregate_only` keeps about an hour of connections
+#[derive(Default)]
+struct Hour {
+    /// Unix time the hour started
+    start: u64,
+    connections: u64,
+    failed: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    tlds: BTreeMap<String, u64>,
+}
+
+lazy_static! {
+    static ref CURRENT: Mutex<Hour> = Mutex::new(Hour::default());
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The top-level domain of a destination, `ip` for an address
+pub fn tld(addr: &Address) -> String {
+    match addr {
+        Address::SocketAddress(_) => "ip".to_string(),
+        Address::DomainAddress(domain, _) => {
+            let domain = String::from_utf8_lossy(domain).to_lowercase();
+            let domain = domain.trim_end_matches('.');
+            match domain.parse::<std::net::IpAddr>() {
+                Ok(_) => "ip".to_string(),
+                Err(_) => domain.rsplit('.').next().unwrap_or_default().to_string(),
+            }
+        }
+    }
+}
+
+/// How a destination shows up in logs, events and traces: in full, or only
+/// by its top-level domain when `aggregate_only` is set
+pub fn shown(aggregate_only: bool, addr: &Address) -> String {
+    match aggregate_only {
+        true => tld(addr),
+        false => destination_addr(addr),
+    }
+}
+
+/// Counts a finished request in this hour's totals, instead of an access
+/// event
+pub fn record(addr: &Address, succeeded: bool, (bytes_up, bytes_down): (u64, u64)) {
+    let mut current = CURRENT.lock().unwrap();
+    let start = unix_now() / HOUR * HOUR;
+    if current.start != start {
+        report(std::mem::take(&mut *current));
+        current.start = start;
+    }
+    current.connections += 1;
+    if !succeeded {
+        current.failed += 1;
+    }
+    current.bytes_up += bytes_up;
+    current.bytes_down += bytes_down;
+    *current.tlds.entry(tld(addr)).or_default() += 1;
+}
+
+fn report(hour: Hour) {
+    if hour.connections == 0 {
+        return;
+    }
+    emit(EventKind::Usage {
+        hour: hour.start,
+        connections: hour.connections,
+        failed: hour.failed,
+        bytes_up: hour.bytes_up,
+        bytes_down: hour.bytes_down,
+        tlds: hour.tlds,
+    });
+}
+
+/// Sends each hour's totals to the logs when the hour is over
+pub async fn report_hourly() {
+    loop {
+        let now = unix_now();
+        sleep(Duration::from_secs(HOUR - now % HOUR)).await;
+
+        let mut current = CURRENT.lock().unwrap();
+        if current.start < unix_now() / HOUR * HOUR {
+            report(std::mem::take(&mut *current));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use log::{error, trace, warn};
+
+#[cfg(feature = "http-client")]
+use serde_json::json;
+
+use crate::{
+    config::{AlertMetric, AlertRule, Config},
+    stats::STATS,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+fn metric_name(metric: AlertMetric) -> &'static str {
+    match metric {
+        AlertMetric::ErrorRate => "error rate",
+        AlertMetric::UpstreamLatencyMs => "upstream latency",
+    }
+}
+
+fn metric_unit(metric: AlertMetric) -> &'static str {
+    match metric {
+        AlertMetric::ErrorRate => "%",
+        AlertMetric::UpstreamLatencyMs => "ms",
+    }
+}
+
+/// The current value of the rule's metric, None when there was no traffic
+fn measure(rule: &AlertRule) -> Option<f64> {
+    let window = Duration::from_secs(rule.window_secs);
+    match rule.metric {
+        AlertMetric::ErrorRate => STATS.error_rate(window).map(|rate| rate * 100.0),
+        AlertMetric::UpstreamLatencyMs => STATS
+            .upstream_latency(window)
+            .map(|latency| latency.as_secs_f64() * 1000.0),
+    }
+}
+
+#[cfg(feature = "http-client")]
+async fn notify_webhook(url: &str, rule: &AlertRule, value: f64, firing: bool) -> Result<()> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .json(&json!({
+            "metric": rule.metric,
+            "value": value,
+            "threshold": rule.above,
+            "window_secs": rule.window_secs,
+            "firing": firing,
+        }))
+        .send()
+        .await?;
+
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(anyhow!("Webhook returned {}", response.status())),
+    }
+}
+
+#[cfg(not(feature = "http-client"))]
+async fn notify_webhook(_url: &str, _rule: &AlertRule, _value: f64, _firing: bool) -> Result<()> {
+    Err(anyhow!("This build has no HTTP client"))
+}
+
+#[cfg(target_os = "linux")]
+fn notify_desktop(message: &str) -> Result<()> {
+    std::process::Command::new("notify-send")
+        .args(["toggleproxy", message])
+        .status()?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn notify_desktop(message: &str) -> Result<()> {
+    std::process::Command::new("osascript")
+        .arg("-e")
+        .arg(format!(
+            "display notification {:?} with title \"toggleproxy\"",
+            message
+        ))
+        .status()?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn notify_desktop(_message: &str) -> Result<()> {
+    Err(anyhow!(
+        "Desktop notifications are not supported on this platform"
+    ))
+}
+
+async fn notify(config: &Config, rule: &AlertRule, value: f64, firing: bool) {
+    let message = format!(
+        "{} is {:.1}{} over the last {}s, {} the {}{} threshold",
+        metric_name(rule.metric),
+        value,
+        metric_unit(rule.metric),
+        rule.window_secs,
+        match firing {
+            true => "above",
+            false => "back under",
+        },
+        rule.above,
+        metric_unit(rule.metric)
+    );
+    match firing {
+        true => warn!("Alert: {}", message),
+        false => warn!("Resolved: {}", message),
+    }
+
+    if let Some(url) = &config.alert_webhook {
+        match notify_webhook(url, rule, value, firing).await {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Failed to send alert webhook");
+                trace!("{}", err);
+            }
+        }
+    }
+
+    if config.alert_desktop {
+        match notify_desktop(&message) {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Failed to show desktop notification");
+                trace!("{}", err);
+            }
+        }
+    }
+}
+
+/// Checks every alert rule periodically and notifies when one starts or
+/// stops firing
+pub async fn alerts(config: Config) {
+    let mut firing = vec![false; config.alerts.len()];
+
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        for (rule, firing) in config.alerts.iter().zip(firing.iter_mut()) {
+            let value = match measure(rule) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if (value > rule.above) != *firing {
+                *firing = !*firing;
+                notify(&config, rule, value, *firing).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use log::warn;
+
+use crate::config::CircuitBreaker;
+
+#[derive(Default)]
+struct Circuit {
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+lazy_static! {
+    static ref CIRCUITS: Mutex<HashMap<String, Circuit>> = Mutex::new(HashMap::new());
+}
+
+/// Whether a connection to `host` may be attempted, false while its circuit
+/// is open after repeated failures
+pub fn allow(host: &str) -> bool {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get_mut(host) {
+        Some(circuit) => circuit,
+        None => return true,
+    };
+
+    match circuit.open_until {
+        Some(until) if until > Instant::now() => false,
+        Some(_) => {
+            // Cooldown is over, let the next attempt decide
+            circuit.open_until = None;
+            circuit.failures.clear();
+            true
+        }
+        None => true,
+    }
+}
+
+pub fn record_success(host: &str) {
+    CIRCUITS.lock().unwrap().remove(host);
+}
+
+/// Counts a failed connection to `host`, which logs call `shown`
+pub fn record_failure(host: &str, shown: &str, config: &CircuitBreaker) {
+    let now = Instant::now();
+    let window = Duration::from_secs(config.window_secs);
+
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = circuits.entry(host.to_string()).or_default();
+    while circuit
+        .failures
+        .front()
+        .is_some_and(|failure| now.duration_since(*failure) > window)
+    {
+        circuit.failures.pop_front();
+    }
+    circuit.failures.push_back(now);
+
+    if circuit.failures.len() >= config.failures as usize {
+        warn!(
+            "{} failed {} times in {}s, refusing connections to it for {}s",
+            shown,
+            circuit.failures.len(),
+            config.window_secs,
+            config.cooldown_secs
+        );
+        circuit.open_until = Some(now + Duration::from_secs(config.cooldown_secs));
+    }
+}
@@ -20,6 +20,9 @@ lazy_static! {
 
 lazy_static! {
     pub static ref CONFIG_FILE: PathBuf = CONFIG_DIR.join("toggleproxy.json");
+    pub static ref STATE_DIR: PathBuf = dirs::data_local_dir()
+        .unwrap_or(CONFIG_DIR.to_path_buf())
+        .join("toggleproxy");
 }
 
 pub fn get_args() -> ArgMatches {
@@ -27,17 +30,196 @@ pub fn get_args() -> ArgMatches {
         .about("A toggleable socks5 proxy")
         .version("1.0")
         .author("Luna")
-        .subcommand_required(true)
         .arg_required_else_help(true)
         .arg(
             arg!(-c --config <FILE> "Sets a custom config file")
                 .value_parser(value_parser!(String))
                 .default_value(CONFIG_FILE.to_str().unwrap()),
         )
+        .arg(
+            arg!(--format <FORMAT> "The config file's format, by its extension otherwise")
+                .value_parser(["json", "toml", "yaml"]),
+        )
         .arg(arg!(-p --port <PORT> "Sets a custom port").value_parser(value_parser!(u16)))
         .arg(arg!(-t --target <TARGET> "Sets a custom target proxy"))
+        .arg(arg!(--"no-persist" "Toggles the running server without saving the change"))
+        .arg(
+            arg!(--"validate-events" <FILE> "Checks an event log against the event schema")
+                .value_parser(value_parser!(String)),
+        )
         .subcommand(command!("run").about("Starts the proxy server"))
-        .subcommand(command!("toggle").about("Toggles the proxy server on or off"))
-        .subcommand(command!("config").about("Writes the config file to disk"))
+        .subcommand(
+            command!("toggle")
+                .about("Toggles the proxy server on or off")
+                .arg(arg!(-a --all "Also toggles every instance in fleet, all of them or none"))
+                .arg(
+                    arg!(-l --listener <NAME> "Toggles only this listener")
+                        .required(false)
+                        .conflicts_with("all"),
+                ),
+        )
+        .subcommand(
+            command!("use")
+                .about("Switches to the target proxies of a profile and turns the proxy on")
+                .arg(arg!(<PROFILE> "The profile's name")),
+        )
+        .subcommand(command!("status").about("Shows whether the running server is on"))
+        .subcommand(command!("reload").about("Makes the running server read its config again"))
+        .subcommand(
+            command!("reload-data").about("Makes the running server load its rule lists again"),
+        )
+        .subcommand(
+            command!("lift")
+                .about("Lets the clients of a client policy reach everything for a while")
+                .arg(arg!(<POLICY> "The policy's name"))
+                .arg(
+                    arg!(--for <SECONDS> "How long the blocks are lifted")
+                        .value_parser(value_parser!(u64))
+                        .default_value("3600"),
+                ),
+        )
+        .subcommand(
+            command!("reroute")
+                .about("Closes an open connection and sends its destination the other way")
+                .arg(
+                    arg!(<ID> "Connection id, as listed by trace --live")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(<ROUTE> "Where new connections to it go")
+                        .value_parser(["direct", "proxy"]),
+                )
+                .arg(
+                    arg!(--for <SECONDS> "How long the route applies")
+                        .value_parser(value_parser!(u64))
+                        .default_value("600"),
+                ),
+        )
+        .subcommand(
+            command!("pause")
+                .about("Stops relaying an open connection without closing it")
+                .arg(
+                    arg!(<ID> "Connection id, as listed by trace --live")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            command!("resume")
+                .about("Relays a paused connection again")
+                .arg(
+                    arg!(<ID> "Connection id, as listed by trace --live")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            command!("replay")
+                .about("Sends a recorded failed negotiation to the server again")
+                .arg(arg!(<FILE> "A recording from record_failures"))
+                .arg(arg!(-s --server <ADDR> "host:port to send it to, this server by default")),
+        )
+        .subcommand(
+            command!("install-service")
+                .about(
+                    "Writes and enables a systemd unit, or a launchd daemon on macOS, \
+                     that runs the proxy server",
+                )
+                .arg(arg!(-u --user <USER> "Runs the service as this user instead of root"))
+                .arg(
+                    arg!(--binary <PATH> "The toggleproxy binary to run, this one by default")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(arg!(--print "Prints the unit or plist instead of installing it")),
+        )
+        .subcommand(
+            command!("config")
+                .about("Writes the config file to disk")
+                .subcommand(command!("lint").about("Flags risky settings in the config"))
+                .subcommand(
+                    command!("init")
+                        .about("Writes the default config, keeping the old file as .bak"),
+                )
+                .subcommand(
+                    command!("get")
+                        .about("Prints a setting")
+                        .arg(arg!(<KEY> "The setting, like target, failover.rounds or rules.0.pattern")),
+                )
+                .subcommand(
+                    command!("set")
+                        .about("Changes a setting in the config file")
+                        .arg(arg!(<KEY> "The setting, like target, failover.rounds or rules.0.pattern"))
+                        .arg(arg!(<VALUE> "The new value, as JSON or a plain string")),
+                )
+                .subcommand(
+                    command!("show")
+                        .about("Prints the config in use, with environment and command line overrides"),
+                ),
+        )
+        .subcommand(
+            command!("discover")
+                .about("Finds SOCKS5 proxies on the local network")
+                .arg(
+                    arg!(-w --wait <SECONDS> "How long to listen for proxies")
+                        .value_parser(value_parser!(u64))
+                        .default_value("3"),
+                )
+                .arg(
+                    arg!(-u --use <INDEX> "Sets a discovered proxy as the target")
+                        .value_parser(value_parser!(usize)),
+                ),
+        )
+        .subcommand(
+            command!("migrate")
+                .about("Prints a config built from another proxy tool's config")
+                .arg(
+                    arg!(-f --from <FORMAT> "The format of FILE")
+                        .value_parser(crate::migrate::FORMATS)
+                        .required(true),
+                )
+                .arg(arg!(<FILE> "The config file to read")),
+        )
+        .subcommand(
+            command!("ping")
+                .about("Measures connect times to a host through the proxy")
+                .arg(arg!(<HOST> "The host to connect to, as host[:port]"))
+                .arg(
+                    arg!(-n --count <COUNT> "How many connections to make")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .default_value("4"),
+                )
+                .arg(
+                    arg!(-i --interval <MS> "Milliseconds to wait between connections")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1000"),
+                ),
+        )
+        .subcommand(
+            command!("stats")
+                .about("Shows the destinations with the most traffic")
+                .arg(
+                    arg!(-n --top <COUNT> "How many destinations to show")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(
+                    arg!(-w --window <SECONDS> "Only count connections closed this recently")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            command!("trace")
+                .about("Shows where connections spend their time")
+                .arg(
+                    arg!([ID] "Keeps the timing of this open connection once it closes")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(arg!(-l --live "Lists open connections instead")),
+        )
+        .subcommand(
+            command!("system-proxy")
+                .about("Points the OS proxy settings at the proxy server")
+                .subcommand_required(true)
+                .subcommand(command!("enable").about("Sets the system proxy"))
+                .subcommand(command!("disable").about("Restores the previous system proxy")),
+        )
         .get_matches();
 }
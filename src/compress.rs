@@ -0,0 +1,416 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "compression")]
+use std::task::ready;
+
+use log::trace;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+#[cfg(feature = "compression")]
+use zstd::bulk::{Compressor, Decompressor};
+
+use crate::transport::BoxStream;
+
+/// Sent by a toggleproxy client before anything else to offer compression.
+/// No SOCKS version starts with `T`, so the listener tells it apart from a
+/// client that speaks SOCKS right away
+pub const OFFER: [u8; 4] = *b"TPZ\x01";
+/// The listener's one byte answer to an offer
+#[cfg(feature = "compression")]
+const ACCEPTED: u8 = 1;
+const DECLINED: u8 = 0;
+
+/// Most bytes one frame carries before compression
+#[cfg(feature = "compression")]
+const MAX_CHUNK: usize = 16384;
+/// Writes shorter than this aren't worth the frame's compression header
+#[cfg(feature = "compression")]
+const MIN_COMPRESSED: usize = 512;
+/// Bits per byte above which data is taken to be compressed or encrypted
+/// already, such as TLS records or video
+#[cfg(feature = "compression")]
+const MAX_ENTROPY: f64 = 7.0;
+/// How much of a write the entropy is estimated from
+#[cfg(feature = "compression")]
+const ENTROPY_SAMPLE: usize = 4096;
+
+#[cfg(feature = "compression")]
+const RAW: u8 = 0;
+#[cfg(feature = "compression")]
+const ZSTD: u8 = 1;
+#[cfg(feature = "compression")]
+const LEVEL: i32 = 3;
+
+/// Shannon entropy of `data` in bits per byte
+#[cfg(feature = "compression")]
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether a write is long enough and repetitive enough to compress
+#[cfg(feature = "compression")]
+fn worth_compressing(data: &[u8]) -> bool {
+    data.len() >= MIN_COMPRESSED && entropy(&data[..data.len().min(ENTROPY_SAMPLE)]) < MAX_ENTROPY
+}
+
+/// Offers compression to a target proxy that is another toggleproxy. The
+/// stream is compressed if the target accepts and left as it is otherwise
+pub async fn offer(mut stream: BoxStream) -> io::Result<BoxStream> {
+    if !cfg!(feature = "compression") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This build has no compression support",
+        ));
+    }
+    stream.write_all(&OFFER).await?;
+    match stream.read_u8().await? {
+        #[cfg(feature = "compression")]
+        ACCEPTED => Ok(Box::new(CompressedStream::new(stream))),
+        DECLINED => {
+            trace!("Target proxy declined compression");
+            Ok(stream)
+        }
+        answer => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected answer {} to the compression offer", answer),
+        )),
+    }
+}
+
+/// Answers a client's compression offer if it starts with one, accepting it
+/// when `enabled`. Clients that don't offer get their stream back untouched
+pub async fn answer(mut stream: BoxStream, enabled: bool) -> io::Result<BoxStream> {
+    let first = stream.read_u8().await?;
+    if first != OFFER[0] {
+        return Ok(Box::new(Unread {
+            first: Some(first),
+            inner: stream,
+        }));
+    }
+
+    let mut rest = [0u8; OFFER.len() - 1];
+    stream.read_exact(&mut rest).await?;
+    if rest != OFFER[1..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unknown compression offer",
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    if enabled {
+        stream.write_all(&[ACCEPTED]).await?;
+        return Ok(Box::new(CompressedStream::new(stream)));
+    }
+    trace!("Declined compression, enabled: {}", enabled);
+    stream.write_all(&[DECLINED]).await?;
+    Ok(stream)
+}
+
+/// A stream with its first byte read already
+struct Unread {
+    first: Option<u8>,
+    inner: BoxStream,
+}
+
+impl AsyncRead for Unread {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.first.take() {
+            Some(first) if buf.remaining() > 0 => {
+                buf.put_slice(&[first]);
+                Poll::Ready(Ok(()))
+            }
+            first => {
+                this.first = first;
+                Pin::new(&mut this.inner).poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Unread {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Carries a byte stream in frames of a kind byte, a big-endian u16 length
+/// and the payload. Each write becomes a zstd frame when it looks
+/// compressible and shrinks, and a raw one otherwise
+#[cfg(feature = "compression")]
+pub struct CompressedStream {
+    inner: BoxStream,
+    compressor: Compressor<'static>,
+    decompressor: Decompressor<'static>,
+    /// Bytes read off the wire that don't make up a whole frame yet
+    incoming: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// The frame of the last write, sent before the next one is taken
+    outgoing: Vec<u8>,
+    write_pos: usize,
+}
+
+#[cfg(feature = "compression")]
+impl CompressedStream {
+    fn new(inner: BoxStream) -> Self {
+        CompressedStream {
+            inner,
+            compressor: Compressor::new(LEVEL).expect("zstd level is valid"),
+            decompressor: Decompressor::new().expect("zstd context is created"),
+            incoming: Vec::new(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            outgoing: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    fn frame(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let compressed = match worth_compressing(chunk) {
+            true => Some(self.compressor.compress(chunk)?),
+            false => None,
+        };
+        let (kind, payload) = match &compressed {
+            Some(compressed) if compressed.len() < chunk.len() => (ZSTD, compressed.as_slice()),
+            _ => (RAW, chunk),
+        };
+
+        let mut frame = Vec::with_capacity(3 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    /// Takes the next whole frame out of `incoming`, decompressed
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.incoming.len() < 3 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([self.incoming[1], self.incoming[2]]) as usize;
+        if len > MAX_CHUNK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Compressed frame too large",
+            ));
+        }
+        if self.incoming.len() < 3 + len {
+            return Ok(None);
+        }
+
+        let kind = self.incoming[0];
+        let payload = self.incoming.drain(..3 + len).skip(3).collect::<Vec<_>>();
+        match kind {
+            RAW => Ok(Some(payload)),
+            ZSTD => Ok(Some(self.decompressor.decompress(&payload, MAX_CHUNK)?)),
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown frame kind {}", kind),
+            )),
+        }
+    }
+
+    fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.outgoing.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.write_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.outgoing.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl AsyncRead for CompressedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_pos == this.read_buf.len() {
+            if let Some(frame) = this.next_frame()? {
+                this.read_buf = frame;
+                this.read_pos = 0;
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                return match this.incoming.is_empty() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Compressed stream ended inside a frame",
+                    ))),
+                };
+            }
+            this.incoming.extend_from_slice(chunk_buf.filled());
+        }
+
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl AsyncWrite for CompressedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let chunk = &buf[..buf.len().min(MAX_CHUNK)];
+        this.outgoing = this.frame(chunk)?;
+        // The frame is taken, whatever doesn't go out now is sent by the
+        // next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_outgoing(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    /// Both ends of a negotiated stream, the listener's accepting when `enabled`
+    async fn negotiated(enabled: bool) -> (BoxStream, BoxStream) {
+        let (client, server) = duplex(1 << 20);
+        let (client, server) =
+            tokio::join!(offer(Box::new(client)), answer(Box::new(server), enabled));
+        (client.unwrap(), server.unwrap())
+    }
+
+    async fn carries(mut from: BoxStream, mut to: BoxStream, data: &[u8]) {
+        from.write_all(data).await.unwrap();
+        from.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        to.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn only_repetitive_writes_are_worth_compressing() {
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(20);
+        let random = (0..4096).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+
+        assert!(worth_compressing(&text));
+        assert!(!worth_compressing(&random));
+        assert!(!worth_compressing(&text[..100]));
+    }
+
+    #[tokio::test]
+    async fn accepted_streams_carry_compressible_and_random_data() {
+        let text = b"hello compression ".repeat(5000);
+        let random = (0..50000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+
+        let (client, server) = negotiated(true).await;
+        carries(client, server, &text).await;
+        let (client, server) = negotiated(true).await;
+        carries(server, client, &random).await;
+    }
+
+    #[tokio::test]
+    async fn compressible_writes_shrink_on_the_wire() {
+        let (client, mut server) = duplex(1 << 20);
+        let (client, _) = tokio::join!(offer(Box::new(client)), async {
+            let mut offer = [0u8; OFFER.len()];
+            server.read_exact(&mut offer).await.unwrap();
+            server.write_all(&[ACCEPTED]).await.unwrap();
+        });
+
+        let text = b"hello compression ".repeat(500);
+        let mut client = client.unwrap();
+        client.write_all(&text).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut wire = Vec::new();
+        server.read_to_end(&mut wire).await.unwrap();
+        assert_eq!(wire[0], ZSTD);
+        assert!(wire.len() < text.len() / 10);
+    }
+
+    #[tokio::test]
+    async fn declined_streams_stay_plain() {
+        let (client, server) = negotiated(false).await;
+        carries(client, server, b"\x05\x01\x00").await;
+    }
+
+    #[tokio::test]
+    async fn clients_without_an_offer_keep_their_first_byte() {
+        let (mut client, server) = duplex(64);
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        drop(client);
+
+        let mut server = answer(Box::new(server), true).await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"\x05\x01\x00");
+    }
+}
@@ -1,28 +1,980 @@
-use crate::clap::get_args;
+use crate::{
+    acl::DestinationPattern,
+    clap::{get_args, STATE_DIR},
+    rule_lists,
+    rules::RuleIndex,
+    schedule::Cron,
+    upgrade::{upgrade, CONFIG_VERSION},
+    validate::validate,
+    wol::MacAddress,
+};
 
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use anyhow::Result;
 
-use log::{error, trace};
+use log::{error, info, trace};
+
+use notify::{RecursiveMode, Watcher};
+
+use tokio::sync::{mpsc, watch};
+
+/// Where a system service keeps its control socket, since it has no
+/// XDG_RUNTIME_DIR
+#[cfg(all(unix, not(target_os = "macos")))]
+const SYSTEM_CONTROL: &str = "/run/toggleproxy/toggleproxy.sock";
+#[cfg(target_os = "macos")]
+const SYSTEM_CONTROL: &str = "/var/run/toggleproxy/toggleproxy.sock";
+
+/// In the user's runtime dir, which only they can enter, rather than a fixed
+/// name in /tmp that anyone could take first
+#[cfg(unix)]
+fn default_control() -> String {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir)
+            .join("toggleproxy.sock")
+            .display()
+            .to_string(),
+        _ => SYSTEM_CONTROL.to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn default_control() -> String {
+    "127.0.0.1:1079".to_string()
+}
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Config {
+    /// Which layout the file has, older ones are upgraded when read
+    pub version: u32,
     pub port: u16,
-    pub target: String,
+    /// PEM certificate chain for clients to reach the listener over TLS,
+    /// plain SOCKS is refused once it is set
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Accept compression offered by toggleproxy clients with
+    /// `target_compression`, their streams go uncompressed otherwise
+    pub compression: bool,
+    pub target: Targets,
+    pub failover: Failover,
+    pub balance: Balance,
+    /// Keep connections to the secondary targets open ahead of time, so
+    /// switching over from a failed target costs no transport handshake
+    pub warm_standby: Option<WarmStandby>,
+    /// Wake the target proxy's machine with a magic packet when it doesn't
+    /// answer, and try it once more before counting it as down
+    pub wake_on_lan: Option<WakeOnLan>,
+    /// What target proxies speak, unless a target starts with `http://`,
+    /// `https://` or `socks5://`
+    pub target_protocol: Protocol,
+    pub target_transport: Transport,
+    pub target_obfs: Option<Obfs>,
+    /// Offer to compress each stream to the target proxy, for slow links. Only
+    /// another toggleproxy with `compression` set takes it up
+    pub target_compression: bool,
+    /// Credentials for target proxies that require username/password auth
+    pub target_username: Option<String>,
+    pub target_password: Option<String>,
+    /// Named sets of target proxy settings, switched to with `use`
+    pub profiles: Vec<Profile>,
+    /// The profile last switched to
+    pub profile: Option<String>,
     pub status: bool,
+    /// More ports served by the same process, each with its own target
+    /// proxies and toggle
+    pub listeners: Vec<Listener>,
+    /// Switches the proxy on or off at set times while the server runs
+    pub schedule: Vec<ScheduledToggle>,
+    /// Switches the proxy on or off when the server finds itself on another
+    /// network, the first matching rule decides
+    pub network_rules: Vec<NetworkRule>,
+    /// Restart the service after changing the config, through launchd on
+    /// macOS
     pub systemd: bool,
+    /// Point the OS SOCKS proxy at toggleproxy while the proxy is on, and put
+    /// the previous setting back when it is off. Applied on every toggle, from
+    /// the command line, the control socket, the schedule or network rules
+    pub system_proxy: bool,
+    pub pac_port: Option<u16>,
+    pub pac_profiles: Vec<PacProfile>,
+    /// Serve `/wpad.dat` on port 80 for clients that auto-discover their proxy.
+    /// Discovery still needs DHCP option 252 or a `wpad.<domain>` DNS record
+    /// pointing at this host.
+    pub wpad: bool,
+    /// PAC profile served as `/wpad.dat`, the default PAC file when unset
+    pub wpad_profile: Option<String>,
+    /// Ask the router to forward the proxy port to this host over UPnP, or
+    /// NAT-PMP when the router doesn't speak UPnP
+    pub port_mapping: bool,
+    /// The listener lets anyone in without a username and password, so
+    /// `port_mapping` is refused unless this is set to open it to the
+    /// internet anyway
+    pub port_mapping_unauthenticated: bool,
+    pub ddns: Option<DdnsConfig>,
+    /// Advertise the proxy on the LAN as a `_socks5._tcp` DNS-SD service
+    pub mdns: bool,
+    /// Where the running server listens for commands, a Unix socket path or a
+    /// localhost host:port. Defaults to toggleproxy.sock in $XDG_RUNTIME_DIR,
+    /// or /run/toggleproxy without one
+    pub control: String,
+    /// Control endpoints of other instances, host:port or a Unix socket path,
+    /// that `toggle --all` switches together with this one
+    pub fleet: Vec<String>,
+    /// Tokens that control requests must carry once any are set. Read-only
+    /// ones can query the server but not change it
+    pub control_tokens: Vec<ControlToken>,
+    pub alerts: Vec<AlertRule>,
+    /// URL that fired alerts are POSTed to as JSON
+    pub alert_webhook: Option<String>,
+    /// Show fired alerts as desktop notifications
+    pub alert_desktop: bool,
+    /// A host:port that is expected to always accept connections, probed when
+    /// the target proxy fails to tell a broken proxy from a broken network
+    pub reachability_probe: Option<String>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Where JSONL access, toggle and audit events go: a file path,
+    /// tcp://host:port or unix:///path
+    pub event_log: Option<String>,
+    /// Where only the access events go, one JSON line per connection, in the
+    /// same forms as `event_log`
+    pub access_log: Option<String>,
+    /// Never keep individual destinations: access events become hourly
+    /// totals per top-level domain, and stats, open connections, logs and
+    /// audit events only name the top-level domain. No connection's trace is
+    /// kept, not even one asked for with `trace`
+    pub aggregate_only: bool,
+    /// Directory the client side of each failed SOCKS negotiation is written
+    /// to, for `replay`
+    pub record_failures: Option<PathBuf>,
+    /// Checked in order, the first rule matching a destination decides its route
+    pub rules: Rules,
+    /// Large lists of domains or IP ranges, such as imported blocklists,
+    /// checked after `rules`
+    pub rule_lists: Vec<RuleList>,
+    /// Share of connections, from 0 to 1, whose timing breakdown is kept
+    pub trace_sample_rate: f64,
+    /// What the listener does with UDP ASSOCIATE and BIND requests
+    pub commands: Commands,
+    pub dns_mode: DnsMode,
+    /// While the proxy is on and the target proxy is down, refuse connections
+    /// the rules would send direct
+    pub fail_closed: bool,
+    /// While the proxy is on, never send anything direct
+    pub never_direct: bool,
+    /// While the proxy is on, send destinations no rule routes whichever way
+    /// has connected to them faster, direct or through the target proxy
+    pub adaptive_routing: bool,
+    /// Connect directly when no target proxy can be reached, unless the kill
+    /// switch forbids it
+    pub fallback_direct: bool,
+    /// DNS server asked over the target proxy in `proxy_only` mode, an
+    /// ip:port reached over TCP
+    pub dns_resolver: String,
+    /// Seconds a name that failed to resolve for a direct connection is
+    /// refused without asking again, 0 to always ask
+    pub dns_negative_ttl_secs: u64,
+    /// How long reaching a destination or a target proxy may take, 0 to wait
+    /// as long as the OS does
+    pub connect_timeout_ms: u64,
+    /// Seconds a tunnel may go without traffic either way before it is
+    /// closed, 0 to keep it open
+    pub idle_timeout_secs: u64,
+    /// Kilobits per second each connection may move in each direction
+    pub max_kbps_per_conn: Option<u64>,
+    /// Kilobits per second all connections together may move in each
+    /// direction
+    pub max_kbps_total: Option<u64>,
+    /// Networks, like `192.168.1.0/24`, whose clients may use the proxy.
+    /// Everyone may when empty
+    pub allow_clients: Vec<IpNet>,
+    /// Networks whose clients are turned away, even when they are allowed
+    pub deny_clients: Vec<IpNet>,
+    /// Destinations no client may connect to, whatever the rules say
+    pub blocked_destinations: Vec<DestinationPattern>,
+    /// Destinations some clients may not connect to, at some times
+    pub client_policies: Vec<ClientPolicy>,
+    /// Closes open connections whose destination a rule change sends
+    /// another way, so the change reaches long-lived ones too
+    pub drain_on_rule_change: Option<DrainPolicy>,
+    /// For testing only, makes every relayed stream behave like a slow,
+    /// lossy link
+    pub simulate: Option<Simulation>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Socks5,
+    /// Tunnels through the target proxy with HTTP CONNECT
+    Http,
+}
+
+/// How the connection to the target proxy is carried. TLS sessions, for `tls`,
+/// `wss` and `https://` targets, are resumed from a cache kept only in memory,
+/// so the first connection after a restart does a full handshake
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Tls {
+        /// Name to verify the certificate against, the target host by default
+        #[serde(default)]
+        server_name: Option<String>,
+        /// Base64 SHA-256 hashes of accepted SubjectPublicKeyInfos. When set,
+        /// the certificate must match one of them instead of a trusted CA
+        #[serde(default)]
+        pin_sha256: Vec<String>,
+    },
+    Ws {
+        /// Host header sent in the upgrade request, the target host by default
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(default = "default_ws_path")]
+        path: String,
+        /// Pad every write up to a multiple of this many bytes on the wire.
+        /// The padding is WebSocket ping frames, which the server drops
+        /// without any setup. TLS has no record a server ignores like that, so
+        /// to pad a TLS connection use `wss`
+        #[serde(default)]
+        pad_to: Option<usize>,
+    },
+    Wss {
+        /// SNI and certificate name, can differ from `host` for CDN fronting
+        #[serde(default)]
+        server_name: Option<String>,
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(default = "default_ws_path")]
+        path: String,
+        #[serde(default)]
+        pin_sha256: Vec<String>,
+        #[serde(default)]
+        pad_to: Option<usize>,
+    },
+}
+
+fn default_ws_path() -> String {
+    "/".to_string()
+}
+
+/// Target proxies in the order they are tried. Written as a plain string
+/// when there is only one
+#[derive(Clone, Default, PartialEq)]
+pub struct Targets(pub Vec<String>);
+
+impl Targets {
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Targets {
+    fn from(target: String) -> Self {
+        Targets(vec![target])
+    }
+}
+
+impl std::fmt::Display for Targets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join(", "))
+    }
+}
+
+impl Serialize for Targets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [target] => target.serialize(serializer),
+            targets => targets.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Targets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(target) => Targets(vec![target]),
+            OneOrMany::Many(targets) => Targets(targets),
+        })
+    }
+}
+
+/// How a connection goes through the list of target proxies. Each round tries
+/// every target once, rounds after the first wait `backoff_ms`, doubling up to
+/// `max_backoff_ms`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Failover {
+    #[serde(default = "default_failover_rounds")]
+    pub rounds: u32,
+    #[serde(default = "default_failover_backoff")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_failover_max_backoff")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Failover {
+            rounds: default_failover_rounds(),
+            backoff_ms: default_failover_backoff(),
+            max_backoff_ms: default_failover_max_backoff(),
+        }
+    }
+}
+
+/// Blocks destinations for the clients in `clients`, like a child's tablet
+/// after bedtime. `lift` lets them through for a while
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientPolicy {
+    pub name: String,
+    pub clients: Vec<IpNet>,
+    /// In the forms of `blocked_destinations`
+    pub blocked: Vec<DestinationPattern>,
+    /// The minutes the blocks apply, as cron expressions like
+    /// `* 21-23 * * *`. Always when empty
+    #[serde(default)]
+    pub active: Vec<Cron>,
+}
+
+/// Sets the proxy to `status` whenever `at` comes round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledToggle {
+    pub at: Cron,
+    pub status: bool,
+}
+
+/// Sets the proxy to `status` on networks matching every condition given,
+/// a rule without any matches every network
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NetworkRule {
+    /// The Wi-Fi network name
+    #[serde(default)]
+    pub ssid: Option<String>,
+    /// The interface of the default route, like `wlan0` or `en0`
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// The default gateway's address
+    #[serde(default)]
+    pub gateway: Option<IpAddr>,
+    pub status: bool,
+}
+
+/// Where destination domains are resolved
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsMode {
+    /// Always on this machine, the target proxy is only given addresses
+    Local,
+    /// By the target proxy when connecting through it, here otherwise
+    Remote,
+    /// Never on this machine, direct connections resolve through
+    /// `dns_resolver` over the target proxy so lookups can't leak
+    ProxyOnly,
+}
+
+/// What the listener does with a SOCKS command other than CONNECT
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandPolicy {
+    /// Serve it through the UDP relay, BIND can't be served and is rejected
+    Relay,
+    /// Reply that the command is not supported
+    Reject,
+    /// Close the connection without replying
+    Close,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Commands {
+    #[serde(default = "default_associate_policy")]
+    pub associate: CommandPolicy,
+    #[serde(default = "default_bind_policy")]
+    pub bind: CommandPolicy,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Commands {
+            associate: default_associate_policy(),
+            bind: default_bind_policy(),
+        }
+    }
+}
+
+fn default_associate_policy() -> CommandPolicy {
+    CommandPolicy::Relay
+}
+
+fn default_bind_policy() -> CommandPolicy {
+    CommandPolicy::Reject
+}
+
+/// Which target proxy a connection tries first, the rest are tried after it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// The first target that works, in the order they are listed
+    Ordered,
+    RoundRobin,
+    /// The target with the fewest open tunnels
+    LeastConnections,
+    /// Picked at random, favouring targets that have connected faster
+    Latency,
+}
+
+/// How many connections to each target after the first are kept ready while
+/// the proxy is on. Targets that just failed are tried last for a while
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct WarmStandby {
+    #[serde(default = "default_standby_connections")]
+    pub connections: usize,
+    /// Ready connections are replaced after this long, before the target
+    /// closes them for being idle
+    #[serde(default = "default_standby_max_idle")]
+    pub max_idle_secs: u64,
+    /// How long a target that failed goes to the back of the list
+    #[serde(default = "default_standby_failed")]
+    pub failed_secs: u64,
+}
+
+/// Target proxy settings under a name like `work` or `tor`. Switching to it
+/// replaces `target`, the credentials and, when given, `target_protocol`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub target: Targets,
+    #[serde(default)]
+    pub target_protocol: Option<Protocol>,
+    #[serde(default)]
+    pub target_username: Option<String>,
+    #[serde(default)]
+    pub target_password: Option<String>,
+}
+
+/// A port of its own, toggled with `toggle --listener <name>`. Everything
+/// but the target proxies and the toggle is shared with the main port
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Listener {
+    pub name: String,
+    pub port: u16,
+    pub target: Targets,
+    #[serde(default)]
+    pub target_protocol: Option<Protocol>,
+    #[serde(default)]
+    pub target_username: Option<String>,
+    #[serde(default)]
+    pub target_password: Option<String>,
+    #[serde(default)]
+    pub status: bool,
+}
+
+/// Where the target proxy's machine listens for Wake-on-LAN
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WakeOnLan {
+    /// Like `aa:bb:cc:dd:ee:ff`
+    pub mac: MacAddress,
+    /// Where the magic packet goes, usually the LAN's broadcast address
+    #[serde(default = "default_wake_broadcast")]
+    pub broadcast: String,
+    /// How long the machine takes to come up, connections wait this long
+    /// before trying it again
+    #[serde(default = "default_wake_wait")]
+    pub wait_secs: u64,
+}
+
+fn default_wake_broadcast() -> String {
+    "255.255.255.255:9".to_string()
+}
+
+fn default_wake_wait() -> u64 {
+    20
+}
+
+fn default_standby_connections() -> usize {
+    1
+}
+
+fn default_standby_max_idle() -> u64 {
+    50
+}
+
+fn default_standby_failed() -> u64 {
+    30
+}
+
+fn default_failover_rounds() -> u32 {
+    1
+}
+
+fn default_failover_backoff() -> u64 {
+    250
+}
+
+fn default_failover_max_backoff() -> u64 {
+    2000
+}
+
+/// Disguises the connection to the target proxy, applied below the transport
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Obfs {
+    Xor { key: String },
+    Http { host: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PacRoute {
+    Proxy,
+    Direct,
+}
+
+/// A named routing policy served as `/pac/<name>.pac`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PacProfile {
+    pub name: String,
+    #[serde(default)]
+    pub proxy: Vec<String>,
+    #[serde(default)]
+    pub direct: Vec<String>,
+    #[serde(default = "default_pac_route")]
+    pub default: PacRoute,
+}
+
+fn default_pac_route() -> PacRoute {
+    PacRoute::Proxy
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum RuleAction {
+    Direct,
+    Proxy,
+    /// Refuse the connection, DNS queries get NXDOMAIN
+    Block,
+    /// Connect to `to` instead, DNS queries are answered with it
+    Redirect {
+        to: IpAddr,
+    },
+}
+
+/// What to do with QUIC (UDP to port 443) instead of the rule's action
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuicPolicy {
+    /// Drop it, browsers fall back to TCP
+    Block,
+    Proxy,
+}
+
+/// Routes a domain and its subdomains, or an IP range such as `10.0.0.0/8`,
+/// regardless of the toggle. Domain patterns only match clients that send
+/// domain names instead of resolving them first
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rule {
+    pub pattern: String,
+    #[serde(flatten)]
+    pub action: RuleAction,
+    #[serde(default)]
+    pub quic: Option<QuicPolicy>,
+    /// While the proxy is on, connect both directly and through the target
+    /// proxy at once and keep whichever connects first
+    #[serde(default)]
+    pub race: bool,
+}
+
+/// A file with one domain or IP range per line, all given the same action.
+/// Plain lists, hosts files and `||domain^` adblock lines are understood
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RuleList {
+    /// Where the list is read from, and where downloads of `url` are kept
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub action: RuleAction,
+    /// Downloaded again every `refresh_secs` while the server runs
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_list_refresh")]
+    pub refresh_secs: u64,
+    /// A URL serving the list's SHA-256 in hex, as `sha256sum` prints it.
+    /// Downloads that don't match are rejected
+    #[serde(default)]
+    pub checksum_url: Option<String>,
+    #[serde(default)]
+    pub on_failure: ListFailure,
+}
+
+fn default_list_refresh() -> u64 {
+    86400
+}
+
+/// What happens to a downloaded list's rules when updating it fails
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFailure {
+    /// Keep using the last good download
+    #[default]
+    KeepStale,
+    /// Drop the list's rules until a download succeeds
+    Disable,
+}
+
+/// The routing rules. Clones share the list and the lookup index, which is
+/// built the first time a destination is matched
+#[derive(Clone, Default)]
+pub struct Rules {
+    list: Arc<Vec<Rule>>,
+    /// Loaded from `rule_lists`, never written back to the config file
+    imported: Arc<Vec<Rule>>,
+    index: Arc<OnceLock<RuleIndex>>,
+}
+
+impl Rules {
+    pub fn push(&mut self, rule: Rule) {
+        Arc::make_mut(&mut self.list).push(rule);
+        self.index = Arc::default();
+    }
+
+    /// Replaces the rules loaded from `rule_lists`
+    pub fn set_imported(&mut self, imported: Vec<Rule>) {
+        self.imported = Arc::new(imported);
+        self.index = Arc::default();
+    }
+
+    /// The configured rules followed by the imported ones
+    pub fn all(&self) -> impl Iterator<Item = &Rule> {
+        self.list.iter().chain(self.imported.iter())
+    }
+
+    /// A rule by its position in [`Rules::all`]
+    pub fn get(&self, position: usize) -> Option<&Rule> {
+        match self.list.get(position) {
+            Some(rule) => Some(rule),
+            None => self.imported.get(position - self.list.len()),
+        }
+    }
+
+    /// Whether both hold the same configured rules, not just equal ones
+    pub fn same_list(&self, other: &Rules) -> bool {
+        Arc::ptr_eq(&self.list, &other.list)
+    }
+
+    /// Whether both are copies of the same rules, configured and imported.
+    /// Any change starts a new index, so they share one until then
+    pub fn same_rules(&self, other: &Rules) -> bool {
+        Arc::ptr_eq(&self.index, &other.index)
+    }
+
+    pub fn index(&self) -> &RuleIndex {
+        self.index.get_or_init(|| RuleIndex::new(self.all()))
+    }
+}
+
+impl std::ops::Deref for Rules {
+    type Target = [Rule];
+
+    fn deref(&self) -> &[Rule] {
+        &self.list
+    }
+}
+
+impl Serialize for Rules {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.list.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rules {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Rules {
+            list: Arc::new(Vec::deserialize(deserializer)?),
+            imported: Arc::default(),
+            index: Arc::default(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlRole {
+    Admin,
+    /// Status, stats, health, timings and events only
+    #[default]
+    ReadOnly,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ControlToken {
+    pub token: String,
+    #[serde(default)]
+    pub role: ControlRole,
+    /// Names the token in audit events, a short hash of it otherwise
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Requests answered a minute, any more are refused until the next
+    #[serde(default)]
+    pub max_requests_per_min: Option<u32>,
+}
+
+/// Keeps a hostname pointed at this instance's public address
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DdnsConfig {
+    #[serde(flatten)]
+    pub provider: DdnsProvider,
+    #[serde(default = "default_ddns_interval")]
+    pub interval_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum DdnsProvider {
+    Duckdns {
+        domain: String,
+        token: String,
+    },
+    Cloudflare {
+        zone_id: String,
+        name: String,
+        token: String,
+    },
+}
+
+fn default_ddns_interval() -> u64 {
+    300
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Percentage of connection attempts that failed
+    ErrorRate,
+    /// Average milliseconds to connect through the target proxy
+    UpstreamLatencyMs,
+}
+
+/// Fires when `metric` stays above `above` over the last `window_secs`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlertRule {
+    pub metric: AlertMetric,
+    pub above: f64,
+    #[serde(default = "default_alert_window")]
+    pub window_secs: u64,
+}
+
+fn default_alert_window() -> u64 {
+    300
+}
+
+/// Stops dialing a destination for `cooldown_secs` once it failed `failures`
+/// times within `window_secs`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CircuitBreaker {
+    #[serde(default = "default_breaker_failures")]
+    pub failures: u32,
+    #[serde(default = "default_breaker_window")]
+    pub window_secs: u64,
+    #[serde(default = "default_breaker_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+fn default_breaker_failures() -> u32 {
+    5
+}
+
+fn default_breaker_window() -> u64 {
+    60
+}
+
+fn default_breaker_cooldown() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct DrainPolicy {
+    /// Closes a drained connection once nothing moved for this long
+    #[serde(default = "default_drain_idle")]
+    pub idle_secs: u64,
+    /// Closes a drained connection this long after the change regardless
+    #[serde(default = "default_drain_after")]
+    pub after_mins: u64,
+}
+
+fn default_drain_idle() -> u64 {
+    30
+}
+
+fn default_drain_after() -> u64 {
+    10
+}
+
+/// Network conditions added to relayed streams, in each direction
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Simulation {
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Up to this much more latency, picked at random for each chunk
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// Share of chunks, from 0 to 1, held back as if lost and sent again
+    #[serde(default)]
+    pub loss: f64,
+    /// Kilobits per second, like `max_kbps_per_conn`
+    #[serde(default)]
+    pub kbps: Option<u64>,
+}
+
+impl Config {
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        (self.connect_timeout_ms > 0).then(|| Duration::from_millis(self.connect_timeout_ms))
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    /// The config connections to `listener` use
+    pub fn for_listener(&self, listener: &Listener) -> Config {
+        let mut config = self.clone();
+        config.port = listener.port;
+        config.target = listener.target.clone();
+        if let Some(protocol) = listener.target_protocol {
+            config.target_protocol = protocol;
+        }
+        config.target_username = listener.target_username.clone();
+        config.target_password = listener.target_password.clone();
+        config.status = listener.status;
+        config.listeners = Vec::new();
+        config
+    }
+
+    /// Switches to profile `name` and turns the proxy on
+    pub fn use_profile(&mut self, name: &str) -> Result<()> {
+        let profile = self
+            .profiles
+            .iter()
+            .find(|profile| profile.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No profile named {}", name))?;
+        self.target = profile.target;
+        if let Some(protocol) = profile.target_protocol {
+            self.target_protocol = protocol;
+        }
+        self.target_username = profile.target_username;
+        self.target_password = profile.target_password;
+        self.profile = Some(profile.name);
+        self.status = true;
+        Ok(())
+    }
+
+    /// The username and password to offer the target proxy, if any
+    pub fn target_credentials(&self) -> Option<(String, String)> {
+        self.target_username
+            .clone()
+            .map(|username| (username, self.target_password.clone().unwrap_or_default()))
+    }
+
+    /// Whether a client connecting from `ip` may use the proxy
+    pub fn client_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny_clients.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow_clients.is_empty() || self.allow_clients.iter().any(|net| net.contains(&ip))
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             port: 1080,
-            target: "127.0.0.1:1081".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            compression: false,
+            target: Targets::from("127.0.0.1:1081".to_string()),
+            failover: Failover::default(),
+            warm_standby: None,
+            wake_on_lan: None,
+            balance: Balance::Ordered,
+            target_protocol: Protocol::Socks5,
+            target_transport: Transport::Tcp,
+            target_obfs: None,
+            target_compression: false,
+            target_username: None,
+            target_password: None,
+            profiles: Vec::new(),
+            profile: None,
             status: false,
+            listeners: Vec::new(),
+            schedule: Vec::new(),
+            network_rules: Vec::new(),
             systemd: false,
+            system_proxy: false,
+            pac_port: None,
+            pac_profiles: Vec::new(),
+            wpad: false,
+            wpad_profile: None,
+            port_mapping: false,
+            port_mapping_unauthenticated: false,
+            ddns: None,
+            mdns: false,
+            control: default_control(),
+            fleet: Vec::new(),
+            control_tokens: Vec::new(),
+            alerts: Vec::new(),
+            alert_webhook: None,
+            alert_desktop: false,
+            reachability_probe: None,
+            circuit_breaker: None,
+            event_log: None,
+            access_log: None,
+            aggregate_only: false,
+            record_failures: None,
+            rules: Rules::default(),
+            rule_lists: Vec::new(),
+            trace_sample_rate: 0.0,
+            commands: Commands::default(),
+            dns_mode: DnsMode::Remote,
+            fail_closed: false,
+            never_direct: false,
+            adaptive_routing: false,
+            fallback_direct: false,
+            dns_resolver: "1.1.1.1:53".to_string(),
+            dns_negative_ttl_secs: 5,
+            connect_timeout_ms: 10000,
+            idle_timeout_secs: 300,
+            max_kbps_per_conn: None,
+            max_kbps_total: None,
+            allow_clients: Vec::new(),
+            deny_clients: Vec::new(),
+            blocked_destinations: Vec::new(),
+            client_policies: Vec::new(),
+            simulate: None,
+            drain_on_rule_change: None,
         }
     }
 }
@@ -43,37 +995,172 @@ pub fn get_real_config_path() -> String {
     };
 }
 
-pub fn get_config() -> Config {
-    let args = get_args();
+/// How the config file is written
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
 
-    let config_path = get_real_config_path();
+/// The format `--format` names, or the one the config file's extension
+/// stands for, JSON otherwise
+pub fn config_format() -> ConfigFormat {
+    let format = get_args().get_one::<String>("format").cloned().or_else(|| {
+        Path::new(&get_real_config_path())
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+    });
+    match format.as_deref() {
+        Some("toml") => ConfigFormat::Toml,
+        Some("yaml" | "yml") => ConfigFormat::Yaml,
+        _ => ConfigFormat::Json,
+    }
+}
 
-    let mut config: Config = match || -> Result<Config> {
-        use std::fs::File;
-        let file = match File::open(config_path) {
-            Ok(file) => file,
-            Err(err) => {
-                error!("Failed to open config file");
-                return Err(err.into());
+/// The settings in `text`, before they are checked against [`Config`]
+fn parse_tree(text: &str, format: ConfigFormat) -> Result<serde_json::Value> {
+    Ok(match format {
+        ConfigFormat::Json => serde_json::from_str(text)?,
+        ConfigFormat::Toml => toml::from_str(text)?,
+        ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+    })
+}
+
+/// Reads a config in any version, returning the version it was in
+fn parse_config(text: &str, format: ConfigFormat) -> Result<(Config, u32)> {
+    let mut tree = parse_tree(text, format)?;
+    let version = upgrade(&mut tree)?;
+    Ok((serde_json::from_value(tree)?, version))
+}
+
+/// Rewrites a config file from an older version, keeping the old one next
+/// to it
+fn save_upgraded(config: &Config, text: &str, version: u32) -> Result<()> {
+    let backup = format!("{}.v{}.bak", get_real_config_path(), version);
+    std::fs::write(&backup, text)?;
+    save_config(config)?;
+    info!(
+        "Upgraded the config from version {} to {}, the old one is in {}",
+        version, CONFIG_VERSION, backup
+    );
+    Ok(())
+}
+
+fn format_config(config: &Config, format: ConfigFormat) -> Result<String> {
+    Ok(match format {
+        ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+        ConfigFormat::Toml => toml::to_string_pretty(config)?,
+        ConfigFormat::Yaml => serde_yaml::to_string(config)?,
+    })
+}
+
+/// The config file as it is, without the environment, the command line or
+/// imported rule lists
+fn read_file() -> Result<Config> {
+    let text = match std::fs::read_to_string(get_real_config_path()) {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Failed to open config file");
+            return Err(err.into());
+        }
+    };
+    match parse_config(&text, config_format()) {
+        Ok((mut config, version)) => {
+            if version < CONFIG_VERSION {
+                if let Err(err) = save_upgraded(&config, &text, version) {
+                    error!("Failed to save the upgraded config, upgrading it again next time");
+                    trace!("{}", err);
+                }
             }
-        };
-        match serde_json::from_reader(file) {
-            Ok(config) => Ok(config),
-            Err(err) => {
-                error!("Failed to parse config file");
-                return Err(err.into());
+            if let Some(state) = read_state() {
+                config.status = state.status;
+                for listener in &mut config.listeners {
+                    if let Some(status) = state.listeners.get(&listener.name) {
+                        listener.status = *status;
+                    }
+                }
             }
+            Ok(config)
         }
-    }() {
-        Ok(config) => config,
         Err(err) => {
-            trace!("{}", err);
-            error!("Warning: Using default config");
-            let config = Config::default();
-            let _ = save_config(&config);
-            config
+            error!("Failed to parse {}: {}", get_real_config_path(), err);
+            Err(err)
         }
-    };
+    }
+}
+
+/// The config file as it is, without the environment or command line
+pub fn read_config() -> Result<Config> {
+    let mut config = read_file()?;
+    let imported = rule_lists::load(&config.rule_lists);
+    config.rules.set_imported(imported);
+    Ok(config)
+}
+
+/// Logs every problem [`validate`] finds, failing if there are any
+fn check_config(config: Config) -> Result<Config> {
+    let problems = validate(&config);
+    if problems.is_empty() {
+        return Ok(config);
+    }
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    Err(anyhow::anyhow!(
+        "{} has {} problem(s)",
+        get_real_config_path(),
+        problems.len()
+    ))
+}
+
+/// Reads `TOGGLEPROXY_<name>`, leaving `current` when it is unset or invalid
+fn env_var<T>(name: &str, current: T, parse: impl Fn(&str) -> Option<T>) -> T {
+    let var = format!("TOGGLEPROXY_{}", name);
+    match std::env::var(&var) {
+        Ok(value) => match parse(value.trim()) {
+            Some(value) => value,
+            None => {
+                error!("Ignoring {}, {:?} is not a valid value", var, value);
+                current
+            }
+        },
+        Err(_) => current,
+    }
+}
+
+/// Overrides from `TOGGLEPROXY_PORT`, `_TARGET`, `_STATUS`, `_CONTROL`,
+/// `_TARGET_USERNAME`, `_TARGET_PASSWORD`, `_EVENT_LOG` and `_ACCESS_LOG`
+fn apply_env(mut config: Config) -> Config {
+    config.port = env_var("PORT", config.port, |value| value.parse().ok());
+    config.target = env_var("TARGET", config.target, |value| {
+        Some(Targets::from(value.to_string()))
+    });
+    config.status = env_var("STATUS", config.status, |value| {
+        match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "on" => Some(true),
+            "0" | "false" | "off" => Some(false),
+            _ => None,
+        }
+    });
+    config.control = env_var("CONTROL", config.control, |value| Some(value.to_string()));
+    config.target_username = env_var("TARGET_USERNAME", config.target_username, |value| {
+        Some(Some(value.to_string()))
+    });
+    config.target_password = env_var("TARGET_PASSWORD", config.target_password, |value| {
+        Some(Some(value.to_string()))
+    });
+    config.event_log = env_var("EVENT_LOG", config.event_log, |value| {
+        Some(Some(value.to_string()))
+    });
+    config.access_log = env_var("ACCESS_LOG", config.access_log, |value| {
+        Some(Some(value.to_string()))
+    });
+    config
+}
+
+fn apply_args(mut config: Config) -> Config {
+    let args = get_args();
 
     config.port = match args.get_one::<u16>("port") {
         Some(port) => *port,
@@ -81,17 +1168,131 @@ pub fn get_config() -> Config {
     };
 
     config.target = match args.get_one::<String>("target") {
-        Some(target) => target.to_owned(),
+        Some(target) => Targets::from(target.to_owned()),
         None => config.target,
     };
 
     return config;
 }
 
+/// The config file with `TOGGLEPROXY_*` environment variables on top and the
+/// command line on top of those. Defaults fill in what the file leaves out,
+/// and a missing file is created with the defaults. A file that doesn't parse
+/// or validate is an error rather than replaced, see [`init_config`]
+pub fn get_config() -> Result<Config> {
+    let config_path = get_real_config_path();
+    let config = match read_config() {
+        Ok(config) => config,
+        Err(err) if Path::new(&config_path).exists() => {
+            // Kept aside in case fixing it goes wrong
+            let backup = format!("{}.bak", config_path);
+            return match std::fs::copy(&config_path, &backup) {
+                Ok(_) => Err(anyhow::anyhow!(
+                    "{}\nA copy is kept in {}. Fix the file, or start over from the \
+                     defaults with `toggleproxy config init`",
+                    err,
+                    backup
+                )),
+                Err(_) => Err(err),
+            };
+        }
+        Err(err) => {
+            trace!("{}", err);
+            error!("Warning: Using default config");
+            let config = Config::default();
+            let _ = save_config(&config);
+            config
+        }
+    };
+
+    check_config(apply_args(apply_env(config)))
+}
+
+/// Writes the default config, moving an existing file to `.bak` first.
+/// Returns where it went
+pub fn init_config() -> Result<Option<String>> {
+    let config_path = get_real_config_path();
+    let backup = match Path::new(&config_path).exists() {
+        true => {
+            let backup = format!("{}.bak", config_path);
+            std::fs::rename(&config_path, &backup)?;
+            Some(backup)
+        }
+        false => None,
+    };
+    save_config(&Config::default())?;
+    Ok(backup)
+}
+
+/// Reads the config file again, with the environment and command line on top
+pub fn reload_config() -> Result<Config> {
+    check_config(apply_args(apply_env(read_config()?)))
+}
+
+/// Re-reads the config file whenever it changes and publishes it to `sender`.
+/// A file that fails to parse is skipped, so a half-written save never
+/// replaces a working config
+pub async fn watch_config(sender: Arc<watch::Sender<Config>>) -> Result<()> {
+    let config_path = PathBuf::from(get_real_config_path());
+    let file_name = config_path.file_name().map(|name| name.to_owned());
+
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == file_name.as_deref())
+            {
+                let _ = changed.send(());
+            }
+        }
+    })?;
+    // Editors often replace the file instead of writing to it, so watch the
+    // directory it is in
+    watcher.watch(
+        config_path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+        RecursiveMode::NonRecursive,
+    )?;
+
+    while changes.recv().await.is_some() {
+        // Saves usually come as several events
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while changes.try_recv().is_ok() {}
+
+        match reload_config() {
+            Ok(config) => {
+                if config.port != sender.borrow().port {
+                    error!("The port can't change while running, restart to use it");
+                }
+                info!("Config reloaded");
+                sender.send_replace(config);
+            }
+            Err(err) => {
+                error!("Keeping the current config");
+                trace!("{}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn save_config(config: &Config) -> Result<()> {
     let config_path = get_real_config_path();
 
-    let file = match std::fs::File::create(config_path) {
+    let text = match format_config(config, config_format()) {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Failed to encode config");
+            trace!("{}", err);
+            return Err(anyhow::anyhow!("Failed to encode config"));
+        }
+    };
+    let mut file = match std::fs::File::create(config_path) {
         Ok(file) => file,
         Err(err) => {
             error!("Failed to create config file");
@@ -99,7 +1300,7 @@ pub fn save_config(config: &Config) -> Result<()> {
             return Err(anyhow::anyhow!("Failed to create config file"));
         }
     };
-    match serde_json::to_writer_pretty(file, &config) {
+    match std::io::Write::write_all(&mut file, text.as_bytes()) {
         Ok(_) => {}
         Err(err) => {
             error!("Failed to write config file");
@@ -111,6 +1312,303 @@ pub fn save_config(config: &Config) -> Result<()> {
     Ok(())
 }
 
-pub fn stringify_config(config: &Config) -> String {
-    return serde_json::to_string_pretty(config).unwrap();
+/// The toggle, kept per user when the config file can't be written
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    status: bool,
+    /// Listener toggles by name
+    #[serde(default)]
+    listeners: HashMap<String, bool>,
+}
+
+fn state_path() -> PathBuf {
+    STATE_DIR.join("state.json")
+}
+
+fn read_state() -> Option<SavedState> {
+    let file = std::fs::File::open(state_path()).ok()?;
+    serde_json::from_reader(file).ok()
+}
+
+/// Where a toggle was saved
+pub enum Persisted {
+    Config,
+    /// The config file is read-only, the toggle only applies to this user
+    StateFile(PathBuf),
+}
+
+/// Changes the config file with `change` and saves it, returning the
+/// result. `change` gets the file as it is, so environment and command line
+/// overrides never end up in it
+pub fn update_config(change: impl FnOnce(&mut Config) -> Result<()>) -> Result<Config> {
+    let mut config = read_file()?;
+    change(&mut config)?;
+    save_config(&config)?;
+    // The config file has the status again
+    let _ = std::fs::remove_file(state_path());
+    Ok(config)
+}
+
+/// Copies the toggle and the listener toggles of `live` into `file`,
+/// leaving everything else as the file has it
+fn copy_toggles(file: &mut Config, live: &Config) {
+    file.status = live.status;
+    for listener in &mut file.listeners {
+        if let Some(live) = live
+            .listeners
+            .iter()
+            .find(|live| live.name == listener.name)
+        {
+            listener.status = live.status;
+        }
+    }
+}
+
+fn config_read_only() -> bool {
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .open(get_real_config_path())
+    {
+        Ok(_) => false,
+        Err(err) => matches!(
+            err.kind(),
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem
+        ),
+    }
+}
+
+/// Saves the toggle and the listener toggles of `config`, to the per-user
+/// state file when the config file is read-only. Nothing else is taken from
+/// `config`, which may carry overrides from the environment
+pub fn save_status(config: &Config) -> Result<Persisted> {
+    if !config_read_only() {
+        update_config(|file| {
+            copy_toggles(file, config);
+            Ok(())
+        })?;
+        return Ok(Persisted::Config);
+    }
+
+    let path = state_path();
+    std::fs::create_dir_all(STATE_DIR.as_path())?;
+    std::fs::write(
+        &path,
+        serde_json::to_vec(&SavedState {
+            status: config.status,
+            listeners: config
+                .listeners
+                .iter()
+                .map(|listener| (listener.name.clone(), listener.status))
+                .collect(),
+        })?,
+    )?;
+    Ok(Persisted::StateFile(path))
+}
+
+/// Switches the config file to the profile `name`
+pub fn save_profile(name: &str) -> Result<()> {
+    if config_read_only() {
+        return Err(anyhow::anyhow!("The config file is read-only"));
+    }
+    update_config(|file| file.use_profile(name))?;
+    Ok(())
+}
+
+/// Saves the `--port` and `--target` given on the command line to the
+/// config file, returning it and the settings that changed, see
+/// [`changed_settings`]
+pub fn save_args() -> Result<(Config, Vec<String>)> {
+    let mut before = None;
+    let after = update_config(|file| {
+        before = Some(file.clone());
+        *file = apply_args(std::mem::take(file));
+        Ok(())
+    })?;
+    let changed = match &before {
+        Some(before) => changed_settings(before, &after)?,
+        None => Vec::new(),
+    };
+    Ok((after, changed))
+}
+
+/// Whether a setting holds a password or token, which must not be logged
+fn is_secret(key: &str) -> bool {
+    key.contains("password") || key.contains("token")
+}
+
+/// `value` with every secret in it replaced
+fn redact(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => object
+            .into_iter()
+            .map(|(key, value)| match is_secret(&key) {
+                true => (key, "<redacted>".into()),
+                false => (key, redact(value)),
+            })
+            .collect(),
+        serde_json::Value::Array(array) => array.into_iter().map(redact).collect(),
+        value => value,
+    }
+}
+
+/// The top-level settings that differ between `before` and `after`, as
+/// `key=value` with passwords and tokens redacted
+pub fn changed_settings(before: &Config, after: &Config) -> Result<Vec<String>> {
+    let before = serde_json::to_value(before)?;
+    let after = serde_json::to_value(after)?;
+    let (before, after) = match (before.as_object(), after.as_object()) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return Ok(Vec::new()),
+    };
+    Ok(after
+        .iter()
+        .filter(|(key, value)| before.get(*key) != Some(value))
+        .map(|(key, value)| match is_secret(key) {
+            true => format!("{}=<redacted>", key),
+            false => format!("{}={}", key, redact(value.clone())),
+        })
+        .collect())
+}
+
+/// `key` as a JSON pointer, escaping what isn't a separator
+fn setting_pointer(key: &str) -> String {
+    key.split('.')
+        .map(|part| format!("/{}", part.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// The setting at `key`, a dotted path like `failover.rounds` or
+/// `rules.0.pattern`
+pub fn get_setting(config: &Config, key: &str) -> Result<serde_json::Value> {
+    serde_json::to_value(config)?
+        .pointer(&setting_pointer(key))
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No setting {}", key))
+}
+
+/// `config` with the setting at `key` changed to `value`, read as JSON or
+/// otherwise as a string, so `10.0.0.2:1080` needs no quotes. The result has
+/// to parse and validate like the config file would
+pub fn set_setting(config: &Config, key: &str, value: &str) -> Result<Config> {
+    let pointer = setting_pointer(key);
+    let tree = serde_json::to_value(config)?;
+    let existed = tree.pointer(&pointer).is_some();
+    let set = |value: serde_json::Value| -> Result<Config> {
+        let mut tree = tree.clone();
+        match tree.pointer_mut(&pointer) {
+            Some(setting) => *setting = value,
+            None => {
+                // Unset optional settings are left out, so add them to the
+                // section they belong in
+                let (parent, name) = key.rsplit_once('.').unwrap_or(("", key));
+                let section = match parent {
+                    "" => Some(&mut tree),
+                    _ => tree.pointer_mut(&setting_pointer(parent)),
+                };
+                match section.and_then(|section| section.as_object_mut()) {
+                    Some(section) => {
+                        section.insert(name.to_string(), value);
+                    }
+                    None => return Err(anyhow::anyhow!("No setting {}", key)),
+                }
+            }
+        }
+        let changed: Config = serde_json::from_value(tree)?;
+        // Unknown keys are dropped when the config is read, so a setting
+        // only exists if it was in the config before or is in it now
+        if !existed && serde_json::to_value(&changed)?.pointer(&pointer).is_none() {
+            return Err(anyhow::anyhow!("No setting {}", key));
+        }
+        check_config(changed)
+    };
+
+    match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(serde_json::Value::String(_)) | Err(_) => set(value.into()),
+        // `123` may be meant as a string too
+        Ok(json) => set(json).or_else(|err| set(value.into()).map_err(|_| err)),
+    }
+}
+
+/// The config as it would be saved
+pub fn stringify_config(config: &Config) -> Result<String> {
+    format_config(config, config_format())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(name: &str, status: bool) -> Listener {
+        Listener {
+            name: name.to_string(),
+            port: 1090,
+            target: Targets::from("127.0.0.1:1091".to_string()),
+            target_protocol: None,
+            target_username: None,
+            target_password: None,
+            status,
+        }
+    }
+
+    #[test]
+    fn saving_toggles_keeps_overrides_out_of_the_file() {
+        let mut file = Config {
+            target_password: Some("from-file".to_string()),
+            listeners: vec![listener("lab", false)],
+            ..Config::default()
+        };
+        // As TOGGLEPROXY_* and the command line leave the live config
+        let live = Config {
+            port: 2080,
+            target: Targets::from("10.0.0.2:1080".to_string()),
+            target_password: Some("from-env".to_string()),
+            control: "0.0.0.0:9090".to_string(),
+            event_log: Some("tcp://10.0.0.3:514".to_string()),
+            status: true,
+            listeners: vec![listener("lab", true), listener("other", true)],
+            ..Config::default()
+        };
+
+        copy_toggles(&mut file, &live);
+
+        assert!(file.status);
+        assert_eq!(file.port, Config::default().port);
+        assert_eq!(
+            file.target.to_string(),
+            Config::default().target.to_string()
+        );
+        assert_eq!(file.target_password.as_deref(), Some("from-file"));
+        assert_eq!(file.control, Config::default().control);
+        assert!(file.event_log.is_none());
+        // Listeners only the live config has aren't added
+        assert_eq!(file.listeners.len(), 1);
+        assert!(file.listeners[0].status);
+    }
+
+    #[test]
+    fn changed_settings_redact_secrets() {
+        let before = Config::default();
+        let after = Config {
+            port: 2080,
+            target_password: Some("hunter2".to_string()),
+            control_tokens: vec![ControlToken {
+                token: "admin-secret".to_string(),
+                name: Some("admin".to_string()),
+                role: ControlRole::Admin,
+                max_requests_per_min: None,
+            }],
+            ..Config::default()
+        };
+
+        let changed = changed_settings(&before, &after).unwrap();
+        assert_eq!(
+            changed,
+            [
+                "control_tokens=<redacted>",
+                "port=2080",
+                "target_password=<redacted>"
+            ]
+        );
+        assert!(changed_settings(&after, &after).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,1410 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use anyhow::Result;
+
+use log::{error, info, trace, warn};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use serde_json::Value;
+
+use sha2::{Digest, Sha256};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, Lines, ReadHalf, WriteHalf,
+    },
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch},
+};
+
+use crate::{
+    config::{
+        reload_config, save_profile, save_status, Config, ControlRole, ControlToken, Persisted,
+    },
+    events::{emit, subscribe, subscribe_since, Event, EventCursor, EventKind, INSTANCE},
+    health::{health, HealthReport},
+    listeners, policy,
+    reroute::{self, Via},
+    rule_lists, standby,
+    stats::{StatsReport, STATS},
+    sysproxy,
+    timing::{self, ConnectionTrace},
+    transport::BoxStream,
+    upstream,
+};
+
+/// A command sent to the running server, one JSON object per line
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Stats {
+        top: usize,
+        window_secs: Option<u64>,
+    },
+    Health,
+    /// Open connections with their timings so far
+    Connections,
+    /// Keeps the timing of connection `id` once it closes, or lists the kept
+    /// timings without an id. Under `aggregate_only`, `id` is answered with
+    /// its timing so far and nothing is kept
+    Trace {
+        id: Option<u64>,
+    },
+    Status,
+    /// Switches the proxy on or off for new connections and saves the
+    /// change, unless `ephemeral`. With `listener`, only switches that one
+    Toggle {
+        #[serde(default)]
+        ephemeral: bool,
+        #[serde(default)]
+        listener: Option<String>,
+    },
+    /// Switches the proxy on or off like `Toggle`, whatever it was before
+    Set {
+        status: bool,
+        #[serde(default)]
+        ephemeral: bool,
+        #[serde(default)]
+        listener: Option<String>,
+    },
+    /// Reads the config file again without waiting for the file watcher
+    Reload,
+    /// Loads the rule lists again, answering with how many rules they hold
+    ReloadData,
+    /// Closes open connection `id` and sends its destination `via` the
+    /// other route for `ttl_secs`, answering with the destination
+    Reroute {
+        id: u64,
+        via: Via,
+        #[serde(default = "default_reroute_ttl")]
+        ttl_secs: u64,
+    },
+    /// Stops relaying open connection `id` without closing it, answering with
+    /// its trace
+    Pause {
+        id: u64,
+    },
+    /// Relays a paused connection again
+    Resume {
+        id: u64,
+    },
+    /// Lets the clients of client policy `policy` through for `ttl_secs`
+    Lift {
+        policy: String,
+        #[serde(default = "default_lift_ttl")]
+        ttl_secs: u64,
+    },
+    /// Switches to the target proxies of profile `profile`, turns the proxy
+    /// on and saves both
+    Use {
+        profile: String,
+    },
+    /// Answers with [`Subscribed`], then streams every event as a line until
+    /// disconnected. With `resume`, the kept events after it come first
+    Events {
+        #[serde(default)]
+        resume: Option<EventCursor>,
+    },
+}
+
+fn default_reroute_ttl() -> u64 {
+    600
+}
+
+fn default_lift_ttl() -> u64 {
+    3600
+}
+
+impl Request {
+    /// Whether a read-only token may send it
+    fn read_only(&self) -> bool {
+        match self {
+            Request::Stats { .. }
+            | Request::Health
+            | Request::Connections
+            | Request::Trace { .. }
+            | Request::Status
+            | Request::Events { .. } => true,
+            Request::Toggle { .. }
+            | Request::Set { .. }
+            | Request::Reload
+            | Request::ReloadData
+            | Request::Reroute { .. }
+            | Request::Pause { .. }
+            | Request::Resume { .. }
+            | Request::Lift { .. }
+            | Request::Use { .. } => false,
+        }
+    }
+}
+
+/// What the running server is doing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+    pub status: bool,
+    pub targets: Vec<String>,
+    pub port: u16,
+    #[serde(default)]
+    pub uptime_secs: u64,
+    #[serde(default)]
+    pub open_tunnels: u64,
+    /// The target proxy the latest connection went through, while the proxy
+    /// is on
+    #[serde(default)]
+    pub active_upstream: Option<String>,
+    /// Target proxies with connections kept ready by `warm_standby`
+    #[serde(default)]
+    pub warm_standby: Vec<String>,
+    /// The profile last switched to with `use`
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The other ports served, see [`crate::config::Listener`]
+    #[serde(default)]
+    pub listeners: Vec<ListenerStatus>,
+    /// Where the router forwards to the proxy, once `port_mapping` succeeded
+    #[serde(default)]
+    pub external_addr: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListenerStatus {
+    pub name: String,
+    pub port: u16,
+    pub status: bool,
+}
+
+impl Status {
+    /// The status of a server that has been running since `started`
+    pub(crate) fn new(config: &Config, port: u16, started: Instant) -> Self {
+        Status {
+            status: config.status,
+            targets: config.target.0.clone(),
+            port,
+            uptime_secs: started.elapsed().as_secs(),
+            open_tunnels: STATS.open_tunnels(),
+            active_upstream: match config.status {
+                true => upstream::last_used(),
+                false => None,
+            },
+            warm_standby: standby::ready_targets(),
+            profile: config.profile.clone(),
+            listeners: listeners::statuses()
+                .into_iter()
+                .map(|(name, port, status)| ListenerStatus { name, port, status })
+                .collect(),
+            #[cfg(feature = "upnp")]
+            external_addr: crate::portmap::external_addr(),
+            #[cfg(not(feature = "upnp"))]
+            external_addr: None,
+        }
+    }
+
+    fn of(config: &Config) -> Self {
+        Status::new(config, config.port, *STARTED)
+    }
+}
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Longest message sent in an error, so a failure can't produce an unbounded reply
+const MAX_ERROR_MESSAGE: usize = 512;
+
+/// Longest request line read, checked before the token so unauthenticated
+/// clients can't make the server buffer without end
+const MAX_REQUEST_LINE: usize = 64 * 1024;
+
+/// Unknown tokens one address may send a minute, slowing down guessing
+const MAX_UNKNOWN_TOKENS: u32 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request wasn't valid JSON or not a known command
+    InvalidRequest,
+    /// The request carried no token, or one the server doesn't know
+    Unauthorized,
+    /// The request's token is read-only and the command changes something
+    Forbidden,
+    /// The token sent more requests this minute than it may, or its address
+    /// sent too many unknown tokens
+    RateLimited,
+    /// The request named something that doesn't exist, like a closed connection
+    NotFound,
+    /// The server couldn't be reached
+    Unavailable,
+    Internal,
+}
+
+/// Why a control request failed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlError {
+    pub code: ErrorCode,
+    /// Whether the same request may succeed later
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl ControlError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        let mut message: String = message.into();
+        if message.len() > MAX_ERROR_MESSAGE {
+            let mut end = MAX_ERROR_MESSAGE;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        ControlError {
+            code,
+            retryable: matches!(
+                code,
+                ErrorCode::Unavailable | ErrorCode::Internal | ErrorCode::RateLimited
+            ),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Ok(Value),
+    Error(ControlError),
+}
+
+fn respond<T: Serialize>(value: T) -> Response {
+    match serde_json::to_value(value) {
+        Ok(value) => Response::Ok(value),
+        Err(err) => Response::Error(ControlError::new(ErrorCode::Internal, err.to_string())),
+    }
+}
+
+/// Switches the proxy on or off, saving the change unless `ephemeral`. The
+/// schedule and network rules toggle through this too, so every toggle the
+/// server makes updates the system proxy
+pub(crate) fn set_status(live: &watch::Sender<Config>, status: bool, ephemeral: bool) -> Response {
+    let mut config = live.borrow().clone();
+    let changed = config.status != status;
+    config.status = status;
+    if !ephemeral {
+        // Nothing changes when the toggle can't be saved
+        match save_status(&config) {
+            Ok(Persisted::Config) => {}
+            Ok(Persisted::StateFile(path)) => {
+                warn!(
+                    "Config file is read-only, saved the toggle to {}",
+                    path.display()
+                );
+            }
+            Err(err) => {
+                return Response::Error(ControlError::new(
+                    ErrorCode::Internal,
+                    format!("Failed to save the toggle: {}", err),
+                ))
+            }
+        }
+    }
+    if changed {
+        emit(EventKind::Toggle { status });
+        sync_system_proxy(&config);
+    }
+    let response = Status::of(&config);
+    live.send_replace(config);
+    respond(response)
+}
+
+/// Points the OS proxy the way the proxy was toggled, with `system_proxy`
+fn sync_system_proxy(config: &Config) {
+    if !config.system_proxy {
+        return;
+    }
+    match sysproxy::sysproxy_sync(config) {
+        Ok(_) => info!("System proxy updated"),
+        Err(err) => error!("Failed to update system proxy: {}", err),
+    }
+}
+
+fn set_listener_status(
+    live: &watch::Sender<Config>,
+    name: &str,
+    status: bool,
+    ephemeral: bool,
+) -> Response {
+    if listeners::listener(name).is_none() {
+        return Response::Error(ControlError::new(
+            ErrorCode::NotFound,
+            format!("No listener named {} is running", name),
+        ));
+    }
+    match listeners::set_status(live, name, status, ephemeral) {
+        Ok(config) => {
+            info!(
+                "Switched listener {} {}",
+                name,
+                match status {
+                    true => "on",
+                    false => "off",
+                }
+            );
+            respond(Status::of(&config))
+        }
+        Err(err) => Response::Error(ControlError::new(
+            ErrorCode::Internal,
+            format!("Failed to save the toggle: {}", err),
+        )),
+    }
+}
+
+fn use_profile(live: &watch::Sender<Config>, profile: &str) -> Response {
+    let mut config = live.borrow().clone();
+    let was_on = config.status;
+    if let Err(err) = config.use_profile(profile) {
+        return Response::Error(ControlError::new(ErrorCode::NotFound, err.to_string()));
+    }
+    // Also clears a toggle saved aside, which would turn the proxy back off
+    if let Err(err) = save_profile(profile) {
+        return Response::Error(ControlError::new(
+            ErrorCode::Internal,
+            format!("Failed to save the profile: {}", err),
+        ));
+    }
+    info!("Switched to profile {}", profile);
+    if !was_on {
+        emit(EventKind::Toggle { status: true });
+        sync_system_proxy(&config);
+    }
+    let response = Status::of(&config);
+    live.send_replace(config);
+    respond(response)
+}
+
+fn paused(id: u64, paused: bool) -> Response {
+    match timing::set_paused(id, paused) {
+        Some(trace) => respond(trace),
+        None => Response::Error(ControlError::new(
+            ErrorCode::NotFound,
+            format!("No open connection with id {}", id),
+        )),
+    }
+}
+
+async fn dispatch(request: Request, live: &watch::Sender<Config>) -> Response {
+    match request {
+        Request::Stats { top, window_secs } => {
+            respond(STATS.report(top, window_secs.map(Duration::from_secs)))
+        }
+        Request::Health => respond(health()),
+        Request::Connections => respond(timing::live()),
+        Request::Trace { id: Some(id) } => {
+            // Kept traces go to the event log, which only gets totals
+            let trace = match live.borrow().aggregate_only {
+                true => timing::trace(id),
+                false => timing::sample(id),
+            };
+            match trace {
+                Some(trace) => respond(vec![trace]),
+                None => Response::Error(ControlError::new(
+                    ErrorCode::NotFound,
+                    format!("No open connection with id {}", id),
+                )),
+            }
+        }
+        Request::Trace { id: None } => respond(timing::recent()),
+        Request::Status => respond(Status::of(&live.borrow())),
+        Request::Toggle {
+            ephemeral,
+            listener: None,
+        } => {
+            let status = !live.borrow().status;
+            set_status(live, status, ephemeral)
+        }
+        Request::Toggle {
+            ephemeral,
+            listener: Some(name),
+        } => match listeners::listener(&name) {
+            Some(listener) => {
+                let status = !listener.borrow().status;
+                set_listener_status(live, &name, status, ephemeral)
+            }
+            None => set_listener_status(live, &name, false, ephemeral),
+        },
+        Request::Set {
+            status,
+            ephemeral,
+            listener: None,
+        } => set_status(live, status, ephemeral),
+        Request::Set {
+            status,
+            ephemeral,
+            listener: Some(name),
+        } => set_listener_status(live, &name, status, ephemeral),
+        Request::Reload => match reload_config() {
+            Ok(config) => {
+                if config.port != live.borrow().port {
+                    error!("The port can't change while running, restart to use it");
+                }
+                info!("Config reloaded");
+                let status = Status::of(&config);
+                live.send_replace(config);
+                respond(status)
+            }
+            Err(err) => Response::Error(ControlError::new(ErrorCode::Internal, err.to_string())),
+        },
+        Request::ReloadData => respond(rule_lists::reload(live).await),
+        Request::Reroute { id, via, ttl_secs } => {
+            match reroute::reroute(id, via, Duration::from_secs(ttl_secs)) {
+                Some(target) => respond(target),
+                None => Response::Error(ControlError::new(
+                    ErrorCode::NotFound,
+                    format!("No open connection with id {}", id),
+                )),
+            }
+        }
+        Request::Pause { id } => paused(id, true),
+        Request::Resume { id } => paused(id, false),
+        Request::Lift { policy, ttl_secs } => {
+            let known = live
+                .borrow()
+                .client_policies
+                .iter()
+                .any(|known| known.name == policy);
+            match known {
+                true => {
+                    policy::lift(&policy, Duration::from_secs(ttl_secs));
+                    respond(())
+                }
+                false => Response::Error(ControlError::new(
+                    ErrorCode::NotFound,
+                    format!("No client policy named {}", policy),
+                )),
+            }
+        }
+        Request::Use { profile } => use_profile(live, &profile),
+        Request::Events { .. } => respond(Subscribed {
+            instance: *INSTANCE,
+            missed: 0,
+        }),
+    }
+}
+
+async fn stream_events<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    backlog: Vec<Event>,
+    mut events: broadcast::Receiver<Event>,
+) -> Result<()> {
+    for event in backlog {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let mut line = serde_json::to_vec(&event)?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                trace!("Control client fell behind by {} events", missed)
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Compares without stopping at the first difference, so a token can't be
+/// guessed a byte at a time from how long the answer takes
+fn same_token(known: &str, given: &str) -> bool {
+    known.len() == given.len()
+        && known
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads the next request line, `None` once the client is done. A line longer
+/// than [`MAX_REQUEST_LINE`] is an error, read only as far as the limit
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Result<String, ControlError>>> {
+    let mut line = Vec::new();
+    let limit = MAX_REQUEST_LINE as u64 + 1;
+    if reader.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    if line.len() > MAX_REQUEST_LINE {
+        return Ok(Some(Err(ControlError::new(
+            ErrorCode::InvalidRequest,
+            format!("Requests are at most {} bytes", MAX_REQUEST_LINE),
+        ))));
+    }
+    Ok(Some(String::from_utf8(line).map_err(|_| {
+        ControlError::new(ErrorCode::InvalidRequest, "Invalid request: not UTF-8")
+    })))
+}
+
+/// A request line as audited when it couldn't be read, without its token
+fn without_token(line: &str) -> Value {
+    let mut value = serde_json::from_str::<Value>(line).unwrap_or(Value::Null);
+    if let Some(object) = value.as_object_mut() {
+        object.remove("token");
+    }
+    value
+}
+
+fn audit_result(err: &ControlError) -> String {
+    format!("{:?}: {}", err.code, err.message)
+}
+
+/// Records a request refused before it could be read, so malformed attempts
+/// also leave their source behind
+fn audit_refused(request: Value, source: &str, err: &ControlError) {
+    emit(EventKind::Control {
+        request,
+        token_id: None,
+        source: source.to_string(),
+        result: audit_result(err),
+    });
+}
+
+/// Reads a request line and the `token` it carries
+fn read_request(line: &str) -> Result<(Request, Option<String>), ControlError> {
+    let invalid = |err: serde_json::Error| {
+        ControlError::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid request: {}", err),
+        )
+    };
+    let mut value = serde_json::from_str::<Value>(line).map_err(invalid)?;
+    let token = value
+        .as_object_mut()
+        .and_then(|object| object.remove("token"))
+        .and_then(|token| token.as_str().map(str::to_string));
+    let request = serde_json::from_value::<Request>(value).map_err(invalid)?;
+    Ok((request, token))
+}
+
+/// The known token `request` carries, which it has to once `control_tokens`
+/// has any. A `source` that sent too many unknown tokens has further unknown
+/// ones refused as rate limited until the minute is over, known tokens still
+/// work
+fn authorize(
+    request: &Request,
+    token: Option<&str>,
+    tokens: &[ControlToken],
+    source: &str,
+) -> Result<Option<ControlToken>, ControlError> {
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let token = token.unwrap_or_default();
+    match tokens.iter().find(|known| same_token(&known.token, token)) {
+        None if !within_rate(
+            &format!("unknown tokens from {}", source),
+            MAX_UNKNOWN_TOKENS,
+        ) =>
+        {
+            Err(ControlError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "More than {} unknown tokens a minute from this address",
+                    MAX_UNKNOWN_TOKENS
+                ),
+            ))
+        }
+        None => Err(ControlError::new(
+            ErrorCode::Unauthorized,
+            "Missing or unknown token",
+        )),
+        Some(known) if known.role == ControlRole::ReadOnly && !request.read_only() => Err(
+            ControlError::new(ErrorCode::Forbidden, "This token can only read"),
+        ),
+        Some(known) => {
+            if let Some(limit) = known.max_requests_per_min {
+                if !within_rate(&token_id(known), limit) {
+                    return Err(ControlError::new(
+                        ErrorCode::RateLimited,
+                        format!("More than {} requests a minute with this token", limit),
+                    ));
+                }
+            }
+            Ok(Some(known.clone()))
+        }
+    }
+}
+
+/// How a token shows up in audit events, its name or a short hash of it
+fn token_id(token: &ControlToken) -> String {
+    match &token.name {
+        Some(name) => name.clone(),
+        None => Sha256::digest(token.token.as_bytes())[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    }
+}
+
+lazy_static! {
+    /// Requests each token made in the current minute, and when it started
+    static ref REQUESTS: Mutex<HashMap<String, (Instant, u32)>> = Mutex::new(HashMap::new());
+}
+
+/// Counts a request with token `id`, returning whether it is within `limit`
+/// a minute
+fn within_rate(id: &str, limit: u32) -> bool {
+    let mut requests = REQUESTS.lock().unwrap();
+    if !requests.contains_key(id) {
+        // Only ids seen this minute are kept, or every address that ever sent
+        // an unknown token would stay
+        requests.retain(|_, (started, _)| started.elapsed() < Duration::from_secs(60));
+    }
+    let (started, count) = requests
+        .entry(id.to_string())
+        .or_insert((Instant::now(), 0));
+    if started.elapsed() >= Duration::from_secs(60) {
+        *started = Instant::now();
+        *count = 0;
+    }
+    *count += 1;
+    *count <= limit
+}
+
+/// Serves the requests of one control connection from `source`, the client
+/// address or `local` for a Unix socket
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    source: String,
+    live: Arc<watch::Sender<Config>>,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    while let Some(line) = read_line(&mut reader).await? {
+        let line = match line {
+            Ok(line) => line,
+            // Nothing after an overlong line can be told apart from it
+            Err(err) => {
+                audit_refused(Value::Null, &source, &err);
+                write_response(&mut writer, &Response::Error(err)).await?;
+                return Ok(());
+            }
+        };
+        let (request, token) = match read_request(&line) {
+            Ok(read) => read,
+            Err(err) => {
+                audit_refused(without_token(&line), &source, &err);
+                write_response(&mut writer, &Response::Error(err)).await?;
+                continue;
+            }
+        };
+        let authorized = authorize(
+            &request,
+            token.as_deref(),
+            &live.borrow().control_tokens,
+            &source,
+        );
+        // Every change is recorded, including refused attempts
+        let audited = match request.read_only() {
+            true => None,
+            false => Some(serde_json::to_value(&request)?),
+        };
+        // Subscribe before answering so no event is missed in between
+        let events = match (&request, &authorized) {
+            (
+                Request::Events {
+                    resume: Some(cursor),
+                },
+                Ok(_),
+            ) => Some(subscribe_since(*cursor)),
+            (Request::Events { resume: None }, Ok(_)) => Some((Vec::new(), 0, subscribe())),
+            _ => None,
+        };
+        let response = match (&authorized, &events) {
+            (Ok(_), Some((_, missed, _))) => respond(Subscribed {
+                instance: *INSTANCE,
+                missed: *missed,
+            }),
+            (Ok(_), None) => dispatch(request, &live).await,
+            (Err(err), _) => Response::Error(err.clone()),
+        };
+
+        if let Some(request) = audited {
+            emit(EventKind::Control {
+                request,
+                token_id: authorized.ok().flatten().map(|known| token_id(&known)),
+                source: source.clone(),
+                result: match &response {
+                    Response::Ok(_) => "ok".to_string(),
+                    Response::Error(err) => audit_result(err),
+                },
+            });
+        }
+        write_response(&mut writer, &response).await?;
+
+        if let Some((backlog, _, events)) = events {
+            return stream_events(writer, backlog, events).await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &Response) -> Result<()> {
+    let mut response = serde_json::to_vec(response)?;
+    response.push(b'\n');
+    writer.write_all(&response).await?;
+    Ok(())
+}
+
+fn spawn_serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    source: String,
+    live: Arc<watch::Sender<Config>>,
+) {
+    tokio::spawn(async move {
+        match serve(stream, source, live).await {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Control connection failed");
+                trace!("{}", err);
+            }
+        }
+    });
+}
+
+/// Listens for control commands on `config.control`, either a host:port for
+/// localhost TCP or the path of a Unix socket. `live` holds the config
+/// new connections use
+pub async fn control_server(config: Config, live: Arc<watch::Sender<Config>>) -> Result<()> {
+    lazy_static::initialize(&STARTED);
+    if let Ok(addr) = config.control.parse::<SocketAddr>() {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Control socket listening on {}", addr);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            spawn_serve(stream, peer.ip().to_string(), live.clone());
+        }
+    }
+
+    control_server_unix(&config.control, live).await
+}
+
+#[cfg(unix)]
+async fn control_server_unix(path: &str, live: Arc<watch::Sender<Config>>) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        if !dir.as_os_str().is_empty() && !dir.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+    }
+    // A socket left behind by a previous run would make bind fail, but
+    // anything else there, or a socket of another user, is not ours to remove
+    if let Ok(existing) = std::fs::symlink_metadata(path) {
+        let uid = unsafe { libc::getuid() };
+        if !existing.file_type().is_socket() || existing.uid() != uid {
+            return Err(anyhow::anyhow!(
+                "{} exists and is not a socket of this user, not replacing it",
+                path
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_serve(stream, "local".to_string(), live.clone());
+    }
+}
+
+#[cfg(not(unix))]
+async fn control_server_unix(path: &str, _live: Arc<watch::Sender<Config>>) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Control socket {} must be a host:port address on this platform",
+        path
+    ))
+}
+
+/// The answer to [`Request::Events`]
+#[derive(Serialize, Deserialize)]
+pub struct Subscribed {
+    /// Sent back in the cursor to resume from, see [`EventCursor`]
+    pub instance: u64,
+    /// Events after the cursor that were no longer kept
+    pub missed: u64,
+}
+
+/// A connection-per-request client for the control socket of a running server
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    token: Option<String>,
+}
+
+/// Events from the running server, see [`Client::events`] and
+/// [`Client::follow_events`]
+pub struct EventStream {
+    client: Client,
+    follow: bool,
+    /// Unknown to servers from before sequence numbers
+    cursor: Option<EventCursor>,
+    missed: u64,
+    lines: Lines<BufReader<ReadHalf<BoxStream>>>,
+    _writer: WriteHalf<BoxStream>,
+}
+
+/// The first wait before reconnecting a followed stream, doubling up to
+/// `MAX_RECONNECT`
+const MIN_RECONNECT: Duration = Duration::from_millis(500);
+const MAX_RECONNECT: Duration = Duration::from_secs(30);
+
+impl EventStream {
+    /// Waits for the next event. `None` once the server goes away, unless
+    /// following, which reconnects and picks up where it left off
+    pub async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            let line = match self.lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) | Err(_) if self.follow => {
+                    self.reconnect().await;
+                    continue;
+                }
+                Ok(None) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            let event: Event = serde_json::from_str(&line)?;
+            if let Some(cursor) = self.cursor.as_mut() {
+                cursor.seq = event.seq;
+            }
+            return Ok(Some(event));
+        }
+    }
+
+    /// Events lost across reconnects, because the server no longer kept them
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    async fn reconnect(&mut self) {
+        let mut wait = MIN_RECONNECT;
+        loop {
+            tokio::time::sleep(wait).await;
+            match self.client.subscribe(self.cursor, true).await {
+                Ok(stream) => {
+                    trace!("Reconnected to the event stream");
+                    let missed = self.missed + stream.missed;
+                    *self = stream;
+                    self.missed = missed;
+                    return;
+                }
+                Err(err) => {
+                    trace!("Failed to reconnect to the event stream: {}", err);
+                    wait = (wait * 2).min(MAX_RECONNECT);
+                }
+            }
+        }
+    }
+}
+
+impl Client {
+    /// `endpoint` is a host:port or the path of a Unix socket, like `control`
+    /// in the config
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Client {
+            endpoint: endpoint.into(),
+            token: None,
+        }
+    }
+
+    /// A client for this instance. The token comes from `TOGGLEPROXY_TOKEN`,
+    /// or is the first admin token in the config, which only whoever can
+    /// read it has
+    pub fn from_config(config: &Config) -> Self {
+        let token = std::env::var("TOGGLEPROXY_TOKEN").ok().or_else(|| {
+            config
+                .control_tokens
+                .iter()
+                .find(|known| known.role == ControlRole::Admin)
+                .map(|known| known.token.clone())
+        });
+        Client {
+            endpoint: config.control.clone(),
+            token,
+        }
+    }
+
+    /// Sends `token` with every request
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// A client with the same token for another endpoint, like an instance
+    /// in `fleet`
+    pub fn with_endpoint(&self, endpoint: impl Into<String>) -> Self {
+        Client {
+            endpoint: endpoint.into(),
+            token: self.token.clone(),
+        }
+    }
+
+    async fn connect(&self) -> Result<BoxStream> {
+        let not_running = |err: std::io::Error| {
+            trace!("{}", err);
+            ControlError::new(
+                ErrorCode::Unavailable,
+                format!(
+                    "Failed to reach the proxy server at {}, is it running?",
+                    self.endpoint
+                ),
+            )
+        };
+
+        if let Ok(addr) = self.endpoint.parse::<SocketAddr>() {
+            let stream = TcpStream::connect(addr).await.map_err(not_running)?;
+            return Ok(Box::new(stream));
+        }
+
+        #[cfg(unix)]
+        {
+            let stream = tokio::net::UnixStream::connect(&self.endpoint)
+                .await
+                .map_err(not_running)?;
+            Ok(Box::new(stream))
+        }
+
+        #[cfg(not(unix))]
+        Err(anyhow::anyhow!(
+            "Control socket {} must be a host:port address on this platform",
+            self.endpoint
+        ))
+    }
+
+    /// Sends `request` and waits for its answer, leaving the connection open
+    async fn open(
+        &self,
+        request: &Request,
+    ) -> Result<(
+        Value,
+        Lines<BufReader<ReadHalf<BoxStream>>>,
+        WriteHalf<BoxStream>,
+    )> {
+        let (reader, mut writer) = tokio::io::split(self.connect().await?);
+
+        let mut request = serde_json::to_value(request)?;
+        if let (Some(token), Some(object)) = (&self.token, request.as_object_mut()) {
+            object.insert("token".to_string(), Value::String(token.clone()));
+        }
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+
+        let mut lines = BufReader::new(reader).lines();
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => {
+                return Err(ControlError::new(
+                    ErrorCode::Unavailable,
+                    "The server closed the control connection",
+                )
+                .into())
+            }
+        };
+
+        match serde_json::from_str::<Response>(&line)? {
+            Response::Ok(value) => Ok((value, lines, writer)),
+            Response::Error(err) => Err(err.into()),
+        }
+    }
+
+    /// Sends a single command, failures are [`ControlError`]s where the
+    /// server or the connection to it failed
+    pub async fn request(&self, request: &Request) -> Result<Value> {
+        Ok(self.open(request).await?.0)
+    }
+
+    async fn typed<T: DeserializeOwned>(&self, request: &Request) -> Result<T> {
+        Ok(serde_json::from_value(self.request(request).await?)?)
+    }
+
+    pub async fn status(&self) -> Result<Status> {
+        self.typed(&Request::Status).await
+    }
+
+    /// Switches the proxy on or off, returning the new status
+    pub async fn toggle(&self) -> Result<Status> {
+        self.typed(&Request::Toggle {
+            ephemeral: false,
+            listener: None,
+        })
+        .await
+    }
+
+    /// Toggles without saving, the server goes back to the saved state when
+    /// it restarts
+    pub async fn toggle_ephemeral(&self) -> Result<Status> {
+        self.typed(&Request::Toggle {
+            ephemeral: true,
+            listener: None,
+        })
+        .await
+    }
+
+    /// Switches the proxy to `status`, saving it unless `ephemeral`
+    pub async fn set(&self, status: bool, ephemeral: bool) -> Result<Status> {
+        self.typed(&Request::Set {
+            status,
+            ephemeral,
+            listener: None,
+        })
+        .await
+    }
+
+    /// Switches listener `name` on or off, returning its new status
+    pub async fn toggle_listener(&self, name: &str, ephemeral: bool) -> Result<Status> {
+        self.typed(&Request::Toggle {
+            ephemeral,
+            listener: Some(name.to_string()),
+        })
+        .await
+    }
+
+    /// Makes the server read its config file again
+    pub async fn reload(&self) -> Result<Status> {
+        self.typed(&Request::Reload).await
+    }
+
+    /// Makes the server load its rule lists again, returning how many rules
+    /// they hold
+    pub async fn reload_data(&self) -> Result<usize> {
+        self.typed(&Request::ReloadData).await
+    }
+
+    /// Closes connection `id` and sends its destination `via` the given route
+    /// for `ttl`, returning the destination
+    pub async fn reroute(&self, id: u64, via: Via, ttl: Duration) -> Result<String> {
+        self.typed(&Request::Reroute {
+            id,
+            via,
+            ttl_secs: ttl.as_secs(),
+        })
+        .await
+    }
+
+    /// Lets the clients of client policy `policy` through for `ttl`
+    pub async fn lift(&self, policy: &str, ttl: Duration) -> Result<()> {
+        self.request(&Request::Lift {
+            policy: policy.to_string(),
+            ttl_secs: ttl.as_secs(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Switches the server to profile `profile` and turns the proxy on
+    pub async fn use_profile(&self, profile: &str) -> Result<Status> {
+        self.typed(&Request::Use {
+            profile: profile.to_string(),
+        })
+        .await
+    }
+
+    /// Stops relaying connection `id` until it is resumed
+    pub async fn pause(&self, id: u64) -> Result<ConnectionTrace> {
+        self.typed(&Request::Pause { id }).await
+    }
+
+    pub async fn resume(&self, id: u64) -> Result<ConnectionTrace> {
+        self.typed(&Request::Resume { id }).await
+    }
+
+    pub async fn health(&self) -> Result<HealthReport> {
+        self.typed(&Request::Health).await
+    }
+
+    pub async fn stats(&self, top: usize, window: Option<Duration>) -> Result<StatsReport> {
+        self.typed(&Request::Stats {
+            top,
+            window_secs: window.map(|window| window.as_secs()),
+        })
+        .await
+    }
+
+    pub async fn connections(&self) -> Result<Vec<ConnectionTrace>> {
+        self.typed(&Request::Connections).await
+    }
+
+    /// Keeps the timing of open connection `id` once it closes, or lists the
+    /// kept timings without an id
+    pub async fn trace(&self, id: Option<u64>) -> Result<Vec<ConnectionTrace>> {
+        self.typed(&Request::Trace { id }).await
+    }
+
+    /// Subscribes to the events the server emits from now on
+    pub async fn events(&self) -> Result<EventStream> {
+        self.subscribe(None, false).await
+    }
+
+    /// Like [`Client::events`], but reconnects whenever the connection drops
+    /// or the server restarts, without losing the events in between unless
+    /// there were too many
+    pub async fn follow_events(&self) -> Result<EventStream> {
+        self.subscribe(None, true).await
+    }
+
+    async fn subscribe(&self, resume: Option<EventCursor>, follow: bool) -> Result<EventStream> {
+        let (value, lines, writer) = self.open(&Request::Events { resume }).await?;
+        let subscribed = serde_json::from_value::<Subscribed>(value).ok();
+        Ok(EventStream {
+            client: self.clone(),
+            follow,
+            // A new server run starts over
+            cursor: subscribed.as_ref().map(|subscribed| EventCursor {
+                instance: subscribed.instance,
+                seq: match resume {
+                    Some(resume) if resume.instance == subscribed.instance => resume.seq,
+                    _ => 0,
+                },
+            }),
+            missed: subscribed.map_or(0, |subscribed| subscribed.missed),
+            lines,
+            _writer: writer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn admin_token() -> ControlToken {
+        ControlToken {
+            token: "secret".to_string(),
+            role: ControlRole::Admin,
+            name: Some("admin".to_string()),
+            max_requests_per_min: None,
+        }
+    }
+
+    /// Serves a control connection from `source` with `admin_token` required,
+    /// returning the client's end
+    fn connect(source: &str) -> BufReader<DuplexStream> {
+        let config = Config {
+            control_tokens: vec![admin_token()],
+            ..Config::default()
+        };
+        let live = Arc::new(watch::channel(config).0);
+        let (client, server) = tokio::io::duplex(1024);
+        let source = source.to_string();
+        tokio::spawn(async move { serve(server, source, live).await });
+        BufReader::new(client)
+    }
+
+    async fn request(client: &mut BufReader<DuplexStream>, line: &str) -> Response {
+        client.write_all(line.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_line(&mut response).await.unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    /// The audit of the next control request from `source`
+    async fn audited(events: &mut broadcast::Receiver<Event>, source: &str) -> (Value, String) {
+        loop {
+            if let EventKind::Control {
+                request,
+                source: from,
+                result,
+                ..
+            } = events.recv().await.unwrap().kind
+            {
+                if from == source {
+                    return (request, result);
+                }
+            }
+        }
+    }
+
+    fn code(response: Response) -> ErrorCode {
+        match response {
+            Response::Error(err) => err.code,
+            Response::Ok(value) => panic!("Succeeded with {}", value),
+        }
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_refused_until_the_minute_is_over() {
+        let id = "rate test";
+        assert!(within_rate(id, 2));
+        assert!(within_rate(id, 2));
+        assert!(!within_rate(id, 2));
+
+        REQUESTS.lock().unwrap().get_mut(id).unwrap().0 -= Duration::from_secs(60);
+        assert!(within_rate(id, 2));
+    }
+
+    #[test]
+    fn ids_from_past_minutes_are_dropped() {
+        let stale = "rate test, stale";
+        within_rate(stale, 2);
+        REQUESTS.lock().unwrap().get_mut(stale).unwrap().0 -= Duration::from_secs(60);
+
+        within_rate("rate test, new", 2);
+        assert!(!REQUESTS.lock().unwrap().contains_key(stale));
+    }
+
+    #[tokio::test]
+    async fn refused_toggles_are_audited() {
+        let source = "192.0.2.10";
+        let mut events = subscribe();
+        let mut client = connect(source);
+
+        let response = request(
+            &mut client,
+            "{\"command\":\"toggle\",\"token\":\"wrong\"}\n",
+        )
+        .await;
+        assert_eq!(code(response), ErrorCode::Unauthorized);
+
+        let (request, result) = audited(&mut events, source).await;
+        assert_eq!(request["command"], "toggle");
+        assert!(request.get("token").is_none());
+        assert!(result.starts_with("Unauthorized"));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_audited() {
+        let source = "192.0.2.11";
+        let mut events = subscribe();
+        let mut client = connect(source);
+
+        let line = "{\"command\":\"toggle\",\"ephemeral\":\"yes\",\"token\":\"secret\"}\n";
+        assert_eq!(
+            code(request(&mut client, line).await),
+            ErrorCode::InvalidRequest
+        );
+
+        let (request, result) = audited(&mut events, source).await;
+        assert_eq!(request["command"], "toggle");
+        assert!(request.get("token").is_none());
+        assert!(result.starts_with("InvalidRequest"));
+    }
+
+    #[tokio::test]
+    async fn overlong_requests_close_the_connection() {
+        let source = "192.0.2.12";
+        let mut events = subscribe();
+        let mut client = connect(source);
+
+        // The server stops reading at the limit and hangs up, failing the rest
+        let line = format!("{{\"command\":\"{}", "a".repeat(MAX_REQUEST_LINE));
+        let _ = client.write_all(line.as_bytes()).await;
+        let mut response = String::new();
+        client.read_line(&mut response).await.unwrap();
+        let response: Response = serde_json::from_str(&response).unwrap();
+        assert_eq!(code(response), ErrorCode::InvalidRequest);
+        assert_eq!(client.read_line(&mut String::new()).await.unwrap(), 0);
+
+        let (request, result) = audited(&mut events, source).await;
+        assert_eq!(request, Value::Null);
+        assert!(result.starts_with("InvalidRequest"));
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_are_limited_per_source() {
+        let source = "192.0.2.13";
+        let mut client = connect(source);
+
+        for _ in 0..MAX_UNKNOWN_TOKENS {
+            let line = "{\"command\":\"status\",\"token\":\"guess\"}\n";
+            let response = request(&mut client, line).await;
+            assert_eq!(code(response), ErrorCode::Unauthorized);
+        }
+        let response = request(
+            &mut client,
+            "{\"command\":\"status\",\"token\":\"guess\"}\n",
+        )
+        .await;
+        assert_eq!(code(response), ErrorCode::RateLimited);
+        // The limit is on guessing, an address that has the token keeps working
+        let response = request(
+            &mut client,
+            "{\"command\":\"status\",\"token\":\"secret\"}\n",
+        )
+        .await;
+        assert!(matches!(response, Response::Ok(_)));
+
+        let mut other = connect("192.0.2.14");
+        let response = request(&mut other, "{\"command\":\"status\",\"token\":\"guess\"}\n").await;
+        assert_eq!(code(response), ErrorCode::Unauthorized);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_control_socket_is_private_and_never_replaces_other_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir()
+            .join(format!("toggleproxy-control-{}", std::process::id()))
+            .join("run");
+        let path = dir.join("toggleproxy.sock").display().to_string();
+        let live = Arc::new(watch::channel(Config::default()).0);
+        let serve = |path: String, live: Arc<watch::Sender<Config>>| {
+            tokio::spawn(async move { control_server_unix(&path, live).await })
+        };
+        let server = serve(path.clone(), live.clone());
+        let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        while std::fs::metadata(&path).map_or(true, |meta| mode(&path) != 0o600) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mode(&dir.display().to_string()), 0o700);
+        server.abort();
+
+        // A socket of ours left behind is replaced, anything else is refused
+        let stale = serve(path.clone(), live.clone());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!stale.is_finished());
+        stale.abort();
+        let _ = stale.await;
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(control_server_unix(&path, live).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        let _ = std::fs::remove_dir_all(dir.parent().unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use async_trait::async_trait;
+
+use log::{error, info, trace};
+
+use reqwest::Client;
+
+use serde_json::{json, Value};
+
+use crate::config::{DdnsConfig, DdnsProvider};
+
+/// A dynamic DNS service that can point a hostname at an address
+#[async_trait]
+pub trait Provider: Send + Sync {
+    async fn update(&self, client: &Client, ip: IpAddr) -> Result<()>;
+}
+
+pub struct Duckdns {
+    domain: String,
+    token: String,
+}
+
+#[async_trait]
+impl Provider for Duckdns {
+    async fn update(&self, client: &Client, ip: IpAddr) -> Result<()> {
+        let response = client
+            .get("https://www.duckdns.org/update")
+            .query(&[
+                ("domains", self.domain.as_str()),
+                ("token", self.token.as_str()),
+                ("ip", &ip.to_string()),
+            ])
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        match response.trim() {
+            "OK" => Ok(()),
+            _ => Err(anyhow!("DuckDNS rejected the update")),
+        }
+    }
+}
+
+pub struct Cloudflare {
+    zone_id: String,
+    name: String,
+    token: String,
+}
+
+#[async_trait]
+impl Provider for Cloudflare {
+    async fn update(&self, client: &Client, ip: IpAddr) -> Result<()> {
+        let record_type = match ip {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        };
+        let records_url = format!(
+            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
+            self.zone_id
+        );
+
+        let records: Value = client
+            .get(&records_url)
+            .bearer_auth(&self.token)
+            .query(&[("name", self.name.as_str()), ("type", record_type)])
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let record = json!({
+            "type": record_type,
+            "name": self.name,
+            "content": ip.to_string(),
+            "ttl": 60,
+        });
+        let request = match records["result"][0]["id"].as_str() {
+            Some(id) => client.put(format!("{}/{}", records_url, id)),
+            None => client.post(&records_url),
+        };
+        let response: Value = request
+            .bearer_auth(&self.token)
+            .json(&record)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        match response["success"].as_bool() {
+            Some(true) => Ok(()),
+            _ => {
+                trace!("{}", response["errors"]);
+                Err(anyhow!("Cloudflare rejected the update"))
+            }
+        }
+    }
+}
+
+pub fn provider(config: &DdnsProvider) -> Box<dyn Provider> {
+    match config.clone() {
+        DdnsProvider::Duckdns { domain, token } => Box::new(Duckdns { domain, token }),
+        DdnsProvider::Cloudflare {
+            zone_id,
+            name,
+            token,
+        } => Box::new(Cloudflare {
+            zone_id,
+            name,
+            token,
+        }),
+    }
+}
+
+/// Prefers the address the router reported for the port mapping, and asks
+/// ipify otherwise
+async fn public_ip(client: &Client) -> Result<IpAddr> {
+    #[cfg(feature = "upnp")]
+    if let Some(addr) = crate::portmap::external_addr() {
+        return Ok(addr.ip());
+    }
+
+    let ip = client
+        .get("https://api.ipify.org")
+        .send()
+        .await?
+        .text()
+        .await?;
+    Ok(ip.trim().parse()?)
+}
+
+/// Updates the DNS record whenever the public address changes
+pub async fn ddns(config: DdnsConfig) {
+    let client = Client::new();
+    let provider = provider(&config.provider);
+    let mut current: Option<IpAddr> = None;
+
+    loop {
+        match public_ip(&client).await {
+            Ok(ip) if current != Some(ip) => match provider.update(&client, ip).await {
+                Ok(_) => {
+                    info!("Updated dynamic DNS to {}", ip);
+                    current = Some(ip);
+                }
+                Err(err) => error!("Failed to update dynamic DNS: {}", err),
+            },
+            Ok(_) => {}
+            Err(err) => {
+                error!("Failed to get the public address");
+                trace!("{}", err);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval_secs)).await;
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::IpAddr;
+
+/// Seconds clients may cache answers we make up
+const ANSWER_TTL: u32 = 60;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+/// The single question of a standard DNS query
+pub struct Query {
+    pub name: String,
+    pub qtype: u16,
+    /// Where the question section ends
+    end: usize,
+}
+
+/// Parses a standard query with one question, anything else is left alone
+pub fn parse_query(pkt: &[u8]) -> Option<Query> {
+    if pkt.len() < 12 {
+        return None;
+    }
+    // QR must be a query, OPCODE a standard query
+    if pkt[2] & 0xf8 != 0 {
+        return None;
+    }
+    if u16::from_be_bytes([pkt[4], pkt[5]]) != 1 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *pkt.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Questions are never compressed
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(pkt.get(pos..pos + len)?).to_string());
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*pkt.get(pos)?, *pkt.get(pos + 1)?]);
+    pkt.get(pos + 2..pos + 4)?;
+
+    Some(Query {
+        name: labels.join("."),
+        qtype,
+        end: pos + 4,
+    })
+}
+
+/// A response header and the question, ready for answers to be appended
+fn response(pkt: &[u8], query: &Query, rcode: u8, answers: u16) -> Vec<u8> {
+    let mut res = pkt[..query.end].to_vec();
+    // QR, keep OPCODE and RD
+    res[2] = 0x80 | (pkt[2] & 0x01);
+    // RA and RCODE
+    res[3] = 0x80 | rcode;
+    res[6..8].copy_from_slice(&answers.to_be_bytes());
+    res[8..12].fill(0);
+    res
+}
+
+/// Answers `pkt` with NXDOMAIN
+pub fn nxdomain(pkt: &[u8], query: &Query) -> Vec<u8> {
+    response(pkt, query, 3, 0)
+}
+
+/// Answers `pkt` with `ip`, or with no records when `ip` is a different
+/// family than was asked for
+pub fn answer(pkt: &[u8], query: &Query, ip: IpAddr) -> Vec<u8> {
+    let rdata = match (query.qtype, ip) {
+        (TYPE_A, IpAddr::V4(ip)) => ip.octets().to_vec(),
+        (TYPE_AAAA, IpAddr::V6(ip)) => ip.octets().to_vec(),
+        _ => return response(pkt, query, 0, 0),
+    };
+
+    let mut res = response(pkt, query, 0, 1);
+    // Pointer to the name in the question
+    res.extend_from_slice(&[0xc0, 0x0c]);
+    res.extend_from_slice(&query.qtype.to_be_bytes());
+    // Class IN
+    res.extend_from_slice(&1u16.to_be_bytes());
+    res.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+    res.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    res.extend_from_slice(&rdata);
+    res
+}
+
+/// A recursive query for `name`, or None when it can't be a DNS name
+pub fn query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    let mut pkt = Vec::with_capacity(name.len() + 18);
+    pkt.extend_from_slice(&id.to_be_bytes());
+    // RD, one question
+    pkt.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        pkt.push(label.len() as u8);
+        pkt.extend_from_slice(label.as_bytes());
+    }
+    pkt.push(0);
+    pkt.extend_from_slice(&qtype.to_be_bytes());
+    // Class IN
+    pkt.extend_from_slice(&1u16.to_be_bytes());
+    Some(pkt)
+}
+
+/// Skips a possibly compressed name, returning where it ends
+fn skip_name(pkt: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        match *pkt.get(pos)? {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// The A and AAAA records in the answer section of a response
+pub fn addresses(pkt: &[u8]) -> Option<Vec<IpAddr>> {
+    if pkt.len() < 12 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([pkt[4], pkt[5]]);
+    let answers = u16::from_be_bytes([pkt[6], pkt[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pkt, pos)? + 4;
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(pkt, pos)?;
+        let header = pkt.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = pkt.get(pos + 10..pos + 10 + len)?;
+        // CNAMEs and the like are skipped, their targets' records follow
+        match (
+            rtype,
+            <[u8; 4]>::try_from(rdata),
+            <[u8; 16]>::try_from(rdata),
+        ) {
+            (TYPE_A, Ok(ip), _) => ips.push(IpAddr::from(ip)),
+            (TYPE_AAAA, _, Ok(ip)) => ips.push(IpAddr::from(ip)),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Some(ips)
+}
@@ -0,0 +1,57 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use tokio::sync::watch;
+
+use crate::{
+    config::{Config, DrainPolicy, Rules},
+    rules,
+    socks5_async::relay::Drain,
+    timing,
+};
+
+/// Drains open connections that the rules would route differently after a
+/// change, while `drain_on_rule_change` is set
+pub async fn drain_on_rule_changes(live: Arc<watch::Sender<Config>>) {
+    let mut changes = live.subscribe();
+    let mut previous = changes.borrow_and_update().rules.clone();
+    while changes.changed().await.is_ok() {
+        let (current, policy) = {
+            let config = changes.borrow_and_update();
+            (config.rules.clone(), config.drain_on_rule_change)
+        };
+        // Toggles and most reloads leave the rules alone
+        if current.same_rules(&previous) {
+            continue;
+        }
+        if let Some(policy) = policy {
+            drain_rerouted(&previous, &current, policy);
+        }
+        previous = current;
+    }
+}
+
+fn drain_rerouted(previous: &Rules, current: &Rules, policy: DrainPolicy) {
+    let drain = Drain {
+        idle: Duration::from_secs(policy.idle_secs),
+        deadline: Instant::now() + Duration::from_secs(policy.after_mins * 60),
+    };
+    let mut drained = 0;
+    for (id, addr) in timing::live_addresses() {
+        let before = rules::match_address(previous, &addr);
+        let after = rules::match_address(current, &addr);
+        if before != after && timing::drain(id, drain) {
+            drained += 1;
+        }
+    }
+    if drained > 0 {
+        info!(
+            "Draining {} connections the new rules route differently",
+            drained
+        );
+    }
+}
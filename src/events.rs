@@ -0,0 +1,366 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{BufRead, BufReader},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use lazy_static::lazy_static;
+
+use log::{error, trace};
+
+use serde::{Deserialize, Serialize};
+
+use serde_json::Value;
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+};
+
+use crate::{config::Config, timing::ConnectionTrace};
+
+/// Where encoded events are written
+type Sink = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Bumped whenever a field is removed or changes meaning, adding fields or
+/// event types keeps the version
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// One line of the event log.
+///
+/// Every event has `v` (schema version), `ts` (unix milliseconds) and `type`,
+/// the remaining fields depend on the type.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub v: u32,
+    pub ts: u64,
+    /// Counts up from 1 while the server runs, missing from events the CLI
+    /// writes itself
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub seq: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    Direct,
+    Upstream,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SocksCommand {
+    #[default]
+    Connect,
+    Associate,
+    Bind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A client request finished
+    Access {
+        client: String,
+        /// Missing from lines written before it was added, which were all
+        /// CONNECT requests
+        #[serde(default)]
+        command: SocksCommand,
+        target: String,
+        route: Route,
+        /// The target proxy that served the connection
+        upstream: Option<String>,
+        /// `succeeded`, or why the connection was refused
+        result: String,
+        bytes_up: u64,
+        bytes_down: u64,
+        duration_ms: u64,
+        /// Set by middleware of an embedded proxy, `adaptive:direct` or
+        /// `adaptive:proxy` where adaptive routing chose the way, and
+        /// `race:direct` or `race:proxy` for the way that won a race
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+    },
+    /// An hour of requests under `aggregate_only`, which writes these
+    /// instead of access events
+    Usage {
+        /// Unix time the hour started
+        hour: u64,
+        connections: u64,
+        failed: u64,
+        bytes_up: u64,
+        bytes_down: u64,
+        /// Requests per top-level domain, `ip` for addresses
+        tlds: BTreeMap<String, u64>,
+    },
+    /// The proxy was switched on or off
+    Toggle { status: bool },
+    /// A change made to the configuration or the system
+    Audit { action: String, detail: String },
+    /// A control request that changes state, sent whether or not it was
+    /// allowed
+    Control {
+        request: Value,
+        /// The token it carried, when the server requires one
+        token_id: Option<String>,
+        /// The client IP, or `local` over a Unix socket
+        source: String,
+        /// `ok`, or the error code and message
+        result: String,
+    },
+    /// The timing breakdown of a sampled connection
+    Trace { trace: ConnectionTrace },
+}
+
+fn is_zero(seq: &u64) -> bool {
+    *seq == 0
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Event {
+            v: SCHEMA_VERSION,
+            seq: 0,
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            kind,
+        }
+    }
+}
+
+/// How many events a slow subscriber may fall behind before it misses some,
+/// and how many are kept for subscribers that reconnect
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+/// Where a subscriber got to, so it can pick up from there after reconnecting
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct EventCursor {
+    /// The server run the events came from, `seq` starts over with each
+    pub instance: u64,
+    pub seq: u64,
+}
+
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// A log events are queued for, and whether it only takes access events
+struct Writer {
+    access_only: bool,
+    sender: mpsc::UnboundedSender<Event>,
+}
+
+lazy_static! {
+    static ref WRITERS: Mutex<Vec<Writer>> = Mutex::new(Vec::new());
+    static ref SUBSCRIBERS: broadcast::Sender<Event> = broadcast::channel(SUBSCRIBER_BACKLOG).0;
+    /// The latest events, oldest first
+    static ref RECENT: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+    /// Tells server runs apart
+    pub static ref INSTANCE: u64 = rand::random();
+}
+
+/// Queues an event for the server's event and access logs and subscribers,
+/// and keeps it for subscribers that reconnect
+pub fn emit(kind: EventKind) {
+    let mut event = Event::new(kind);
+    event.seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == SUBSCRIBER_BACKLOG {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // Sent while holding the backlog, so a subscriber resuming gets each
+        // event once, from one or the other
+        if SUBSCRIBERS.receiver_count() > 0 {
+            let _ = SUBSCRIBERS.send(event.clone());
+        }
+    }
+
+    let writers = WRITERS.lock().unwrap();
+    let access = matches!(
+        event.kind,
+        EventKind::Access { .. } | EventKind::Usage { .. }
+    );
+    for writer in writers.iter() {
+        if access || !writer.access_only {
+            let _ = writer.sender.send(event.clone());
+        }
+    }
+}
+
+/// Receives every event emitted from now on
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    SUBSCRIBERS.subscribe()
+}
+
+/// Like [`subscribe`], also returning the kept events after `cursor` and how
+/// many after it are no longer kept. A cursor from an earlier server run
+/// starts from the first event of this one
+pub fn subscribe_since(cursor: EventCursor) -> (Vec<Event>, u64, broadcast::Receiver<Event>) {
+    let recent = RECENT.lock().unwrap();
+    let receiver = SUBSCRIBERS.subscribe();
+    let after = match cursor.instance == *INSTANCE {
+        true => cursor.seq,
+        false => 0,
+    };
+    let oldest = recent
+        .front()
+        .map_or(NEXT_SEQ.load(Ordering::Relaxed), |event| event.seq);
+    let backlog = recent
+        .iter()
+        .filter(|event| event.seq > after)
+        .cloned()
+        .collect();
+    (backlog, oldest.saturating_sub(after + 1), receiver)
+}
+
+/// Opens an event log target: a file path, `tcp://host:port` or `unix:///path`
+async fn open(target: &str) -> Result<Sink> {
+    if let Some(addr) = target.strip_prefix("tcp://") {
+        return Ok(Box::new(TcpStream::connect(addr).await?));
+    }
+
+    if let Some(path) = target.strip_prefix("unix://") {
+        #[cfg(unix)]
+        return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+        #[cfg(not(unix))]
+        return Err(anyhow!(
+            "Unix sockets are not supported, can't log to {}",
+            path
+        ));
+    }
+
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target)
+        .await?;
+    Ok(Box::new(file))
+}
+
+fn encode(event: &Event) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Writes every event to `target`, reconnecting after write failures
+pub async fn event_writer(target: String) {
+    write_events(target, false).await
+}
+
+/// Writes access events to `target`, one line per connection
+pub async fn access_writer(target: String) {
+    write_events(target, true).await
+}
+
+async fn write_events(target: String, access_only: bool) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    WRITERS.lock().unwrap().push(Writer {
+        access_only,
+        sender,
+    });
+
+    let mut sink: Option<Sink> = None;
+    while let Some(event) = receiver.recv().await {
+        let line = match encode(&event) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to encode event");
+                trace!("{}", err);
+                continue;
+            }
+        };
+
+        for _ in 0..2 {
+            if sink.is_none() {
+                sink = match open(&target).await {
+                    Ok(sink) => Some(sink),
+                    Err(err) => {
+                        error!("Failed to open log {}", target);
+                        trace!("{}", err);
+                        break;
+                    }
+                };
+            }
+
+            match sink.as_mut().unwrap().write_all(&line).await {
+                Ok(_) => break,
+                Err(err) => {
+                    trace!("{}", err);
+                    sink = None;
+                }
+            }
+        }
+    }
+}
+
+async fn append(target: &str, kind: EventKind) -> Result<()> {
+    let mut sink = open(target).await?;
+    sink.write_all(&encode(&Event::new(kind))?).await?;
+    sink.flush().await?;
+    Ok(())
+}
+
+/// Writes a single event straight away, for commands that run outside the server
+pub async fn record(config: &Config, kind: EventKind) {
+    if let Some(target) = &config.event_log {
+        match append(target, kind).await {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Failed to write event to {}", target);
+                trace!("{}", err);
+            }
+        }
+    }
+}
+
+/// Replays an event log through the schema, printing every line that doesn't
+/// conform. Returns the number of invalid lines
+pub fn validate_events(path: &str) -> Result<usize> {
+    let file = std::fs::File::open(path)?;
+
+    let mut valid = 0;
+    let mut invalid = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let problem = match serde_json::from_str::<Event>(&line) {
+            Ok(event) if event.v > SCHEMA_VERSION => Some(anyhow!(
+                "schema version {} is newer than {}",
+                event.v,
+                SCHEMA_VERSION
+            )),
+            Ok(event) if event.v == 0 => Some(anyhow!("schema version 0 does not exist")),
+            Ok(_) => None,
+            Err(err) => Some(err.into()),
+        };
+
+        match problem {
+            Some(problem) => {
+                println!("line {}: {}", number + 1, problem);
+                invalid += 1;
+            }
+            None => valid += 1,
+        }
+    }
+
+    println!(
+        "{} valid, {} invalid events (schema version {})",
+        valid, invalid, SCHEMA_VERSION
+    );
+    Ok(invalid)
+}
@@ -0,0 +1,113 @@
+use futures::future::join_all;
+
+use log::warn;
+
+use crate::{config::Config, control::Client};
+
+/// What happened to one instance in a fleet toggle
+pub enum HostOutcome {
+    Switched,
+    Failed(String),
+    /// Switched, then put back because another instance failed
+    RolledBack,
+    /// Left alone because another instance couldn't be reached
+    Untouched,
+}
+
+pub struct FleetReport {
+    /// What every instance was meant to switch to
+    pub status: bool,
+    /// Each control endpoint, this instance's first
+    pub hosts: Vec<(String, HostOutcome)>,
+    /// Whether every instance ended up switched, otherwise none were
+    pub applied: bool,
+}
+
+/// Toggles this instance and every one in `config.fleet` to the same state,
+/// the opposite of this instance's. When one fails the others are put back
+/// as they were
+pub async fn toggle_all(config: &Config, ephemeral: bool) -> FleetReport {
+    let mut endpoints = vec![config.control.clone()];
+    for endpoint in &config.fleet {
+        if !endpoints.contains(endpoint) {
+            endpoints.push(endpoint.clone());
+        }
+    }
+    // Instances of a fleet are expected to share their admin token
+    let local = Client::from_config(config);
+    let clients = endpoints
+        .iter()
+        .map(|endpoint| local.with_endpoint(endpoint.clone()))
+        .collect::<Vec<_>>();
+
+    // Every instance has to answer before any of them changes
+    let before = join_all(clients.iter().map(|client| client.status())).await;
+    let status = match &before[0] {
+        Ok(local) => !local.status,
+        Err(_) => !config.status,
+    };
+    if before.iter().any(|result| result.is_err()) {
+        let hosts = endpoints
+            .into_iter()
+            .zip(before)
+            .map(|(endpoint, result)| match result {
+                Ok(_) => (endpoint, HostOutcome::Untouched),
+                Err(err) => (endpoint, HostOutcome::Failed(err.to_string())),
+            })
+            .collect();
+        return FleetReport {
+            status,
+            hosts,
+            applied: false,
+        };
+    }
+
+    let after = join_all(clients.iter().map(|client| client.set(status, ephemeral))).await;
+    let applied = after.iter().all(|result| result.is_ok());
+    let mut hosts = Vec::new();
+    for (index, result) in after.into_iter().enumerate() {
+        let outcome = match result {
+            Ok(_) if applied => HostOutcome::Switched,
+            Ok(_) => {
+                let previous = before[index]
+                    .as_ref()
+                    .map_or(status, |before| before.status);
+                match clients[index].set(previous, ephemeral).await {
+                    Ok(_) => HostOutcome::RolledBack,
+                    Err(err) => {
+                        warn!("Failed to put {} back: {}", endpoints[index], err);
+                        HostOutcome::Failed(format!("Switched but not put back: {}", err))
+                    }
+                }
+            }
+            Err(err) => HostOutcome::Failed(err.to_string()),
+        };
+        hosts.push((endpoints[index].clone(), outcome));
+    }
+    FleetReport {
+        status,
+        hosts,
+        applied,
+    }
+}
+
+pub fn print_report(report: &FleetReport) {
+    let state = match report.status {
+        true => "on",
+        false => "off",
+    };
+    for (endpoint, outcome) in &report.hosts {
+        match outcome {
+            HostOutcome::Switched => println!("{}: {}", endpoint, state),
+            HostOutcome::Failed(err) => println!("{}: failed, {}", endpoint, err),
+            HostOutcome::RolledBack => println!("{}: put back", endpoint),
+            HostOutcome::Untouched => println!("{}: unchanged", endpoint),
+        }
+    }
+    match report.applied {
+        true => println!("All proxy servers are now {}", state),
+        false => {
+            println!("Not every proxy server could be switched, the rest were left as they were")
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+use log::{error, info, warn};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a probe result is reused, so a burst of failed connections
+/// doesn't probe once each
+const PROBE_CACHE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamHealth {
+    /// Nothing went through the target proxy yet
+    Unknown,
+    Healthy,
+    /// The target proxy failed but the network is up, or there is no probe
+    UpstreamDown,
+    /// The reachability probe failed too, the whole network is down
+    NetworkDown,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthReport {
+    pub upstream: UpstreamHealth,
+    /// Unix time of the last change
+    pub since: u64,
+    pub last_error: Option<String>,
+}
+
+struct State {
+    report: HealthReport,
+    probed: Option<(Instant, bool)>,
+}
+
+lazy_static! {
+    static ref HEALTH: Mutex<State> = Mutex::new(State {
+        report: HealthReport {
+            upstream: UpstreamHealth::Unknown,
+            since: unix_now(),
+            last_error: None,
+        },
+        probed: None,
+    });
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn set_health(upstream: UpstreamHealth, last_error: Option<String>) {
+    let mut state = HEALTH.lock().unwrap();
+    if state.report.upstream != upstream {
+        match upstream {
+            UpstreamHealth::Healthy => info!("Target proxy is reachable again"),
+            UpstreamHealth::UpstreamDown => warn!("Target proxy is down but the network is up"),
+            UpstreamHealth::NetworkDown => {
+                error!("Network is down, the target proxy can't be reached")
+            }
+            UpstreamHealth::Unknown => {}
+        }
+        state.report.upstream = upstream;
+        state.report.since = unix_now();
+    }
+    if last_error.is_some() {
+        state.report.last_error = last_error;
+    }
+}
+
+pub fn health() -> HealthReport {
+    HEALTH.lock().unwrap().report.clone()
+}
+
+pub fn upstream_ok() {
+    set_health(UpstreamHealth::Healthy, None);
+}
+
+/// Whether a connection may go direct while the proxy is on. The kill switch
+/// refuses it always with `never_direct`, and while the target proxy is down
+/// with `fail_closed`
+pub fn direct_allowed(config: &Config) -> bool {
+    let down = matches!(
+        health().upstream,
+        UpstreamHealth::UpstreamDown | UpstreamHealth::NetworkDown
+    );
+    !(config.never_direct || (config.fail_closed && down))
+}
+
+/// Whether the configured reachability probe answers, None without a probe
+pub async fn network_reachable(config: &Config) -> Option<bool> {
+    let probe = config.reachability_probe.as_ref()?;
+
+    if let Some((at, reachable)) = HEALTH.lock().unwrap().probed {
+        if at.elapsed() < PROBE_CACHE {
+            return Some(reachable);
+        }
+    }
+
+    let reachable = matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(probe)).await,
+        Ok(Ok(_))
+    );
+    HEALTH.lock().unwrap().probed = Some((Instant::now(), reachable));
+    Some(reachable)
+}
+
+/// Classifies a failed connection to the target proxy
+pub async fn upstream_failed(config: &Config, err: &std::io::Error) -> UpstreamHealth {
+    let upstream = match network_reachable(config).await {
+        Some(false) => UpstreamHealth::NetworkDown,
+        Some(true) | None => UpstreamHealth::UpstreamDown,
+    };
+    set_health(upstream, Some(err.to_string()));
+    upstream
+}
@@ -0,0 +1,115 @@
+use std::io;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest response header an HTTP proxy may send before the tunnel starts
+const MAX_RESPONSE: usize = 16384;
+
+/// Whether `target` can go into the request line and Host header as is. A
+/// client's domain with CR or LF in it would otherwise add headers or a
+/// second request next to our Proxy-Authorization
+fn valid_target(target: &str) -> bool {
+    !target.is_empty() && target.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Asks an HTTP proxy to open a tunnel to `target`, a host:port
+pub async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &str,
+    credentials: Option<(String, String)>,
+) -> io::Result<()> {
+    if !valid_target(target) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Target is not a valid host for an HTTP proxy",
+        ));
+    }
+
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time so nothing from the tunnel is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_RESPONSE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP proxy response headers too large",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "HTTP proxy requires authentication",
+        )),
+        _ => Err(io::Error::other(format!(
+            "HTTP proxy refused the tunnel: {}",
+            status_line
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_targets_that_would_inject_headers() {
+        for target in [
+            "example.com:443\r\nX-Injected: 1",
+            "example.com:443\nGET / HTTP/1.1",
+            "exa mple.com:443",
+            "ex\u{0}ample.com:443",
+            "exämple.com:443",
+            "",
+        ] {
+            let (mut client, mut proxy) = duplex(1024);
+            let err = http_connect(&mut client, target, Some(("user".into(), "pass".into())))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+            // Nothing may reach the proxy
+            drop(client);
+            let mut sent = Vec::new();
+            proxy.read_to_end(&mut sent).await.unwrap();
+            assert!(sent.is_empty(), "{:?} sent {:?}", target, sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_valid_targets() {
+        let (mut client, mut proxy) = duplex(1024);
+        let tunnel =
+            tokio::spawn(async move { http_connect(&mut client, "[2001:db8::1]:443", None).await });
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(proxy.read_u8().await.unwrap());
+        }
+        assert_eq!(
+            request,
+            b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n"
+        );
+        proxy
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        tunnel.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{anyhow, Result};
+
+use log::{error, trace};
+
+use crate::systemd::ServiceOptions;
+
+/// The job's label, `launchctl` refers to it by this
+pub const LABEL: &str = "io.github.imlvna.toggleproxy";
+
+/// Where `install-service` puts the LaunchDaemon on macOS
+pub const PLIST_PATH: &str = "/Library/LaunchDaemons/io.github.imlvna.toggleproxy.plist";
+
+/// Where the daemon's output goes, launchd drops it otherwise
+const LOG_PATH: &str = "/var/log/toggleproxy.log";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A LaunchDaemon that starts the proxy server at boot and again whenever it
+/// fails
+pub fn daemon_plist(options: &ServiceOptions) -> String {
+    let arguments = [
+        options.binary.display().to_string(),
+        "--config".to_string(),
+        options.config_path.display().to_string(),
+        "run".to_string(),
+    ];
+    let mut plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \t<key>Label</key>\n\
+         \t<string>{}</string>\n\
+         \t<key>ProgramArguments</key>\n\
+         \t<array>\n",
+        LABEL
+    );
+    for argument in &arguments {
+        plist.push_str(&format!("\t\t<string>{}</string>\n", escape(argument)));
+    }
+    plist.push_str(
+        "\t</array>\n\
+         \t<key>RunAtLoad</key>\n\
+         \t<true/>\n\
+         \t<key>KeepAlive</key>\n\
+         \t<dict>\n\
+         \t\t<key>SuccessfulExit</key>\n\
+         \t\t<false/>\n\
+         \t</dict>\n",
+    );
+    if let Some(user) = &options.user {
+        plist.push_str(&format!(
+            "\t<key>UserName</key>\n\t<string>{}</string>\n",
+            escape(user)
+        ));
+    }
+    plist.push_str(&format!(
+        "\t<key>StandardOutPath</key>\n\
+         \t<string>{0}</string>\n\
+         \t<key>StandardErrorPath</key>\n\
+         \t<string>{0}</string>\n\
+         </dict>\n\
+         </plist>\n",
+        LOG_PATH
+    ));
+    plist
+}
+
+fn launchctl(args: &[&str]) -> Result<()> {
+    let output = match std::process::Command::new("launchctl").args(args).output() {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Failed to run launchctl");
+            trace!("{}", err);
+            return Err(anyhow!("Failed to run launchctl"));
+        }
+    };
+    match output.status.success() {
+        true => Ok(()),
+        false => {
+            trace!("{}", String::from_utf8_lossy(&output.stderr));
+            Err(anyhow!("launchctl {} failed", args[0]))
+        }
+    }
+}
+
+/// Restarts the daemon, starting it if it isn't running
+pub fn kickstart() -> Result<()> {
+    launchctl(&["kickstart", "-k", &format!("system/{}", LABEL)])
+}
+
+/// Writes the LaunchDaemon and loads it, which starts it
+pub fn install_daemon(options: &ServiceOptions) -> Result<()> {
+    std::fs::write(PLIST_PATH, daemon_plist(options))
+        .map_err(|err| anyhow!("Failed to write {}: {}", PLIST_PATH, err))?;
+    // An older copy has to go first, bootstrap refuses loaded jobs
+    let _ = launchctl(&["bootout", &format!("system/{}", LABEL)]);
+    launchctl(&["bootstrap", "system", PLIST_PATH])
+}
@@ -0,0 +1,66 @@
+pub mod acl;
+pub mod adaptive;
+pub mod aggregate;
+pub mod alerts;
+pub mod breaker;
+pub mod clap;
+pub mod compress;
+pub mod config;
+pub mod control;
+#[cfg(feature = "http-client")]
+pub mod ddns;
+pub mod dns;
+pub mod drain;
+pub mod events;
+pub mod fleet;
+pub mod health;
+pub mod http_proxy;
+pub mod launchd;
+pub mod lint;
+pub mod listeners;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod migrate;
+pub mod network;
+pub mod obfs;
+pub mod pac;
+pub mod ping;
+pub mod policy;
+#[cfg(feature = "upnp")]
+pub mod portmap;
+pub mod proto;
+pub mod proxy;
+pub mod record;
+pub mod reroute;
+pub mod resolve;
+pub mod rule_lists;
+#[cfg(feature = "http-client")]
+pub mod rule_updates;
+pub mod rules;
+pub mod schedule;
+pub mod server;
+pub mod simulate;
+pub mod socks4;
+pub mod socks5;
+pub mod socks5_async;
+pub mod standby;
+pub mod stats;
+pub mod sysproxy;
+pub mod systemd;
+pub mod throttle;
+pub mod timing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod udp;
+pub mod upgrade;
+pub mod upstream;
+pub mod validate;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod wol;
+
+pub use proxy::Proxy;
+
+#[cfg(not(any(feature = "external-socks", feature = "vendored-socks")))]
+compile_error!("Either the external-socks or the vendored-socks feature is needed");
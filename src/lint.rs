@@ -0,0 +1,134 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::lookup_host;
+
+use crate::{
+    config::{get_real_config_path, Config, Transport},
+    transport::parse_target,
+};
+
+/// A risky setting, with why it matters and what to do about it
+pub struct Finding {
+    pub title: String,
+    pub explanation: String,
+    pub fix: String,
+}
+
+/// Whether `ip` can only be reached from a private network
+fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                // Carrier-grade NAT, also used by overlay VPNs
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+#[cfg(unix)]
+fn world_writable(path: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o002 != 0)
+}
+
+#[cfg(not(unix))]
+fn world_writable(_path: &str) -> bool {
+    false
+}
+
+impl Finding {
+    fn new(title: String, explanation: &str, fix: String) -> Self {
+        Finding {
+            title,
+            explanation: explanation.to_string(),
+            fix,
+        }
+    }
+}
+
+/// Checks `config` for combinations that expose the proxy or its traffic
+pub async fn lint(config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    // The listener always binds every interface
+    if config.allow_clients.is_empty() {
+        findings.push(Finding::new(
+            format!(
+                "Port {} accepts unauthenticated clients on all interfaces",
+                config.port
+            ),
+            "Anyone who can reach this host can relay traffic through it, \
+             and through the target proxy when the proxy is on",
+            format!(
+                "Set allow_clients to your trusted networks, or firewall port {}",
+                config.port
+            ),
+        ));
+    }
+
+    if let Ok(addr) = config.control.parse::<SocketAddr>() {
+        if !addr.ip().is_loopback() && config.control_tokens.is_empty() {
+            findings.push(Finding::new(
+                format!("The control socket {} needs no token", addr),
+                "Anyone who can reach it can toggle the proxy, reload the config \
+                 and close connections",
+                "Add control_tokens, or bind control to 127.0.0.1".to_string(),
+            ));
+        }
+    }
+
+    let config_path = get_real_config_path();
+    if world_writable(&config_path) {
+        findings.push(Finding::new(
+            format!("{} is world-writable", config_path),
+            "Any local user can change the target proxy and send your traffic \
+             wherever they like",
+            format!("chmod o-w {}", config_path),
+        ));
+    }
+
+    let encrypted = matches!(
+        config.target_transport,
+        Transport::Tls { .. } | Transport::Wss { .. }
+    );
+    for target in config.target.iter() {
+        let parsed = parse_target(config, target);
+        let public = match lookup_host(parsed.addr).await {
+            Ok(mut addrs) => addrs.any(|addr| !is_private(addr.ip())),
+            Err(_) => false,
+        };
+        if encrypted || parsed.tls || !public {
+            continue;
+        }
+        findings.push(Finding::new(
+            format!(
+                "The target proxy {} is reached without TLS over the internet",
+                target
+            ),
+            match config.target_credentials() {
+                Some(_) => {
+                    "Destinations, traffic and the target proxy credentials \
+                     can be read by anyone on the path"
+                }
+                None => "Destinations and traffic can be read by anyone on the path",
+            },
+            "Set target_transport to tls or wss, or reach the target proxy over a VPN".to_string(),
+        ));
+    }
+
+    findings
+}
+
+pub fn print_findings(findings: &[Finding]) {
+    if findings.is_empty() {
+        println!("No problems found");
+    }
+    for finding in findings {
+        println!("warning: {}", finding.title);
+        println!("  why: {}", finding.explanation);
+        println!("  fix: {}\n", finding.fix);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+
+use lazy_static::lazy_static;
+
+use log::{error, info, warn};
+
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{
+    config::{save_status, Config, Persisted},
+    server::{accept, socks_listener},
+};
+
+lazy_static! {
+    /// The config each listener's connections use, by listener name
+    static ref LISTENERS: Mutex<HashMap<String, Arc<watch::Sender<Config>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The live config of listener `name`
+pub fn listener(name: &str) -> Option<Arc<watch::Sender<Config>>> {
+    LISTENERS.lock().unwrap().get(name).cloned()
+}
+
+/// Every running listener's name, port and toggle
+pub fn statuses() -> Vec<(String, u16, bool)> {
+    let mut statuses = LISTENERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, live)| {
+            let config = live.borrow();
+            (name.clone(), config.port, config.status)
+        })
+        .collect::<Vec<_>>();
+    statuses.sort();
+    statuses
+}
+
+/// Switches listener `name` in `live`, saving it unless `ephemeral`. The
+/// listener picks it up from there like any other config change
+pub fn set_status(
+    live: &watch::Sender<Config>,
+    name: &str,
+    status: bool,
+    ephemeral: bool,
+) -> Result<Config> {
+    let mut config = live.borrow().clone();
+    let listener = config
+        .listeners
+        .iter_mut()
+        .find(|listener| listener.name == name)
+        .ok_or_else(|| anyhow!("No listener named {}", name))?;
+    listener.status = status;
+    if !ephemeral {
+        if let Persisted::StateFile(path) = save_status(&config)? {
+            warn!(
+                "Config file is read-only, saved the toggle to {}",
+                path.display()
+            );
+        }
+    }
+    live.send_replace(config.clone());
+    let listener = config
+        .listeners
+        .iter()
+        .find(|listener| listener.name == name)
+        .unwrap();
+    Ok(config.for_listener(listener))
+}
+
+/// Serves every listener in the config on its own port. Listeners follow
+/// the main config through reloads, those added later need a restart
+pub async fn serve_listeners(live: Arc<watch::Sender<Config>>) {
+    let listeners = live.borrow().listeners.clone();
+    for listener in listeners {
+        let socket = match TcpListener::bind(format!("0.0.0.0:{}", listener.port)).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!(
+                    "Failed to listen on port {} for {}: {}",
+                    listener.port, listener.name, err
+                );
+                continue;
+            }
+        };
+        info!("Listener {} on port {}", listener.name, listener.port);
+
+        let listener_live = Arc::new(watch::channel(live.borrow().for_listener(&listener)).0);
+        LISTENERS
+            .lock()
+            .unwrap()
+            .insert(listener.name.clone(), listener_live.clone());
+        tokio::spawn(follow(live.clone(), listener_live.clone(), listener.name));
+        let server = socks_listener(socket);
+        tokio::spawn(accept(server, listener_live, Arc::new(Vec::new())));
+    }
+}
+
+/// Keeps a listener's config in step with the main one
+async fn follow(
+    live: Arc<watch::Sender<Config>>,
+    listener_live: Arc<watch::Sender<Config>>,
+    name: String,
+) {
+    let mut changes = live.subscribe();
+    while changes.changed().await.is_ok() {
+        let config = changes.borrow_and_update().clone();
+        match config
+            .listeners
+            .iter()
+            .find(|listener| listener.name == name)
+        {
+            Some(listener) => {
+                let port = listener_live.borrow().port;
+                let mut derived = config.for_listener(listener);
+                if derived.port != port {
+                    error!(
+                        "Listener {} can't change its port while running, restart to use it",
+                        name
+                    );
+                    derived.port = port;
+                }
+                listener_live.send_replace(derived);
+            }
+            None => error!("Listener {} was removed, restart to stop it", name),
+        }
+    }
+}
@@ -1,24 +1,64 @@
-use config::get_config;
+use std::{path::PathBuf, time::Duration};
 
-use crate::{
+use toggleproxy::{
     clap::get_args,
-    config::{save_config, stringify_config},
+    config::{
+        get_config, get_real_config_path, get_setting, init_config, read_config, save_args,
+        save_config, save_profile, save_status, set_setting, stringify_config, update_config,
+        Config, Persisted,
+    },
+    control::{Client, ControlError, ErrorCode, Status},
+    events::{self, record, EventKind},
+    fleet, lint, migrate, ping,
+    record::replay,
+    reroute::Via,
     server::server,
+    stats, sysproxy,
+    systemd::{self, ServiceOptions},
+    timing,
 };
 
-pub mod clap;
-pub mod config;
-pub mod server;
-pub mod socks5_async;
-pub mod systemd;
-
 #[tokio::main]
 async fn main() {
     simple_logger::init().unwrap();
 
-    let mut config = get_config();
     let args = get_args();
 
+    // Works without a config that parses, since it replaces it
+    if let Some(("config", sub_matches)) = args.subcommand() {
+        if sub_matches.subcommand_name() == Some("init") {
+            match init_config() {
+                Ok(Some(backup)) => println!("Config reset, the old one is in {}", backup),
+                Ok(None) => println!("Config created at {}", get_real_config_path()),
+                Err(err) => {
+                    println!("Failed to write config: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+
+    let mut config = match get_config() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid config: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(path) = args.get_one::<String>("validate-events") {
+        match events::validate_events(path) {
+            Ok(0) => {}
+            Ok(_) => std::process::exit(1),
+            Err(err) => {
+                println!("Failed to read event log: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     match args.subcommand() {
         Some(("run", _)) => {
             println!("Running proxy server on port {}", config.port);
@@ -29,37 +69,325 @@ async fn main() {
                 }
             }
         }
+        Some(("toggle", sub_matches)) if sub_matches.get_flag("all") => {
+            let report = fleet::toggle_all(&config, args.get_flag("no-persist")).await;
+            fleet::print_report(&report);
+            if !report.applied {
+                std::process::exit(1);
+            }
+        }
+        Some(("toggle", sub_matches)) if sub_matches.contains_id("listener") => {
+            let name = sub_matches.get_one::<String>("listener").unwrap();
+            let no_persist = args.get_flag("no-persist");
+            let toggled = match Client::from_config(&config)
+                .toggle_listener(name, no_persist)
+                .await
+            {
+                Ok(status) => status
+                    .listeners
+                    .into_iter()
+                    .find(|listener| listener.name == *name)
+                    .map(|listener| listener.status)
+                    .ok_or_else(|| anyhow::anyhow!("No listener named {}", name)),
+                Err(err) if is_unavailable(&err) && !no_persist => {
+                    toggle_listener_saved(&mut config, name)
+                }
+                Err(err) => Err(err),
+            };
+            match toggled {
+                Ok(status) => println!(
+                    "Listener {} is now {}",
+                    name,
+                    match status {
+                        true => "on",
+                        false => "off",
+                    }
+                ),
+                Err(err) => {
+                    println!("Failed to toggle listener {}: {}", name, err);
+                }
+            }
+        }
         Some(("toggle", _)) => {
-            config.status = !config.status;
-            println!(
-                "Proxy server is now {}",
-                match config.status {
-                    true => "on",
-                    false => "off",
+            // A running server toggles itself, otherwise the config file is
+            // changed for the next start
+            let client = Client::from_config(&config);
+            let no_persist = args.get_flag("no-persist");
+            let toggled = match no_persist {
+                true => client.toggle_ephemeral().await,
+                false => client.toggle().await,
+            };
+            let offline = matches!(&toggled, Err(err) if is_unavailable(err) && !no_persist);
+            let toggled = match toggled {
+                Ok(status) => {
+                    config.status = status.status;
+                    Ok(())
                 }
-            );
-            match save_config(&config) {
+                Err(_) if offline => toggle_saved(&mut config).await,
+                Err(err) => Err(err),
+            };
+            match toggled {
                 Ok(_) => {
-                    if config.systemd {
-                        match systemd::systemd_restart() {
-                            Ok(_) => {
-                                println!("Systemd service restarted");
-                            }
-                            Err(err) => {
-                                println!("Failed to restart systemd service: {}", err);
-                            }
+                    println!(
+                        "Proxy server is now {}",
+                        match config.status {
+                            true => "on",
+                            false => "off",
+                        }
+                    );
+                    // A running server updates the system proxy itself
+                    if offline {
+                        sync_system_proxy(&config);
+                    }
+                }
+                Err(err) => {
+                    println!("Failed to toggle: {}", err);
+                }
+            }
+        }
+        Some(("use", sub_matches)) => {
+            let profile = sub_matches.get_one::<String>("PROFILE").unwrap();
+            // A running server switches itself, otherwise the config file is
+            // changed for the next start
+            let switched = Client::from_config(&config).use_profile(profile).await;
+            let offline = matches!(&switched, Err(err) if is_unavailable(err));
+            let switched = match switched {
+                Ok(status) => {
+                    config.status = status.status;
+                    Ok(())
+                }
+                Err(_) if offline => config
+                    .use_profile(profile)
+                    .and_then(|_| save_profile(profile)),
+                Err(err) => Err(err),
+            };
+            match switched {
+                Ok(_) => {
+                    println!("Using profile {}, the proxy is on", profile);
+                    record(
+                        &config,
+                        EventKind::Audit {
+                            action: "profile_used".to_string(),
+                            detail: profile.to_string(),
+                        },
+                    )
+                    .await;
+                    // A running server updates the system proxy itself
+                    if offline {
+                        sync_system_proxy(&config);
+                    }
+                }
+                Err(err) => {
+                    println!("Failed to use profile {}: {}", profile, err);
+                }
+            }
+        }
+        Some(("status", _)) => match Client::from_config(&config).status().await {
+            Ok(status) => print_status(&status),
+            Err(err) if is_unavailable(&err) => {
+                println!(
+                    "Proxy server is not running, it will start {}",
+                    match config.status {
+                        true => "on",
+                        false => "off",
+                    }
+                );
+                std::process::exit(3);
+            }
+            Err(err) => {
+                println!("Failed to get status: {}", err);
+            }
+        },
+        Some(("reload-data", _)) => match Client::from_config(&config).reload_data().await {
+            Ok(count) => {
+                println!("Rule lists reloaded, {} rules", count);
+            }
+            Err(err) => {
+                println!("Failed to reload rule lists: {}", err);
+            }
+        },
+        Some(("lift", sub_matches)) => {
+            let policy = sub_matches.get_one::<String>("POLICY").unwrap();
+            let ttl = Duration::from_secs(*sub_matches.get_one::<u64>("for").unwrap());
+            match Client::from_config(&config).lift(policy, ttl).await {
+                Ok(()) => {
+                    println!("Lifted {} for {}s", policy, ttl.as_secs());
+                }
+                Err(err) => {
+                    println!("Failed to lift {}: {}", policy, err);
+                }
+            }
+        }
+        Some(("reroute", sub_matches)) => {
+            let id = *sub_matches.get_one::<u64>("ID").unwrap();
+            let route = sub_matches.get_one::<String>("ROUTE").unwrap();
+            let via = match route.as_str() {
+                "direct" => Via::Direct,
+                _ => Via::Proxy,
+            };
+            let ttl = Duration::from_secs(*sub_matches.get_one::<u64>("for").unwrap());
+            match Client::from_config(&config).reroute(id, via, ttl).await {
+                Ok(target) => {
+                    println!(
+                        "Closed connection {}, {} now goes {} for {}s",
+                        id,
+                        target,
+                        route,
+                        ttl.as_secs()
+                    );
+                }
+                Err(err) => {
+                    println!("Failed to reroute: {}", err);
+                }
+            }
+        }
+        Some((command @ ("pause" | "resume"), sub_matches)) => {
+            let id = *sub_matches.get_one::<u64>("ID").unwrap();
+            let client = Client::from_config(&config);
+            let result = match command {
+                "pause" => client.pause(id).await,
+                _ => client.resume(id).await,
+            };
+            match result {
+                Ok(trace) => timing::print_traces(&[trace]),
+                Err(err) => {
+                    println!("Failed to {}: {}", command, err);
+                }
+            }
+        }
+        Some(("replay", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("FILE").unwrap();
+            let server = match sub_matches.get_one::<String>("server") {
+                Some(server) => server.clone(),
+                None => format!("127.0.0.1:{}", config.port),
+            };
+            match replay(path, &server).await {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("Failed to replay {}: {}", path, err);
+                }
+            }
+        }
+        Some(("install-service", sub_matches)) => {
+            let binary = match sub_matches.get_one::<PathBuf>("binary") {
+                Some(binary) => binary.clone(),
+                None => {
+                    std::env::current_exe().unwrap_or(PathBuf::from("/usr/local/bin/toggleproxy"))
+                }
+            };
+            let config_path = PathBuf::from(get_real_config_path());
+            let options = ServiceOptions {
+                binary,
+                // The service doesn't start in this directory
+                config_path: config_path.canonicalize().unwrap_or(config_path),
+                user: sub_matches.get_one::<String>("user").cloned(),
+                privileged_port: config.port < 1024 || config.wpad,
+            };
+            if sub_matches.get_flag("print") {
+                print!("{}", systemd::service_file(&options));
+                return;
+            }
+            match systemd::install_service(&options) {
+                Ok(()) => {
+                    println!("Installed {} and enabled toggleproxy", systemd::UNIT_PATH);
+                    // So toggles restart the service
+                    if !config.systemd {
+                        config.systemd = true;
+                        let saved = update_config(|file| {
+                            file.systemd = true;
+                            Ok(())
+                        });
+                        match saved {
+                            Ok(_) => println!("Set systemd to true in the config"),
+                            Err(err) => println!("Failed to save config: {}", err),
                         }
                     }
+                    println!("Start it with: {}", systemd::START_COMMAND);
                 }
                 Err(err) => {
-                    println!("Failed to save config: {}", err);
+                    println!("Failed to install service: {}", err);
                 }
             }
         }
-        Some(("config", _)) => match save_config(&config) {
-            Ok(_) => {
+        Some(("reload", _)) => match Client::from_config(&config).reload().await {
+            Ok(status) => {
+                println!("Config reloaded");
+                print_status(&status);
+            }
+            Err(err) => {
+                println!("Failed to reload: {}", err);
+            }
+        },
+        Some(("config", sub_matches)) if sub_matches.subcommand_name() == Some("lint") => {
+            let findings = lint::lint(&config).await;
+            lint::print_findings(&findings);
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Some(("config", sub_matches)) if sub_matches.subcommand_name() == Some("get") => {
+            let key = sub_matches
+                .subcommand_matches("get")
+                .unwrap()
+                .get_one::<String>("KEY")
+                .unwrap();
+            match get_setting(&config, key) {
+                Ok(serde_json::Value::String(value)) => println!("{}", value),
+                Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+                Err(err) => {
+                    println!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("config", sub_matches)) if sub_matches.subcommand_name() == Some("set") => {
+            let set_matches = sub_matches.subcommand_matches("set").unwrap();
+            let key = set_matches.get_one::<String>("KEY").unwrap();
+            let value = set_matches.get_one::<String>("VALUE").unwrap();
+            // Environment and command line overrides stay out of the file
+            let changed = read_config().and_then(|file| set_setting(&file, key, value));
+            match changed.and_then(|changed| save_config(&changed)) {
+                Ok(_) => {
+                    println!("Set {}", key);
+                    record(
+                        &config,
+                        EventKind::Audit {
+                            action: "config_set".to_string(),
+                            detail: key.to_string(),
+                        },
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    println!("Failed to set {}: {}", key, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("config", sub_matches)) if sub_matches.subcommand_name() == Some("show") => {
+            match stringify_config(&config) {
+                Ok(text) => println!("{}", text),
+                Err(err) => {
+                    println!("Failed to show the config: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("config", _)) => match save_args() {
+            Ok((config, changed)) => {
                 println!("Config saved");
-                println!("The config is now:\n{}", stringify_config(&config));
+                // Only what changed, the config holds passwords and tokens
+                record(
+                    &config,
+                    EventKind::Audit {
+                        action: "config_saved".to_string(),
+                        detail: changed.join(", "),
+                    },
+                )
+                .await;
+                // Saving already wrote it out in this format
+                let text = stringify_config(&config).unwrap_or_default();
+                println!("The config is now:\n{}", text);
 
                 if config.systemd {
                     match systemd::systemd_restart() {
@@ -76,6 +404,300 @@ async fn main() {
                 println!("Failed to save config: {}", err);
             }
         },
+        #[cfg(feature = "mdns")]
+        Some(("discover", sub_matches)) => {
+            let wait = *sub_matches.get_one::<u64>("wait").unwrap();
+            println!("Looking for proxies for {} seconds...", wait);
+            match toggleproxy::mdns::mdns_discover(Duration::from_secs(wait)) {
+                Ok(found) => {
+                    if found.is_empty() {
+                        println!("No proxies found");
+                    }
+                    for (index, discovered) in found.iter().enumerate() {
+                        println!(
+                            "[{}] {} at {}{}",
+                            index,
+                            discovered.name,
+                            discovered.addr,
+                            match discovered.toggleproxy {
+                                true => " (toggleproxy)",
+                                false => "",
+                            }
+                        );
+                    }
+
+                    if let Some(index) = sub_matches.get_one::<usize>("use") {
+                        match found.get(*index) {
+                            Some(discovered) => {
+                                config.target = discovered.addr.to_string().into();
+                                let saved = update_config(|file| {
+                                    file.target = config.target.clone();
+                                    Ok(())
+                                });
+                                match saved {
+                                    Ok(_) => {
+                                        println!("Target set to {}", config.target);
+                                        record(
+                                            &config,
+                                            EventKind::Audit {
+                                                action: "target_changed".to_string(),
+                                                detail: config.target.to_string(),
+                                            },
+                                        )
+                                        .await;
+                                        if config.systemd {
+                                            match systemd::systemd_restart() {
+                                                Ok(_) => {
+                                                    println!("Systemd service restarted");
+                                                }
+                                                Err(err) => {
+                                                    println!(
+                                                        "Failed to restart systemd service: {}",
+                                                        err
+                                                    );
+                                                }
+                                            }
+                                        }
+                                    }
+                                    Err(err) => {
+                                        println!("Failed to save config: {}", err);
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("No proxy with index {}", index);
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    println!("Failed to discover proxies: {}", err);
+                }
+            }
+        }
+        #[cfg(not(feature = "mdns"))]
+        Some(("discover", _)) => {
+            println!("This build has no mDNS support");
+        }
+        Some(("migrate", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("FILE").unwrap();
+            let from = sub_matches.get_one::<String>("from").unwrap();
+            match std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| migrate::migrate(from, &text, &mut config))
+            {
+                Ok(notes) => {
+                    for note in notes {
+                        eprintln!("Note: {}", note);
+                    }
+                    match stringify_config(&config) {
+                        Ok(text) => println!("{}", text),
+                        Err(err) => println!("Failed to write the migrated config: {}", err),
+                    }
+                }
+                Err(err) => {
+                    println!("Failed to migrate {}: {}", path, err);
+                }
+            }
+        }
+        Some(("ping", sub_matches)) => {
+            match ping::ping(
+                &config,
+                sub_matches.get_one::<String>("HOST").unwrap(),
+                *sub_matches.get_one::<u32>("count").unwrap(),
+                Duration::from_millis(*sub_matches.get_one::<u64>("interval").unwrap()),
+            )
+            .await
+            {
+                Ok(_) => {}
+                Err(err) => {
+                    println!("Failed to ping: {}", err);
+                }
+            }
+        }
+        Some(("stats", sub_matches)) => {
+            let report = Client::from_config(&config)
+                .stats(
+                    *sub_matches.get_one::<usize>("top").unwrap(),
+                    sub_matches
+                        .get_one::<u64>("window")
+                        .map(|window| Duration::from_secs(*window)),
+                )
+                .await;
+            match report {
+                Ok(report) => stats::print_report(&report),
+                Err(err) => {
+                    println!("Failed to get stats: {}", err);
+                }
+            }
+        }
+        Some(("trace", sub_matches)) => {
+            let client = Client::from_config(&config);
+            let traces = match sub_matches.get_flag("live") {
+                true => client.connections().await,
+                false => {
+                    client
+                        .trace(sub_matches.get_one::<u64>("ID").copied())
+                        .await
+                }
+            };
+            match traces {
+                Ok(traces) => timing::print_traces(&traces),
+                Err(err) => {
+                    println!("Failed to get traces: {}", err);
+                }
+            }
+        }
+        Some(("system-proxy", sub_matches)) => match sub_matches.subcommand() {
+            Some(("enable", _)) => match sysproxy::sysproxy_enable(&config) {
+                Ok(_) => {
+                    println!("System proxy set to 127.0.0.1:{}", config.port);
+                    record(
+                        &config,
+                        EventKind::Audit {
+                            action: "system_proxy_enabled".to_string(),
+                            detail: format!("127.0.0.1:{}", config.port),
+                        },
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    println!("Failed to set system proxy: {}", err);
+                }
+            },
+            Some(("disable", _)) => match sysproxy::sysproxy_disable() {
+                Ok(_) => {
+                    println!("System proxy restored");
+                    record(
+                        &config,
+                        EventKind::Audit {
+                            action: "system_proxy_disabled".to_string(),
+                            detail: String::new(),
+                        },
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    println!("Failed to restore system proxy: {}", err);
+                }
+            },
+            _ => {}
+        },
         _ => {}
     }
 }
+
+/// Whether `err` means no server answered on the control socket
+fn is_unavailable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ControlError>()
+        .is_some_and(|err| err.code == ErrorCode::Unavailable)
+}
+
+/// Points the OS proxy the way the proxy was switched without a running
+/// server, with `system_proxy`
+fn sync_system_proxy(config: &Config) {
+    if !config.system_proxy {
+        return;
+    }
+    match sysproxy::sysproxy_sync(config) {
+        Ok(_) => {
+            println!("System proxy updated");
+        }
+        Err(err) => {
+            println!("Failed to update system proxy: {}", err);
+        }
+    }
+}
+
+/// Toggles the proxy in the config file while the server isn't running
+async fn toggle_saved(config: &mut Config) -> anyhow::Result<()> {
+    config.status = !config.status;
+    match save_status(config) {
+        Ok(Persisted::Config) => {}
+        Ok(Persisted::StateFile(path)) => {
+            println!(
+                "The config file is read-only, the toggle is saved for this user in {}",
+                path.display()
+            );
+        }
+        Err(err) => {
+            config.status = !config.status;
+            return Err(err);
+        }
+    }
+    record(
+        config,
+        EventKind::Toggle {
+            status: config.status,
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// Toggles a listener in the config file while the server isn't running
+fn toggle_listener_saved(config: &mut Config, name: &str) -> anyhow::Result<bool> {
+    let listener = config
+        .listeners
+        .iter_mut()
+        .find(|listener| listener.name == name)
+        .ok_or_else(|| anyhow::anyhow!("No listener named {}", name))?;
+    listener.status = !listener.status;
+    let status = listener.status;
+    if let Persisted::StateFile(path) = save_status(config)? {
+        println!(
+            "The config file is read-only, the toggle is saved for this user in {}",
+            path.display()
+        );
+    }
+    Ok(status)
+}
+
+fn print_status(status: &Status) {
+    println!(
+        "Proxy server is running on port {}, up {}",
+        status.port,
+        format_uptime(status.uptime_secs)
+    );
+    println!(
+        "Proxy is {}",
+        match status.status {
+            true => "on",
+            false => "off",
+        }
+    );
+    if let Some(profile) = &status.profile {
+        println!("Profile: {}", profile);
+    }
+    match &status.active_upstream {
+        Some(upstream) => println!("Active target: {}", upstream),
+        None => println!("Targets: {}", status.targets.join(", ")),
+    }
+    if !status.warm_standby.is_empty() {
+        println!("Warm standby: {}", status.warm_standby.join(", "));
+    }
+    if let Some(addr) = status.external_addr {
+        println!("Reachable from outside at {}", addr);
+    }
+    for listener in &status.listeners {
+        println!(
+            "Listener {} on port {}: {}",
+            listener.name,
+            listener.port,
+            match listener.status {
+                true => "on",
+                false => "off",
+            }
+        );
+    }
+    println!("Open tunnels: {}", status.open_tunnels);
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use log::info;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::config::Config;
+
+pub const SERVICE_TYPE: &str = "_socks5._tcp.local.";
+
+/// TXT record that tells toggleproxy instances apart from other SOCKS5 servers
+pub const APP_PROPERTY: (&str, &str) = ("app", "toggleproxy");
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("toggleproxy".to_string())
+}
+
+/// Registers the proxy with DNS-SD, the advertisement lasts as long as the
+/// returned daemon is kept alive
+pub fn mdns_advertise(config: &Config) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    let hostname = hostname();
+
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &format!("toggleproxy on {}", hostname),
+        &format!("{}.local.", hostname),
+        "",
+        config.port,
+        &[APP_PROPERTY, ("version", env!("CARGO_PKG_VERSION"))][..],
+    )?
+    .enable_addr_auto();
+
+    daemon.register(service)?;
+    info!(
+        "Advertising proxy as {} on port {}",
+        SERVICE_TYPE, config.port
+    );
+
+    Ok(daemon)
+}
+
+/// A SOCKS5 server found on the LAN
+pub struct Discovered {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub toggleproxy: bool,
+}
+
+/// Browses for SOCKS5 servers for `wait`, toggleproxy instances are listed first
+pub fn mdns_discover(wait: Duration) -> Result<Vec<Discovered>> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + wait;
+
+    let mut found: Vec<Discovered> = Vec::new();
+    while let Ok(event) = receiver.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(service) = event {
+            let name = service
+                .get_fullname()
+                .trim_end_matches(SERVICE_TYPE)
+                .trim_end_matches('.')
+                .to_string();
+            let toggleproxy = service.get_property_val_str(APP_PROPERTY.0) == Some(APP_PROPERTY.1);
+
+            for ip in service.get_addresses() {
+                let addr = SocketAddr::new(*ip, service.get_port());
+                if !found.iter().any(|discovered| discovered.addr == addr) {
+                    found.push(Discovered {
+                        name: name.clone(),
+                        addr,
+                        toggleproxy,
+                    });
+                }
+            }
+        }
+    }
+
+    let _ = daemon.shutdown();
+    found.sort_by_key(|discovered| !discovered.toggleproxy);
+    Ok(found)
+}
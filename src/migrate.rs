@@ -0,0 +1,169 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{Config, Rule, RuleAction, Targets};
+
+/// Formats `migrate` can read
+pub const FORMATS: [&str; 3] = ["privoxy", "ssh-config", "proxychains"];
+
+fn direct_rule(pattern: String) -> Rule {
+    Rule {
+        pattern,
+        action: RuleAction::Direct,
+        quic: None,
+        race: false,
+    }
+}
+
+fn proxy_rule(pattern: String) -> Rule {
+    Rule {
+        pattern,
+        action: RuleAction::Proxy,
+        quic: None,
+        race: false,
+    }
+}
+
+/// Converts a privoxy URL pattern to a rule pattern, when it is a plain
+/// domain or address
+fn privoxy_pattern(pattern: &str) -> Option<String> {
+    let pattern = pattern.trim_end_matches('/').trim_start_matches('.');
+    match pattern.is_empty() || pattern.contains(['*', '/', '[', '?']) {
+        true => None,
+        false => Some(pattern.to_string()),
+    }
+}
+
+/// Reads `forward-socks5`/`forward-socks5t` and `forward` lines
+fn privoxy(text: &str, config: &mut Config, notes: &mut Vec<String>) {
+    for (number, line) in text.lines().enumerate() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields.as_slice() {
+            [action @ ("forward-socks5" | "forward-socks5t"), pattern, proxy, ..] => {
+                match (*pattern, privoxy_pattern(pattern)) {
+                    ("/", _) => config.target = proxy.to_string().into(),
+                    (_, Some(pattern)) => {
+                        if config.target.is_empty() {
+                            config.target = proxy.to_string().into();
+                        }
+                        config.rules.push(proxy_rule(pattern));
+                    }
+                    (_, None) => notes.push(format!(
+                        "line {}: skipped {} pattern {}",
+                        number + 1,
+                        action,
+                        pattern
+                    )),
+                }
+            }
+            ["forward", pattern, "."] => match privoxy_pattern(pattern) {
+                Some(pattern) => config.rules.push(direct_rule(pattern)),
+                None => notes.push(format!(
+                    "line {}: skipped forward pattern {}",
+                    number + 1,
+                    pattern
+                )),
+            },
+            [action @ ("forward" | "forward-socks4" | "forward-socks4a"), ..] => {
+                notes.push(format!("line {}: {} has no equivalent", number + 1, action))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads `DynamicForward`, which makes ssh a SOCKS proxy on localhost
+fn ssh_config(text: &str, config: &mut Config, notes: &mut Vec<String>) {
+    let mut host = "*".to_string();
+    for line in text.lines() {
+        let line = line.trim();
+        let (key, value) = match line.split_once([' ', '\t', '=']) {
+            Some((key, value)) => (key, value.trim().trim_start_matches('=').trim()),
+            None => continue,
+        };
+
+        if key.eq_ignore_ascii_case("Host") {
+            host = value.to_string();
+        } else if key.eq_ignore_ascii_case("DynamicForward") {
+            let target = match value.rsplit_once(':') {
+                Some((bind, port)) if bind.is_empty() || bind == "*" || bind == "localhost" => {
+                    format!("127.0.0.1:{}", port)
+                }
+                Some(_) => value.to_string(),
+                None => format!("127.0.0.1:{}", value),
+            };
+            match config.target.is_empty() {
+                true => {
+                    config.target = target.into();
+                    notes.push(format!(
+                        "target is the forward of Host {}, run `ssh -N {}` for it to work",
+                        host, host
+                    ));
+                }
+                false => notes.push(format!(
+                    "skipped the DynamicForward of Host {}, only one target is supported",
+                    host
+                )),
+            }
+        }
+    }
+}
+
+/// Reads the first socks5 proxy in `[ProxyList]` and `localnet` exclusions
+fn proxychains(text: &str, config: &mut Config, notes: &mut Vec<String>) {
+    let mut in_list = false;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.starts_with('[') {
+            in_list = line.eq_ignore_ascii_case("[ProxyList]");
+            continue;
+        }
+
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match (in_list, fields.as_slice()) {
+            (false, ["localnet", net]) => {
+                let (addr, mask) = net.split_once('/').unwrap_or((net, "255.255.255.255"));
+                let prefix = mask
+                    .parse::<Ipv4Addr>()
+                    .map(|mask| u32::from(mask).count_ones().to_string())
+                    .unwrap_or(mask.to_string());
+                config
+                    .rules
+                    .push(direct_rule(format!("{}/{}", addr, prefix)));
+            }
+            (true, ["socks5", host, port, credentials @ ..]) if config.target.is_empty() => {
+                config.target = format!("{}:{}", host, port).into();
+                if let [username, password] = credentials {
+                    config.target_username = Some(username.to_string());
+                    config.target_password = Some(password.to_string());
+                }
+            }
+            (true, [kind, ..]) => notes.push(format!(
+                "line {}: skipped {} proxy, only the first socks5 proxy is used",
+                number + 1,
+                kind
+            )),
+            _ => {}
+        }
+    }
+}
+
+/// Builds on `config` with the upstream and rules found in another tool's
+/// config, returning notes on anything that couldn't be carried over
+pub fn migrate(from: &str, text: &str, config: &mut Config) -> Result<Vec<String>> {
+    let mut notes = Vec::new();
+    config.target = Targets::default();
+
+    match from {
+        "privoxy" => privoxy(text, config, &mut notes),
+        "ssh-config" => ssh_config(text, config, &mut notes),
+        "proxychains" => proxychains(text, config, &mut notes),
+        _ => return Err(anyhow!("Unknown format {}", from)),
+    }
+
+    if config.target.is_empty() {
+        return Err(anyhow!("No SOCKS5 upstream found"));
+    }
+    Ok(notes)
+}
@@ -0,0 +1,175 @@
+use std::{net::IpAddr, process::Command, sync::Arc, time::Duration};
+
+use log::{error, info, trace};
+
+use tokio::{sync::watch, time::sleep};
+
+use crate::{
+    config::{Config, NetworkRule},
+    control::{set_status, Response},
+};
+
+/// How often the network is looked at
+const CHECK_EVERY: Duration = Duration::from_secs(5);
+
+/// The network this machine is on, as far as `network_rules` can tell
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct Network {
+    /// The interface of the default route
+    pub interface: Option<String>,
+    pub gateway: Option<IpAddr>,
+    /// The Wi-Fi network, only looked up when a rule asks for it
+    pub ssid: Option<String>,
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.ssid, &self.interface, &self.gateway) {
+            (Some(ssid), _, _) => write!(f, "Wi-Fi {}", ssid),
+            (None, Some(interface), Some(gateway)) => write!(f, "{} via {}", interface, gateway),
+            (None, Some(interface), None) => write!(f, "{}", interface),
+            _ => write!(f, "no network"),
+        }
+    }
+}
+
+impl NetworkRule {
+    /// Whether every condition the rule sets holds on `network`
+    pub fn matches(&self, network: &Network) -> bool {
+        (self.ssid.is_none() || self.ssid == network.ssid)
+            && (self.interface.is_none() || self.interface == network.interface)
+            && (self.gateway.is_none() || self.gateway == network.gateway)
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !stdout.is_empty()).then_some(stdout)
+}
+
+/// The default IPv4 route from the kernel's routing table, lowest metric first
+#[cfg(target_os = "linux")]
+fn default_route() -> (Option<String>, Option<IpAddr>) {
+    let table = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let route = table
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        // Iface Destination Gateway Flags RefCnt Use Metric Mask
+        .filter(|fields| fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000")
+        .min_by_key(|fields| fields[6].parse::<u32>().unwrap_or(u32::MAX));
+    match route {
+        Some(fields) => {
+            // Printed as a native-endian u32 holding the address bytes
+            let gateway = u32::from_str_radix(fields[2], 16)
+                .ok()
+                .filter(|gateway| *gateway != 0)
+                .map(|gateway| IpAddr::from(gateway.to_ne_bytes()));
+            (Some(fields[0].to_string()), gateway)
+        }
+        None => (None, None),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn ssid(_interface: Option<&str>) -> Option<String> {
+    run("iwgetid", &["-r"]).or_else(|| {
+        run("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"])?
+            .lines()
+            .find_map(|line| line.strip_prefix("yes:").map(str::to_string))
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn default_route() -> (Option<String>, Option<IpAddr>) {
+    let output = run("route", &["-n", "get", "default"]).unwrap_or_default();
+    let field = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.trim().split_once(':')?;
+            (key == name).then(|| value.trim().to_string())
+        })
+    };
+    (
+        field("interface"),
+        field("gateway").and_then(|gateway| gateway.parse().ok()),
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn ssid(interface: Option<&str>) -> Option<String> {
+    let output = run("networksetup", &["-getairportnetwork", interface?])?;
+    output
+        .strip_prefix("Current Wi-Fi Network: ")
+        .map(str::to_string)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn default_route() -> (Option<String>, Option<IpAddr>) {
+    (None, None)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn ssid(_interface: Option<&str>) -> Option<String> {
+    None
+}
+
+/// Looks at the default route, and the Wi-Fi network with `with_ssid`
+pub fn current(with_ssid: bool) -> Network {
+    let (interface, gateway) = default_route();
+    let ssid = match with_ssid {
+        true => ssid(interface.as_deref()),
+        false => None,
+    };
+    Network {
+        interface,
+        gateway,
+        ssid,
+    }
+}
+
+/// Switches the proxy as the first matching `network_rules` entry says
+/// whenever the network changes, including the one the server starts on.
+/// A manual toggle holds until the next change
+pub async fn watch_network(live: Arc<watch::Sender<Config>>) {
+    if cfg!(not(any(target_os = "linux", target_os = "macos"))) {
+        if !live.borrow().network_rules.is_empty() {
+            error!("Network changes can't be detected on this platform, ignoring network_rules");
+        }
+        return;
+    }
+
+    let mut last = None;
+    loop {
+        let rules = live.borrow().network_rules.clone();
+        if !rules.is_empty() {
+            let with_ssid = rules.iter().any(|rule| rule.ssid.is_some());
+            let network = tokio::task::spawn_blocking(move || current(with_ssid))
+                .await
+                .unwrap_or_default();
+            if last.as_ref() != Some(&network) {
+                trace!("Network is now {:?}", network);
+                if let Some(rule) = rules.iter().find(|rule| rule.matches(&network)) {
+                    switch(&live, &network, rule.status);
+                }
+                last = Some(network);
+            }
+        }
+        sleep(CHECK_EVERY).await;
+    }
+}
+
+fn switch(live: &watch::Sender<Config>, network: &Network, status: bool) {
+    if live.borrow().status == status {
+        return;
+    }
+    let switched = match status {
+        true => "on",
+        false => "off",
+    };
+    // Also points the system proxy the new way
+    match set_status(live, status, false) {
+        Response::Ok(_) => info!("On {}, switched the proxy {}", network, switched),
+        Response::Error(err) => error!("Failed to switch the proxy on a network change: {}", err),
+    }
+}
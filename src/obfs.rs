@@ -0,0 +1,248 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use async_trait::async_trait;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{config::Obfs, transport::BoxStream};
+
+/// A layer that disguises the connection to the target proxy. The target end
+/// has to run the matching server, e.g. simple-obfs for `http`
+#[async_trait]
+pub trait Obfuscator: Send + Sync {
+    /// Wraps a fresh connection, performing any handshake the scheme needs
+    async fn wrap(&self, stream: BoxStream) -> io::Result<BoxStream>;
+}
+
+pub fn obfuscator(config: &Obfs) -> Box<dyn Obfuscator> {
+    match config.clone() {
+        Obfs::Xor { key } => Box::new(Xor {
+            key: key.into_bytes(),
+        }),
+        Obfs::Http { host } => Box::new(Http { host }),
+    }
+}
+
+/// XORs every byte with a repeating key, hides protocol signatures from naive DPI
+pub struct Xor {
+    key: Vec<u8>,
+}
+
+#[async_trait]
+impl Obfuscator for Xor {
+    async fn wrap(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        Ok(Box::new(XorStream {
+            inner: stream,
+            key: self.key.clone(),
+            read_pos: 0,
+            write_pos: 0,
+        }))
+    }
+}
+
+struct XorStream {
+    inner: BoxStream,
+    key: Vec<u8>,
+    read_pos: usize,
+    write_pos: usize,
+}
+
+impl XorStream {
+    fn apply(key: &[u8], pos: &mut usize, data: &mut [u8]) {
+        if key.is_empty() {
+            return;
+        }
+        for byte in data {
+            *byte ^= key[*pos % key.len()];
+            *pos = (*pos + 1) % key.len();
+        }
+    }
+}
+
+impl AsyncRead for XorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        XorStream::apply(
+            &this.key,
+            &mut this.read_pos,
+            &mut buf.filled_mut()[filled..],
+        );
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for XorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut data = buf.to_vec();
+        let mut pos = this.write_pos;
+        XorStream::apply(&this.key, &mut pos, &mut data);
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &data))?;
+        if !this.key.is_empty() {
+            this.write_pos = (this.write_pos + written) % this.key.len();
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// simple-obfs style HTTP disguise, the first write is sent as the body of a
+/// websocket upgrade request and the server's response headers are skipped
+pub struct Http {
+    host: String,
+}
+
+#[async_trait]
+impl Obfuscator for Http {
+    async fn wrap(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        Ok(Box::new(HttpStream {
+            inner: stream,
+            host: self.host.clone(),
+            request: Vec::new(),
+            request_pos: 0,
+            request_sent: false,
+            body_len: 0,
+            response: Vec::new(),
+            response_pos: 0,
+            response_skipped: false,
+        }))
+    }
+}
+
+struct HttpStream {
+    inner: BoxStream,
+    host: String,
+    request: Vec<u8>,
+    request_pos: usize,
+    request_sent: bool,
+    body_len: usize,
+    response: Vec<u8>,
+    response_pos: usize,
+    response_skipped: bool,
+}
+
+impl HttpStream {
+    fn build_request(&self, body: &[u8]) -> Vec<u8> {
+        let key = STANDARD.encode(rand::random::<[u8; 16]>());
+        let mut request = format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/8.4.0\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+            self.host,
+            key,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        request
+    }
+}
+
+impl AsyncRead for HttpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.response_skipped {
+            let mut chunk = [0u8; 1024];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.response.extend_from_slice(chunk_buf.filled());
+
+            if let Some(end) = this
+                .response
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            {
+                this.response_pos = end + 4;
+                this.response_skipped = true;
+            } else if this.response.len() > 16384 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Obfuscation response headers too large",
+                )));
+            }
+        }
+
+        // Data that arrived in the same packets as the response headers
+        if this.response_pos < this.response.len() {
+            let len = buf.remaining().min(this.response.len() - this.response_pos);
+            buf.put_slice(&this.response[this.response_pos..this.response_pos + len]);
+            this.response_pos += len;
+            if this.response_pos == this.response.len() {
+                this.response = Vec::new();
+                this.response_pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HttpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.request_sent {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.request.is_empty() {
+            this.request = this.build_request(buf);
+            this.body_len = buf.len();
+        }
+        while this.request_pos < this.request.len() {
+            let written = ready!(
+                Pin::new(&mut this.inner).poll_write(cx, &this.request[this.request_pos..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.request_pos += written;
+        }
+
+        this.request = Vec::new();
+        this.request_sent = true;
+        Poll::Ready(Ok(this.body_len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
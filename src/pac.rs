@@ -0,0 +1,212 @@
+use std::{net::IpAddr, sync::Arc};
+
+use crate::config::{Config, PacProfile, PacRoute, RuleAction};
+
+use anyhow::Result;
+
+use ipnet::IpNet;
+
+use log::{error, info, trace};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Serves `/proxy.pac` plus one `/pac/<name>.pac` per configured profile, and
+/// `/wpad.dat` when WPAD is enabled. Each request gets what `live` holds then,
+/// so clients that fetch the PAC file again follow toggles and rule changes
+pub async fn pac_server(live: Arc<watch::Sender<Config>>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    info!("Serving PAC files on port {}", port);
+
+    while let Ok((stream, _)) = listener.accept().await {
+        let config = live.borrow().clone();
+        tokio::spawn(async move {
+            match serve(stream, config).await {
+                Ok(_) => {}
+                Err(err) => {
+                    error!("Failed to serve PAC request");
+                    trace!("{}", err);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, config: Config) -> Result<()> {
+    let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+        if len == buf.len() {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        match stream.read(&mut buf[len..]).await? {
+            0 => return Ok(()),
+            read => len += read,
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut lines = request.lines();
+    let path = match lines.next().map(|line| line.split(' ').collect::<Vec<_>>()) {
+        Some(parts) if parts.len() == 3 && parts[0] == "GET" => parts[1].to_string(),
+        _ => return respond(&mut stream, "405 Method Not Allowed", "").await,
+    };
+
+    // Clients reach the proxy on the same address they fetched the PAC file from
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| strip_port(value.trim()).to_string())
+        .unwrap_or("127.0.0.1".to_string());
+
+    let profile = match path.as_str() {
+        "/proxy.pac" => None,
+        "/wpad.dat" if config.wpad => match &config.wpad_profile {
+            Some(name) => match config.pac_profiles.iter().find(|p| &p.name == name) {
+                Some(profile) => Some(profile),
+                None => return respond(&mut stream, "404 Not Found", "").await,
+            },
+            None => None,
+        },
+        path => match path
+            .strip_prefix("/pac/")
+            .and_then(|name| name.strip_suffix(".pac"))
+            .and_then(|name| config.pac_profiles.iter().find(|p| p.name == name))
+        {
+            Some(profile) => Some(profile),
+            None => return respond(&mut stream, "404 Not Found", "").await,
+        },
+    };
+
+    let pac = match profile {
+        Some(profile) => generate_pac(&host, config.port, Some(profile)),
+        None => live_pac(&host, &config),
+    };
+    respond(&mut stream, "200 OK", &pac).await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nCache-Control: no-cache\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+fn pac_route(route: PacRoute, proxy: &str) -> String {
+    match route {
+        PacRoute::Proxy => proxy.to_string(),
+        PacRoute::Direct => "DIRECT".to_string(),
+    }
+}
+
+/// A domain pattern matches the domain itself and all of its subdomains
+fn pac_condition(domains: &[String]) -> String {
+    domains
+        .iter()
+        .map(|domain| domain.trim_start_matches("*.").trim_start_matches('.'))
+        .map(|domain| {
+            format!(
+                "host == \"{}\" || dnsDomainIs(host, \".{}\")",
+                domain, domain
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ||\n        ")
+}
+
+fn pac_proxy(host: &str, port: u16) -> String {
+    format!("SOCKS5 {}:{}; SOCKS {}:{}", host, port, host, port)
+}
+
+/// The PAC condition for a rule's pattern. IPv6 ranges have none, `isInNet`
+/// only knows IPv4
+fn rule_condition(pattern: &str) -> Option<String> {
+    match (pattern.parse::<IpNet>(), pattern.parse::<IpAddr>()) {
+        (Ok(IpNet::V4(net)), _) => Some(format!(
+            "/^[0-9.]+$/.test(host) && isInNet(host, \"{}\", \"{}\")",
+            net.network(),
+            net.netmask()
+        )),
+        (Ok(IpNet::V6(_)), _) => None,
+        (_, Ok(ip)) => Some(format!("host == \"{}\"", ip)),
+        _ => Some(pac_condition(&[pattern.to_string()])),
+    }
+}
+
+/// The default PAC file: through the proxy while it is on and direct while it
+/// is off, except where the configured rules say otherwise. Rules only
+/// imported from `rule_lists` are left to the proxy
+pub fn live_pac(host: &str, config: &Config) -> String {
+    let proxy = pac_proxy(host, config.port);
+    let mut body = String::new();
+    for rule in config.rules.iter() {
+        // Blocks and redirects need the proxy to happen at all
+        let route = match rule.action {
+            RuleAction::Direct => PacRoute::Direct,
+            _ => PacRoute::Proxy,
+        };
+        if let Some(condition) = rule_condition(&rule.pattern) {
+            body.push_str(&format!(
+                "    if ({})\n        return \"{}\";\n",
+                condition,
+                pac_route(route, &proxy)
+            ));
+        }
+    }
+
+    let default = match config.status {
+        true => PacRoute::Proxy,
+        false => PacRoute::Direct,
+    };
+    body.push_str(&format!("    return \"{}\";\n", pac_route(default, &proxy)));
+
+    format!("function FindProxyForURL(url, host) {{\n{}}}\n", body)
+}
+
+pub fn generate_pac(host: &str, port: u16, profile: Option<&PacProfile>) -> String {
+    let proxy = pac_proxy(host, port);
+
+    let mut body = String::new();
+    if let Some(profile) = profile {
+        for (domains, route) in [
+            (&profile.direct, PacRoute::Direct),
+            (&profile.proxy, PacRoute::Proxy),
+        ] {
+            if !domains.is_empty() {
+                body.push_str(&format!(
+                    "    if ({})\n        return \"{}\";\n",
+                    pac_condition(domains),
+                    pac_route(route, &proxy)
+                ));
+            }
+        }
+    }
+
+    let default = match profile {
+        Some(profile) => profile.default,
+        None => PacRoute::Proxy,
+    };
+    body.push_str(&format!("    return \"{}\";\n", pac_route(default, &proxy)));
+
+    format!("function FindProxyForURL(url, host) {{\n{}}}\n", body)
+}
@@ -0,0 +1,84 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::proto::Address;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{config::Config, server::connect_target};
+
+/// Parses `host[:port]`, defaulting to port 80
+pub fn parse_address(target: &str) -> Result<Address> {
+    if let Ok(addr) = target.parse() {
+        return Ok(Address::SocketAddress(addr));
+    }
+
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, port.parse()?),
+        _ => (target, 80),
+    };
+    if host.is_empty() {
+        return Err(anyhow!("No host given"));
+    }
+
+    Ok(match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress((ip, port).into()),
+        Err(_) => Address::DomainAddress(host.as_bytes().to_vec(), port),
+    })
+}
+
+/// Measures TCP connect times to `target` over the route new connections take
+pub async fn ping(config: &Config, target: &str, count: u32, interval: Duration) -> Result<()> {
+    let addr = parse_address(target)?;
+
+    println!(
+        "Connecting to {} {}",
+        target,
+        match config.status {
+            true => format!("through {}", config.target),
+            false => "directly".to_string(),
+        }
+    );
+
+    let mut times: Vec<Duration> = Vec::new();
+    for seq in 0..count {
+        if seq > 0 {
+            tokio::time::sleep(interval).await;
+        }
+
+        let start = Instant::now();
+        match connect_target(config, &addr).await {
+            Ok(mut stream) => {
+                let elapsed = start.elapsed();
+                let _ = stream.shutdown().await;
+                println!("seq={} time={:.1}ms", seq, elapsed.as_secs_f64() * 1000.0);
+                times.push(elapsed);
+            }
+            Err(err) => {
+                println!("seq={} failed: {}", seq, err);
+            }
+        }
+    }
+
+    println!(
+        "{} connects, {} succeeded, {:.0}% failed",
+        count,
+        times.len(),
+        (count as usize - times.len()) as f64 / count as f64 * 100.0
+    );
+    if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
+        let avg = times.iter().sum::<Duration>() / times.len() as u32;
+        println!(
+            "min/avg/max = {:.1}/{:.1}/{:.1} ms",
+            min.as_secs_f64() * 1000.0,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        );
+    }
+
+    Ok(())
+}
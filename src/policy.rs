@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+
+use lazy_static::lazy_static;
+
+use crate::proto::Address;
+
+use crate::{
+    acl,
+    config::ClientPolicy,
+    events::{emit, EventKind},
+};
+
+lazy_static! {
+    /// Policies whose blocks are lifted until the deadline
+    static ref LIFTED: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Longest a block can be lifted for, far short of where `Instant` overflows
+const MAX_LIFT: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Lets the clients of policy `name` reach everything for `ttl`
+pub fn lift(name: &str, ttl: Duration) {
+    let ttl = ttl.min(MAX_LIFT);
+    let mut lifted = LIFTED.lock().unwrap();
+    lifted.retain(|_, until| *until > Instant::now());
+    lifted.insert(name.to_string(), Instant::now() + ttl);
+    drop(lifted);
+
+    emit(EventKind::Audit {
+        action: "policy_lifted".to_string(),
+        detail: format!("{} for {}s", name, ttl.as_secs()),
+    });
+}
+
+fn is_lifted(name: &str) -> bool {
+    LIFTED
+        .lock()
+        .unwrap()
+        .get(name)
+        .is_some_and(|until| *until > Instant::now())
+}
+
+impl ClientPolicy {
+    /// Whether the policy keeps `client` from reaching `addr` right now
+    pub fn blocks(&self, client: IpAddr, addr: &Address) -> bool {
+        let client = client.to_canonical();
+        if !self.clients.iter().any(|net| net.contains(&client)) {
+            return false;
+        }
+        let now = Local::now();
+        (self.active.is_empty() || self.active.iter().any(|cron| cron.matches(&now)))
+            && acl::is_blocked(&self.blocked, addr)
+            && !is_lifted(&self.name)
+    }
+}
+
+/// The first of `policies` keeping `client` from reaching `addr` right now
+pub fn blocking<'a>(
+    policies: &'a [ClientPolicy],
+    client: IpAddr,
+    addr: &Address,
+) -> Option<&'a ClientPolicy> {
+    policies.iter().find(|policy| policy.blocks(client, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_lifts_are_cut_short() {
+        lift("forever", Duration::MAX);
+        assert!(is_lifted("forever"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use igd_next::{aio::tokio::search_gateway, PortMappingProtocol, SearchOptions};
+
+use lazy_static::lazy_static;
+
+use log::{error, info, trace};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::network;
+
+const LEASE_DURATION: u32 = 3600;
+
+/// Where NAT-PMP gateways listen, RFC 6886
+const NATPMP_PORT: u16 = 5351;
+
+/// NAT-PMP requests are retried with the wait doubling from this
+const NATPMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+
+const NATPMP_ATTEMPTS: u32 = 4;
+
+lazy_static! {
+    static ref EXTERNAL_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
+}
+
+/// The address the router forwards to the proxy, once a mapping succeeded
+pub fn external_addr() -> Option<SocketAddr> {
+    *EXTERNAL_ADDR.lock().unwrap()
+}
+
+/// Finds the local address used to reach `remote`, without sending anything
+async fn local_ip_towards(remote: SocketAddr) -> Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(remote).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+async fn map_port_upnp(port: u16) -> Result<SocketAddr> {
+    let gateway = match search_gateway(SearchOptions::default()).await {
+        Ok(gateway) => gateway,
+        Err(err) => {
+            trace!("{}", err);
+            return Err(anyhow!("No UPnP gateway found"));
+        }
+    };
+
+    let local_addr = SocketAddr::new(local_ip_towards(gateway.addr).await?, port);
+    match gateway
+        .add_port(
+            PortMappingProtocol::TCP,
+            port,
+            local_addr,
+            LEASE_DURATION,
+            "toggleproxy",
+        )
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            trace!("{}", err);
+            return Err(anyhow!("The gateway refused to map port {}", port));
+        }
+    }
+
+    match gateway.get_external_ip().await {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(err) => {
+            trace!("{}", err);
+            Err(anyhow!("Failed to get the external address"))
+        }
+    }
+}
+
+/// Sends a NAT-PMP request to `gateway` until an answer to `opcode` comes
+async fn natpmp_request(gateway: Ipv4Addr, request: &[u8], opcode: u8) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NATPMP_PORT)).await?;
+
+    let mut wait = NATPMP_FIRST_WAIT;
+    for _ in 0..NATPMP_ATTEMPTS {
+        socket.send(request).await?;
+        let mut buf = [0u8; 16];
+        if let Ok(received) = timeout(wait, socket.recv(&mut buf)).await {
+            let len = received?;
+            // Version 0, the request's opcode with the high bit set
+            if len >= 4 && buf[0] == 0 && buf[1] == opcode | 0x80 {
+                match u16::from_be_bytes([buf[2], buf[3]]) {
+                    0 => return Ok(buf[..len].to_vec()),
+                    code => return Err(anyhow!("The gateway refused with NAT-PMP code {}", code)),
+                }
+            }
+        }
+        wait *= 2;
+    }
+    Err(anyhow!("No NAT-PMP gateway answered"))
+}
+
+async fn map_port_natpmp(port: u16) -> Result<SocketAddr> {
+    let gateway = match network::current(false).gateway {
+        Some(IpAddr::V4(gateway)) => gateway,
+        _ => return Err(anyhow!("No IPv4 default gateway")),
+    };
+
+    let response = natpmp_request(gateway, &[0, 0], 0).await?;
+    let ip: [u8; 4] = match response.get(8..12) {
+        Some(ip) => ip.try_into()?,
+        None => return Err(anyhow!("Short NAT-PMP response")),
+    };
+
+    // Map TCP, asking for the same port outside
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&LEASE_DURATION.to_be_bytes());
+    let response = natpmp_request(gateway, &request, 2).await?;
+    let external_port = match response.get(10..12) {
+        Some(external) => u16::from_be_bytes([external[0], external[1]]),
+        None => return Err(anyhow!("Short NAT-PMP response")),
+    };
+
+    Ok(SocketAddr::new(Ipv4Addr::from(ip).into(), external_port))
+}
+
+/// Maps `port` over UPnP, or NAT-PMP for routers without it
+async fn map_port(port: u16) -> Result<SocketAddr> {
+    match map_port_upnp(port).await {
+        Ok(addr) => Ok(addr),
+        Err(upnp_err) => match map_port_natpmp(port).await {
+            Ok(addr) => Ok(addr),
+            Err(natpmp_err) => {
+                trace!("NAT-PMP: {}", natpmp_err);
+                Err(upnp_err)
+            }
+        },
+    }
+}
+
+/// Maps `port` on the router and renews the lease before it expires
+pub async fn port_mapping(port: u16) {
+    loop {
+        match map_port(port).await {
+            Ok(addr) => {
+                if external_addr() != Some(addr) {
+                    info!("Proxy is reachable from outside at {}", addr);
+                }
+                *EXTERNAL_ADDR.lock().unwrap() = Some(addr);
+            }
+            Err(err) => {
+                error!("Failed to map port {}: {}", port, err);
+                *EXTERNAL_ADDR.lock().unwrap() = None;
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(LEASE_DURATION as u64 / 2)).await;
+    }
+}
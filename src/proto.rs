@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "vendored-socks")]
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+use crate::socks5_async::lib::{Response, TargetAddr};
+
+#[cfg(not(feature = "vendored-socks"))]
+pub use socks5_proto::{Address, Reply};
+
+/// Where a client asked to go, as the SOCKS stack parsed it
+#[cfg(feature = "vendored-socks")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    SocketAddress(SocketAddr),
+    DomainAddress(Vec<u8>, u16),
+}
+
+#[cfg(feature = "vendored-socks")]
+impl Address {
+    pub const fn unspecified() -> Self {
+        Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
+
+#[cfg(feature = "vendored-socks")]
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::SocketAddress(addr)
+    }
+}
+
+#[cfg(feature = "vendored-socks")]
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::SocketAddress(addr) => write!(f, "{}", addr),
+            Address::DomainAddress(domain, port) => {
+                write!(f, "{}:{}", String::from_utf8_lossy(domain), port)
+            }
+        }
+    }
+}
+
+/// The answer to a client's request
+#[cfg(feature = "vendored-socks")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded,
+    GeneralFailure,
+    ConnectionNotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+}
+
+impl From<Reply> for Response {
+    fn from(reply: Reply) -> Response {
+        match reply {
+            Reply::Succeeded => Response::Success,
+            Reply::GeneralFailure => Response::Failure,
+            Reply::ConnectionNotAllowed => Response::RuleFailure,
+            Reply::NetworkUnreachable => Response::NetworkUnreachable,
+            Reply::HostUnreachable => Response::HostUnreachable,
+            Reply::ConnectionRefused => Response::ConnectionRefused,
+            Reply::TtlExpired => Response::TtlExpired,
+            Reply::CommandNotSupported => Response::CommandNotSupported,
+            Reply::AddressTypeNotSupported => Response::AddrTypeNotSupported,
+        }
+    }
+}
+
+impl From<Address> for TargetAddr {
+    fn from(addr: Address) -> TargetAddr {
+        match addr {
+            Address::SocketAddress(SocketAddr::V4(addr)) => TargetAddr::V4(addr),
+            Address::SocketAddress(SocketAddr::V6(addr)) => TargetAddr::V6(addr),
+            Address::DomainAddress(domain, port) => {
+                TargetAddr::Domain((String::from_utf8_lossy(&domain).into_owned(), port))
+            }
+        }
+    }
+}
+
+impl From<TargetAddr> for Address {
+    fn from(addr: TargetAddr) -> Address {
+        match addr {
+            TargetAddr::V4(addr) => Address::SocketAddress(addr.into()),
+            TargetAddr::V6(addr) => Address::SocketAddress(addr.into()),
+            TargetAddr::Domain((domain, port)) => Address::DomainAddress(domain.into_bytes(), port),
+        }
+    }
+}
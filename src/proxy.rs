@@ -0,0 +1,173 @@
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use crate::proto::Address;
+
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::{Config, Targets},
+    control::Status,
+    events::{emit, subscribe, Event, EventKind},
+    rule_lists,
+    server::{accept, socks_listener},
+    transport::BoxStream,
+};
+
+/// A CONNECT request as middleware sees it
+pub struct ConnectionInfo {
+    pub client: SocketAddr,
+    /// Where the client asked to go, middleware can point it elsewhere
+    pub target: Address,
+    /// Added to the connection's access event
+    pub tags: Vec<String>,
+}
+
+/// What happens to a connection after a middleware has seen it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Decision {
+    /// Hand it to the next middleware, then the rules
+    Continue,
+    Direct,
+    Proxy,
+    Deny,
+}
+
+/// Inspects and changes connections of an embedded proxy, in the order the
+/// middleware was added
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn on_connect(&self, info: &mut ConnectionInfo) -> Decision;
+
+    /// Wraps the stream to the destination once it is connected
+    fn wrap(&self, stream: BoxStream, _info: &ConnectionInfo) -> BoxStream {
+        stream
+    }
+}
+
+/// A toggleable SOCKS5 proxy running inside the current process, started
+/// with `Proxy::builder().listen(..).upstream(..).spawn()`
+pub struct Proxy;
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder {
+            config: Config::default(),
+            listen: SocketAddr::from(([127, 0, 0, 1], Config::default().port)),
+            upstreams: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+}
+
+pub struct ProxyBuilder {
+    config: Config,
+    listen: SocketAddr,
+    upstreams: Vec<String>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl ProxyBuilder {
+    /// Starts from `config` instead of the defaults, only the settings that
+    /// apply to connections are used
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Where to accept SOCKS5 clients, 127.0.0.1:1080 by default
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listen = addr;
+        self
+    }
+
+    /// Adds a target proxy, tried in the order they are added
+    pub fn upstream(mut self, target: impl Into<String>) -> Self {
+        self.upstreams.push(target.into());
+        self
+    }
+
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Whether connections start out going through the target proxy
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.config.status = enabled;
+        self
+    }
+
+    pub async fn spawn(self) -> Result<ProxyHandle> {
+        let mut config = self.config;
+        if !self.upstreams.is_empty() {
+            config.target = Targets(self.upstreams);
+        }
+        let imported = rule_lists::load(&config.rule_lists);
+        config.rules.set_imported(imported);
+
+        let listener = TcpListener::bind(self.listen).await?;
+        let local_addr = listener.local_addr()?;
+        config.port = local_addr.port();
+
+        let live = Arc::new(watch::channel(config).0);
+        let server = socks_listener(listener);
+        let task = tokio::spawn(accept(server, live.clone(), Arc::new(self.middleware)));
+
+        Ok(ProxyHandle {
+            live,
+            local_addr,
+            started: Instant::now(),
+            task,
+        })
+    }
+}
+
+/// Controls a proxy started with [`ProxyBuilder::spawn`]
+pub struct ProxyHandle {
+    live: Arc<watch::Sender<Config>>,
+    local_addr: SocketAddr,
+    started: Instant,
+    task: JoinHandle<()>,
+}
+
+impl ProxyHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn status(&self) -> Status {
+        Status::new(&self.live.borrow(), self.local_addr.port(), self.started)
+    }
+
+    /// Switches the proxy on or off for new connections, returning the new
+    /// status
+    pub fn toggle(&self) -> Status {
+        self.live
+            .send_modify(|config| config.status = !config.status);
+        let status = self.status();
+        emit(EventKind::Toggle {
+            status: status.status,
+        });
+        status
+    }
+
+    /// Events from every proxy in the process, including access events for
+    /// each finished connection
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        subscribe()
+    }
+
+    /// Stops accepting connections, open ones carry on until they close
+    pub async fn shutdown(self) {
+        self.task.abort();
+        let _ = self.task.await;
+    }
+}
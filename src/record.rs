@@ -0,0 +1,342 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use log::{error, trace};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::config::Config;
+
+/// Longest message kept, a SOCKS5 request is at most 262 bytes
+const MAX_MESSAGE: usize = 512;
+
+/// How long `replay` waits for each answer from the server
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a client sent during its SOCKS negotiation, never the payload after
+#[derive(Serialize, Deserialize)]
+pub struct Recording {
+    pub client: String,
+    /// Seconds since the Unix epoch
+    pub ts: u64,
+    pub error: String,
+    /// Each message in the order sent, base64
+    pub messages: Vec<String>,
+}
+
+/// The client's messages so far, shared with the streams recording them
+#[derive(Default)]
+struct Tape {
+    messages: Vec<Vec<u8>>,
+    /// Read since the server last wrote
+    current: Vec<u8>,
+    stopped: bool,
+}
+
+impl Tape {
+    fn end_message(&mut self) {
+        if !self.current.is_empty() {
+            let message = std::mem::take(&mut self.current);
+            self.messages.push(message);
+        }
+    }
+}
+
+/// Keeps the negotiation of a connection while `record_failures` is set, and
+/// writes it there if the connection fails
+pub struct Recorder {
+    dir: Option<PathBuf>,
+    peer: SocketAddr,
+    tape: Arc<Mutex<Tape>>,
+}
+
+impl Recorder {
+    pub fn new(config: &Config, peer: SocketAddr) -> Self {
+        Recorder {
+            dir: config.record_failures.clone(),
+            peer,
+            tape: Arc::new(Mutex::new(Tape::default())),
+        }
+    }
+
+    /// Keeps the next message from the client without taking it off the
+    /// socket, waiting for it to arrive. For SOCKS stacks that own the
+    /// socket, others read through `tee`
+    pub async fn capture(&self, stream: &TcpStream) {
+        if self.dir.is_none() {
+            return;
+        }
+        let mut message = vec![0; MAX_MESSAGE];
+        match stream.peek(&mut message).await {
+            Ok(0) | Err(_) => {}
+            Ok(read) => {
+                message.truncate(read);
+                self.tape.lock().unwrap().messages.push(message);
+            }
+        }
+    }
+
+    /// Wraps the client's stream so what is read from it is kept, one
+    /// message per answer the server writes, until `stop`
+    pub fn tee<S>(&self, stream: S) -> Recorded<S> {
+        Recorded {
+            inner: stream,
+            tape: self.dir.as_ref().map(|_| self.tape.clone()),
+        }
+    }
+
+    /// Ends the recording once the client's request is read, the payload
+    /// after it is never kept
+    pub fn stop(&self) {
+        let mut tape = self.tape.lock().unwrap();
+        tape.end_message();
+        tape.stopped = true;
+    }
+
+    /// Writes the negotiation so far, for `replay`
+    pub async fn failed(self, err: String) {
+        let messages = {
+            let mut tape = self.tape.lock().unwrap();
+            tape.end_message();
+            std::mem::take(&mut tape.messages)
+        };
+        let dir = match self.dir {
+            Some(dir) if !messages.is_empty() => dir,
+            _ => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let recording = Recording {
+            client: self.peer.to_string(),
+            ts: now.as_secs(),
+            error: err,
+            messages: messages
+                .iter()
+                .map(|message| STANDARD.encode(message))
+                .collect(),
+        };
+        let path = dir.join(format!("{}-{}.json", now.as_millis(), self.peer.port()));
+        match write_recording(&dir, &path, &recording).await {
+            Ok(()) => trace!("Recorded failed session to {}", path.display()),
+            Err(err) => {
+                error!("Failed to record session");
+                trace!("{}", err);
+            }
+        }
+    }
+}
+
+/// A client stream read through a [`Recorder`]
+pub struct Recorded<S> {
+    inner: S,
+    tape: Option<Arc<Mutex<Tape>>>,
+}
+
+impl<S> Recorded<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let stopped = match &this.tape {
+            Some(tape) => {
+                let mut tape = tape.lock().unwrap();
+                if !tape.stopped {
+                    let room = MAX_MESSAGE.saturating_sub(tape.current.len());
+                    let read = &buf.filled()[filled..];
+                    tape.current
+                        .extend_from_slice(&read[..read.len().min(room)]);
+                }
+                tape.stopped
+            }
+            None => false,
+        };
+        // Nothing more to keep, relaying goes straight through
+        if stopped {
+            this.tape = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // The server answering ends the client's message
+        if let Some(tape) = &this.tape {
+            tape.lock().unwrap().end_message();
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_recording(dir: &Path, path: &Path, recording: &Recording) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(path, serde_json::to_vec_pretty(recording)?).await?;
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Sends a recorded negotiation to `server` one message at a time, printing
+/// what the server answers to each
+pub async fn replay(path: &str, server: &str) -> Result<()> {
+    let recording: Recording = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    println!(
+        "Replaying {} from {}, which failed with: {}",
+        path, recording.client, recording.error
+    );
+
+    let mut stream = TcpStream::connect(server).await?;
+    for message in &recording.messages {
+        let message = STANDARD
+            .decode(message)
+            .map_err(|err| anyhow!("Invalid message in recording: {}", err))?;
+        println!("> {}", hex(&message));
+        stream.write_all(&message).await?;
+
+        let mut reply = vec![0; MAX_MESSAGE];
+        match timeout(REPLY_TIMEOUT, stream.read(&mut reply)).await {
+            Ok(Ok(0)) => {
+                println!("Server closed the connection");
+                return Ok(());
+            }
+            Ok(Ok(read)) => println!("< {}", hex(&reply[..read])),
+            Ok(Err(err)) => {
+                println!("Connection failed: {}", err);
+                return Ok(());
+            }
+            Err(_) => println!("No answer within {:?}", REPLY_TIMEOUT),
+        }
+    }
+    println!("Negotiation done, the connection is still open");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, sync::watch};
+
+    use super::*;
+
+    use crate::server::{accept, socks_listener};
+
+    const GREETING: [u8; 3] = [5, 1, 0];
+    /// A CONNECT to a 20 byte domain that ends after 5 of them
+    const REQUEST: [u8; 10] = [5, 1, 0, 3, 20, b'e', b'x', b'a', b'm', b'p'];
+
+    /// Sends a failing CONNECT through a listener recording into `dir`
+    async fn fail_connect(dir: &Path) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            record_failures: Some(dir.to_path_buf()),
+            ..Config::default()
+        };
+        let live = Arc::new(watch::channel(config).0);
+        tokio::spawn(accept(socks_listener(listener), live, Arc::new(Vec::new())));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&GREETING).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&REQUEST).await.unwrap();
+        client.shutdown().await.unwrap();
+        let _ = client.read_to_end(&mut Vec::new()).await;
+    }
+
+    /// The first recording written to `dir`
+    async fn recording(dir: &Path) -> PathBuf {
+        for _ in 0..100 {
+            if let Ok(mut entries) = std::fs::read_dir(dir) {
+                if let Some(Ok(entry)) = entries.next() {
+                    return entry.path();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Nothing recorded to {}", dir.display());
+    }
+
+    #[tokio::test]
+    async fn failed_connects_are_recorded_and_replayed_with_their_request() {
+        let dir = std::env::temp_dir().join(format!("toggleproxy-record-{}", std::process::id()));
+        fail_connect(&dir).await;
+        let path = recording(&dir).await;
+
+        let recorded: Recording = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let messages = recorded
+            .messages
+            .iter()
+            .map(|message| STANDARD.decode(message).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, [GREETING.to_vec(), REQUEST.to_vec()]);
+
+        // A server answering each message the way a SOCKS5 server would
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut conn, _) = server.accept().await.unwrap();
+            let mut greeting = [0u8; GREETING.len()];
+            conn.read_exact(&mut greeting).await.unwrap();
+            conn.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; REQUEST.len()];
+            conn.read_exact(&mut request).await.unwrap();
+            conn.write_all(&[5, 1, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            (greeting, request)
+        });
+
+        replay(path.to_str().unwrap(), &server_addr.to_string())
+            .await
+            .unwrap();
+        assert_eq!(received.await.unwrap(), (GREETING, REQUEST));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::RuleAction,
+    events::{emit, EventKind},
+    stats::destination_addr,
+    timing,
+};
+
+/// Where a rerouted destination goes
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Via {
+    Direct,
+    Proxy,
+}
+
+impl Via {
+    fn action(self) -> RuleAction {
+        match self {
+            Via::Direct => RuleAction::Direct,
+            Via::Proxy => RuleAction::Proxy,
+        }
+    }
+}
+
+lazy_static! {
+    /// host:port destinations whose new connections skip the rules until the
+    /// deadline
+    static ref OVERRIDES: Mutex<HashMap<String, (Instant, Via)>> = Mutex::new(HashMap::new());
+}
+
+/// Longest a reroute lasts, far short of where `Instant` overflows
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Closes open connection `id` and sends new connections to the same
+/// destination `via` for `ttl`, so a client that reconnects lands on the
+/// other route. Returns the destination as the connection's trace shows it,
+/// only its top-level domain under `aggregate_only`
+pub fn reroute(id: u64, via: Via, ttl: Duration) -> Option<String> {
+    let target = destination_addr(&timing::address(id)?);
+    let shown = timing::trace(id)?.target?;
+    // Pinned before closing, so an immediate reconnect already sees it
+    let ttl = ttl.min(MAX_TTL);
+    let mut overrides = OVERRIDES.lock().unwrap();
+    overrides.retain(|_, (until, _)| *until > Instant::now());
+    overrides.insert(target, (Instant::now() + ttl, via));
+    drop(overrides);
+
+    timing::cancel(id);
+    emit(EventKind::Audit {
+        action: "reroute".to_string(),
+        detail: format!(
+            "{} {}",
+            shown,
+            match via {
+                Via::Direct => "direct",
+                Via::Proxy => "proxy",
+            }
+        ),
+    });
+    Some(shown)
+}
+
+/// The action a reroute pinned for `target`, a host:port
+pub fn override_for(target: &str) -> Option<RuleAction> {
+    let overrides = OVERRIDES.lock().unwrap();
+    match overrides.get(target) {
+        Some((until, via)) if *until > Instant::now() => Some(via.action()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{proto::Address, timing::Timing};
+
+    use super::*;
+
+    #[test]
+    fn long_reroutes_are_cut_short() {
+        let timing = Timing::start(([127, 0, 0, 1], 50000).into(), 0.0);
+        timing.set_target(&Address::DomainAddress(b"example.com".to_vec(), 443), false);
+        let target = reroute(timing.id(), Via::Direct, Duration::MAX);
+        assert_eq!(target.as_deref(), Some("example.com:443"));
+        assert!(matches!(
+            override_for("example.com:443"),
+            Some(RuleAction::Direct)
+        ));
+        timing.finish();
+    }
+
+    #[test]
+    fn aggregate_only_reroutes_answer_with_the_top_level_domain() {
+        let timing = Timing::start(([127, 0, 0, 1], 50001).into(), 0.0);
+        timing.set_target(&Address::DomainAddress(b"example.net".to_vec(), 443), true);
+        let target = reroute(timing.id(), Via::Proxy, Duration::from_secs(60));
+        assert_eq!(target.as_deref(), Some("net"));
+        // The destination itself is still pinned
+        assert!(matches!(
+            override_for("example.net:443"),
+            Some(RuleAction::Proxy)
+        ));
+        timing.finish();
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::proto::Address;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::lookup_host,
+    time::timeout,
+};
+
+use crate::{
+    config::{Config, DnsMode},
+    dns,
+    server::connect_through_upstream,
+};
+
+/// How long names resolved over the target proxy are reused
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a name's negative TTL doubles while it keeps failing
+const NEGATIVE_BACKOFF_STEPS: u32 = 3;
+
+/// A name that failed to resolve
+struct Failure {
+    until: Instant,
+    failures: u32,
+}
+
+lazy_static! {
+    static ref NEGATIVE: Mutex<HashMap<String, Failure>> = Mutex::new(HashMap::new());
+    static ref CACHE: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>> = Mutex::new(HashMap::new());
+}
+
+/// The addresses `addr` resolves to for a connection that doesn't go
+/// through the target proxy
+pub async fn resolve(config: &Config, addr: &Address) -> io::Result<Vec<SocketAddr>> {
+    let (domain, port) = match addr {
+        Address::SocketAddress(addr) => return Ok(vec![*addr]),
+        Address::DomainAddress(domain, port) => (domain_str(domain)?, *port),
+    };
+    let key = domain.to_lowercase();
+    if recently_failed(&key) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Domain recently failed to resolve",
+        ));
+    }
+
+    let resolved = match config.dns_mode {
+        DnsMode::ProxyOnly => resolve_over_upstream(config, domain).await.map(|ips| {
+            ips.into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect::<Vec<_>>()
+        }),
+        DnsMode::Local | DnsMode::Remote => lookup_host((domain, port))
+            .await
+            .map(|addrs| addrs.collect::<Vec<_>>()),
+    };
+    match &resolved {
+        Ok(addrs) if !addrs.is_empty() => {
+            NEGATIVE.lock().unwrap().remove(&key);
+        }
+        // Failing to reach the target proxy says nothing about the name
+        Err(err)
+            if config.dns_mode == DnsMode::ProxyOnly && err.kind() != io::ErrorKind::NotFound => {}
+        _ => remember_failure(config, key),
+    }
+    resolved
+}
+
+fn recently_failed(domain: &str) -> bool {
+    NEGATIVE
+        .lock()
+        .unwrap()
+        .get(domain)
+        .is_some_and(|failure| failure.until > Instant::now())
+}
+
+/// Keeps a failed name from being looked up again for `dns_negative_ttl_secs`,
+/// doubling each time it fails again
+fn remember_failure(config: &Config, domain: String) {
+    if config.dns_negative_ttl_secs == 0 {
+        return;
+    }
+
+    let mut negative = NEGATIVE.lock().unwrap();
+    // Names that failed again soon after their TTL ran out keep backing off
+    let forget_after = Duration::from_secs(config.dns_negative_ttl_secs << NEGATIVE_BACKOFF_STEPS);
+    negative.retain(|_, failure| failure.until + forget_after > Instant::now());
+    let failure = negative.entry(domain).or_insert(Failure {
+        until: Instant::now(),
+        failures: 0,
+    });
+    let backoff = 1 << failure.failures.min(NEGATIVE_BACKOFF_STEPS);
+    failure.failures += 1;
+    failure.until = Instant::now() + Duration::from_secs(config.dns_negative_ttl_secs * backoff);
+}
+
+/// The addresses `addr` resolves to with the system resolver
+pub async fn resolve_locally(addr: &Address) -> io::Result<Vec<SocketAddr>> {
+    match addr {
+        Address::SocketAddress(addr) => Ok(vec![*addr]),
+        Address::DomainAddress(domain, port) => {
+            Ok(lookup_host((domain_str(domain)?, *port)).await?.collect())
+        }
+    }
+}
+
+fn domain_str(domain: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(domain)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Domain is not UTF-8"))
+}
+
+/// Asks `config.dns_resolver` for the A and AAAA records of `domain` over a
+/// tunnel through the target proxy
+async fn resolve_over_upstream(config: &Config, domain: &str) -> io::Result<Vec<IpAddr>> {
+    let domain = domain.to_lowercase();
+    if let Some((resolved, ips)) = CACHE.lock().unwrap().get(&domain) {
+        if resolved.elapsed() < CACHE_TTL {
+            return Ok(ips.clone());
+        }
+    }
+
+    let resolver = config.dns_resolver.parse::<SocketAddr>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid dns_resolver {}", config.dns_resolver),
+        )
+    })?;
+
+    let ips = timeout(QUERY_TIMEOUT, async {
+        let resolver = Address::SocketAddress(resolver);
+        let (mut stream, _) = connect_through_upstream(config, &resolver, None).await?;
+
+        // TCP DNS lets both queries share the connection
+        for qtype in [dns::TYPE_A, dns::TYPE_AAAA] {
+            let query = match dns::query(rand::random(), &domain, qtype) {
+                Some(query) => query,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Invalid domain",
+                    ))
+                }
+            };
+            stream
+                .write_all(&(query.len() as u16).to_be_bytes())
+                .await?;
+            stream.write_all(&query).await?;
+        }
+
+        let mut ips = Vec::new();
+        for _ in 0..2 {
+            let len = stream.read_u16().await? as usize;
+            let mut response = vec![0; len];
+            stream.read_exact(&mut response).await?;
+            ips.extend(dns::addresses(&response).unwrap_or_default());
+        }
+        Ok(ips)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))??;
+
+    if ips.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Domain did not resolve to any address",
+        ));
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|_, (resolved, _)| resolved.elapsed() < CACHE_TTL);
+    cache.insert(domain, (Instant::now(), ips.clone()));
+    Ok(ips)
+}
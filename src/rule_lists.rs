@@ -0,0 +1,322 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use lazy_static::lazy_static;
+
+use log::{error, info, trace};
+
+use notify::{RecursiveMode, Watcher};
+
+use sha2::{Digest, Sha256};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    clap::STATE_DIR,
+    config::{Config, Rule, RuleList},
+};
+
+/// Starts every compiled list, the last byte is the format version
+const MAGIC: &[u8; 4] = b"TPR\x01";
+
+/// Longest pattern kept, a domain name is at most 253 bytes
+const MAX_PATTERN: usize = 255;
+
+/// Names every hosts file maps that aren't worth a rule
+const HOSTS_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+lazy_static! {
+    /// Lists left out after failing to update
+    static ref DISABLED: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Leaves the list at `path` out of [`load`] or puts it back, returning
+/// whether that changed anything
+pub fn set_disabled(path: &Path, disabled: bool) -> bool {
+    let mut lists = DISABLED.lock().unwrap();
+    match disabled {
+        true => lists.insert(path.to_path_buf()),
+        false => lists.remove(path),
+    }
+}
+
+/// The rules from every list in order, lists that can't be read are skipped
+pub fn load(lists: &[RuleList]) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for list in lists {
+        if DISABLED.lock().unwrap().contains(&list.path) {
+            continue;
+        }
+        match patterns(&list.path) {
+            Ok(patterns) => rules.extend(patterns.into_iter().map(|pattern| Rule {
+                pattern,
+                action: list.action.clone(),
+                quic: None,
+                race: false,
+            })),
+            Err(err) => {
+                error!("Failed to load rule list {}", list.path.display());
+                trace!("{}", err);
+            }
+        }
+    }
+    rules
+}
+
+/// Loads every rule list again and swaps them into `live`, returning how many
+/// rules they hold. Open connections keep the rules they started with
+pub async fn reload(live: &watch::Sender<Config>) -> usize {
+    let lists = live.borrow().rule_lists.clone();
+    let mut rules = live.borrow().rules.clone();
+    // Loaded and indexed off the runtime, so no connection waits for either
+    let rules = tokio::task::spawn_blocking(move || {
+        rules.set_imported(load(&lists));
+        rules.index();
+        rules
+    })
+    .await;
+    let rules = match rules {
+        Ok(rules) => rules,
+        Err(err) => {
+            error!("Failed to reload rule lists");
+            trace!("{}", err);
+            return 0;
+        }
+    };
+
+    let count = rules.all().count() - rules.len();
+    live.send_if_modified(|config| {
+        // A config reload in the meantime loaded the lists itself
+        if !config.rules.same_list(&rules) {
+            return false;
+        }
+        config.rules = rules;
+        true
+    });
+    count
+}
+
+/// Reloads the rule lists whenever one of the files changes
+pub async fn watch_rule_lists(live: Arc<watch::Sender<Config>>) -> Result<()> {
+    let mut config_changes = live.subscribe();
+    loop {
+        let paths = config_changes
+            .borrow_and_update()
+            .rule_lists
+            .iter()
+            .map(|list| list.path.clone())
+            .collect::<Vec<_>>();
+        let file_names = paths
+            .iter()
+            .filter_map(|path| path.file_name().map(|name| name.to_owned()))
+            .collect::<Vec<_>>();
+
+        let (changed, mut changes) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if event.paths.iter().any(|path| {
+                        path.file_name()
+                            .is_some_and(|name| file_names.iter().any(|list| list == name))
+                    }) {
+                        let _ = changed.send(());
+                    }
+                }
+            })?;
+        // Lists are often replaced by whatever downloads them, so watch the
+        // directories they are in
+        for path in &paths {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                error!("Failed to watch rule list {}", path.display());
+                trace!("{}", err);
+            }
+        }
+
+        tokio::select! {
+            // The lists may have changed with the config
+            changed = config_changes.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+            Some(_) = changes.recv() => {
+                // Writes usually come as several events
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                while changes.try_recv().is_ok() {}
+                let count = reload(&live).await;
+                info!("Rule lists reloaded, {} rules", count);
+            }
+        }
+    }
+}
+
+/// The patterns in the list at `path`, from its compiled copy in the state
+/// dir unless the file changed since
+fn patterns(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read(path)?;
+    let stamp = stamp(&text);
+    let cache = cache_path(path);
+    if let Some(patterns) = fs::read(&cache)
+        .ok()
+        .and_then(|cached| decode(&cached, &stamp))
+    {
+        return Ok(patterns);
+    }
+
+    let patterns = parse(std::str::from_utf8(&text)?);
+    info!(
+        "Compiled rule list {}, {} entries",
+        path.display(),
+        patterns.len()
+    );
+    if let Err(err) = write_cache(&cache, &stamp, &patterns) {
+        trace!("Failed to cache rule list: {}", err);
+    }
+    Ok(patterns)
+}
+
+/// Changes whenever the list's contents do. Copies that keep the size and
+/// modification time, like `cp -p` or `rsync -t`, still change it
+fn stamp(text: &[u8]) -> [u8; 32] {
+    Sha256::digest(text).into()
+}
+
+pub(crate) fn cache_path(path: &Path) -> PathBuf {
+    let key = Sha256::digest(path.to_string_lossy().as_bytes());
+    let name = key[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    STATE_DIR.join("rules").join(format!("{}.bin", name))
+}
+
+/// One pattern per line. `#` starts a comment, hosts file lines give the
+/// name after the address and adblock lines look like `||example.com^`
+fn parse(text: &str) -> Vec<String> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<String> {
+    let line = line.split('#').next()?.trim();
+    // Adblock comments and section headers
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return None;
+    }
+
+    let pattern = match line.strip_prefix("||") {
+        // Only whole-domain adblock rules, not paths or options
+        Some(domain) => domain.strip_suffix('^')?,
+        None => {
+            let mut fields = line.split_whitespace();
+            let first = fields.next()?;
+            match fields.next() {
+                Some(name) if first.parse::<IpAddr>().is_ok() => name,
+                Some(_) => return None,
+                None => first,
+            }
+        }
+    };
+    if pattern.len() > MAX_PATTERN || HOSTS_NAMES.contains(&pattern) {
+        return None;
+    }
+    Some(pattern.to_ascii_lowercase())
+}
+
+/// The magic, the stamp, a count and then each pattern prefixed by its length
+fn encode(stamp: &[u8; 32], patterns: &[String]) -> Vec<u8> {
+    let size = patterns
+        .iter()
+        .map(|pattern| pattern.len() + 1)
+        .sum::<usize>();
+    let mut data = Vec::with_capacity(MAGIC.len() + stamp.len() + 4 + size);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(stamp);
+    data.extend_from_slice(&(patterns.len() as u32).to_le_bytes());
+    for pattern in patterns {
+        data.push(pattern.len() as u8);
+        data.extend_from_slice(pattern.as_bytes());
+    }
+    data
+}
+
+fn decode(data: &[u8], stamp: &[u8; 32]) -> Option<Vec<String>> {
+    let rest = data.strip_prefix(MAGIC)?.strip_prefix(stamp.as_slice())?;
+    let count = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let mut rest = &rest[4..];
+
+    let mut patterns = Vec::with_capacity(count.min(rest.len()));
+    for _ in 0..count {
+        let (&len, tail) = rest.split_first()?;
+        let pattern = tail.get(..len as usize)?;
+        patterns.push(std::str::from_utf8(pattern).ok()?.to_string());
+        rest = &tail[len as usize..];
+    }
+    rest.is_empty().then_some(patterns)
+}
+
+/// Written next to the cache and renamed over it, so a reader never sees a
+/// partial file
+fn write_cache(cache: &Path, stamp: &[u8; 32], patterns: &[String]) -> Result<()> {
+    if let Some(dir) = cache.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = cache.with_extension("tmp");
+    fs::write(&partial, encode(stamp, patterns))?;
+    fs::rename(&partial, cache)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns() -> Vec<String> {
+        vec!["example.com".to_string(), "10.0.0.0/8".to_string()]
+    }
+
+    #[test]
+    fn compiled_lists_decode_to_their_patterns() {
+        let stamp = stamp(b"example.com\n10.0.0.0/8\n");
+        let data = encode(&stamp, &patterns());
+        assert_eq!(decode(&data, &stamp), Some(patterns()));
+    }
+
+    #[test]
+    fn compiled_lists_of_other_contents_are_stale() {
+        // The same size, as a replaced copy keeping its mtime would be
+        let old = stamp(b"example.com\n");
+        let new = stamp(b"example.org\n");
+        assert_ne!(old, new);
+
+        let data = encode(&old, &patterns());
+        assert_eq!(decode(&data, &new), None);
+    }
+
+    #[test]
+    fn truncated_compiled_lists_are_rejected() {
+        let stamp = stamp(b"example.com\n");
+        let data = encode(&stamp, &patterns());
+        assert_eq!(decode(&data[..data.len() - 1], &stamp), None);
+        assert_eq!(decode(&data[1..], &stamp), None);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use log::{error, info, trace};
+
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, StatusCode,
+};
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use tokio::sync::watch;
+
+use crate::{
+    config::{Config, ListFailure, RuleList},
+    rule_lists,
+};
+
+/// How often lists are checked for being due
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest list accepted, blocklists are a few MB
+const MAX_LIST: usize = 64 * 1024 * 1024;
+
+/// What the server said about the last download, sent back so an unchanged
+/// list isn't downloaded again
+#[derive(Serialize, Deserialize, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+fn validators_path(path: &Path) -> PathBuf {
+    rule_lists::cache_path(path).with_extension("http.json")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Downloads `list.url` into `list.path` unless the server says it hasn't
+/// changed, returning whether it did
+async fn update(client: &Client, list: &RuleList, url: &str) -> Result<bool> {
+    let validators_path = validators_path(&list.path);
+    let validators = tokio::fs::read(&validators_path)
+        .await
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Validators>(&data).ok())
+        .unwrap_or_default();
+
+    let mut request = client.get(url).timeout(DOWNLOAD_TIMEOUT);
+    // Only worth asking when the last download is still there
+    if tokio::fs::try_exists(&list.path).await.unwrap_or(false) {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(false);
+    }
+    if !response.status().is_success() {
+        return Err(anyhow!("Server returned {}", response.status()));
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    let body = response.bytes().await?;
+    if body.len() > MAX_LIST {
+        return Err(anyhow!("List is larger than {} bytes", MAX_LIST));
+    }
+
+    if let Some(checksum_url) = &list.checksum_url {
+        let checksum = client
+            .get(checksum_url)
+            .timeout(DOWNLOAD_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let expected = checksum
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let actual = hex(&Sha256::digest(&body));
+        if expected != actual {
+            return Err(anyhow!(
+                "Checksum mismatch, expected {} but got {}",
+                expected,
+                actual
+            ));
+        }
+    }
+
+    // Renamed over the old list so it is never read half-written
+    if let Some(dir) = list.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let partial = list.path.with_extension("partial");
+    tokio::fs::write(&partial, &body).await?;
+    tokio::fs::rename(&partial, &list.path).await?;
+
+    if let Some(dir) = validators_path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(&validators_path, serde_json::to_vec(&validators)?).await?;
+    Ok(true)
+}
+
+/// Keeps every rule list with a `url` up to date, reloading the rules when
+/// one changes
+pub async fn update_rule_lists(live: Arc<watch::Sender<Config>>) {
+    let client = Client::new();
+    let mut due = HashMap::<String, Instant>::new();
+    loop {
+        let lists = live.borrow().rule_lists.clone();
+        let mut changed = false;
+        for list in &lists {
+            let url = match &list.url {
+                Some(url) => url,
+                None => continue,
+            };
+            if due.get(url).is_some_and(|due| *due > Instant::now()) {
+                continue;
+            }
+            due.insert(
+                url.clone(),
+                Instant::now() + Duration::from_secs(list.refresh_secs.max(60)),
+            );
+
+            match update(&client, list, url).await {
+                Ok(updated) => {
+                    if updated {
+                        info!("Updated rule list {}", url);
+                    }
+                    changed |= rule_lists::set_disabled(&list.path, false) || updated;
+                }
+                Err(err) => {
+                    error!("Failed to update rule list {}", url);
+                    trace!("{}", err);
+                    if list.on_failure == ListFailure::Disable {
+                        changed |= rule_lists::set_disabled(&list.path, true);
+                    }
+                }
+            }
+        }
+
+        if changed {
+            let count = rule_lists::reload(&live).await;
+            info!("Rule lists reloaded, {} rules", count);
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
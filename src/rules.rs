@@ -0,0 +1,206 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use ipnet::IpNet;
+
+use crate::proto::Address;
+
+use crate::config::{Rule, RuleAction, Rules};
+
+/// Marks a trie node no rule ends at
+const NO_RULE: u32 = u32::MAX;
+
+/// A domain label trie node, children are keyed by the next label to the left
+#[derive(Default)]
+struct DomainNode {
+    children: HashMap<Box<str>, u32>,
+    rule: Option<u32>,
+}
+
+/// A binary trie node, one level per address bit
+struct IpNode {
+    children: [u32; 2],
+    rule: u32,
+}
+
+impl IpNode {
+    const EMPTY: IpNode = IpNode {
+        children: [0; 2],
+        rule: NO_RULE,
+    };
+}
+
+/// One address family's CIDR ranges. Node 0 is the root, so 0 doubles as
+/// "no child"
+struct IpTrie {
+    nodes: Vec<IpNode>,
+}
+
+impl IpTrie {
+    fn new() -> Self {
+        IpTrie {
+            nodes: vec![IpNode::EMPTY],
+        }
+    }
+
+    fn insert(&mut self, bits: u128, prefix: u8, rule: u32) {
+        let mut node = 0;
+        for depth in 0..prefix {
+            let bit = (bits >> (127 - depth)) as usize & 1;
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(IpNode::EMPTY);
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        // Earlier rules win
+        if self.nodes[node].rule == NO_RULE {
+            self.nodes[node].rule = rule;
+        }
+    }
+
+    /// The first rule whose range covers `bits`
+    fn find(&self, bits: u128, len: u8) -> Option<u32> {
+        let mut node = 0;
+        let mut found = self.nodes[0].rule;
+        for depth in 0..len {
+            let bit = (bits >> (127 - depth)) as usize & 1;
+            node = match self.nodes[node].children[bit] {
+                0 => break,
+                child => child as usize,
+            };
+            found = found.min(self.nodes[node].rule);
+        }
+        (found != NO_RULE).then_some(found)
+    }
+}
+
+/// An address as bits from the most significant end, and how many there are
+fn ip_bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => ((u32::from(ip) as u128) << 96, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+/// The rules arranged so a lookup follows the destination's labels or bits
+/// instead of trying every rule, built once per rule set
+pub struct RuleIndex {
+    domains: Vec<DomainNode>,
+    v4: IpTrie,
+    v6: IpTrie,
+}
+
+impl RuleIndex {
+    pub fn new<'a>(rules: impl Iterator<Item = &'a Rule>) -> Self {
+        let mut index = RuleIndex {
+            domains: vec![DomainNode::default()],
+            v4: IpTrie::new(),
+            v6: IpTrie::new(),
+        };
+        for (position, rule) in rules.enumerate() {
+            let position = position as u32;
+            let pattern = rule.pattern.as_str();
+            match pattern.parse::<IpNet>() {
+                Ok(net) => index.insert_ip(net.network(), net.prefix_len(), position),
+                Err(_) => {
+                    if let Ok(ip) = pattern.parse::<IpAddr>() {
+                        index.insert_ip(ip, ip_bits(ip).1, position);
+                    }
+                    // Clients may also send an address as a domain name
+                    index.insert_domain(pattern, position);
+                }
+            }
+        }
+        index
+    }
+
+    fn insert_ip(&mut self, ip: IpAddr, prefix: u8, rule: u32) {
+        let (bits, _) = ip_bits(ip);
+        match ip {
+            IpAddr::V4(_) => self.v4.insert(bits, prefix, rule),
+            IpAddr::V6(_) => self.v6.insert(bits, prefix, rule),
+        }
+    }
+
+    /// `example.com`, `.example.com` and `*.example.com` all cover the domain
+    /// and every subdomain
+    fn insert_domain(&mut self, pattern: &str, rule: u32) {
+        let pattern = pattern.trim_start_matches("*.").trim_start_matches('.');
+        if pattern.is_empty() {
+            return;
+        }
+
+        let mut node = 0;
+        for label in pattern.to_ascii_lowercase().rsplit('.') {
+            node = match self.domains[node].children.get(label) {
+                Some(child) => *child as usize,
+                None => {
+                    self.domains.push(DomainNode::default());
+                    let child = self.domains.len() - 1;
+                    self.domains[node]
+                        .children
+                        .insert(label.into(), child as u32);
+                    child
+                }
+            };
+        }
+        self.domains[node].rule.get_or_insert(rule);
+    }
+
+    /// The position of the first rule covering `domain`
+    fn find_domain(&self, domain: &str) -> Option<usize> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut node = 0;
+        let mut found = None;
+        for label in domain.rsplit('.') {
+            node = match self.domains[node].children.get(label) {
+                Some(child) => *child as usize,
+                None => break,
+            };
+            if let Some(rule) = self.domains[node].rule {
+                found = Some(found.map_or(rule, |found: u32| found.min(rule)));
+            }
+        }
+        found.map(|rule| rule as usize)
+    }
+
+    /// The position of the first rule covering `ip`
+    fn find_ip(&self, ip: IpAddr) -> Option<usize> {
+        let ip = ip.to_canonical();
+        let (bits, len) = ip_bits(ip);
+        let found = match ip {
+            IpAddr::V4(_) => self.v4.find(bits, len),
+            IpAddr::V6(_) => self.v6.find(bits, len),
+        };
+        found.map(|rule| rule as usize)
+    }
+}
+
+/// The action of the first rule matching `domain`
+pub fn match_domain<'a>(rules: &'a Rules, domain: &str) -> Option<&'a RuleAction> {
+    rules
+        .index()
+        .find_domain(domain)
+        .and_then(|position| rules.get(position))
+        .map(|rule| &rule.action)
+}
+
+/// The first rule matching a requested address
+pub fn match_rule<'a>(rules: &'a Rules, addr: &Address) -> Option<&'a Rule> {
+    let position = match addr {
+        Address::DomainAddress(domain, _) => {
+            rules.index().find_domain(&String::from_utf8_lossy(domain))
+        }
+        Address::SocketAddress(addr) => rules.index().find_ip(addr.ip()),
+    };
+    position.and_then(|position| rules.get(position))
+}
+
+/// The action of the first rule matching a requested address
+pub fn match_address<'a>(rules: &'a Rules, addr: &Address) -> Option<&'a RuleAction> {
+    match_rule(rules, addr).map(|rule| &rule.action)
+}
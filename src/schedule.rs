@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Error, Result};
+
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+
+use log::{error, info};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{sync::watch, time::sleep};
+
+use crate::{
+    config::Config,
+    control::{set_status, Response},
+};
+
+/// When a [`crate::config::ScheduledToggle`] fires, as the five fields of a
+/// crontab line in local time: minute, hour, day of month, month and day of
+/// week, like `0 9 * * 1-5`. Fields take `*`, numbers, ranges, lists and
+/// `/step`. Sunday is 0 or 7
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// When both day fields are restricted either one is enough, as in cron
+    either_day: bool,
+}
+
+/// The values allowed by one field, as bits
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (low, high) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((low, high)) => (low.parse().ok()?, high.parse().ok()?),
+                // `5/15` runs from 5 to the end
+                None if step > 1 => (range.parse().ok()?, max),
+                None => (range.parse().ok()?, range.parse().ok()?),
+            },
+        };
+        if low < min || high > max || low > high {
+            return None;
+        }
+        for value in (low..=high).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl TryFrom<String> for Cron {
+    type Error = Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        let fields = text.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(anyhow!(
+                "Expected minute, hour, day, month and weekday in {}",
+                text
+            ));
+        }
+        let field = |index: usize, min, max| {
+            parse_field(fields[index], min, max)
+                .ok_or_else(|| anyhow!("Invalid field {} in {}", fields[index], text))
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        // Sunday is also 7
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            either_day: fields[2] != "*" && fields[4] != "*",
+            text,
+        })
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.text
+    }
+}
+
+impl Cron {
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let has = |bits: u64, value: u32| bits & 1 << value != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match self.either_day {
+            true => day || weekday,
+            false => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day
+    }
+}
+
+/// Switches the proxy as `schedule` says, checking at the start of every
+/// minute. A manual toggle holds until the next scheduled one
+pub async fn run_schedule(live: Arc<watch::Sender<Config>>) {
+    loop {
+        let now = Local::now();
+        let into_minute = Duration::new(now.second() as u64, now.nanosecond() % 1_000_000_000);
+        sleep(Duration::from_secs(60).saturating_sub(into_minute)).await;
+
+        let now = Local::now();
+        // The last matching entry wins
+        let status = live
+            .borrow()
+            .schedule
+            .iter()
+            .rev()
+            .find(|toggle| toggle.at.matches(&now))
+            .map(|toggle| toggle.status);
+        let status = match status {
+            Some(status) if status != live.borrow().status => status,
+            _ => continue,
+        };
+        match set_status(&live, status, false) {
+            Response::Ok(_) => info!(
+                "Schedule switched the proxy {}",
+                match status {
+                    true => "on",
+                    false => "off",
+                }
+            ),
+            Response::Error(err) => error!("Failed to apply the schedule: {}", err),
+        }
+    }
+}
@@ -1,142 +1,1148 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Instant};
 
-use log::error;
-use tokio::{io::AsyncWriteExt, net::TcpListener, net::TcpStream};
+use log::{error, info, trace, warn};
+use tokio::{io::AsyncWriteExt, net::TcpListener, net::TcpStream, sync::watch};
 
-use crate::{config::Config, socks5_async::lib::TargetAddr};
+use crate::{
+    acl,
+    adaptive::{self, Path},
+    aggregate,
+    alerts::alerts,
+    breaker, compress,
+    config::{watch_config, Config, DnsMode, Protocol, RuleAction},
+    control::control_server,
+    drain::drain_on_rule_changes,
+    events::{access_writer, emit, event_writer, EventKind, Route, SocksCommand},
+    health::{direct_allowed, upstream_failed, upstream_ok},
+    http_proxy::http_connect,
+    listeners::serve_listeners,
+    network::watch_network,
+    pac::pac_server,
+    policy,
+    proxy::{ConnectionInfo, Decision, Middleware},
+    record::Recorder,
+    reroute,
+    resolve::{resolve, resolve_locally},
+    rule_lists::watch_rule_lists,
+    rules,
+    schedule::run_schedule,
+    simulate::SimulatedStream,
+    socks4, socks5,
+    socks5_async::lib::TargetAddr,
+    socks5_async::relay::{relay, RelayOptions},
+    standby::keep_warm,
+    stats::{destination_addr, destination_host, STATS},
+    systemd,
+    throttle::ThrottledStream,
+    timing::{Phase, TimedStream, Timing},
+    transport::{connect_with_failover, tls_accept, with_connect_timeout, BoxStream},
+    upstream, wol,
+};
 
-use tokio::io::copy_bidirectional;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 use anyhow::Result;
 
+use async_trait::async_trait;
+
+#[cfg(not(feature = "vendored-socks"))]
 use socks5_server::{
-    auth::NoAuth, connection::state::NeedCommand, Command, IncomingConnection, Server,
+    auth::NoAuth,
+    connection::{
+        associate::state as associate,
+        bind::state as bind,
+        connect::state::{NeedReply, Ready},
+        state::{NeedAuthenticate, NeedCommand},
+    },
+    Associate, Bind, Command, Connect, IncomingConnection, Server,
 };
 
-use socks5_proto::{Address, Reply};
+use crate::proto::{Address, Reply};
 
-use crate::socks5_async::lib::SocksStream;
+use crate::socks5_async::lib::{connect_with_stream, Response};
 
 pub async fn server(config: Config) -> Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
+    let listener = match systemd::activated_listener() {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+            info!("Listening on {} from systemd", listener.local_addr()?);
+            listener
+        }
+        None => TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?,
+    };
+
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        #[cfg(feature = "tls")]
+        crate::tls::load_acceptor(cert, key)
+            .map_err(|err| anyhow::anyhow!("Failed to load {}: {}", cert.display(), err))?;
+        #[cfg(not(feature = "tls"))]
+        error!("This build has no TLS support, every client will be refused");
+        info!(
+            "Clients connect over TLS with {} and {}",
+            cert.display(),
+            key.display()
+        );
+    }
+
+    let server = socks_listener(listener);
+
+    // The config new connections use, replaced on reloads and toggles
+    let live_config = Arc::new(watch::channel(config.clone()).0);
+
+    let control_config = config.clone();
+    let control_live = live_config.clone();
+    tokio::spawn(async move {
+        match control_server(control_config, control_live).await {
+            Ok(_) => {}
+            Err(err) => error!("Failed to run control socket: {:?}", err),
+        }
+    });
+
+    if !config.alerts.is_empty() {
+        tokio::spawn(alerts(config.clone()));
+    }
+
+    if config.simulate.is_some() {
+        warn!("Simulating a slow link, every tunnel is delayed on purpose");
+    }
+
+    #[cfg(feature = "upnp")]
+    if config.port_mapping && !config.port_mapping_unauthenticated {
+        error!(
+            "Not mapping port {}, anyone on the internet could use the proxy \
+             without a password. Set port_mapping_unauthenticated to map it anyway",
+            config.port
+        );
+    }
+    #[cfg(feature = "upnp")]
+    if config.port_mapping && config.port_mapping_unauthenticated {
+        tokio::spawn(crate::portmap::port_mapping(config.port));
+    }
+    #[cfg(not(feature = "upnp"))]
+    if config.port_mapping {
+        error!("This build has no UPnP support, not mapping the port");
+    }
+
+    #[cfg(feature = "http-client")]
+    if let Some(ddns_config) = config.ddns.clone() {
+        tokio::spawn(crate::ddns::ddns(ddns_config));
+    }
+    #[cfg(not(feature = "http-client"))]
+    if config.ddns.is_some() {
+        error!("This build has no HTTP client, not updating dynamic DNS");
+    }
+
+    if let Some(event_log) = config.event_log.clone() {
+        tokio::spawn(event_writer(event_log));
+    }
 
-    let auth = Arc::new(NoAuth) as Arc<_>;
+    if let Some(access_log) = config.access_log.clone() {
+        tokio::spawn(access_writer(access_log));
+    }
 
-    let server = Server::new(listener, auth);
+    tokio::spawn(aggregate::report_hourly());
 
-    while let Ok((conn, _)) = server.accept().await {
-        let config = config.clone();
+    #[cfg(feature = "mdns")]
+    let _mdns = match config.mdns {
+        true => match crate::mdns::mdns_advertise(&config) {
+            Ok(daemon) => Some(daemon),
+            Err(err) => {
+                error!("Failed to advertise proxy over mDNS: {:?}", err);
+                None
+            }
+        },
+        false => None,
+    };
+    #[cfg(not(feature = "mdns"))]
+    if config.mdns {
+        error!("This build has no mDNS support, not advertising the proxy");
+    }
+
+    let mut pac_ports = config.pac_port.into_iter().collect::<Vec<u16>>();
+    // WPAD clients always fetch http://wpad.<domain>/wpad.dat
+    if config.wpad && !pac_ports.contains(&80) {
+        pac_ports.push(80);
+    }
+    for pac_port in pac_ports {
+        let live = live_config.clone();
         tokio::spawn(async move {
-            match conn.authenticate().await {
-                Ok((conn, _)) => match handle(conn, config).await {
-                    Ok(()) => {}
-                    Err(err) => error!("Failed to execute command: {:?}", err),
-                },
-                Err(err) => error!("Failed to authenticate connection: {:?}", err),
+            match pac_server(live, pac_port).await {
+                Ok(_) => {}
+                Err(err) => error!("Failed to run PAC server: {:?}", err),
             }
         });
     }
 
+    let watched_config = live_config.clone();
+    tokio::spawn(async move {
+        match watch_config(watched_config).await {
+            Ok(_) => {}
+            Err(err) => error!("Failed to watch config file: {:?}", err),
+        }
+    });
+
+    let has_urls = config.rule_lists.iter().any(|list| list.url.is_some());
+    #[cfg(feature = "http-client")]
+    if has_urls {
+        tokio::spawn(crate::rule_updates::update_rule_lists(live_config.clone()));
+    }
+    #[cfg(not(feature = "http-client"))]
+    if has_urls {
+        error!("This build has no HTTP client, rule lists won't be downloaded");
+    }
+
+    let watched_lists = live_config.clone();
+    tokio::spawn(async move {
+        match watch_rule_lists(watched_lists).await {
+            Ok(_) => {}
+            Err(err) => error!("Failed to watch rule lists: {:?}", err),
+        }
+    });
+
+    tokio::spawn(drain_on_rule_changes(live_config.clone()));
+    tokio::spawn(keep_warm(live_config.clone()));
+    tokio::spawn(run_schedule(live_config.clone()));
+    tokio::spawn(watch_network(live_config.clone()));
+    serve_listeners(live_config.clone()).await;
+
+    accept(server, live_config, Arc::new(Vec::new())).await;
+
     Ok(())
 }
 
-async fn handle(conn: IncomingConnection<(), NeedCommand>, config: Config) -> Result<()> {
-    println!("Connected");
-    match conn.wait().await {
-        // Handle connect command
-        Ok(Command::Connect(connect, addr)) => {
-            let target = match config.status {
-                false => match addr.clone() {
-                    Address::SocketAddress(addr) => TcpStream::connect(addr).await,
-                    Address::DomainAddress(domain, port) => {
-                        TcpStream::connect((String::from_utf8(domain).unwrap(), port)).await
-                    }
-                },
-                true => {
-                    SocksStream::connect(
-                        config.target.parse::<SocketAddr>().unwrap(),
-                        match addr.clone() {
-                            Address::SocketAddress(addr) => match addr {
-                                SocketAddr::V4(addr) => TargetAddr::V4(addr),
-                                SocketAddr::V6(addr) => TargetAddr::V6(addr),
-                            },
-                            Address::DomainAddress(domain, port) => {
-                                TargetAddr::Domain((String::from_utf8(domain).unwrap(), port))
-                            }
-                        },
-                        None,
-                    )
-                    .await
-                }
-            };
+/// The listener SOCKS clients come in on, from whichever SOCKS stack is built in
+#[cfg(not(feature = "vendored-socks"))]
+pub(crate) type SocksListener = Server<()>;
+#[cfg(feature = "vendored-socks")]
+pub(crate) type SocksListener = TcpListener;
 
-            match target {
-                Ok(mut target) => {
-                    let reply = connect.reply(Reply::Succeeded, addr).await;
+pub(crate) fn socks_listener(listener: TcpListener) -> SocksListener {
+    #[cfg(not(feature = "vendored-socks"))]
+    return Server::new(listener, Arc::new(NoAuth) as Arc<_>);
+    #[cfg(feature = "vendored-socks")]
+    return listener;
+}
 
-                    let mut conn = match reply {
-                        Ok(conn) => conn,
-                        Err((err, mut conn)) => {
-                            let _ = conn.shutdown().await;
-                            return Err(err.into());
-                        }
-                    };
+/// Gives plain TCP connections the accessors of socks5_server's, so both
+/// stacks accept the same way
+#[cfg(feature = "vendored-socks")]
+trait Incoming {
+    fn get_ref(&self) -> &TcpStream;
+    fn into_inner(self) -> TcpStream;
+}
+
+#[cfg(feature = "vendored-socks")]
+impl Incoming for TcpStream {
+    fn get_ref(&self) -> &TcpStream {
+        self
+    }
+
+    fn into_inner(self) -> TcpStream {
+        self
+    }
+}
 
-                    let _ = copy_bidirectional(&mut target, &mut conn).await;
-                    let _ = conn.shutdown().await;
-                    let _ = target.shutdown().await;
+/// Serves SOCKS5 connections with whatever config `live_config` holds when
+/// each one is accepted
+pub(crate) async fn accept(
+    server: SocksListener,
+    live_config: Arc<watch::Sender<Config>>,
+    middleware: Arc<Vec<Box<dyn Middleware>>>,
+) {
+    while let Ok((conn, peer)) = server.accept().await {
+        if !live_config.borrow().client_allowed(peer.ip()) {
+            trace!("Refused client {}", peer);
+            continue;
+        }
+        // Connections keep the config they started with
+        let config = live_config.borrow().clone();
+        let middleware = middleware.clone();
+        let sample_rate = match config.aggregate_only {
+            true => 0.0,
+            false => config.trace_sample_rate,
+        };
+        let timing = Timing::start(peer, sample_rate);
+        let recorder = Recorder::new(&config, peer);
+        let tls = config.tls_cert.clone().zip(config.tls_key.clone());
+        tokio::spawn(async move {
+            if let Some((cert, key)) = tls {
+                let served = async {
+                    let stream = tls_accept(conn.into_inner(), &cert, &key).await?;
+                    let stream = compress::answer(stream, config.compression).await?;
+                    socks5::handle_stream(stream, peer, config, &middleware, &timing, &recorder)
+                        .await
                 }
-                Err(err) => {
-                    error!("Failed to connect to target: {:?}", err);
-                    let mut conn = match connect
-                        .reply(Reply::HostUnreachable, Address::unspecified())
+                .await;
+                if let Err(err) = served {
+                    error!("Failed to serve TLS connection: {:?}", err);
+                    recorder.failed(format!("{:?}", err)).await;
+                }
+                timing.finish();
+                return;
+            }
+
+            // SOCKS4 clients have no greeting, their request starts with the
+            // version. Compression offers start with a byte no SOCKS version has
+            let mut first = [0u8; 1];
+            let first = match conn.get_ref().peek(&mut first).await {
+                Ok(1) => Some(first[0]),
+                _ => None,
+            };
+
+            match first {
+                Some(first) if first == compress::OFFER[0] => {
+                    let served = async {
+                        let stream = Box::new(conn.into_inner());
+                        let stream = compress::answer(stream, config.compression).await?;
+                        socks5::handle_stream(stream, peer, config, &middleware, &timing, &recorder)
+                            .await
+                    }
+                    .await;
+                    if let Err(err) = served {
+                        error!("Failed to serve compressed connection: {:?}", err);
+                        recorder.failed(format!("{:?}", err)).await;
+                    }
+                }
+                Some(socks4::VERSION) => {
+                    let stream = conn.into_inner();
+                    match socks4::handle(stream, peer, config, &middleware, &timing, &recorder)
                         .await
                     {
-                        Ok(conn) => conn,
-                        Err((err, mut conn)) => {
-                            let _ = conn.shutdown().await;
-                            return Err(err.into());
+                        Ok(()) => {}
+                        Err(err) => {
+                            error!("Failed to execute SOCKS4 command: {:?}", err);
+                            recorder.failed(format!("{:?}", err)).await;
                         }
-                    };
-                    let _ = conn.close().await;
+                    }
+                }
+                _ => serve_socks5(conn, peer, config, &middleware, &timing, recorder).await,
+            }
+            timing.finish();
+        });
+    }
+}
+
+#[cfg(not(feature = "vendored-socks"))]
+async fn serve_socks5(
+    conn: IncomingConnection<(), NeedAuthenticate>,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: Recorder,
+) {
+    recorder.capture(conn.get_ref()).await;
+    match conn.authenticate().await {
+        Ok((conn, _)) => {
+            timing.mark(Phase::Auth);
+            recorder.capture(conn.get_ref()).await;
+            match handle(conn, peer, config, middleware, timing).await {
+                Ok(()) => {}
+                Err(err) => {
+                    error!("Failed to execute command: {:?}", err);
+                    recorder.failed(format!("{:?}", err)).await;
                 }
             }
         }
+        Err((err, _)) => {
+            error!("Failed to authenticate connection: {:?}", err);
+            recorder.failed(format!("{:?}", err)).await;
+        }
+    }
+}
 
-        // Kill unknown commands
-        Ok(Command::Associate(cmd, _)) => {
-            let mut conn = match cmd
-                .reply(Reply::CommandNotSupported, Address::unspecified())
-                .await
+#[cfg(feature = "vendored-socks")]
+async fn serve_socks5(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: Recorder,
+) {
+    match socks5::handle(stream, peer, config, middleware, timing, &recorder).await {
+        Ok(()) => {}
+        Err(err) => {
+            error!("Failed to execute command: {:?}", err);
+            recorder.failed(format!("{:?}", err)).await;
+        }
+    }
+}
+
+/// Connects to `addr` directly, or through the target proxy when the proxy is on
+pub async fn connect_target(config: &Config, addr: &Address) -> std::io::Result<BoxStream> {
+    Ok(dial(config, addr, None).await?.0)
+}
+
+/// Resolves and connects to `addr` directly, trying each address in turn
+async fn connect_direct(
+    config: &Config,
+    addr: &Address,
+    timing: Option<&Timing>,
+) -> std::io::Result<TcpStream> {
+    if let Address::SocketAddress(addr) = addr {
+        return with_connect_timeout(config, TcpStream::connect(addr)).await;
+    }
+    let addrs = resolve(config, addr).await?;
+    if let Some(timing) = timing {
+        timing.mark(Phase::Resolve);
+    }
+    acl::check_resolved(&config.blocked_destinations, &addrs)?;
+
+    let mut last_err = std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "Domain did not resolve to any address",
+    );
+    for addr in addrs {
+        match with_connect_timeout(config, TcpStream::connect(addr)).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+async fn dial(
+    config: &Config,
+    addr: &Address,
+    timing: Option<&Timing>,
+) -> std::io::Result<(BoxStream, Option<String>)> {
+    let connected = connect_route(config, addr, timing).await?;
+    if let Some(timing) = timing {
+        timing.mark(Phase::Dial);
+    }
+    Ok(connected)
+}
+
+/// Connects directly and through the target proxy at once, keeping whichever
+/// succeeds first. Dropping the other closes it
+async fn dial_race(
+    config: &Config,
+    addr: &Address,
+    timing: &Timing,
+) -> std::io::Result<(BoxStream, Option<String>)> {
+    let mut direct_config = config.clone();
+    direct_config.status = false;
+    let mut proxy_config = config.clone();
+    proxy_config.status = true;
+    // Already racing a direct connection
+    proxy_config.fallback_direct = false;
+
+    let direct = dial(&direct_config, addr, Some(timing));
+    let proxied = dial(&proxy_config, addr, Some(timing));
+    tokio::pin!(direct, proxied);
+    tokio::select! {
+        connected = &mut direct => match connected {
+            Ok(connected) => Ok(connected),
+            Err(err) => {
+                trace!("Direct connection failed ({}), waiting for the target proxy", err);
+                proxied.await
+            }
+        },
+        connected = &mut proxied => match connected {
+            Ok(connected) => Ok(connected),
+            Err(err) => {
+                trace!("Target proxy failed ({}), waiting for the direct connection", err);
+                direct.await
+            }
+        },
+    }
+}
+
+/// Also returns the target proxy that was used
+async fn connect_route(
+    config: &Config,
+    addr: &Address,
+    timing: Option<&Timing>,
+) -> std::io::Result<(BoxStream, Option<String>)> {
+    match config.status {
+        false => Ok((Box::new(connect_direct(config, addr, timing).await?), None)),
+        true => match connect_through_upstream(config, addr, timing).await {
+            Ok((stream, target)) => Ok((stream, Some(target))),
+            Err(err)
+                if config.fallback_direct
+                    && direct_allowed(config)
+                    && !acl::is_blocked_address(&err) =>
             {
-                Ok(conn) => conn,
-                Err((err, mut conn)) => {
-                    let _ = conn.shutdown().await;
-                    return Err(err.into());
+                let target = aggregate::shown(config.aggregate_only, addr);
+                warn!(
+                    "Target proxy failed ({}), connecting to {} directly",
+                    err, target
+                );
+                emit(EventKind::Audit {
+                    action: "fallback_direct".to_string(),
+                    detail: target,
+                });
+                Ok((Box::new(connect_direct(config, addr, timing).await?), None))
+            }
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Connects to `addr` through the first target proxy that works, returning
+/// which one it was
+pub(crate) async fn connect_through_upstream(
+    config: &Config,
+    addr: &Address,
+    timing: Option<&Timing>,
+) -> std::io::Result<(BoxStream, String)> {
+    // The target proxy is only given an address to connect to
+    let resolved;
+    let addr = match (config.dns_mode, addr) {
+        (DnsMode::Local, Address::DomainAddress(..)) => {
+            let addrs = resolve_locally(addr).await?;
+            acl::check_resolved(&config.blocked_destinations, &addrs)?;
+            resolved = match addrs.first() {
+                Some(resolved) => Address::SocketAddress(*resolved),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "Domain did not resolve to any address",
+                    ))
                 }
             };
-            let _ = conn.close().await;
+            if let Some(timing) = timing {
+                timing.mark(Phase::Resolve);
+            }
+            &resolved
         }
-        Ok(Command::Bind(cmd, _)) => {
-            let mut conn = match cmd
-                .reply(Reply::CommandNotSupported, Address::unspecified())
-                .await
-            {
-                Ok(conn) => conn,
-                Err((err, mut conn)) => {
-                    let _ = conn.shutdown().await;
-                    return Err(err.into());
+        // The target proxy resolves it, likely to the same addresses. Names
+        // only it can resolve are let through, and `ProxyOnly` never looks
+        // them up here
+        (DnsMode::Remote, Address::DomainAddress(..))
+            if acl::has_ranges(&config.blocked_destinations) =>
+        {
+            if let Ok(addrs) = resolve_locally(addr).await {
+                acl::check_resolved(&config.blocked_destinations, &addrs)?;
+            }
+            addr
+        }
+        _ => addr,
+    };
+    let target_addr = match addr.clone() {
+        Address::SocketAddress(addr) => match addr {
+            SocketAddr::V4(addr) => TargetAddr::V4(addr),
+            SocketAddr::V6(addr) => TargetAddr::V6(addr),
+        },
+        Address::DomainAddress(domain, port) => match String::from_utf8(domain) {
+            Ok(domain) => TargetAddr::Domain((domain, port)),
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Domain is not valid UTF-8",
+                ))
+            }
+        },
+    };
+    let targets = upstream::order(config);
+    let handshake = |mut stream, protocol| {
+        let target_addr = target_addr.clone();
+        // Only HTTP targets need the address as text
+        let http_target = match protocol {
+            Protocol::Socks5 => String::new(),
+            Protocol::Http => destination_addr(addr),
+        };
+        let credentials = config.target_credentials();
+        async move {
+            match protocol {
+                Protocol::Socks5 => connect_with_stream(&mut stream, target_addr, credentials)
+                    .await
+                    .map_err(|err| match err.downcast::<Response>() {
+                        Ok(reply) => std::io::Error::new(reply.kind(), *reply),
+                        Err(err) => std::io::Error::other(err.to_string()),
+                    })?,
+                Protocol::Http => http_connect(&mut stream, &http_target, credentials).await?,
+            }
+            Ok(stream)
+        }
+    };
+    let upstream = match connect_with_failover(config, &targets, handshake).await {
+        // A sleeping target gets woken and one more try before it counts as down
+        Err(err) if config.wake_on_lan.is_some() && !refused_by_upstream(&err) => {
+            trace!("Target proxy failed: {}", err);
+            match wol::wake(config.wake_on_lan.as_ref().unwrap()).await {
+                Ok(()) => connect_with_failover(config, &targets, handshake).await,
+                Err(wake_err) => {
+                    error!("Failed to send Wake-on-LAN packet");
+                    trace!("{}", wake_err);
+                    Err(err)
                 }
+            }
+        }
+        upstream => upstream,
+    };
+
+    match upstream {
+        Ok(connected) => {
+            upstream_ok();
+            Ok(connected)
+        }
+        Err(err) if refused_by_upstream(&err) => {
+            upstream_ok();
+            Err(err)
+        }
+        Err(err) => {
+            upstream_failed(config, &err).await;
+            Err(err)
+        }
+    }
+}
+
+/// Whether the target proxy itself answered, but couldn't reach the
+/// destination, so it is up all the same
+fn refused_by_upstream(err: &std::io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Response>())
+}
+
+/// Records a finished request in the event log
+pub(crate) fn log_access(
+    config: &Config,
+    info: &ConnectionInfo,
+    command: SocksCommand,
+    upstream: Option<&str>,
+    result: &str,
+    started: Instant,
+    (bytes_up, bytes_down): (u64, u64),
+) {
+    if config.aggregate_only {
+        aggregate::record(&info.target, result == "succeeded", (bytes_up, bytes_down));
+        return;
+    }
+    emit(EventKind::Access {
+        client: info.client.to_string(),
+        command,
+        target: destination_addr(&info.target),
+        route: match (upstream, config.status) {
+            (Some(_), _) => Route::Upstream,
+            // Refused before connecting, the route is the one it would have taken
+            (None, true) if result != "succeeded" => Route::Upstream,
+            (None, _) => Route::Direct,
+        },
+        upstream: upstream.map(str::to_string),
+        result: result.to_string(),
+        bytes_up,
+        bytes_down,
+        duration_ms: started.elapsed().as_millis() as u64,
+        tags: info.tags.clone(),
+    });
+}
+
+/// A CONNECT request waiting for its reply, which hands over the stream to
+/// relay once sent
+#[async_trait]
+pub(crate) trait ConnectRequest: Send {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    async fn reply(self, reply: Reply, addr: Address) -> Result<Self::Stream>;
+}
+
+#[cfg(not(feature = "vendored-socks"))]
+#[async_trait]
+impl ConnectRequest for Connect<NeedReply> {
+    type Stream = Connect<Ready>;
+
+    async fn reply(self, reply: Reply, addr: Address) -> Result<Self::Stream> {
+        match Connect::reply(self, reply, addr).await {
+            Ok(conn) => Ok(conn),
+            Err((err, mut conn)) => {
+                let _ = conn.shutdown().await;
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Why a CONNECT request was refused. Each cause gets its own SOCKS5 reply
+/// where one fits, so clients can tell policy from a broken destination
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Refusal {
+    /// A rule with the block action
+    Blocked,
+    /// `blocked_destinations`
+    BlockedDestination,
+    /// `client_policies`
+    ClientPolicy,
+    /// Middleware of an embedded proxy
+    Denied,
+    /// The kill switch kept the connection from going direct
+    KillSwitch,
+    /// The destination failed too often lately to try again yet
+    CircuitOpen,
+    /// A domain that isn't valid UTF-8, which can't be resolved or passed on
+    UnsupportedAddress,
+    /// Dialing the destination or the target proxy failed
+    Unreachable(ErrorKind),
+}
+
+impl Refusal {
+    fn reply(self) -> Reply {
+        match self {
+            Refusal::Blocked
+            | Refusal::BlockedDestination
+            | Refusal::ClientPolicy
+            | Refusal::Denied => Reply::ConnectionNotAllowed,
+            Refusal::KillSwitch => Reply::NetworkUnreachable,
+            Refusal::CircuitOpen => Reply::GeneralFailure,
+            Refusal::UnsupportedAddress => Reply::AddressTypeNotSupported,
+            Refusal::Unreachable(ErrorKind::ConnectionRefused) => Reply::ConnectionRefused,
+            Refusal::Unreachable(_) => Reply::HostUnreachable,
+        }
+    }
+
+    /// The `result` of its access event
+    fn result(self) -> &'static str {
+        match self {
+            Refusal::Blocked => "blocked",
+            Refusal::BlockedDestination => "blocked_destination",
+            Refusal::ClientPolicy => "client_policy",
+            Refusal::Denied => "denied",
+            Refusal::KillSwitch => "kill_switch",
+            Refusal::CircuitOpen => "circuit_open",
+            Refusal::UnsupportedAddress => "address_not_supported",
+            Refusal::Unreachable(ErrorKind::ConnectionRefused) => "connection_refused",
+            Refusal::Unreachable(ErrorKind::TimedOut) => "timed_out",
+            Refusal::Unreachable(_) => "host_unreachable",
+        }
+    }
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Refusal::Blocked => "blocked by a rule",
+            Refusal::BlockedDestination => "blocked by blocked_destinations",
+            Refusal::ClientPolicy => "blocked by a client policy",
+            Refusal::Denied => "denied by middleware",
+            Refusal::KillSwitch => "the kill switch forbids going direct",
+            Refusal::CircuitOpen => "the destination failed too often lately",
+            Refusal::UnsupportedAddress => "the domain is not valid UTF-8",
+            Refusal::Unreachable(ErrorKind::ConnectionRefused) => "the connection was refused",
+            Refusal::Unreachable(ErrorKind::TimedOut) => "connecting timed out",
+            Refusal::Unreachable(_) => "the destination is unreachable",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Answers a CONNECT request with why it was refused, logging the reason
+/// under the connection id
+async fn refuse<R: ConnectRequest>(
+    request: R,
+    refusal: Refusal,
+    config: &Config,
+    info: &ConnectionInfo,
+    started: Instant,
+    timing: &Timing,
+) -> Result<()> {
+    info!(
+        "Refused connection #{} to {}, {}",
+        timing.id(),
+        aggregate::shown(config.aggregate_only, &info.target),
+        refusal
+    );
+    log_access(
+        config,
+        info,
+        SocksCommand::Connect,
+        None,
+        refusal.result(),
+        started,
+        (0, 0),
+    );
+    let mut conn = request
+        .reply(refusal.reply(), Address::unspecified())
+        .await?;
+    let _ = conn.shutdown().await;
+    Ok(())
+}
+
+/// Serves a CONNECT request to `addr`, from either SOCKS version
+pub(crate) async fn serve_connect<R: ConnectRequest>(
+    request: R,
+    addr: Address,
+    peer: SocketAddr,
+    mut config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+) -> Result<()> {
+    timing.set_target(&addr, config.aggregate_only);
+    let started = Instant::now();
+    let toggled = config.status;
+    let mut info = ConnectionInfo {
+        client: peer,
+        target: addr,
+        tags: Vec::new(),
+    };
+
+    if let Address::DomainAddress(domain, _) = &info.target {
+        if std::str::from_utf8(domain).is_err() {
+            let refusal = Refusal::UnsupportedAddress;
+            return refuse(request, refusal, &config, &info, started, timing).await;
+        }
+    }
+
+    // Middleware goes first, the rules only decide when it doesn't
+    let mut decision = Decision::Continue;
+    for middleware in middleware {
+        decision = middleware.on_connect(&mut info).await;
+        if decision != Decision::Continue {
+            break;
+        }
+    }
+    // Not even middleware can reach a blocked destination
+    if acl::is_blocked(&config.blocked_destinations, &info.target) {
+        let refusal = Refusal::BlockedDestination;
+        return refuse(request, refusal, &config, &info, started, timing).await;
+    }
+    if let Some(policy) = policy::blocking(&config.client_policies, peer.ip(), &info.target) {
+        trace!("Client policy {} applies to {}", policy.name, peer);
+        let refusal = Refusal::ClientPolicy;
+        return refuse(request, refusal, &config, &info, started, timing).await;
+    }
+    let mut race = false;
+    let action = match decision {
+        Decision::Continue => match reroute::override_for(&destination_addr(&info.target)) {
+            Some(action) => Some(action),
+            None => {
+                let rule = rules::match_rule(&config.rules, &info.target);
+                race = rule.is_some_and(|rule| rule.race);
+                rule.map(|rule| rule.action.clone())
+            }
+        },
+        Decision::Direct => Some(RuleAction::Direct),
+        Decision::Proxy => Some(RuleAction::Proxy),
+        Decision::Deny => {
+            return refuse(request, Refusal::Denied, &config, &info, started, timing).await;
+        }
+    };
+
+    match action {
+        Some(RuleAction::Block) => {
+            return refuse(request, Refusal::Blocked, &config, &info, started, timing).await;
+        }
+        Some(RuleAction::Redirect { to }) => {
+            let port = match info.target {
+                Address::SocketAddress(addr) => addr.port(),
+                Address::DomainAddress(_, port) => port,
             };
-            let _ = conn.close().await;
+            info.target = Address::SocketAddress((to, port).into());
+        }
+        Some(RuleAction::Direct) => config.status = false,
+        Some(RuleAction::Proxy) => config.status = true,
+        None => {}
+    }
+    let addr = &info.target;
+    let host = destination_host(addr);
+    let stats_host = match config.aggregate_only {
+        true => aggregate::tld(addr),
+        false => host.clone(),
+    };
+
+    // Only where nothing else decided and going direct is allowed
+    let adaptive = match action {
+        None if config.adaptive_routing && config.status && direct_allowed(&config) => {
+            let path = adaptive::choose(&host);
+            config.status = path == Path::Proxy;
+            info.tags.push(path.tag().to_string());
+            Some(path)
+        }
+        _ => None,
+    };
+
+    if toggled && !config.status && !direct_allowed(&config) {
+        return refuse(
+            request,
+            Refusal::KillSwitch,
+            &config,
+            &info,
+            started,
+            timing,
+        )
+        .await;
+    }
+
+    if config.circuit_breaker.is_some() && !breaker::allow(&host) {
+        return refuse(
+            request,
+            Refusal::CircuitOpen,
+            &config,
+            &info,
+            started,
+            timing,
+        )
+        .await;
+    }
+
+    let target = match race && toggled && direct_allowed(&config) {
+        true => {
+            let target = dial_race(&config, addr, timing).await;
+            if let Ok((_, upstream)) = &target {
+                config.status = upstream.is_some();
+                info.tags.push(
+                    match config.status {
+                        true => "race:proxy",
+                        false => "race:direct",
+                    }
+                    .to_string(),
+                );
+            }
+            target
+        }
+        false => dial(&config, addr, Some(timing)).await,
+    };
+    let connect_time = started.elapsed();
+
+    if let Some(path) = adaptive {
+        adaptive::record(&host, path, target.is_ok().then_some(connect_time));
+    }
+
+    if let Some(circuit_breaker) = &config.circuit_breaker {
+        match &target {
+            Ok(_) => breaker::record_success(&host),
+            Err(err) if acl::is_blocked_address(err) => {}
+            Err(_) => breaker::record_failure(&host, &stats_host, circuit_breaker),
         }
+    }
+
+    match target {
+        Ok((target, upstream)) => {
+            let _lease = upstream.as_deref().map(upstream::lease);
+            let target = middleware
+                .iter()
+                .fold(target, |target, middleware| middleware.wrap(target, &info));
+            let target = TimedStream::new(target, timing.clone());
+            let mut target = SimulatedStream::new(target, config.simulate);
+            let conn = request.reply(Reply::Succeeded, addr.clone()).await?;
+            let conn = ThrottledStream::new(conn, &config);
+            let mut conn = SimulatedStream::new(conn, config.simulate);
 
-        // Kill errors
-        Err((err, mut conn)) => {
-            print!("hit");
+            STATS.opened(
+                &stats_host,
+                match config.status {
+                    true => Some(connect_time),
+                    false => None,
+                },
+            );
+            let (up, down) = relay(
+                &mut target,
+                &mut conn,
+                RelayOptions {
+                    idle_timeout: config.idle_timeout(),
+                    ..RelayOptions::default()
+                },
+                timing.cancelled(),
+                timing.steering(),
+            )
+            .await;
+            STATS.closed(&stats_host, up, down);
+            log_access(
+                &config,
+                &info,
+                SocksCommand::Connect,
+                upstream.as_deref(),
+                "succeeded",
+                started,
+                (up, down),
+            );
             let _ = conn.shutdown().await;
-            return Err(err.into());
+            let _ = target.shutdown().await;
+        }
+        Err(err) if acl::is_blocked_address(&err) => {
+            trace!("{}", err);
+            let refusal = Refusal::BlockedDestination;
+            return refuse(request, refusal, &config, &info, started, timing).await;
+        }
+        Err(err) => {
+            error!("Failed to connect to target: {:?}", err);
+            STATS.failed(&stats_host);
+            let refusal = Refusal::Unreachable(err.kind());
+            return refuse(request, refusal, &config, &info, started, timing).await;
         }
     }
     Ok(())
 }
+
+/// A BIND or UDP ASSOCIATE request socks5_server read
+#[cfg(not(feature = "vendored-socks"))]
+enum ExternalRequest {
+    Associate(Associate<associate::NeedReply>),
+    Bind(Bind<bind::NeedFirstReply>),
+}
+
+#[cfg(not(feature = "vendored-socks"))]
+#[async_trait]
+impl socks5::CommandRequest for ExternalRequest {
+    type Associate = Associate<associate::NeedReply>;
+
+    fn into_associate(self) -> Result<Associate<associate::NeedReply>, Self> {
+        match self {
+            ExternalRequest::Associate(associate) => Ok(associate),
+            bind => Err(bind),
+        }
+    }
+
+    async fn not_supported(self) -> Result<()> {
+        let (reply, addr) = (Reply::CommandNotSupported, Address::unspecified());
+        let replied = match self {
+            ExternalRequest::Associate(associate) => associate
+                .reply(reply, addr)
+                .await
+                .map(Associate::into_inner),
+            ExternalRequest::Bind(bind) => bind.reply(reply, addr).await.map(Bind::into_inner),
+        };
+        let mut stream = match replied {
+            Ok(stream) => stream,
+            Err((err, mut stream)) => {
+                let _ = stream.shutdown().await;
+                return Err(err.into());
+            }
+        };
+        let _ = stream.shutdown().await;
+        Ok(())
+    }
+}
+
+/// Serves a SOCKS5 client of the listener, when socks5_server is the SOCKS
+/// stack built in
+#[cfg(not(feature = "vendored-socks"))]
+async fn handle(
+    conn: IncomingConnection<(), NeedCommand>,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+) -> Result<()> {
+    trace!("Connection from {}", peer);
+    let command = conn.wait().await;
+    timing.mark(Phase::Command);
+    let (request, command, addr) = match command {
+        Ok(Command::Connect(connect, addr)) => {
+            return serve_connect(connect, addr, peer, config, middleware, timing).await
+        }
+        Ok(Command::Associate(associate, addr)) => (
+            ExternalRequest::Associate(associate),
+            SocksCommand::Associate,
+            addr,
+        ),
+        Ok(Command::Bind(bind, addr)) => (ExternalRequest::Bind(bind), SocksCommand::Bind, addr),
+        Err((err, mut stream)) => {
+            let _ = stream.shutdown().await;
+            return Err(err.into());
+        }
+    };
+    socks5::serve_command(request, command, addr, peer, &config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::DuplexStream, sync::oneshot};
+
+    use super::*;
+
+    /// A CONNECT request that hands its reply to the test
+    struct TestConnect {
+        replied: oneshot::Sender<Reply>,
+        stream: DuplexStream,
+    }
+
+    #[async_trait]
+    impl ConnectRequest for TestConnect {
+        type Stream = DuplexStream;
+
+        async fn reply(self, reply: Reply, _addr: Address) -> Result<DuplexStream> {
+            let _ = self.replied.send(reply);
+            Ok(self.stream)
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_domains_that_are_not_utf8() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let (replied, reply) = oneshot::channel();
+        let (stream, _client) = tokio::io::duplex(64);
+        let request = TestConnect { replied, stream };
+        let addr = Address::DomainAddress(vec![b'a', 0xff, b'.', b'c', b'o', b'm'], 443);
+
+        serve_connect(
+            request,
+            addr,
+            peer,
+            Config::default(),
+            &[],
+            &Timing::start(peer, 0.0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.await.unwrap(), Reply::AddressTypeNotSupported);
+    }
+
+    #[tokio::test]
+    async fn refuses_domains_resolving_into_blocked_ranges() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let (replied, reply) = oneshot::channel();
+        let (stream, _client) = tokio::io::duplex(64);
+        let request = TestConnect { replied, stream };
+        let config = Config {
+            blocked_destinations: vec![
+                "127.0.0.0/8".to_string().try_into().unwrap(),
+                "::1".to_string().try_into().unwrap(),
+            ],
+            ..Config::default()
+        };
+        let addr = Address::DomainAddress(b"localhost".to_vec(), 9);
+
+        serve_connect(request, addr, peer, config, &[], &Timing::start(peer, 0.0))
+            .await
+            .unwrap();
+        assert_eq!(reply.await.unwrap(), Reply::ConnectionNotAllowed);
+    }
+
+    #[tokio::test]
+    async fn aggregate_only_keeps_destinations_out_of_events_and_traces() {
+        // An address no other test connects to, sent as a domain
+        let destination = TcpListener::bind("127.0.0.77:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move { while destination.accept().await.is_ok() {} });
+        let host = "127.0.0.77";
+
+        let peer = SocketAddr::from(([192, 0, 2, 1], 50000));
+        let (replied, reply) = oneshot::channel();
+        let (stream, _) = tokio::io::duplex(64);
+        let request = TestConnect { replied, stream };
+        // The target proxy is down, so the connection falls back to direct
+        let config = Config {
+            status: true,
+            target: "127.0.0.1:1".to_string().into(),
+            fallback_direct: true,
+            aggregate_only: true,
+            ..Config::default()
+        };
+        let addr = Address::DomainAddress(host.as_bytes().to_vec(), port);
+        let timing = Timing::start(peer, 0.0);
+
+        let mut events = crate::events::subscribe();
+        serve_connect(request, addr, peer, config, &[], &timing)
+            .await
+            .unwrap();
+        assert_eq!(reply.await.unwrap(), Reply::Succeeded);
+        assert_eq!(
+            crate::timing::trace(timing.id()).unwrap().target.as_deref(),
+            Some("ip")
+        );
+        assert_eq!(
+            reroute::reroute(timing.id(), reroute::Via::Proxy, Default::default()).as_deref(),
+            Some("ip")
+        );
+
+        let mut fell_back = false;
+        while let Ok(event) = events.try_recv() {
+            assert!(!serde_json::to_string(&event).unwrap().contains(host));
+            if let EventKind::Audit { action, detail } = event.kind {
+                fell_back |= action == "fallback_direct" && detail == "ip";
+            }
+        }
+        assert!(fell_back);
+    }
+}
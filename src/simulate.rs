@@ -0,0 +1,143 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Sleep},
+};
+
+use crate::config::Simulation;
+
+/// Most bytes held back at once, reading stops until some are handed over
+const MAX_QUEUED: usize = 256 * 1024;
+
+const CHUNK: usize = 16 * 1024;
+
+/// Least extra delay for a lost chunk, about TCP's minimum retransmit timeout
+const MIN_RETRANSMIT: Duration = Duration::from_millis(200);
+
+/// Hands over what is read from `inner` only after the simulated link would
+/// have delivered it. Writes go straight through, wrap both ends of a tunnel
+/// to delay both directions
+pub struct SimulatedStream<S> {
+    inner: S,
+    simulation: Option<Simulation>,
+    /// Chunks with when they may be handed over, an empty one is the end
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    queued: usize,
+    eof: bool,
+    /// Chunks never overtake each other, however the jitter falls
+    last_due: Option<Instant>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> SimulatedStream<S> {
+    pub fn new(inner: S, simulation: Option<Simulation>) -> Self {
+        SimulatedStream {
+            inner,
+            simulation,
+            queue: VecDeque::new(),
+            queued: 0,
+            eof: false,
+            last_due: None,
+            sleep: None,
+        }
+    }
+
+    fn push(&mut self, simulation: &Simulation, chunk: Vec<u8>) {
+        let mut delay = Duration::from_millis(simulation.latency_ms);
+        if simulation.jitter_ms > 0 {
+            delay += Duration::from_millis(rand::random::<u64>() % (simulation.jitter_ms + 1));
+        }
+        if simulation.loss > 0.0 && rand::random::<f64>() < simulation.loss {
+            delay += (delay * 2).max(MIN_RETRANSMIT);
+        }
+        let due = (Instant::now() + delay).max(self.last_due.unwrap_or_else(Instant::now));
+        self.last_due = Some(due);
+        self.queued += chunk.len();
+        self.queue.push_back((due, chunk));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SimulatedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let simulation = match this.simulation {
+            Some(simulation) => simulation,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        // Take in everything that has arrived, so it is delayed from now
+        while !this.eof && this.queued < MAX_QUEUED {
+            let mut chunk = vec![0; CHUNK];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {
+                    let read = chunk_buf.filled().len();
+                    chunk.truncate(read);
+                    this.eof = read == 0;
+                    this.push(&simulation, chunk);
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            let due = match this.queue.front() {
+                Some((due, _)) => *due,
+                // Already handed over the end
+                None if this.eof => return Poll::Ready(Ok(())),
+                None => return Poll::Pending,
+            };
+            if due > Instant::now() {
+                match this.sleep.as_mut() {
+                    Some(sleeping) => sleeping.as_mut().reset(due.into()),
+                    None => this.sleep = Some(Box::pin(sleep_until(due.into()))),
+                }
+                match this.sleep.as_mut().unwrap().as_mut().poll(cx) {
+                    Poll::Ready(()) => continue,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            let (due, mut chunk) = this.queue.pop_front().unwrap();
+            let taken = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..taken]);
+            this.queued -= taken;
+            if taken < chunk.len() {
+                chunk.drain(..taken);
+                this.queue.push_front((due, chunk));
+            }
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SimulatedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use log::trace;
+
+use crate::proto::{Address, Reply};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{CommandPolicy, Config},
+    events::SocksCommand,
+    proxy::{ConnectionInfo, Middleware},
+    record::Recorder,
+    server::{log_access, serve_connect, ConnectRequest},
+    timing::{Phase, Timing},
+};
+
+/// The first byte of every SOCKS4 request
+pub const VERSION: u8 = 0x04;
+
+const CONNECT: u8 = 0x01;
+
+const GRANTED: u8 = 0x5a;
+const REJECTED: u8 = 0x5b;
+
+/// Longest user id or SOCKS4a domain accepted
+const MAX_FIELD: usize = 255;
+
+/// A SOCKS4 CONNECT request waiting for its reply
+struct Socks4Connect {
+    stream: TcpStream,
+}
+
+#[async_trait]
+impl ConnectRequest for Socks4Connect {
+    type Stream = TcpStream;
+
+    async fn reply(mut self, reply: Reply, _addr: Address) -> Result<TcpStream> {
+        // SOCKS4 only knows granted and rejected, the address is ignored by clients
+        let status = match reply {
+            Reply::Succeeded => GRANTED,
+            _ => REJECTED,
+        };
+        self.stream
+            .write_all(&[0, status, 0, 0, 0, 0, 0, 0])
+            .await?;
+        Ok(self.stream)
+    }
+}
+
+/// Reads a null-terminated field
+async fn read_field<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            byte if field.len() < MAX_FIELD => field.push(byte),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 field too long",
+                ))
+            }
+        }
+    }
+}
+
+/// Reads a SOCKS4 or SOCKS4a request, returning its command and destination
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(u8, Address)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    let command = header[1];
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+
+    // The user id, there is no way to check it
+    let mut user_id = 0;
+    while stream.read_u8().await? != 0 {
+        user_id += 1;
+        if user_id > MAX_FIELD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SOCKS4 user id too long",
+            ));
+        }
+    }
+
+    // SOCKS4a sends 0.0.0.x and the domain after the user id
+    let addr = match ip.octets() {
+        [0, 0, 0, last] if last != 0 => Address::DomainAddress(read_field(stream).await?, port),
+        _ => Address::SocketAddress(SocketAddr::from((ip, port))),
+    };
+    Ok((command, addr))
+}
+
+/// Serves a connection from a SOCKS4 or SOCKS4a client, CONNECT requests go
+/// the same way as SOCKS5 ones
+pub(crate) async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: &Recorder,
+) -> Result<()> {
+    trace!("SOCKS4 connection from {}", peer);
+    let mut tee = recorder.tee(stream);
+    let (command, addr) = read_request(&mut tee).await?;
+    recorder.stop();
+    let mut stream = tee.into_inner();
+    timing.mark(Phase::Command);
+
+    if command == CONNECT {
+        let request = Socks4Connect { stream };
+        return serve_connect(request, addr, peer, config, middleware, timing).await;
+    }
+
+    // BIND is the only other SOCKS4 command
+    let info = ConnectionInfo {
+        client: peer,
+        target: addr,
+        tags: Vec::new(),
+    };
+    let started = Instant::now();
+    let command = SocksCommand::Bind;
+    match config.commands.bind {
+        CommandPolicy::Relay | CommandPolicy::Reject => {
+            log_access(
+                &config,
+                &info,
+                command,
+                None,
+                "not_supported",
+                started,
+                (0, 0),
+            );
+            stream.write_all(&[0, REJECTED, 0, 0, 0, 0, 0, 0]).await?;
+            let _ = stream.shutdown().await;
+        }
+        CommandPolicy::Close => {
+            log_access(&config, &info, command, None, "closed", started, (0, 0));
+        }
+    }
+    Ok(())
+}
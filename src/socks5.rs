@@ -0,0 +1,236 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Instant};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use log::trace;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "vendored-socks")]
+use tokio::net::TcpStream;
+
+use crate::{
+    config::{CommandPolicy, Config},
+    events::SocksCommand,
+    proto::{Address, Reply},
+    proxy::{ConnectionInfo, Middleware},
+    record::Recorder,
+    server::{log_access, serve_connect, ConnectRequest},
+    socks5_async::lib::{
+        accept_socks5, AcceptOptions, Command, RespondHandle, Response, TargetAddr,
+    },
+    timing::{Phase, Timing},
+    transport::BoxStream,
+    udp::{self, read_until_closed, AssociateRequest},
+};
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ConnectRequest for RespondHandle<S> {
+    type Stream = S;
+
+    async fn reply(self, reply: Reply, addr: Address) -> Result<S> {
+        Ok(RespondHandle::reply(self, reply.into(), TargetAddr::from(addr)).await?)
+    }
+}
+
+/// A UDP ASSOCIATE request, with the addresses of the connection it came on
+struct Associate<S> {
+    respond: RespondHandle<S>,
+    peer: SocketAddr,
+    local: SocketAddr,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AssociateRequest for Associate<S> {
+    type Control = S;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    async fn reply(self, reply: Reply, addr: Address) -> Result<S> {
+        Ok(self
+            .respond
+            .reply(reply.into(), TargetAddr::from(addr))
+            .await?)
+    }
+
+    async fn closed(control: &mut S) {
+        read_until_closed(control).await
+    }
+}
+
+/// A BIND or UDP ASSOCIATE request waiting for its reply, from either SOCKS
+/// stack
+#[async_trait]
+pub(crate) trait CommandRequest: Send + Sized {
+    type Associate: AssociateRequest;
+
+    /// The request as a UDP ASSOCIATE to relay, or back as it was when its
+    /// connection can't carry UDP
+    fn into_associate(self) -> Result<Self::Associate, Self>;
+    /// Replies command not supported
+    async fn not_supported(self) -> Result<()>;
+}
+
+/// Answers BIND and UDP ASSOCIATE as `config.commands` says. Only an
+/// ASSOCIATE is ever relayed, there is nothing to relay BIND to
+pub(crate) async fn serve_command<R: CommandRequest>(
+    request: R,
+    command: SocksCommand,
+    addr: Address,
+    peer: SocketAddr,
+    config: &Config,
+) -> Result<()> {
+    let policy = match command {
+        SocksCommand::Bind => match config.commands.bind {
+            CommandPolicy::Relay => CommandPolicy::Reject,
+            policy => policy,
+        },
+        _ => config.commands.associate,
+    };
+    let info = ConnectionInfo {
+        client: peer,
+        target: addr,
+        tags: Vec::new(),
+    };
+    let started = Instant::now();
+    let request = match policy {
+        CommandPolicy::Relay => match request.into_associate() {
+            Ok(associate) => {
+                let relayed = udp::associate(associate, config).await;
+                let result = match relayed {
+                    Ok(_) => "succeeded",
+                    Err(_) => "failed",
+                };
+                log_access(config, &info, command, None, result, started, (0, 0));
+                return relayed;
+            }
+            Err(request) => request,
+        },
+        CommandPolicy::Reject => request,
+        CommandPolicy::Close => {
+            log_access(config, &info, command, None, "closed", started, (0, 0));
+            return Ok(());
+        }
+    };
+    log_access(
+        config,
+        &info,
+        command,
+        None,
+        "not_supported",
+        started,
+        (0, 0),
+    );
+    request.not_supported().await
+}
+
+/// A BIND or UDP ASSOCIATE request socks5_async read, with the addresses of
+/// the connection it came on
+struct Socks5Request<S> {
+    respond: RespondHandle<S>,
+    peer: SocketAddr,
+    local: Option<SocketAddr>,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> CommandRequest for Socks5Request<S> {
+    type Associate = Associate<S>;
+
+    fn into_associate(self) -> Result<Associate<S>, Self> {
+        match self.local {
+            Some(local) => Ok(Associate {
+                respond: self.respond,
+                peer: self.peer,
+                local,
+            }),
+            None => Err(self),
+        }
+    }
+
+    async fn not_supported(self) -> Result<()> {
+        self.respond.fail(Response::CommandNotSupported).await?;
+        Ok(())
+    }
+}
+
+/// Serves SOCKS5 from a client through socks5_async. `local` is the address
+/// the client connected to, for the UDP relay, which is only offered when
+/// it is given
+async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    peer: SocketAddr,
+    local: Option<SocketAddr>,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: &Recorder,
+) -> Result<()> {
+    let stream = recorder.tee(stream);
+    let (request, respond) = accept_socks5(stream, &AcceptOptions::default()).await?;
+    recorder.stop();
+    timing.mark(Phase::Auth);
+    timing.mark(Phase::Command);
+    let addr = Address::from(request.target);
+
+    let command = match request.command {
+        Command::Connect => {
+            return serve_connect(respond, addr, peer, config, middleware, timing).await
+        }
+        Command::UdpAssosiate => SocksCommand::Associate,
+        Command::Bind => SocksCommand::Bind,
+    };
+    let request = Socks5Request {
+        respond,
+        peer,
+        local,
+    };
+    serve_command(request, command, addr, peer, &config).await
+}
+
+/// Serves a SOCKS5 client of the listener, when socks5_async is the SOCKS
+/// stack built in
+#[cfg(feature = "vendored-socks")]
+pub(crate) async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: &Recorder,
+) -> Result<()> {
+    trace!("Connection from {}", peer);
+    let local = stream.local_addr()?;
+    serve(
+        stream,
+        peer,
+        Some(local),
+        config,
+        middleware,
+        timing,
+        recorder,
+    )
+    .await
+}
+
+/// Serves SOCKS5 from a client that came in over TLS or compressed. UDP
+/// can't be carried inside such a connection, so only CONNECT and BIND are
+/// answered
+pub(crate) async fn handle_stream(
+    stream: BoxStream,
+    peer: SocketAddr,
+    config: Config,
+    middleware: &[Box<dyn Middleware>],
+    timing: &Arc<Timing>,
+    recorder: &Recorder,
+) -> Result<()> {
+    trace!("Wrapped connection from {}", peer);
+    serve(stream, peer, None, config, middleware, timing, recorder).await
+}
@@ -0,0 +1,255 @@
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use log::{info, warn};
+
+use crate::socks5_async::lib::{read_addr, TargetAddr, ToTargetAddr, MAX_FIELD};
+use crate::socks5_async::socks::{AuthMethod, Command, Response, AUTH_VERSION, RESERVED, VERSION5};
+use crate::socks5_async::wire::{Addr, Message, Reply};
+
+/// Checks a username and password, true lets the client in
+pub type AuthCheck = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// How [`accept_socks5`] negotiates with a client
+#[derive(Clone)]
+pub struct AcceptOptions {
+    /// Lets clients in without a username and password
+    pub allow_no_auth: bool,
+    /// Rejects clients that stray from RFC 1928 and RFC 1929, like
+    /// `SocksServer::strict`
+    pub strict: bool,
+    /// Offered to clients that send a username and password, who are
+    /// refused without it
+    pub auth: Option<AuthCheck>,
+}
+impl Default for AcceptOptions {
+    fn default() -> Self {
+        AcceptOptions {
+            allow_no_auth: true,
+            strict: false,
+            auth: None,
+        }
+    }
+}
+
+/// What a client asked for once it was let in
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub command: Command,
+    /// As the client sent it, a domain is left for the embedder to resolve
+    pub target: TargetAddr,
+    /// Who the client authenticated as, if it did
+    pub username: Option<String>,
+}
+
+/// The client's connection, waiting for the reply to its [`Request`]
+pub struct RespondHandle<S> {
+    stream: S,
+}
+impl<S: AsyncWrite + Unpin> RespondHandle<S> {
+    /// Tells the client its request succeeded, `bound` being the address
+    /// used for it, and hands back the connection to carry the traffic
+    pub async fn succeed(self, bound: impl ToTargetAddr) -> io::Result<S> {
+        self.reply(Response::Success, bound).await
+    }
+
+    /// Tells the client why its request failed and closes the connection
+    pub async fn fail(self, response: Response) -> io::Result<()> {
+        let unspecified = TargetAddr::V4("0.0.0.0:0".parse().unwrap());
+        self.reply(response, unspecified).await?.shutdown().await
+    }
+
+    /// Sends any reply and hands back the connection, whatever it said
+    pub async fn reply(mut self, response: Response, bound: impl ToTargetAddr) -> io::Result<S> {
+        write_reply(&mut self.stream, response, bound.target_addr()).await?;
+        Ok(self.stream)
+    }
+
+    /// The connection without replying, for replies `succeed` and `fail`
+    /// can't send, like the two of a BIND
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+async fn write_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: Response,
+    bound: TargetAddr,
+) -> io::Result<()> {
+    let mut data = [0u8; 7 + MAX_FIELD];
+    let reply = Reply {
+        response,
+        addr: Addr::from(&bound),
+    };
+    let len = reply.encode_into(&mut data)?;
+    stream.write_all(&data[..len]).await
+}
+
+/// Fails with `msg` when `strict` and the client doesn't conform
+fn conform(strict: bool, conforms: bool, msg: &str) -> io::Result<()> {
+    match strict && !conforms {
+        true => {
+            warn!("{}", msg);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+        }
+        false => Ok(()),
+    }
+}
+
+/// Negotiates with a SOCKS5 client up to its request and returns it without
+/// dialing anything, so embedders can carry out commands their own way. An
+/// unknown command is refused here
+///
+/// # Example
+/// ```ignore
+/// let (request, respond) = accept_socks5(socket, &AcceptOptions::default()).await?;
+/// match request.command {
+///     Command::Connect => {
+///         let upstream = dial(request.target).await?;
+///         let client = respond.succeed(upstream.local_addr()?).await?;
+///         // Relay between client and upstream
+///     }
+///     _ => respond.fail(Response::CommandNotSupported).await?,
+/// }
+/// ```
+pub async fn accept_socks5<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    options: &AcceptOptions,
+) -> io::Result<(Request, RespondHandle<S>)> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+
+    // Accept only version 5
+    if header[0] != VERSION5 {
+        warn!("Unsupported version");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported SOCKS version",
+        ));
+    }
+    conform(
+        options.strict,
+        header[1] > 0,
+        "No authentication methods offered",
+    )?;
+
+    let mut methods = [0u8; MAX_FIELD];
+    let methods = &mut methods[..header[1] as usize];
+    stream.read_exact(methods).await?;
+    let offered = |method: AuthMethod| methods.contains(&(method as u8));
+
+    let username = match &options.auth {
+        Some(auth) if offered(AuthMethod::UserPass) => {
+            Some(auth_user_pass(&mut stream, auth, options.strict).await?)
+        }
+        _ if options.allow_no_auth && offered(AuthMethod::NoAuth) => {
+            warn!("Client connected with no authentication");
+            stream
+                .write_all(&[VERSION5, AuthMethod::NoAuth as u8])
+                .await?;
+            None
+        }
+        _ => {
+            stream
+                .write_all(&[VERSION5, AuthMethod::NoMethods as u8])
+                .await?;
+            warn!("No acceptable method found.");
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "No acceptable method found",
+            ));
+        }
+    };
+
+    // Read request header
+    let mut data = [0u8; 3];
+    stream.read_exact(&mut data).await?;
+    conform(
+        options.strict,
+        data[0] == VERSION5,
+        "Invalid SOCKS version in request",
+    )?;
+    conform(
+        options.strict,
+        data[2] == RESERVED,
+        "Reserved byte is not zero",
+    )?;
+    let target = read_addr(&mut stream).await?;
+
+    let respond = RespondHandle { stream };
+    let command = match Command::from(data[1] as usize) {
+        Some(command) => command,
+        None => {
+            warn!("Command not supported.");
+            respond.fail(Response::CommandNotSupported).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                Response::CommandNotSupported,
+            ));
+        }
+    };
+    Ok((
+        Request {
+            command,
+            target,
+            username,
+        },
+        respond,
+    ))
+}
+
+/// The username/password sub-negotiation of RFC 1929, returning the username
+async fn auth_user_pass<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: &AuthCheck,
+    strict: bool,
+) -> io::Result<String> {
+    stream
+        .write_all(&[VERSION5, AuthMethod::UserPass as u8])
+        .await?;
+
+    // Read username
+    let mut ulen = [0u8; 2];
+    stream.read_exact(&mut ulen).await?;
+    conform(
+        strict,
+        ulen[0] == AUTH_VERSION,
+        "Invalid username/password sub-negotiation version",
+    )?;
+    conform(strict, ulen[1] > 0, "Empty username")?;
+    let mut username = vec![0u8; ulen[1] as usize];
+    stream.read_exact(&mut username).await?;
+
+    // Read password
+    let plen = stream.read_u8().await?;
+    conform(strict, plen > 0, "Empty password")?;
+    let mut password = vec![0u8; plen as usize];
+    stream.read_exact(&mut password).await?;
+
+    let (username, password) = match (String::from_utf8(username), String::from_utf8(password)) {
+        (Ok(username), Ok(password)) => (username, password),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Username or password is not UTF-8",
+            ))
+        }
+    };
+    if auth(&username, &password) {
+        info!("User authenticated: {}", username);
+        stream
+            .write_all(&[AUTH_VERSION, Response::Success as u8])
+            .await?;
+        Ok(username)
+    } else {
+        stream
+            .write_all(&[AUTH_VERSION, Response::Failure as u8])
+            .await?;
+        warn!("Authentication failed.");
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Authentication failed",
+        ))
+    }
+}
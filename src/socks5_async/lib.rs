@@ -1,30 +1,65 @@
-pub use crate::socks5_async::socks::AuthMethod;
-use crate::socks5_async::socks::{AddrType, Command, Response, RESERVED, VERSION5};
-use futures::future::try_join;
+pub use crate::socks5_async::accept::{
+    accept_socks5, AcceptOptions, AuthCheck, Request, RespondHandle,
+};
+pub use crate::socks5_async::relay::{relay, RelayOptions, Steering};
+pub use crate::socks5_async::resolver::{Resolver, SystemResolver};
+use crate::socks5_async::socks::{AddrType, AUTH_VERSION, RESERVED, VERSION5};
+pub use crate::socks5_async::socks::{AuthMethod, Command, Response};
+pub use crate::socks5_async::wire::WireError;
+use crate::socks5_async::wire::{self, Addr, Message};
 use std::{
     boxed::Box,
     error::Error,
     io,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, oneshot},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::watch,
+    task::JoinSet,
 };
 
 use log::{error, info, warn};
 
-// Transmited over mpsc channel to check user authentication
-type AuthCheckMsg = (String, String, oneshot::Sender<bool>);
+/// Longest domain, username or password a SOCKS5 message can carry
+pub(crate) const MAX_FIELD: usize = 255;
 
 use anyhow::Result;
 
 /// A SOCKS5 Server
 pub struct SocksServer {
     listener: TcpListener,
-    allow_no_auth: bool,
-    auth_tx: mpsc::Sender<AuthCheckMsg>,
+    options: AcceptOptions,
+    resolver: Arc<dyn Resolver>,
+    max_connections: Option<usize>,
+    /// One task per connection being served
+    tasks: JoinSet<()>,
+    active: Arc<AtomicUsize>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// Reports on and stops a `SocksServer` while it serves
+#[derive(Clone)]
+pub struct ServerHandle {
+    active: Arc<AtomicUsize>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+impl ServerHandle {
+    /// How many connections are being served
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Makes `serve` stop accepting, abort every connection and return
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
 }
 impl SocksServer {
     /// Creates and returns a new `SocksServer`
@@ -33,30 +68,65 @@ impl SocksServer {
         allow_no_auth: bool,
         auth: Box<dyn Fn(String, String) -> bool + Send>,
     ) -> SocksServer {
-        let (tx, mut rx) = mpsc::channel::<AuthCheckMsg>(100);
-        tokio::spawn(async move {
-            while let Some((username, password, sender)) = rx.recv().await {
-                if let Err(_) = sender.send(auth(username, password)) {
-                    error!("Failed to send back authentication result.");
-                }
-            }
-        });
+        // Connections check users from their own tasks
+        let auth = Mutex::new(auth);
         println!("SOCKS5 server listening on {}", socket_addr);
         SocksServer {
             listener: TcpListener::bind(socket_addr).await.unwrap(),
-            allow_no_auth,
-            auth_tx: tx,
+            options: AcceptOptions {
+                allow_no_auth,
+                strict: false,
+                auth: Some(Arc::new(move |username: &str, password: &str| {
+                    (auth.lock().unwrap())(username.to_string(), password.to_string())
+                })),
+            },
+            resolver: Arc::new(SystemResolver),
+            max_connections: None,
+            tasks: JoinSet::new(),
+            active: Arc::new(AtomicUsize::new(0)),
+            shutdown: Arc::new(watch::channel(false).0),
         }
     }
 
-    /// Starts the server. It **should** be called after initializing server
+    /// Serves at most `max` connections at once, the rest wait in the
+    /// listen backlog until one finishes
+    pub fn max_connections(mut self, max: usize) -> SocksServer {
+        self.max_connections = Some(max.max(1));
+        self
+    }
+
+    /// A handle to watch and stop the server from other tasks
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            active: self.active.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Resolves the domains clients ask for with `resolver` instead of the
+    /// system's
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> SocksServer {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Rejects clients that stray from RFC 1928 and RFC 1929 in any way,
+    /// such as a non-zero reserved byte, an empty field or the wrong
+    /// sub-negotiation version, instead of reading past it
+    pub fn strict(mut self, strict: bool) -> SocksServer {
+        self.options.strict = strict;
+        self
+    }
+
+    /// Starts the server. It **should** be called after initializing server,
+    /// and returns once `ServerHandle::shutdown` is called
     ///
     /// # Example
-    /// ```
-    /// use socks5_async::SocksServer;
+    /// ```no_run
+    /// # async fn example() {
+    /// use toggleproxy::socks5_async::lib::SocksServer;
     /// use std::{
     ///     boxed::Box,
-    ///     error::Error,
     ///     net::SocketAddr,
     /// };
     ///
@@ -73,185 +143,80 @@ impl SocksServer {
     ///     }),
     /// ).await;
     /// socks5.serve().await;
-    ///
+    /// # }
     /// ```
 
     pub async fn serve(&mut self) {
+        let mut shutdown = self.shutdown.subscribe();
         loop {
-            let no_auth = self.allow_no_auth.clone();
-            if let Ok((socket, address)) = self.listener.accept().await {
-                let tx2 = self.auth_tx.clone();
-                tokio::spawn(async move {
-                    info!("Client connected: {}", address);
-                    let mut client = SocksServerConnection::new(socket, no_auth, tx2);
-                    match client.serve().await {
-                        Ok(_) => info!("Request was served successfully."),
-                        Err(err) => error!("{}", err.to_string()),
+            let full = self
+                .max_connections
+                .is_some_and(|max| self.tasks.len() >= max);
+            tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => break,
+                Some(joined) = self.tasks.join_next(), if !self.tasks.is_empty() => {
+                    if let Err(err) = joined {
+                        error!("Connection task failed: {}", err);
                     }
-                });
+                }
+                accepted = self.listener.accept(), if !full => match accepted {
+                    Ok((socket, address)) => {
+                        let options = self.options.clone();
+                        let resolver = self.resolver.clone();
+                        self.tasks.spawn(async move {
+                            info!("Client connected: {}", address);
+                            match serve_client(socket, &options, resolver.as_ref()).await {
+                                Ok(_) => info!("Request was served successfully."),
+                                Err(err) => error!("{}", err.to_string()),
+                            }
+                        });
+                    }
+                    Err(err) => warn!("Failed to accept a client: {}", err),
+                },
             }
+            self.active.store(self.tasks.len(), Ordering::Relaxed);
         }
+
+        info!("Shutting down, aborting {} connections", self.tasks.len());
+        self.tasks.shutdown().await;
+        self.active.store(0, Ordering::Relaxed);
     }
 }
 
-// Represents a SOCKS5 Client (connected to SocksServer)
-struct SocksServerConnection {
+/// Serves a client of `SocksServer`, which only carries out CONNECT
+async fn serve_client(
     socket: TcpStream,
-    no_auth: bool,
-    auth_ch: mpsc::Sender<AuthCheckMsg>,
-}
-impl SocksServerConnection {
-    fn new(
-        socket: TcpStream,
-        no_auth: bool,
-        auth_ch: mpsc::Sender<(String, String, oneshot::Sender<bool>)>,
-    ) -> SocksServerConnection {
-        SocksServerConnection {
-            socket,
-            no_auth,
-            auth_ch,
-        }
-    }
-
-    fn shutdown(&mut self, msg: &str) -> Result<(), Box<dyn Error>> {
-        warn!("{}", msg);
-        Ok(())
-    }
-
-    async fn serve(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut header = [0u8; 2];
-        self.socket.read_exact(&mut header).await?;
-
-        // Accept only version 5
-        if header[0] != VERSION5 {
-            self.shutdown("Unsupported version")?;
-            Err(Response::Failure)?;
-        }
-
-        // Get available methods
-        let methods = AuthMethod::get_available_methods(header[1], &mut self.socket).await?;
-
-        // Authenticate the user
-        self.auth(methods).await?;
-
-        // Handle the request
-        self.handle_req().await?;
-
-        Ok(())
-    }
-
-    async fn auth(&mut self, methods: Vec<AuthMethod>) -> Result<(), Box<dyn Error>> {
-        if methods.contains(&AuthMethod::UserPass) {
-            // Authenticate with username/password
-            self.socket
-                .write_all(&[VERSION5, AuthMethod::UserPass as u8])
-                .await?;
-
-            // Read username
-            let mut ulen = [0u8; 2];
-            self.socket.read_exact(&mut ulen).await?;
-            let ulen = ulen[1];
-            let mut username: Vec<u8> = Vec::with_capacity(ulen as usize);
-            for _ in 0..ulen {
-                username.push(0)
-            }
-            self.socket.read_exact(&mut username).await?;
-            let username = String::from_utf8(username).unwrap();
-
-            // Read Password
-            let mut plen = [0u8; 1];
-            self.socket.read_exact(&mut plen).await?;
-            let plen = plen[0];
-            let mut password: Vec<u8> = Vec::with_capacity(plen as usize);
-            for _ in 0..plen {
-                password.push(0)
-            }
-            self.socket.read_exact(&mut password).await?;
-            let password = String::from_utf8(password).unwrap();
-
-            // Authenticate user
-            let (tx, rx) = oneshot::channel::<bool>();
-            self.auth_ch.send((username.clone(), password, tx)).await?;
-            if rx.await? {
-                info!("User authenticated: {}", username);
-                self.socket.write_all(&[1, Response::Success as u8]).await?;
-            } else {
-                self.socket
-                    .write_all(&[VERSION5, Response::Failure as u8])
-                    .await?;
-                self.shutdown("Authentication failed.")?;
-            }
-        } else if self.no_auth && methods.contains(&AuthMethod::NoAuth) {
-            warn!("Client connected with no authentication");
-            self.socket
-                .write_all(&[VERSION5, AuthMethod::NoAuth as u8])
-                .await?
-        } else {
-            self.socket
-                .write_all(&[VERSION5, Response::Failure as u8])
-                .await?;
-            self.shutdown("No acceptable method found.")?;
-        }
-        Ok(())
-    }
-
-    async fn handle_req(&mut self) -> Result<(), Box<dyn Error>> {
-        // Read request header
-        let mut data = [0u8; 3];
-        self.socket.read(&mut data).await?;
-
-        // Read socket address
-        let addresses = AddrType::get_socket_addrs(&mut self.socket).await?;
-
-        // Proccess the command
-        match Command::from(data[1] as usize) {
-            // Note: Currently only connect is accepted
-            Some(Command::Connect) => self.cmd_connect(addresses).await?,
-            _ => {
-                self.shutdown("Command not supported.")?;
-                Err(Response::CommandNotSupported)?;
-            }
-        };
-
-        Ok(())
+    options: &AcceptOptions,
+    resolver: &dyn Resolver,
+) -> Result<(), Box<dyn Error>> {
+    let (request, respond) = accept_socks5(socket, options).await?;
+    if request.command != Command::Connect {
+        warn!("Command not supported.");
+        respond.fail(Response::CommandNotSupported).await?;
+        return Err(Response::CommandNotSupported.into());
     }
 
-    async fn cmd_connect(&mut self, addrs: Vec<SocketAddr>) -> Result<(), Box<dyn Error>> {
-        let mut dest = TcpStream::connect(&addrs[..]).await?;
-
-        self.socket
-            .write_all(&[
-                VERSION5,
-                Response::Success as u8,
-                RESERVED,
-                1,
-                127,
-                0,
-                0,
-                1,
-                0,
-                0,
-            ])
-            .await
-            .unwrap();
-
-        let (mut ro, mut wo) = dest.split();
-        let (mut ri, mut wi) = self.socket.split();
-
-        let client_to_server = async {
-            tokio::io::copy(&mut ri, &mut wo).await?;
-            wo.shutdown().await
-        };
-
-        let server_to_client = async {
-            tokio::io::copy(&mut ro, &mut wi).await?;
-            wi.shutdown().await
-        };
-
-        try_join(client_to_server, server_to_client).await?;
+    let addrs: Vec<SocketAddr> = match request.target {
+        TargetAddr::V4(addr) => vec![addr.into()],
+        TargetAddr::V6(addr) => vec![addr.into()],
+        TargetAddr::Domain((domain, port)) => resolver.resolve(&domain, port).await?,
+    };
+    let mut dest = TcpStream::connect(&addrs[..]).await?;
+    let mut socket = respond.succeed(dest.local_addr()?).await?;
+
+    // Nothing outside steers these
+    let (_steer, steering) = watch::channel(Steering::default());
+    let (up, down) = relay(
+        &mut dest,
+        &mut socket,
+        RelayOptions::default(),
+        std::future::pending(),
+        steering,
+    )
+    .await;
+    info!("Relayed {} bytes up and {} down", up, down);
 
-        Ok(())
-    }
+    Ok(())
 }
 
 /// A SOCKS5 Stream
@@ -263,8 +228,10 @@ impl SocksStream {
     /// is authenticated via provided methods and ready to transfer data.
     ///
     /// # Example
-    /// ```
-    /// use socks5_async::SocksStream;
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// use std::net::{SocketAddr, SocketAddrV4};
+    /// use toggleproxy::socks5_async::lib::SocksStream;
     ///
     /// // SOCKS5 proxy server address
     /// let proxy: SocketAddr = "127.0.0.1:1080".parse().unwrap();
@@ -281,6 +248,8 @@ impl SocksStream {
     /// ).await?;
     ///
     /// // Use tcp stream ...
+    /// # Ok(())
+    /// # }
     /// ```
     /// # Note
     /// This methods uses `connect_with_stream()` under the hood.
@@ -289,40 +258,150 @@ impl SocksStream {
         target_addr: impl ToTargetAddr,
         user_pass: Option<(String, String)>,
     ) -> Result<TcpStream, std::io::Error> {
+        let socket = bind_socket(proxy_addr, None, None)?;
+        SocksStream::connect_with_socket(socket, proxy_addr, target_addr, user_pass).await
+    }
+
+    /// Like `connect()`, but dials `proxy_addr` from `socket`, so the source
+    /// address, interface and other options can be set on it beforehand.
+    /// Needed on multi-homed hosts, see `bind_socket()`
+    ///
+    /// # Example
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// use std::net::{SocketAddr, SocketAddrV4};
+    /// use toggleproxy::socks5_async::lib::{bind_socket, SocksStream};
+    ///
+    /// let proxy: SocketAddr = "10.0.0.1:1080".parse().unwrap();
+    /// let target: SocketAddrV4 = "127.0.0.1:3033".parse().unwrap();
+    ///
+    /// // Leave through 192.168.1.20
+    /// let socket = bind_socket(proxy, Some("192.168.1.20:0".parse().unwrap()), None)?;
+    /// let stream = SocksStream::connect_with_socket(socket, proxy, target, None).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect_with_socket(
+        socket: TcpSocket,
+        proxy_addr: SocketAddr,
+        target_addr: impl ToTargetAddr,
+        user_pass: Option<(String, String)>,
+    ) -> Result<TcpStream, std::io::Error> {
+        SocksStream::connect_with_report(socket, proxy_addr, target_addr, user_pass)
+            .await
+            .map(|(stream, _)| stream)
+    }
+
+    /// Like `connect_with_socket()`, also returning how the handshake went.
+    /// Pass `bind_socket(proxy_addr, None, None)?` for an ordinary socket
+    pub async fn connect_with_report(
+        socket: TcpSocket,
+        proxy_addr: SocketAddr,
+        target_addr: impl ToTargetAddr,
+        user_pass: Option<(String, String)>,
+    ) -> Result<(TcpStream, ConnectReport), std::io::Error> {
         let mut socks_stream = SocksStream {
-            stream: TcpStream::connect(proxy_addr).await?,
+            stream: socket.connect(proxy_addr).await?,
         };
-        match connect_with_stream(&mut socks_stream.stream, target_addr, user_pass).await {
-            Ok(_) => Ok(socks_stream.stream),
-            Err(err) => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                err.to_string(),
-            )),
+        match connect_with_stream_report(&mut socks_stream.stream, target_addr, user_pass).await {
+            Ok(report) => Ok((socks_stream.stream, report)),
+            Err(err) => match err.downcast::<Response>() {
+                Ok(reply) => Err(io::Error::new(reply.kind(), *reply)),
+                Err(err) => Err(io::Error::other(err.to_string())),
+            },
         }
     }
 }
 
+/// How connecting through a SOCKS5 proxy went, for logging or choosing
+/// between proxies
+#[derive(Debug, Clone)]
+pub struct ConnectReport {
+    /// How long the proxy took to answer the greeting, a single round trip
+    pub rtt: Duration,
+    /// How long the proxy took to answer `CONNECT`, which includes its own
+    /// connection to the target
+    pub connect_time: Duration,
+    pub auth_method: AuthMethod,
+    /// The address the proxy connected to the target from, as it reported it
+    pub bound_addr: TargetAddr,
+}
+
+/// A socket for reaching `proxy_addr`, bound to `local_addr` and, on Linux,
+/// to the network interface named `interface` when given
+pub fn bind_socket(
+    proxy_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    interface: Option<&str>,
+) -> io::Result<TcpSocket> {
+    let socket = match proxy_addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    if let Some(local_addr) = local_addr {
+        socket.bind(local_addr)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is only supported on Linux",
+    ))
+}
+
 /// Perform SOCKS5 handshake through a TCP stream
-pub async fn socks_handshake(
-    stream: &mut TcpStream,
+pub async fn socks_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     user_pass: Option<(String, String)>,
 ) -> Result<(), Box<dyn Error>> {
-    let with_userpass = user_pass.is_some();
-    let methods_len = if with_userpass { 2 } else { 1 };
-
-    // Start SOCKS5 communication
-    let mut data = vec![0; methods_len + 2];
-    data[0] = VERSION5; // Set SOCKS version
-    data[1] = methods_len as u8; // Set authentiaction methods count
-    if with_userpass {
-        data[2] = AuthMethod::UserPass as u8;
+    handshake(stream, user_pass, false).await?;
+    Ok(())
+}
+
+/// Fails with `msg` when `strict` and the server's reply doesn't conform
+fn conform(strict: bool, conforms: bool, msg: &str) -> io::Result<()> {
+    match strict && !conforms {
+        true => Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_string())),
+        false => Ok(()),
     }
-    data[1 + methods_len] = AuthMethod::NoAuth as u8;
-    stream.write_all(&mut data).await?;
+}
+
+/// The SOCKS5 handshake, returning the method the server picked and how
+/// long it took to pick it
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    user_pass: Option<(String, String)>,
+    strict: bool,
+) -> Result<(AuthMethod, Duration), Box<dyn Error>> {
+    // Start SOCKS5 communication, offering username/password first when we
+    // have credentials
+    let greeting: &[u8] = match user_pass.is_some() {
+        true => &[
+            VERSION5,
+            2,
+            AuthMethod::UserPass as u8,
+            AuthMethod::NoAuth as u8,
+        ],
+        false => &[VERSION5, 1, AuthMethod::NoAuth as u8],
+    };
+    let sent = Instant::now();
+    stream.write_all(greeting).await?;
 
     // Read method selection response
     let mut response = [0u8; 2];
     stream.read_exact(&mut response).await?;
+    let rtt = sent.elapsed();
 
     // Check SOCKS version
     if response[0] != VERSION5 {
@@ -334,18 +413,31 @@ pub async fn socks_handshake(
 
     if response[1] == AuthMethod::UserPass as u8 {
         if let Some((username, password)) = user_pass {
+            if username.len() > MAX_FIELD || password.len() > MAX_FIELD {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Username or password longer than 255 bytes",
+                ))?;
+            }
+
             // Send username & password
-            let mut data = vec![0; username.len() + password.len() + 3];
-            data[0] = VERSION5;
+            let mut data = [0u8; 3 + 2 * MAX_FIELD];
+            let len = username.len() + password.len() + 3;
+            data[0] = AUTH_VERSION;
             data[1] = username.len() as u8;
             data[2..2 + username.len()].copy_from_slice(username.as_bytes());
             data[2 + username.len()] = password.len() as u8;
-            data[3 + username.len()..].copy_from_slice(password.as_bytes());
-            stream.write_all(&data).await?;
+            data[3 + username.len()..len].copy_from_slice(password.as_bytes());
+            stream.write_all(&data[..len]).await?;
 
             // Read & check server response
             let mut response = [0; 2];
             stream.read_exact(&mut response).await?;
+            conform(
+                strict,
+                response[0] == AUTH_VERSION,
+                "Invalid username/password sub-negotiation version",
+            )?;
             if response[1] != Response::Success as u8 {
                 Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -365,47 +457,180 @@ pub async fn socks_handshake(
         ))?;
     }
 
-    Ok(())
+    Ok((AuthMethod::from(response[1]), rtt))
 }
 
-/// Send `CONNECT` command to a SOCKS server
-pub async fn cmd_connect(
-    stream: &mut TcpStream,
+/// Send `CONNECT` command to a SOCKS server, returning the address the
+/// server connected from. A refusal is returned as the `Response` it carried
+pub async fn cmd_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     target_addr: impl ToTargetAddr,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<TargetAddr, Box<dyn Error>> {
+    request_connect(stream, target_addr, false).await
+}
+
+async fn request_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_addr: impl ToTargetAddr,
+    strict: bool,
+) -> Result<TargetAddr, Box<dyn Error>> {
     let target_addr = target_addr.target_addr();
 
     // Send connect command
-    let mut data = vec![0; 6 + target_addr.len()];
-    data[0] = VERSION5;
-    data[1] = Command::Connect as u8;
-    data[2] = RESERVED;
-    data[3] = target_addr.addr_type() as u8;
-    target_addr.write_to(&mut data[4..]);
-    stream.write_all(&data).await?;
+    let mut data = [0u8; 7 + MAX_FIELD];
+    let request = wire::Request {
+        command: Command::Connect,
+        addr: Addr::from(&target_addr),
+    };
+    let len = request.encode_into(&mut data).map_err(io::Error::from)?;
+    stream.write_all(&data[..len]).await?;
 
     // Read server response
     let mut response = [0u8; 3];
-    stream.read(&mut response).await?;
+    stream.read_exact(&mut response).await?;
+    if response[0] != VERSION5 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid SOCKS version",
+        ))?;
+    }
+    match Response::from(response[1]) {
+        Some(Response::Success) => {}
+        Some(failure) => Err(failure)?,
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown reply code {}", response[1]),
+        ))?,
+    }
+    conform(strict, response[2] == RESERVED, "Reserved byte is not zero")?;
 
     // Read socket address
-    AddrType::get_socket_addrs(stream).await?;
+    Ok(read_addr(stream).await?)
+}
 
-    Ok(())
+/// Reads an address and port, like the one the server bound for us,
+/// without resolving it
+pub(crate) async fn read_addr<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<TargetAddr> {
+    let addr_type = AddrType::from(stream.read_u8().await? as usize);
+    let len = match addr_type {
+        Some(AddrType::V4) => 4,
+        Some(AddrType::V6) => 16,
+        Some(AddrType::Domain) => stream.read_u8().await? as usize,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid address type",
+            ))
+        }
+    };
+
+    // Address and port
+    let mut buf = [0u8; MAX_FIELD + 2];
+    stream.read_exact(&mut buf[..len + 2]).await?;
+    let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+    Ok(match addr_type {
+        Some(AddrType::V4) => {
+            let ip: [u8; 4] = buf[..4].try_into().unwrap();
+            TargetAddr::V4(SocketAddrV4::new(ip.into(), port))
+        }
+        Some(AddrType::V6) => {
+            let ip: [u8; 16] = buf[..16].try_into().unwrap();
+            TargetAddr::V6(SocketAddrV6::new(ip.into(), port, 0, 0))
+        }
+        _ => TargetAddr::Domain((String::from_utf8_lossy(&buf[..len]).into_owned(), port)),
+    })
 }
 
 /// Perform SOCKS5 handshake and send `CONNECT` command through a TCP stream
-pub async fn connect_with_stream(
-    stream: &mut TcpStream,
+pub async fn connect_with_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     target_addr: impl ToTargetAddr,
     user_pass: Option<(String, String)>,
 ) -> Result<(), Box<dyn Error>> {
-    socks_handshake(stream, user_pass).await?;
-    cmd_connect(stream, target_addr).await?;
-
+    connect_with_stream_report(stream, target_addr, user_pass).await?;
     Ok(())
 }
 
+/// Like `connect_with_stream()`, also returning how the handshake went
+pub async fn connect_with_stream_report<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_addr: impl ToTargetAddr,
+    user_pass: Option<(String, String)>,
+) -> Result<ConnectReport, Box<dyn Error>> {
+    connect_reporting(stream, target_addr, user_pass, false).await
+}
+
+/// Like `connect_with_stream_report()`, but fails on any reply that strays
+/// from RFC 1928 and RFC 1929, such as a non-zero reserved byte or the wrong
+/// sub-negotiation version, instead of reading past it
+pub async fn connect_with_stream_strict<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_addr: impl ToTargetAddr,
+    user_pass: Option<(String, String)>,
+) -> Result<ConnectReport, Box<dyn Error>> {
+    connect_reporting(stream, target_addr, user_pass, true).await
+}
+
+async fn connect_reporting<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_addr: impl ToTargetAddr,
+    user_pass: Option<(String, String)>,
+    strict: bool,
+) -> Result<ConnectReport, Box<dyn Error>> {
+    let (auth_method, rtt) = handshake(stream, user_pass, strict).await?;
+    let sent = Instant::now();
+    let bound_addr = request_connect(stream, target_addr, strict).await?;
+    Ok(ConnectReport {
+        rtt,
+        connect_time: sent.elapsed(),
+        auth_method,
+        bound_addr,
+    })
+}
+
+/// Send `UDP ASSOCIATE` command to a SOCKS server, returning the address of
+/// its UDP relay
+pub async fn cmd_udp_associate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    // We don't know which address we'll send from, so ask for any
+    let data = [
+        VERSION5,
+        Command::UdpAssosiate as u8,
+        RESERVED,
+        AddrType::V4 as u8,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    stream.write_all(&data).await?;
+
+    // Read server response
+    let mut response = [0u8; 3];
+    stream.read_exact(&mut response).await?;
+    if response[1] != Response::Success as u8 {
+        Err(io::Error::other("UDP ASSOCIATE was refused"))?;
+    }
+
+    // Read relay address
+    match AddrType::get_socket_addrs(stream).await?.into_iter().next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::other("Relay address did not resolve"))?,
+    }
+}
+
+/// Perform SOCKS5 handshake and send `UDP ASSOCIATE` command through a TCP stream
+pub async fn udp_associate_with_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    user_pass: Option<(String, String)>,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    socks_handshake(stream, user_pass).await?;
+    cmd_udp_associate(stream).await
+}
+
 /// Socket Address of the target, required by `SocksStream`
 #[derive(Debug, Clone)]
 pub enum TargetAddr {
@@ -414,38 +639,19 @@ pub enum TargetAddr {
     Domain((String, u16)),
 }
 impl TargetAddr {
-    fn len(&self) -> usize {
-        match self {
-            TargetAddr::V4(_) => 4,
-            TargetAddr::V6(_) => 16,
-            TargetAddr::Domain((domain, _)) => domain.len() + 1,
-        }
-    }
-    fn addr_type(&self) -> AddrType {
-        match self {
-            TargetAddr::V4(_) => AddrType::V4,
-            TargetAddr::V6(_) => AddrType::V4,
-            TargetAddr::Domain(_) => AddrType::Domain,
-        }
-    }
-    fn write_to(&self, buf: &mut [u8]) {
-        match self {
-            TargetAddr::V4(addr) => {
-                let mut ip = addr.ip().octets().to_vec();
-                ip.extend(&addr.port().to_be_bytes());
-                buf[..].copy_from_slice(&ip[..]);
-            }
-            TargetAddr::V6(addr) => {
-                let mut ip = addr.ip().octets().to_vec();
-                ip.extend(&addr.port().to_be_bytes());
-                buf[..].copy_from_slice(&ip[..]);
-            }
-            TargetAddr::Domain((domain, port)) => {
-                let mut ip = domain.as_bytes().to_vec();
-                ip.extend(&port.to_be_bytes());
-                buf[0] = domain.len() as u8;
-                buf[1..].copy_from_slice(&ip[..]);
-            }
+    /// The address with a domain resolved by `resolver`, for proxies that
+    /// only take IP addresses or to keep names from reaching DNS elsewhere
+    pub async fn resolve(self, resolver: &dyn Resolver) -> io::Result<TargetAddr> {
+        let (domain, port) = match self {
+            TargetAddr::Domain(domain) => domain,
+            addr => return Ok(addr),
+        };
+        match resolver.resolve(&domain, port).await?.into_iter().next() {
+            Some(addr) => Ok(addr.target_addr()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve", domain),
+            )),
         }
     }
 }
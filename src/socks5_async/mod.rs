@@ -1,2 +1,6 @@
+pub mod accept;
 pub mod lib;
+pub mod relay;
+pub mod resolver;
 pub mod socks;
+pub mod wire;
@@ -0,0 +1,458 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::trace;
+
+use tokio::{
+    io::{copy_bidirectional_with_sizes, AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+    time::sleep_until,
+};
+
+/// How `relay` copies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RelayOptions {
+    /// Bytes buffered each way. Larger buffers move bulk transfers with fewer
+    /// wakeups, smaller ones keep memory down with many connections open
+    pub buffer_size: usize,
+    /// Closes once nothing has moved either way for this long
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        RelayOptions {
+            buffer_size: 8 * 1024,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// How an open relay is held or wound down from outside. `relay` follows
+/// every value sent on its channel as soon as it is sent
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Steering {
+    /// Nothing is read or written either way, but both sides stay open. Time
+    /// spent paused doesn't count towards any idle timeout
+    pub paused: bool,
+    /// Winds the relay down, see `Drain`
+    pub drain: Option<Drain>,
+}
+
+/// Closes a relay early without cutting it off mid-transfer if it can help it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Drain {
+    /// Closes once nothing has moved either way for this long
+    pub idle: Duration,
+    /// Closes by then regardless
+    pub deadline: Instant,
+}
+
+/// When a stream last moved data and how much it moved each way
+struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`
+    last_ms: AtomicU64,
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let ms = self.started.elapsed().as_millis() as u64;
+        self.last_ms.store(ms, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Notes every read and write going through a client connection
+struct ActivityStream<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        if read > 0 {
+            this.activity.read.fetch_add(read as u64, Ordering::Relaxed);
+            this.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.activity
+                .written
+                .fetch_add(written as u64, Ordering::Relaxed);
+            this.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies between the client and the destination until both have closed,
+/// nothing has moved either way for `idle_timeout`, `cancel` completes or
+/// `steering` drains it. Nothing is copied while paused, but both stay open.
+/// Returns the bytes sent up by the client and down to it.
+///
+/// When one side finishes sending, the other side's write half is shut down
+/// and the other direction carries on, so half-closed connections work. A
+/// side stops being read while the other can't take more, so a slow reader
+/// holds back a fast writer instead of growing a buffer, so wrapping either
+/// side in a stream that delays its reads or writes limits the rate.
+///
+/// Once the sender of `steering` is dropped, the last value sent is kept
+/// except for a pause, which ends.
+///
+/// Pass `std::future::pending()` and the receiver of a
+/// `watch::channel(Steering::default())` to relay without outside control
+pub async fn relay<T, C>(
+    target: &mut T,
+    conn: &mut C,
+    options: RelayOptions,
+    cancel: impl Future<Output = ()>,
+    mut steering: watch::Receiver<Steering>,
+) -> (u64, u64)
+where
+    T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let activity = Arc::new(Activity {
+        started: Instant::now(),
+        last_ms: AtomicU64::new(0),
+        read: AtomicU64::new(0),
+        written: AtomicU64::new(0),
+    });
+    let mut conn = ActivityStream {
+        inner: conn,
+        activity: activity.clone(),
+    };
+
+    // Not polling the copy holds both sides where they are
+    let copy =
+        copy_bidirectional_with_sizes(target, &mut conn, options.buffer_size, options.buffer_size);
+    tokio::pin!(copy);
+    tokio::pin!(cancel);
+    // What is left to follow once nothing can resume the relay
+    let mut orphaned: Option<Steering> = None;
+    loop {
+        let current = orphaned.unwrap_or_else(|| *steering.borrow_and_update());
+        if current.paused {
+            trace!("Pausing tunnel");
+            tokio::select! {
+                resumed = steering.wait_for(|steering| !steering.paused) => {
+                    if resumed.is_err() {
+                        orphaned = Some(Steering {
+                            paused: false,
+                            ..current
+                        });
+                    }
+                }
+                _ = &mut cancel => {
+                    trace!("Closing cancelled tunnel");
+                    break;
+                }
+            }
+            // Time spent paused doesn't count as idle
+            activity.touch();
+            trace!("Resuming tunnel");
+            continue;
+        }
+
+        let idle_timeout = match (options.idle_timeout, current.drain) {
+            (Some(idle_timeout), Some(drain)) => Some(idle_timeout.min(drain.idle)),
+            (idle_timeout, drain) => idle_timeout.or(drain.map(|drain| drain.idle)),
+        };
+        let idled = async {
+            match idle_timeout {
+                Some(idle_timeout) => idle(&activity, idle_timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let drained = async {
+            match current.drain {
+                Some(drain) => sleep_until(drain.deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = &mut copy => break,
+            _ = idled => {
+                trace!("Closing tunnel idle for {:?}", idle_timeout);
+                break;
+            }
+            _ = drained => {
+                trace!("Closing drained tunnel");
+                break;
+            }
+            _ = &mut cancel => {
+                trace!("Closing cancelled tunnel");
+                break;
+            }
+            Ok(()) = steering.changed() => {}
+        }
+    }
+    (
+        activity.read.load(Ordering::Relaxed),
+        activity.written.load(Ordering::Relaxed),
+    )
+}
+
+/// Completes once `idle_timeout` has passed since the last activity
+async fn idle(activity: &Activity, idle_timeout: Duration) {
+    loop {
+        let deadline = activity.last() + idle_timeout;
+        if deadline <= Instant::now() {
+            return;
+        }
+        sleep_until(deadline.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::oneshot,
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// The ends a test drives: the client's and the destination's
+    struct Ends {
+        client: DuplexStream,
+        target: DuplexStream,
+        steering: watch::Sender<Steering>,
+        cancel: Option<oneshot::Sender<()>>,
+        relay: JoinHandle<(u64, u64)>,
+    }
+
+    fn start(options: RelayOptions) -> Ends {
+        let (client, mut conn) = duplex(64);
+        let (mut target_side, target) = duplex(64);
+        let (steering, steering_rx) = watch::channel(Steering::default());
+        let (cancel, cancelled) = oneshot::channel::<()>();
+        let relay = tokio::spawn(async move {
+            let cancelled = async {
+                let _ = cancelled.await;
+            };
+            relay(&mut target_side, &mut conn, options, cancelled, steering_rx).await
+        });
+        Ends {
+            client,
+            target,
+            steering,
+            cancel: Some(cancel),
+            relay,
+        }
+    }
+
+    async fn read_exact(stream: &mut DuplexStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        timeout(WAIT, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf
+    }
+
+    async fn finished(relay: JoinHandle<(u64, u64)>) -> (u64, u64) {
+        timeout(WAIT, relay).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn copies_both_ways_and_counts_bytes() {
+        let mut ends = start(RelayOptions::default());
+        ends.client.write_all(b"hello").await.unwrap();
+        assert_eq!(read_exact(&mut ends.target, 5).await, b"hello");
+        ends.target.write_all(b"world!").await.unwrap();
+        assert_eq!(read_exact(&mut ends.client, 6).await, b"world!");
+
+        // More than either buffer holds
+        let bulk = vec![7u8; 100_000];
+        let mut sink = ends.target;
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            sink.read_to_end(&mut received).await.unwrap();
+            received.len()
+        });
+        ends.client.write_all(&bulk).await.unwrap();
+        ends.client.shutdown().await.unwrap();
+        assert_eq!(timeout(WAIT, reader).await.unwrap().unwrap(), bulk.len());
+
+        assert_eq!(finished(ends.relay).await, (5 + 100_000, 6));
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_the_other_direction() {
+        let mut ends = start(RelayOptions::default());
+        ends.client.write_all(b"request").await.unwrap();
+        ends.client.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        timeout(WAIT, ends.target.read_to_end(&mut request))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request, b"request");
+
+        // The client stopped sending, it still gets the response
+        ends.target.write_all(b"response").await.unwrap();
+        assert_eq!(read_exact(&mut ends.client, 8).await, b"response");
+        assert!(!ends.relay.is_finished());
+
+        ends.target.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        timeout(WAIT, ends.client.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(finished(ends.relay).await, (7, 8));
+    }
+
+    #[tokio::test]
+    async fn closes_after_idle_timeout() {
+        let mut ends = start(RelayOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..RelayOptions::default()
+        });
+        // Traffic keeps it open past the timeout
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            ends.client.write_all(b"x").await.unwrap();
+            read_exact(&mut ends.target, 1).await;
+        }
+        assert!(!ends.relay.is_finished());
+
+        let quiet = Instant::now();
+        assert_eq!(finished(ends.relay).await, (3, 0));
+        assert!(quiet.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn pause_holds_data_until_resumed() {
+        let mut ends = start(RelayOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..RelayOptions::default()
+        });
+        ends.steering.send_modify(|steering| steering.paused = true);
+        ends.client.write_all(b"held").await.unwrap();
+
+        // Longer than the idle timeout, which doesn't run while paused
+        let mut buf = [0; 4];
+        let held = timeout(Duration::from_millis(300), ends.target.read(&mut buf)).await;
+        assert!(held.is_err());
+        assert!(!ends.relay.is_finished());
+
+        ends.steering
+            .send_modify(|steering| steering.paused = false);
+        assert_eq!(read_exact(&mut ends.target, 4).await, b"held");
+        drop(ends.client);
+        drop(ends.target);
+        assert_eq!(finished(ends.relay).await.0, 4);
+    }
+
+    #[tokio::test]
+    async fn drain_closes_once_idle() {
+        let mut ends = start(RelayOptions::default());
+        ends.client.write_all(b"x").await.unwrap();
+        read_exact(&mut ends.target, 1).await;
+
+        ends.steering.send_modify(|steering| {
+            steering.drain = Some(Drain {
+                idle: Duration::from_millis(50),
+                deadline: Instant::now() + WAIT * 2,
+            })
+        });
+        assert_eq!(finished(ends.relay).await, (1, 0));
+    }
+
+    #[tokio::test]
+    async fn drain_closes_by_its_deadline() {
+        let mut ends = start(RelayOptions::default());
+        ends.steering.send_modify(|steering| {
+            steering.drain = Some(Drain {
+                idle: WAIT * 2,
+                deadline: Instant::now() + Duration::from_millis(200),
+            })
+        });
+        // Busy right up to the deadline
+        let busy = async {
+            loop {
+                ends.client.write_all(b"x").await.unwrap();
+                read_exact(&mut ends.target, 1).await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let relay = ends.relay;
+        tokio::select! {
+            _ = busy => unreachable!(),
+            closed = finished(relay) => assert!(closed.0 > 0),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_closes_even_while_paused() {
+        let mut ends = start(RelayOptions::default());
+        ends.steering.send_modify(|steering| steering.paused = true);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let _ = ends.cancel.take().unwrap().send(());
+        assert_eq!(finished(ends.relay).await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_steering_ends_a_pause() {
+        let mut ends = start(RelayOptions::default());
+        ends.steering.send_modify(|steering| steering.paused = true);
+        ends.client.write_all(b"held").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        drop(ends.steering);
+        assert_eq!(read_exact(&mut ends.target, 4).await, b"held");
+        drop(ends.client);
+        drop(ends.target);
+        assert_eq!(finished(ends.relay).await.0, 4);
+    }
+}
@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+use std::{io, net::SocketAddr};
+
+/// Turns a domain into addresses wherever socks5_async needs one, so
+/// embedders can bring their own DNS, a fake-IP map or a fixed table in tests
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves through the operating system, like `TcpStream::connect` would
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((domain, port)).await?.collect())
+    }
+}
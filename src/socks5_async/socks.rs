@@ -1,16 +1,21 @@
 #[allow(dead_code)]
 use std::{
     error::Error,
-    fmt,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
+use crate::socks5_async::resolver::{Resolver, SystemResolver};
+
 // Const bytes
 pub const VERSION5: u8 = 0x05;
 pub const RESERVED: u8 = 0x00;
+/// Version of the username/password sub-negotiation, RFC 1929
+pub const AUTH_VERSION: u8 = 0x01;
 
 // Request command
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
@@ -46,6 +51,14 @@ impl AddrType {
 
     pub async fn get_socket_addrs<S: AsyncRead + AsyncWrite + Unpin>(
         socket: &mut S,
+    ) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        AddrType::get_socket_addrs_with(socket, &SystemResolver).await
+    }
+
+    /// Reads an address, resolving a domain with `resolver`
+    pub async fn get_socket_addrs_with<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: &mut S,
+        resolver: &dyn Resolver,
     ) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
         // Read address type
         let mut addr_type = [0u8; 1];
@@ -106,10 +119,8 @@ impl AddrType {
                 port,
             ))]),
             AddrType::Domain => {
-                let mut domain = String::from_utf8_lossy(&addr[..]).to_string();
-                domain.push_str(&":");
-                domain.push_str(&port.to_string());
-                Ok(domain.to_socket_addrs()?.collect())
+                let domain = String::from_utf8_lossy(&addr[..]);
+                Ok(resolver.resolve(&domain, port).await?)
             }
         }
     }
@@ -117,7 +128,7 @@ impl AddrType {
 
 // Server response codes
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Response {
     Success = 0x00,
     Failure = 0x01,
@@ -129,22 +140,64 @@ pub enum Response {
     CommandNotSupported = 0x07,
     AddrTypeNotSupported = 0x08,
 }
+impl Response {
+    /// The reply code from a server, `None` for codes the RFC doesn't define
+    pub fn from(byte: u8) -> Option<Response> {
+        match byte {
+            0 => Some(Response::Success),
+            1 => Some(Response::Failure),
+            2 => Some(Response::RuleFailure),
+            3 => Some(Response::NetworkUnreachable),
+            4 => Some(Response::HostUnreachable),
+            5 => Some(Response::ConnectionRefused),
+            6 => Some(Response::TtlExpired),
+            7 => Some(Response::CommandNotSupported),
+            8 => Some(Response::AddrTypeNotSupported),
+            _ => None,
+        }
+    }
+
+    /// The closest `io::ErrorKind` to a failure the server replied with
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Response::Success => io::ErrorKind::Other,
+            Response::Failure => io::ErrorKind::Other,
+            Response::RuleFailure => io::ErrorKind::PermissionDenied,
+            Response::NetworkUnreachable => io::ErrorKind::NetworkUnreachable,
+            Response::HostUnreachable => io::ErrorKind::HostUnreachable,
+            Response::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            Response::TtlExpired => io::ErrorKind::TimedOut,
+            Response::CommandNotSupported => io::ErrorKind::Unsupported,
+            Response::AddrTypeNotSupported => io::ErrorKind::Unsupported,
+        }
+    }
+}
 impl Error for Response {}
 impl fmt::Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error: {}", self)
+        f.write_str(match self {
+            Response::Success => "Succeeded",
+            Response::Failure => "General SOCKS server failure",
+            Response::RuleFailure => "Connection not allowed by ruleset",
+            Response::NetworkUnreachable => "Network unreachable",
+            Response::HostUnreachable => "Host unreachable",
+            Response::ConnectionRefused => "Connection refused",
+            Response::TtlExpired => "TTL expired",
+            Response::CommandNotSupported => "Command not supported",
+            Response::AddrTypeNotSupported => "Address type not supported",
+        })
     }
 }
 
 /// Available authentication methods and their hex value
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum AuthMethod {
     NoAuth = 0x00,
     UserPass = 0x02,
     NoMethods = 0xFF,
 }
 impl AuthMethod {
-    fn from(byte: u8) -> AuthMethod {
+    pub(crate) fn from(byte: u8) -> AuthMethod {
         if byte == (AuthMethod::NoAuth as u8) {
             AuthMethod::NoAuth
         } else if byte == (AuthMethod::UserPass as u8) {
@@ -0,0 +1,623 @@
+use std::{
+    error::Error,
+    fmt, io,
+    net::{SocketAddrV4, SocketAddrV6},
+};
+
+use crate::socks5_async::lib::TargetAddr;
+use crate::socks5_async::socks::{AddrType, Command, Response, AUTH_VERSION, RESERVED, VERSION5};
+
+/// Why a message couldn't be encoded or decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireError {
+    /// The buffer ends before the message does, decode again once at least
+    /// this many bytes are in
+    Incomplete(usize),
+    /// The buffer to encode into is shorter than the message
+    BufferTooSmall(usize),
+    /// A field longer than the 255 bytes its length byte can say
+    FieldTooLong,
+    Invalid(&'static str),
+}
+impl Error for WireError {}
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Incomplete(needed) => {
+                write!(f, "Message incomplete, {} bytes needed", needed)
+            }
+            WireError::BufferTooSmall(needed) => {
+                write!(f, "Buffer too small, {} bytes needed", needed)
+            }
+            WireError::FieldTooLong => f.write_str("Field longer than 255 bytes"),
+            WireError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+impl From<WireError> for io::Error {
+    fn from(err: WireError) -> io::Error {
+        let kind = match err {
+            WireError::Incomplete(_) => io::ErrorKind::UnexpectedEof,
+            WireError::BufferTooSmall(_) | WireError::FieldTooLong => io::ErrorKind::InvalidInput,
+            WireError::Invalid(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// A SOCKS5 message that can be written to and read from a buffer without
+/// allocating, for callers doing their own IO such as a UDP relay
+pub trait Message<'a>: Sized {
+    /// How many bytes `encode_into` writes
+    fn encoded_len(&self) -> usize;
+
+    /// Writes the message to the start of `buf`, returning its length
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError>;
+
+    /// Reads a message from the start of `buf`, returning it with how many
+    /// bytes it took. Fields borrow from `buf`
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError>;
+}
+
+/// Fails unless `buf` holds at least `len` bytes
+fn need(buf: &[u8], len: usize) -> Result<(), WireError> {
+    match buf.len() < len {
+        true => Err(WireError::Incomplete(len)),
+        false => Ok(()),
+    }
+}
+
+fn room(buf: &[u8], len: usize) -> Result<(), WireError> {
+    match buf.len() < len {
+        true => Err(WireError::BufferTooSmall(len)),
+        false => Ok(()),
+    }
+}
+
+fn field_len(field: &[u8]) -> Result<u8, WireError> {
+    u8::try_from(field.len()).map_err(|_| WireError::FieldTooLong)
+}
+
+fn check_version(byte: u8) -> Result<(), WireError> {
+    match byte {
+        VERSION5 => Ok(()),
+        _ => Err(WireError::Invalid("Invalid SOCKS version")),
+    }
+}
+
+/// An address and port as SOCKS5 carries them, a domain borrowed as sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Addr<'a> {
+    V4(SocketAddrV4),
+    V6(SocketAddrV6),
+    Domain(&'a [u8], u16),
+}
+impl<'a> Message<'a> for Addr<'a> {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Addr::V4(_) => 1 + 4 + 2,
+            Addr::V6(_) => 1 + 16 + 2,
+            Addr::Domain(domain, _) => 1 + 1 + domain.len() + 2,
+        }
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let len = self.encoded_len();
+        room(buf, len)?;
+        let port = match self {
+            Addr::V4(addr) => {
+                buf[0] = AddrType::V4 as u8;
+                buf[1..5].copy_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Addr::V6(addr) => {
+                buf[0] = AddrType::V6 as u8;
+                buf[1..17].copy_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Addr::Domain(domain, port) => {
+                buf[0] = AddrType::Domain as u8;
+                buf[1] = field_len(domain)?;
+                buf[2..2 + domain.len()].copy_from_slice(domain);
+                *port
+            }
+        };
+        buf[len - 2..len].copy_from_slice(&port.to_be_bytes());
+        Ok(len)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 1)?;
+        let addr_type = AddrType::from(buf[0] as usize);
+        let (start, addr_len) = match addr_type {
+            Some(AddrType::V4) => (1, 4),
+            Some(AddrType::V6) => (1, 16),
+            Some(AddrType::Domain) => {
+                need(buf, 2)?;
+                (2, buf[1] as usize)
+            }
+            None => return Err(WireError::Invalid("Invalid address type")),
+        };
+        let len = start + addr_len + 2;
+        need(buf, len)?;
+        let addr = &buf[start..start + addr_len];
+        let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+        let addr = match addr_type {
+            Some(AddrType::V4) => {
+                let ip: [u8; 4] = addr.try_into().unwrap();
+                Addr::V4(SocketAddrV4::new(ip.into(), port))
+            }
+            Some(AddrType::V6) => {
+                let ip: [u8; 16] = addr.try_into().unwrap();
+                Addr::V6(SocketAddrV6::new(ip.into(), port, 0, 0))
+            }
+            _ => Addr::Domain(addr, port),
+        };
+        Ok((addr, len))
+    }
+}
+impl<'a> From<&'a TargetAddr> for Addr<'a> {
+    fn from(addr: &'a TargetAddr) -> Addr<'a> {
+        match addr {
+            TargetAddr::V4(addr) => Addr::V4(*addr),
+            TargetAddr::V6(addr) => Addr::V6(*addr),
+            TargetAddr::Domain((domain, port)) => Addr::Domain(domain.as_bytes(), *port),
+        }
+    }
+}
+impl From<Addr<'_>> for TargetAddr {
+    fn from(addr: Addr) -> TargetAddr {
+        match addr {
+            Addr::V4(addr) => TargetAddr::V4(addr),
+            Addr::V6(addr) => TargetAddr::V6(addr),
+            Addr::Domain(domain, port) => {
+                TargetAddr::Domain((String::from_utf8_lossy(domain).into_owned(), port))
+            }
+        }
+    }
+}
+
+/// The client's greeting, offering authentication methods as their bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MethodSelection<'a> {
+    pub methods: &'a [u8],
+}
+impl<'a> Message<'a> for MethodSelection<'a> {
+    fn encoded_len(&self) -> usize {
+        2 + self.methods.len()
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let len = self.encoded_len();
+        room(buf, len)?;
+        buf[0] = VERSION5;
+        buf[1] = field_len(self.methods)?;
+        buf[2..len].copy_from_slice(self.methods);
+        Ok(len)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 2)?;
+        check_version(buf[0])?;
+        let len = 2 + buf[1] as usize;
+        need(buf, len)?;
+        Ok((
+            MethodSelection {
+                methods: &buf[2..len],
+            },
+            len,
+        ))
+    }
+}
+
+/// The method the server picked, 0xFF when none was acceptable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MethodReply {
+    pub method: u8,
+}
+impl Message<'_> for MethodReply {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        room(buf, 2)?;
+        buf[..2].copy_from_slice(&[VERSION5, self.method]);
+        Ok(2)
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 2)?;
+        check_version(buf[0])?;
+        Ok((MethodReply { method: buf[1] }, 2))
+    }
+}
+
+/// The username/password sub-negotiation of RFC 1929
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AuthRequest<'a> {
+    pub username: &'a [u8],
+    pub password: &'a [u8],
+}
+impl<'a> Message<'a> for AuthRequest<'a> {
+    fn encoded_len(&self) -> usize {
+        3 + self.username.len() + self.password.len()
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let len = self.encoded_len();
+        room(buf, len)?;
+        let ulen = self.username.len();
+        buf[0] = AUTH_VERSION;
+        buf[1] = field_len(self.username)?;
+        buf[2..2 + ulen].copy_from_slice(self.username);
+        buf[2 + ulen] = field_len(self.password)?;
+        buf[3 + ulen..len].copy_from_slice(self.password);
+        Ok(len)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 2)?;
+        if buf[0] != AUTH_VERSION {
+            return Err(WireError::Invalid(
+                "Invalid username/password sub-negotiation version",
+            ));
+        }
+        let ulen = buf[1] as usize;
+        need(buf, 3 + ulen)?;
+        let len = 3 + ulen + buf[2 + ulen] as usize;
+        need(buf, len)?;
+        Ok((
+            AuthRequest {
+                username: &buf[2..2 + ulen],
+                password: &buf[3 + ulen..len],
+            },
+            len,
+        ))
+    }
+}
+
+/// Whether the username and password were accepted, zero when they were
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AuthReply {
+    pub status: u8,
+}
+impl Message<'_> for AuthReply {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        room(buf, 2)?;
+        buf[..2].copy_from_slice(&[AUTH_VERSION, self.status]);
+        Ok(2)
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 2)?;
+        if buf[0] != AUTH_VERSION {
+            return Err(WireError::Invalid(
+                "Invalid username/password sub-negotiation version",
+            ));
+        }
+        Ok((AuthReply { status: buf[1] }, 2))
+    }
+}
+
+/// A command and where it is for. The reserved byte isn't checked, like
+/// outside strict mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request<'a> {
+    pub command: Command,
+    pub addr: Addr<'a>,
+}
+impl<'a> Message<'a> for Request<'a> {
+    fn encoded_len(&self) -> usize {
+        3 + self.addr.encoded_len()
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        room(buf, self.encoded_len())?;
+        buf[..3].copy_from_slice(&[VERSION5, self.command as u8, RESERVED]);
+        Ok(3 + self.addr.encode_into(&mut buf[3..])?)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 3)?;
+        check_version(buf[0])?;
+        let command =
+            Command::from(buf[1] as usize).ok_or(WireError::Invalid("Command not supported"))?;
+        let (addr, len) = Addr::decode(&buf[3..]).map_err(|err| match err {
+            WireError::Incomplete(len) => WireError::Incomplete(3 + len),
+            err => err,
+        })?;
+        Ok((Request { command, addr }, 3 + len))
+    }
+}
+
+/// The server's answer to a `Request`, with the address it bound. The
+/// reserved byte isn't checked, like outside strict mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reply<'a> {
+    pub response: Response,
+    pub addr: Addr<'a>,
+}
+impl<'a> Message<'a> for Reply<'a> {
+    fn encoded_len(&self) -> usize {
+        3 + self.addr.encoded_len()
+    }
+
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        room(buf, self.encoded_len())?;
+        buf[..3].copy_from_slice(&[VERSION5, self.response as u8, RESERVED]);
+        Ok(3 + self.addr.encode_into(&mut buf[3..])?)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), WireError> {
+        need(buf, 3)?;
+        check_version(buf[0])?;
+        let response = Response::from(buf[1]).ok_or(WireError::Invalid("Unknown reply code"))?;
+        let (addr, len) = Addr::decode(&buf[3..]).map_err(|err| match err {
+            WireError::Incomplete(len) => WireError::Incomplete(3 + len),
+            err => err,
+        })?;
+        Ok((Reply { response, addr }, 3 + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Debug, net::Ipv6Addr};
+
+    use super::*;
+
+    const COMMANDS: [Command; 3] = [Command::Connect, Command::Bind, Command::UdpAssosiate];
+
+    const RESPONSES: [Response; 9] = [
+        Response::Success,
+        Response::Failure,
+        Response::RuleFailure,
+        Response::NetworkUnreachable,
+        Response::HostUnreachable,
+        Response::ConnectionRefused,
+        Response::TtlExpired,
+        Response::CommandNotSupported,
+        Response::AddrTypeNotSupported,
+    ];
+
+    /// Domains of the shortest, a one byte and the longest length
+    fn domains() -> Vec<Vec<u8>> {
+        vec![Vec::new(), b"a".to_vec(), vec![b'd'; 255]]
+    }
+
+    fn addrs(domains: &[Vec<u8>]) -> Vec<Addr<'_>> {
+        let mut addrs = vec![
+            Addr::V4(SocketAddrV4::new([192, 0, 2, 1].into(), 1080)),
+            Addr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                65535,
+                0,
+                0,
+            )),
+        ];
+        addrs.extend(domains.iter().map(|domain| Addr::Domain(domain, 443)));
+        addrs
+    }
+
+    /// Encodes `msg`, checking every shorter buffer is refused
+    fn encode<'a, M: Message<'a>>(msg: &M) -> Vec<u8> {
+        let len = msg.encoded_len();
+        for short in 0..len {
+            let mut buf = vec![0; short];
+            assert_eq!(
+                msg.encode_into(&mut buf),
+                Err(WireError::BufferTooSmall(len))
+            );
+        }
+        let mut buf = vec![0; len];
+        assert_eq!(msg.encode_into(&mut buf), Ok(len));
+        buf
+    }
+
+    /// Decodes `buf` back to `msg`, also with bytes following it, and checks
+    /// every truncation asks for more
+    fn round_trip<'a, M: Message<'a> + Copy + PartialEq + Debug>(
+        msg: M,
+        buf: &'a [u8],
+        trailing: &'a [u8],
+    ) {
+        assert_eq!(M::decode(buf), Ok((msg, buf.len())));
+        assert_eq!(M::decode(trailing), Ok((msg, buf.len())));
+        for len in 0..buf.len() {
+            match M::decode(&buf[..len]) {
+                Err(WireError::Incomplete(needed)) => {
+                    assert!(needed > len && needed <= buf.len(), "{} of {}", needed, len)
+                }
+                other => panic!("{} of {} bytes decoded to {:?}", len, buf.len(), other),
+            }
+        }
+    }
+
+    /// Round trips a message through `encode` and `round_trip`
+    macro_rules! check {
+        ($msg:expr) => {{
+            let msg = $msg;
+            let buf = encode(&msg);
+            let mut trailing = buf.clone();
+            trailing.extend_from_slice(&[0xAA, 0xBB]);
+            round_trip(msg, &buf, &trailing);
+        }};
+    }
+
+    #[test]
+    fn addr_round_trips() {
+        let domains = domains();
+        for addr in addrs(&domains) {
+            check!(addr);
+        }
+    }
+
+    #[test]
+    fn addr_converts_to_target_addr_and_back() {
+        let domains = domains();
+        for addr in addrs(&domains) {
+            let target = TargetAddr::from(addr);
+            assert_eq!(Addr::from(&target), addr);
+        }
+    }
+
+    #[test]
+    fn method_selection_round_trips() {
+        let methods = (0..=255).collect::<Vec<u8>>();
+        for methods in [&[][..], &[0], &[0, 2], &methods[..255]] {
+            check!(MethodSelection { methods });
+        }
+    }
+
+    #[test]
+    fn method_reply_round_trips() {
+        for method in [0x00, 0x02, 0xFF] {
+            check!(MethodReply { method });
+        }
+    }
+
+    #[test]
+    fn auth_request_round_trips() {
+        let long = vec![b'p'; 255];
+        for (username, password) in [
+            (&b""[..], &b""[..]),
+            (b"u", b"p"),
+            (b"user", &long[..]),
+            (&long[..], b""),
+        ] {
+            check!(AuthRequest { username, password });
+        }
+    }
+
+    #[test]
+    fn auth_reply_round_trips() {
+        for status in [0x00, 0x01] {
+            check!(AuthReply { status });
+        }
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let domains = domains();
+        for command in COMMANDS {
+            for addr in addrs(&domains) {
+                check!(Request { command, addr });
+            }
+        }
+    }
+
+    #[test]
+    fn reply_round_trips() {
+        let domains = domains();
+        for response in RESPONSES {
+            for addr in addrs(&domains) {
+                check!(Reply { response, addr });
+            }
+        }
+    }
+
+    #[test]
+    fn fields_over_255_bytes_are_refused() {
+        let long = vec![b'x'; 256];
+        let mut buf = [0; 512];
+        assert_eq!(
+            Addr::Domain(&long, 80).encode_into(&mut buf),
+            Err(WireError::FieldTooLong)
+        );
+        assert_eq!(
+            MethodSelection { methods: &long }.encode_into(&mut buf),
+            Err(WireError::FieldTooLong)
+        );
+        let request = AuthRequest {
+            username: b"user",
+            password: &long,
+        };
+        assert_eq!(request.encode_into(&mut buf), Err(WireError::FieldTooLong));
+    }
+
+    #[test]
+    fn invalid_address_type_is_refused() {
+        let invalid = Err(WireError::Invalid("Invalid address type"));
+        for addr_type in [0x00, 0x02, 0x05, 0xFF] {
+            let addr = [addr_type, 127, 0, 0, 1, 0, 80];
+            assert_eq!(Addr::decode(&addr).map(|_| ()), invalid);
+            let request = [
+                VERSION5,
+                Command::Connect as u8,
+                RESERVED,
+                addr_type,
+                127,
+                0,
+                0,
+                1,
+                0,
+                80,
+            ];
+            assert_eq!(Request::decode(&request).map(|_| ()), invalid);
+            let reply = [
+                VERSION5,
+                Response::Success as u8,
+                RESERVED,
+                addr_type,
+                127,
+                0,
+                0,
+                1,
+                0,
+                80,
+            ];
+            assert_eq!(Reply::decode(&reply).map(|_| ()), invalid);
+        }
+    }
+
+    #[test]
+    fn invalid_version_is_refused() {
+        let invalid = Err(WireError::Invalid("Invalid SOCKS version"));
+        for version in [0x00, 0x04, 0x06] {
+            assert_eq!(
+                MethodSelection::decode(&[version, 1, 0]).map(|_| ()),
+                invalid
+            );
+            assert_eq!(MethodReply::decode(&[version, 0]).map(|_| ()), invalid);
+            let addr = [AddrType::V4 as u8, 127, 0, 0, 1, 0, 80];
+            let request = [&[version, Command::Connect as u8, RESERVED][..], &addr].concat();
+            assert_eq!(Request::decode(&request).map(|_| ()), invalid);
+            let reply = [&[version, Response::Success as u8, RESERVED][..], &addr].concat();
+            assert_eq!(Reply::decode(&reply).map(|_| ()), invalid);
+        }
+        let invalid = Err(WireError::Invalid(
+            "Invalid username/password sub-negotiation version",
+        ));
+        assert_eq!(
+            AuthRequest::decode(&[VERSION5, 1, b'u', 1, b'p']).map(|_| ()),
+            invalid
+        );
+        assert_eq!(AuthReply::decode(&[VERSION5, 0]).map(|_| ()), invalid);
+    }
+
+    #[test]
+    fn unknown_command_and_reply_code_are_refused() {
+        let addr = [AddrType::V4 as u8, 127, 0, 0, 1, 0, 80];
+        let request = [&[VERSION5, 0x04, RESERVED][..], &addr].concat();
+        assert_eq!(
+            Request::decode(&request).map(|_| ()),
+            Err(WireError::Invalid("Command not supported"))
+        );
+        let reply = [&[VERSION5, 0x09, RESERVED][..], &addr].concat();
+        assert_eq!(
+            Reply::decode(&reply).map(|_| ()),
+            Err(WireError::Invalid("Unknown reply code"))
+        );
+    }
+
+    #[test]
+    fn incomplete_is_unexpected_eof() {
+        let err = io::Error::from(WireError::Incomplete(3));
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
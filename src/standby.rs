@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use log::trace;
+
+use tokio::{
+    sync::{watch, Notify},
+    time::sleep,
+};
+
+use crate::{
+    config::{Config, WarmStandby},
+    transport::{connect_upstream, with_connect_timeout, BoxStream},
+};
+
+/// How often ready connections are checked for age when none are taken
+const CHECK_EVERY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Ready connections to each target, oldest first
+    static ref READY: Mutex<HashMap<String, Vec<(Instant, BoxStream)>>> =
+        Mutex::new(HashMap::new());
+    static ref TAKEN: Notify = Notify::new();
+}
+
+/// A connection to `target` opened ahead of time, if one is ready. The target
+/// proxy's handshake still has to go over it
+pub fn take(config: &Config, target: &str) -> Option<BoxStream> {
+    let max_idle = Duration::from_secs(config.warm_standby?.max_idle_secs);
+    let mut ready = READY.lock().unwrap();
+    let pool = ready.get_mut(target)?;
+    pool.retain(|(opened, _)| opened.elapsed() < max_idle);
+    let stream = pool.pop().map(|(_, stream)| stream);
+    if stream.is_some() {
+        trace!("Using a warm connection to target proxy {}", target);
+        TAKEN.notify_one();
+    }
+    stream
+}
+
+/// The target proxies with connections ready
+pub fn ready_targets() -> Vec<String> {
+    let ready = READY.lock().unwrap();
+    let mut targets = ready
+        .iter()
+        .filter(|(_, pool)| !pool.is_empty())
+        .map(|(target, _)| target.clone())
+        .collect::<Vec<_>>();
+    targets.sort();
+    targets
+}
+
+/// Keeps connections to the secondary targets ready while `warm_standby` is
+/// set and the proxy is on, replacing them as they are used or grow old
+pub async fn keep_warm(live: Arc<watch::Sender<Config>>) {
+    let mut changes = live.subscribe();
+    loop {
+        let config = changes.borrow_and_update().clone();
+        match (config.warm_standby, config.status) {
+            (Some(standby), true) => top_up(&config, standby).await,
+            _ => READY.lock().unwrap().clear(),
+        }
+        tokio::select! {
+            _ = TAKEN.notified() => {}
+            _ = sleep(CHECK_EVERY) => {}
+            changed = changes.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn top_up(config: &Config, standby: WarmStandby) {
+    let max_idle = Duration::from_secs(standby.max_idle_secs);
+    let secondaries = config.target.0.iter().skip(1).collect::<Vec<_>>();
+    let missing = {
+        let mut ready = READY.lock().unwrap();
+        ready.retain(|target, _| secondaries.contains(&target));
+        secondaries
+            .iter()
+            .map(|target| {
+                let pool = ready.entry(target.to_string()).or_default();
+                pool.retain(|(opened, _)| opened.elapsed() < max_idle);
+                (*target, standby.connections.saturating_sub(pool.len()))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (target, missing) in missing {
+        for _ in 0..missing {
+            match with_connect_timeout(config, connect_upstream(config, target)).await {
+                Ok(stream) => READY
+                    .lock()
+                    .unwrap()
+                    .entry(target.clone())
+                    .or_default()
+                    .push((Instant::now(), stream)),
+                Err(err) => {
+                    trace!("Failed to warm up target proxy {}: {}", target, err);
+                    break;
+                }
+            }
+        }
+    }
+}
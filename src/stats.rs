@@ -0,0 +1,325 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+use serde::{Deserialize, Serialize};
+
+use crate::proto::Address;
+
+/// How much per-minute history is kept for each destination
+const HISTORY_MINUTES: u64 = 24 * 60;
+
+lazy_static! {
+    pub static ref STATS: Stats = Stats::default();
+}
+
+/// Traffic closed during a single minute
+#[derive(Default)]
+struct Bucket {
+    minute: u64,
+    closed: u64,
+    failed: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+/// Connection attempts across all destinations during a single minute
+#[derive(Default)]
+struct GlobalBucket {
+    minute: u64,
+    connects: u64,
+    failures: u64,
+    upstream_connects: u64,
+    upstream_latency: Duration,
+}
+
+#[derive(Default)]
+struct Destination {
+    active: u64,
+    history: VecDeque<Bucket>,
+}
+
+impl Destination {
+    fn bucket(&mut self, minute: u64) -> &mut Bucket {
+        while self
+            .history
+            .front()
+            .is_some_and(|bucket| bucket.minute + HISTORY_MINUTES <= minute)
+        {
+            self.history.pop_front();
+        }
+        if self.history.back().map(|bucket| bucket.minute) != Some(minute) {
+            self.history.push_back(Bucket {
+                minute,
+                ..Default::default()
+            });
+        }
+        self.history.back_mut().unwrap()
+    }
+}
+
+/// Totals for one destination host, transfer volumes are counted when a
+/// connection closes
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DestinationSummary {
+    pub host: String,
+    pub active: u64,
+    pub closed: u64,
+    pub failed: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+/// Connection counts and transfer volumes keyed by destination host
+#[derive(Default)]
+pub struct Stats {
+    destinations: Mutex<HashMap<String, Destination>>,
+    global: Mutex<VecDeque<GlobalBucket>>,
+    padded_bytes: AtomicU64,
+    padding_bytes: AtomicU64,
+}
+
+/// Everything the `stats` command shows
+#[derive(Serialize, Deserialize)]
+pub struct StatsReport {
+    pub destinations: Vec<DestinationSummary>,
+    /// Payload bytes sent over a padded transport
+    pub padded_bytes: u64,
+    /// Padding added on top of `padded_bytes`
+    pub padding_bytes: u64,
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+/// The host part of a requested address, used as the stats key
+pub fn destination_host(addr: &Address) -> String {
+    match addr {
+        Address::SocketAddress(addr) => addr.ip().to_string(),
+        Address::DomainAddress(domain, _) => String::from_utf8_lossy(domain).to_lowercase(),
+    }
+}
+
+/// A requested address as `host:port`
+pub fn destination_addr(addr: &Address) -> String {
+    match addr {
+        Address::SocketAddress(addr) => addr.to_string(),
+        Address::DomainAddress(domain, port) => {
+            format!("{}:{}", String::from_utf8_lossy(domain), port)
+        }
+    }
+}
+
+impl Stats {
+    fn record_connect(&self, failed: bool, upstream_latency: Option<Duration>) {
+        let minute = current_minute();
+        let mut global = self.global.lock().unwrap();
+        while global
+            .front()
+            .is_some_and(|bucket| bucket.minute + HISTORY_MINUTES <= minute)
+        {
+            global.pop_front();
+        }
+        if global.back().map(|bucket| bucket.minute) != Some(minute) {
+            global.push_back(GlobalBucket {
+                minute,
+                ..Default::default()
+            });
+        }
+
+        let bucket = global.back_mut().unwrap();
+        bucket.connects += 1;
+        if failed {
+            bucket.failures += 1;
+        }
+        if let Some(latency) = upstream_latency {
+            bucket.upstream_connects += 1;
+            bucket.upstream_latency += latency;
+        }
+    }
+
+    fn global_since(&self, window: Duration) -> Vec<(u64, u64, u64, Duration)> {
+        let since = current_minute().saturating_sub(window.as_secs() / 60);
+        self.global
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|bucket| bucket.minute >= since)
+            .map(|bucket| {
+                (
+                    bucket.connects,
+                    bucket.failures,
+                    bucket.upstream_connects,
+                    bucket.upstream_latency,
+                )
+            })
+            .collect()
+    }
+
+    /// The share of connection attempts that failed within `window`
+    pub fn error_rate(&self, window: Duration) -> Option<f64> {
+        let (connects, failures) = self
+            .global_since(window)
+            .iter()
+            .fold((0, 0), |(c, f), bucket| (c + bucket.0, f + bucket.1));
+        match connects {
+            0 => None,
+            _ => Some(failures as f64 / connects as f64),
+        }
+    }
+
+    /// The average time to connect through the target proxy within `window`
+    pub fn upstream_latency(&self, window: Duration) -> Option<Duration> {
+        let (connects, latency) = self
+            .global_since(window)
+            .iter()
+            .fold((0, Duration::ZERO), |(c, l), bucket| {
+                (c + bucket.2, l + bucket.3)
+            });
+        match connects {
+            0 => None,
+            _ => Some(latency / connects as u32),
+        }
+    }
+
+    /// Records a successful connect, `upstream_latency` is how long it took
+    /// when it went through the target proxy
+    pub fn opened(&self, host: &str, upstream_latency: Option<Duration>) {
+        self.record_connect(false, upstream_latency);
+
+        let mut destinations = self.destinations.lock().unwrap();
+        destinations.entry(host.to_string()).or_default().active += 1;
+    }
+
+    /// How many connections are relaying data right now
+    pub fn open_tunnels(&self) -> u64 {
+        let destinations = self.destinations.lock().unwrap();
+        destinations
+            .values()
+            .map(|destination| destination.active)
+            .sum()
+    }
+
+    pub fn closed(&self, host: &str, bytes_up: u64, bytes_down: u64) {
+        let mut destinations = self.destinations.lock().unwrap();
+        let destination = destinations.entry(host.to_string()).or_default();
+        destination.active = destination.active.saturating_sub(1);
+
+        let bucket = destination.bucket(current_minute());
+        bucket.closed += 1;
+        bucket.bytes_up += bytes_up;
+        bucket.bytes_down += bytes_down;
+    }
+
+    pub fn failed(&self, host: &str) {
+        self.record_connect(true, None);
+        let mut destinations = self.destinations.lock().unwrap();
+        let destination = destinations.entry(host.to_string()).or_default();
+        destination.bucket(current_minute()).failed += 1;
+    }
+
+    /// Records `padding` bytes sent along with `payload` bytes of data
+    pub fn padded(&self, payload: u64, padding: u64) {
+        self.padded_bytes.fetch_add(payload, Ordering::Relaxed);
+        self.padding_bytes.fetch_add(padding, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, limit: usize, window: Option<Duration>) -> StatsReport {
+        StatsReport {
+            destinations: self.top(limit, window),
+            padded_bytes: self.padded_bytes.load(Ordering::Relaxed),
+            padding_bytes: self.padding_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The `limit` destinations with the most traffic, counting only
+    /// connections closed within `window` when given
+    pub fn top(&self, limit: usize, window: Option<Duration>) -> Vec<DestinationSummary> {
+        let since = match window {
+            Some(window) => current_minute().saturating_sub(window.as_secs() / 60),
+            None => 0,
+        };
+
+        let destinations = self.destinations.lock().unwrap();
+        let mut summaries = destinations
+            .iter()
+            .map(|(host, destination)| {
+                let mut summary = DestinationSummary {
+                    host: host.clone(),
+                    active: destination.active,
+                    closed: 0,
+                    failed: 0,
+                    bytes_up: 0,
+                    bytes_down: 0,
+                };
+                for bucket in destination.history.iter().filter(|b| b.minute >= since) {
+                    summary.closed += bucket.closed;
+                    summary.failed += bucket.failed;
+                    summary.bytes_up += bucket.bytes_up;
+                    summary.bytes_down += bucket.bytes_down;
+                }
+                summary
+            })
+            .filter(|summary| summary.active > 0 || summary.closed > 0 || summary.failed > 0)
+            .collect::<Vec<_>>();
+
+        summaries.sort_by_key(|summary| {
+            std::cmp::Reverse((summary.bytes_up + summary.bytes_down, summary.active))
+        });
+        summaries.truncate(limit);
+        summaries
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+pub fn print_report(report: &StatsReport) {
+    println!(
+        "{:<40} {:>7} {:>7} {:>7} {:>11} {:>11}",
+        "HOST", "ACTIVE", "CLOSED", "FAILED", "UP", "DOWN"
+    );
+    for summary in &report.destinations {
+        println!(
+            "{:<40} {:>7} {:>7} {:>7} {:>11} {:>11}",
+            summary.host,
+            summary.active,
+            summary.closed,
+            summary.failed,
+            format_bytes(summary.bytes_up),
+            format_bytes(summary.bytes_down)
+        );
+    }
+
+    if report.padded_bytes > 0 {
+        println!(
+            "\nPadding: {} on top of {} sent ({:.1}% overhead)",
+            format_bytes(report.padding_bytes),
+            format_bytes(report.padded_bytes),
+            report.padding_bytes as f64 / report.padded_bytes as f64 * 100.0
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use super::{clear_saved, load_saved, save_previous};
+
+use anyhow::{anyhow, Result};
+
+use log::{error, trace};
+
+use serde::{Deserialize, Serialize};
+
+use std::process::Command;
+
+/// The desktop proxy settings before toggleproxy took over, values are kept in
+/// the format the desktop's own tools print them in
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "desktop")]
+enum Saved {
+    Gnome {
+        mode: String,
+        host: String,
+        port: String,
+    },
+    Kde {
+        proxy_type: String,
+        socks_proxy: String,
+    },
+}
+
+enum Desktop {
+    Gnome,
+    Kde,
+}
+
+/// Picks the settings backend from the running desktop environment
+fn detect_desktop() -> Result<Desktop> {
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP")
+        .or_else(|_| std::env::var("DESKTOP_SESSION"))
+        .unwrap_or_default()
+        .to_uppercase();
+
+    for name in desktop.split(':') {
+        match name {
+            "KDE" | "PLASMA" => return Ok(Desktop::Kde),
+            "GNOME" | "UNITY" | "CINNAMON" | "BUDGIE" | "PANTHEON" | "X-CINNAMON" => {
+                return Ok(Desktop::Gnome)
+            }
+            _ => {}
+        }
+    }
+
+    match std::env::var("KDE_FULL_SESSION").is_ok() {
+        true => Ok(Desktop::Kde),
+        false => Err(anyhow!(
+            "Unsupported desktop environment, only GNOME and KDE are supported"
+        )),
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Failed to run {}", program);
+            trace!("{}", err);
+            return Err(anyhow!("Failed to run {}", program));
+        }
+    };
+
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => {
+            trace!("{}", String::from_utf8_lossy(&output.stderr));
+            Err(anyhow!("{} failed", program))
+        }
+    }
+}
+
+/// KDE 6 ships kreadconfig6/kwriteconfig6, KDE 5 the older names
+fn kconfig(tool: &str, args: &[&str]) -> Result<String> {
+    run(&format!("{}6", tool), args).or_else(|_| run(&format!("{}5", tool), args))
+}
+
+fn kioslaverc(key: &str) -> Result<String> {
+    kconfig(
+        "kreadconfig",
+        &[
+            "--file",
+            "kioslaverc",
+            "--group",
+            "Proxy Settings",
+            "--key",
+            key,
+        ],
+    )
+}
+
+fn set_kioslaverc(key: &str, value: &str) -> Result<()> {
+    kconfig(
+        "kwriteconfig",
+        &[
+            "--file",
+            "kioslaverc",
+            "--group",
+            "Proxy Settings",
+            "--key",
+            key,
+            value,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Asks running KIO applications to reload the proxy settings
+fn notify_kde() {
+    let _ = run(
+        "dbus-send",
+        &[
+            "--type=signal",
+            "/KIO/Scheduler",
+            "org.kde.KIO.Scheduler.reparseSlaveConfiguration",
+            "string:''",
+        ],
+    );
+}
+
+pub fn enable(host: &str, port: u16) -> Result<()> {
+    match detect_desktop()? {
+        Desktop::Gnome => {
+            save_previous(&Saved::Gnome {
+                mode: run("gsettings", &["get", "org.gnome.system.proxy", "mode"])?,
+                host: run(
+                    "gsettings",
+                    &["get", "org.gnome.system.proxy.socks", "host"],
+                )?,
+                port: run(
+                    "gsettings",
+                    &["get", "org.gnome.system.proxy.socks", "port"],
+                )?,
+            })?;
+
+            run(
+                "gsettings",
+                &["set", "org.gnome.system.proxy.socks", "host", host],
+            )?;
+            run(
+                "gsettings",
+                &[
+                    "set",
+                    "org.gnome.system.proxy.socks",
+                    "port",
+                    &port.to_string(),
+                ],
+            )?;
+            run(
+                "gsettings",
+                &["set", "org.gnome.system.proxy", "mode", "manual"],
+            )?;
+        }
+        Desktop::Kde => {
+            save_previous(&Saved::Kde {
+                proxy_type: kioslaverc("ProxyType")?,
+                socks_proxy: kioslaverc("socksProxy")?,
+            })?;
+
+            set_kioslaverc("socksProxy", &format!("socks://{} {}", host, port))?;
+            set_kioslaverc("ProxyType", "1")?;
+            notify_kde();
+        }
+    }
+
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    match load_saved::<Saved>() {
+        Some(Saved::Gnome { mode, host, port }) => {
+            run(
+                "gsettings",
+                &["set", "org.gnome.system.proxy.socks", "host", &host],
+            )?;
+            run(
+                "gsettings",
+                &["set", "org.gnome.system.proxy.socks", "port", &port],
+            )?;
+            run(
+                "gsettings",
+                &["set", "org.gnome.system.proxy", "mode", &mode],
+            )?;
+        }
+        Some(Saved::Kde {
+            proxy_type,
+            socks_proxy,
+        }) => {
+            set_kioslaverc("socksProxy", &socks_proxy)?;
+            set_kioslaverc(
+                "ProxyType",
+                match proxy_type.is_empty() {
+                    true => "0",
+                    false => &proxy_type,
+                },
+            )?;
+            notify_kde();
+        }
+        None => return Ok(()),
+    }
+
+    clear_saved();
+    Ok(())
+}
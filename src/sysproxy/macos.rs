@@ -0,0 +1,133 @@
+use super::{clear_saved, load_saved, save_previous};
+
+use anyhow::{anyhow, Result};
+
+use log::{error, trace, warn};
+
+use serde::{Deserialize, Serialize};
+
+use std::process::Command;
+
+/// The SOCKS proxy settings of a network service before toggleproxy took over
+#[derive(Serialize, Deserialize)]
+struct ServiceProxy {
+    service: String,
+    enabled: bool,
+    server: String,
+    port: u16,
+}
+
+fn networksetup(args: &[&str]) -> Result<String> {
+    let output = match Command::new("networksetup").args(args).output() {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Failed to run networksetup");
+            trace!("{}", err);
+            return Err(anyhow!("Failed to run networksetup"));
+        }
+    };
+
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).to_string()),
+        false => {
+            trace!("{}", String::from_utf8_lossy(&output.stderr));
+            Err(anyhow!("networksetup {} failed", args[0]))
+        }
+    }
+}
+
+/// Lists the enabled network services, disabled ones are marked with an asterisk
+fn active_services() -> Result<Vec<String>> {
+    Ok(networksetup(&["-listallnetworkservices"])?
+        .lines()
+        .skip(1)
+        .filter(|line| !line.is_empty() && !line.starts_with('*'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+fn get_socks_proxy(service: &str) -> Result<ServiceProxy> {
+    let output = networksetup(&["-getsocksfirewallproxy", service])?;
+
+    let mut proxy = ServiceProxy {
+        service: service.to_string(),
+        enabled: false,
+        server: String::new(),
+        port: 0,
+    };
+    for line in output.lines() {
+        match line.split_once(": ") {
+            Some(("Enabled", value)) => proxy.enabled = value.trim() == "Yes",
+            Some(("Server", value)) => proxy.server = value.trim().to_string(),
+            Some(("Port", value)) => proxy.port = value.trim().parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    Ok(proxy)
+}
+
+pub fn enable(host: &str, port: u16) -> Result<()> {
+    let services = active_services()?;
+
+    save_previous(
+        &services
+            .iter()
+            .filter_map(|service| get_socks_proxy(service).ok())
+            .collect::<Vec<ServiceProxy>>(),
+    )?;
+
+    let port = port.to_string();
+    for service in services {
+        match networksetup(&["-setsocksfirewallproxy", &service, host, &port])
+            .and_then(|_| networksetup(&["-setsocksfirewallproxystate", &service, "on"]))
+        {
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Failed to set the SOCKS proxy for {}", service);
+                trace!("{}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    let saved = match load_saved::<Vec<ServiceProxy>>() {
+        Some(saved) => saved,
+        None => return Ok(()),
+    };
+
+    for proxy in saved {
+        let result = match proxy.server.is_empty() {
+            true => Ok(String::new()),
+            false => networksetup(&[
+                "-setsocksfirewallproxy",
+                &proxy.service,
+                &proxy.server,
+                &proxy.port.to_string(),
+            ]),
+        }
+        .and_then(|_| {
+            networksetup(&[
+                "-setsocksfirewallproxystate",
+                &proxy.service,
+                match proxy.enabled {
+                    true => "on",
+                    false => "off",
+                },
+            ])
+        });
+
+        match result {
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Failed to restore the SOCKS proxy for {}", proxy.service);
+                trace!("{}", err);
+            }
+        }
+    }
+
+    clear_saved();
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use crate::{clap::STATE_DIR, config::Config};
+
+use anyhow::Result;
+
+use log::{error, trace};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(target_os = "windows")]
+mod windows;
+#[cfg(target_os = "windows")]
+use windows as platform;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+use macos as platform;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+use linux as platform;
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "linux"))]
+pub fn sysproxy_enable(config: &Config) -> Result<()> {
+    platform::enable("127.0.0.1", config.port)
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "linux"))]
+pub fn sysproxy_disable() -> Result<()> {
+    platform::disable()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+pub fn sysproxy_enable(_config: &Config) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Setting the system proxy is not supported on this platform"
+    ))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+pub fn sysproxy_disable() -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Setting the system proxy is not supported on this platform"
+    ))
+}
+
+/// Points the system proxy at toggleproxy when the proxy is on, and restores
+/// the previous settings when it is off
+pub fn sysproxy_sync(config: &Config) -> Result<()> {
+    match config.status {
+        true => sysproxy_enable(config),
+        false => sysproxy_disable(),
+    }
+}
+
+fn state_path() -> std::path::PathBuf {
+    STATE_DIR.join("sysproxy.json")
+}
+
+/// Loads the system proxy settings saved before toggleproxy replaced them
+fn load_saved<T: DeserializeOwned>() -> Option<T> {
+    let file = std::fs::File::open(state_path()).ok()?;
+    match serde_json::from_reader(file) {
+        Ok(saved) => Some(saved),
+        Err(err) => {
+            error!("Failed to parse saved system proxy settings");
+            trace!("{}", err);
+            None
+        }
+    }
+}
+
+/// Saves the current system proxy settings, unless a previous enable already did
+fn save_previous<T: Serialize>(previous: &T) -> Result<()> {
+    if state_path().exists() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(STATE_DIR.as_path())?;
+    let file = std::fs::File::create(state_path())?;
+    serde_json::to_writer_pretty(file, previous)?;
+    Ok(())
+}
+
+fn clear_saved() {
+    let _ = std::fs::remove_file(state_path());
+}
@@ -0,0 +1,92 @@
+use super::{clear_saved, load_saved, save_previous};
+
+use anyhow::Result;
+
+use log::info;
+
+use serde::{Deserialize, Serialize};
+
+use windows_sys::Win32::Networking::WinInet::{
+    InternetSetOptionW, INTERNET_OPTION_REFRESH, INTERNET_OPTION_SETTINGS_CHANGED,
+};
+
+use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+const INTERNET_SETTINGS: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings";
+
+/// The WinINET settings that were in place before toggleproxy took over
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    proxy_enable: u32,
+    proxy_server: Option<String>,
+    proxy_override: Option<String>,
+}
+
+pub fn enable(host: &str, port: u16) -> Result<()> {
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let (settings, _) = hkcu.create_subkey(INTERNET_SETTINGS)?;
+
+    save_previous(&Saved {
+        proxy_enable: settings.get_value("ProxyEnable").unwrap_or(0),
+        proxy_server: settings.get_value("ProxyServer").ok(),
+        proxy_override: settings.get_value("ProxyOverride").ok(),
+    })?;
+
+    let server = format!("socks={}:{}", host, port);
+    settings.set_value("ProxyEnable", &1u32)?;
+    settings.set_value("ProxyServer", &server)?;
+    settings.set_value("ProxyOverride", &"<local>")?;
+    notify_wininet();
+
+    // WinHTTP only takes HTTP proxies, so its clients, mostly services and
+    // updaters, are left as they are
+    info!("WinHTTP has no SOCKS support, its clients keep their own proxy settings");
+
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    let saved = match load_saved::<Saved>() {
+        Some(saved) => saved,
+        None => return Ok(()),
+    };
+
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let (settings, _) = hkcu.create_subkey(INTERNET_SETTINGS)?;
+
+    settings.set_value("ProxyEnable", &saved.proxy_enable)?;
+    restore_string(&settings, "ProxyServer", &saved.proxy_server)?;
+    restore_string(&settings, "ProxyOverride", &saved.proxy_override)?;
+    notify_wininet();
+
+    clear_saved();
+    Ok(())
+}
+
+fn restore_string(key: &RegKey, name: &str, value: &Option<String>) -> Result<()> {
+    match value {
+        Some(value) => key.set_value(name, value)?,
+        None => {
+            let _ = key.delete_value(name);
+        }
+    }
+    Ok(())
+}
+
+/// Tells running WinINET applications to pick up the new registry values
+fn notify_wininet() {
+    unsafe {
+        InternetSetOptionW(
+            std::ptr::null(),
+            INTERNET_OPTION_SETTINGS_CHANGED,
+            std::ptr::null(),
+            0,
+        );
+        InternetSetOptionW(
+            std::ptr::null(),
+            INTERNET_OPTION_REFRESH,
+            std::ptr::null(),
+            0,
+        );
+    }
+}
@@ -1,7 +1,45 @@
+use std::path::PathBuf;
+
 use anyhow::{anyhow, Result};
-use log::{error, trace};
+use log::{error, trace, warn};
+
+/// The listening socket systemd passed with socket activation, if it did.
+/// It keeps the socket open across restarts, so no connection is refused
+#[cfg(unix)]
+pub fn activated_listener() -> Option<std::net::TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SD_LISTEN_FDS_START, passed sockets follow stdin, stdout and stderr
+    const FIRST_FD: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    // Inherited from a parent the sockets were meant for
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+    // So nothing started from here thinks the sockets are its own
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only the first is used", fds);
+    }
+    Some(unsafe { std::net::TcpListener::from_raw_fd(FIRST_FD) })
+}
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(unix))]
+pub fn activated_listener() -> Option<std::net::TcpListener> {
+    None
+}
+
+/// Restarts the launchd daemon, macOS has no systemd
+#[cfg(target_os = "macos")]
+pub fn systemd_restart() -> Result<()> {
+    crate::launchd::kickstart()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
 pub fn systemd_restart() -> Result<()> {
     Err(anyhow!("Systemd is not supported on this platform"))
 }
@@ -19,3 +57,116 @@ pub fn systemd_restart() -> Result<()> {
         }
     }
 }
+
+/// Where `install-service` puts the unit
+#[cfg(not(target_os = "macos"))]
+pub const UNIT_PATH: &str = "/etc/systemd/system/toggleproxy.service";
+#[cfg(target_os = "macos")]
+pub const UNIT_PATH: &str = crate::launchd::PLIST_PATH;
+
+/// How to start the service once installed
+#[cfg(not(target_os = "macos"))]
+pub const START_COMMAND: &str = "systemctl start toggleproxy";
+#[cfg(target_os = "macos")]
+pub const START_COMMAND: &str = "sudo launchctl kickstart system/io.github.imlvna.toggleproxy";
+
+/// What the unit written by `install-service` runs
+pub struct ServiceOptions {
+    pub binary: PathBuf,
+    pub config_path: PathBuf,
+    /// Runs as root when unset
+    pub user: Option<String>,
+    /// Whether the service binds a port below 1024
+    pub privileged_port: bool,
+}
+
+/// A unit for the proxy server, locked down to what it needs: writing its
+/// config file, state dir and control socket
+pub fn service_unit(options: &ServiceOptions) -> String {
+    let mut unit = format!(
+        "[Unit]\n\
+         Description=A togglable socks5 proxy\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={} --config {} run\n\
+         Restart=on-failure\n",
+        options.binary.display(),
+        options.config_path.display()
+    );
+    if let Some(user) = &options.user {
+        unit.push_str(&format!("User={}\n", user));
+        if options.privileged_port {
+            unit.push_str("AmbientCapabilities=CAP_NET_BIND_SERVICE\n");
+        }
+    }
+    // The state dir is found through XDG_DATA_HOME, so it lands in
+    // /var/lib/toggleproxy whoever the service runs as
+    unit.push_str(
+        "StateDirectory=toggleproxy\n\
+         RuntimeDirectory=toggleproxy\n\
+         RuntimeDirectoryMode=0700\n\
+         Environment=XDG_DATA_HOME=/var/lib\n\
+         NoNewPrivileges=yes\n\
+         ProtectSystem=strict\n\
+         ProtectHome=yes\n\
+         ProtectKernelTunables=yes\n\
+         ProtectKernelModules=yes\n\
+         ProtectControlGroups=yes\n\
+         RestrictSUIDSGID=yes\n\
+         LockPersonality=yes\n",
+    );
+    // Toggles are saved to the config file, the control socket is in the
+    // runtime dir. The leading - lets the service start before the config file
+    // exists
+    unit.push_str(&format!(
+        "ReadWritePaths=-{}\n",
+        options.config_path.display()
+    ));
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+/// What `install-service` writes on this platform, a LaunchDaemon on macOS
+pub fn service_file(options: &ServiceOptions) -> String {
+    match cfg!(target_os = "macos") {
+        true => crate::launchd::daemon_plist(options),
+        false => service_unit(options),
+    }
+}
+
+/// Writes the LaunchDaemon and loads it
+#[cfg(target_os = "macos")]
+pub fn install_service(options: &ServiceOptions) -> Result<()> {
+    crate::launchd::install_daemon(options)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn install_service(_options: &ServiceOptions) -> Result<()> {
+    Err(anyhow!("Systemd is not supported on this platform"))
+}
+
+/// Writes the unit, reloads systemd and enables the service
+#[cfg(target_os = "linux")]
+pub fn install_service(options: &ServiceOptions) -> Result<()> {
+    std::fs::write(UNIT_PATH, service_unit(options))
+        .map_err(|err| anyhow!("Failed to write {}: {}", UNIT_PATH, err))?;
+
+    let reloaded = std::process::Command::new("systemctl")
+        .arg("daemon-reload")
+        .status()?;
+    if !reloaded.success() {
+        return Err(anyhow!("systemctl daemon-reload failed"));
+    }
+    match systemctl::enable("toggleproxy") {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(anyhow!("Failed to enable toggleproxy")),
+        Err(err) => {
+            error!("Failed to enable toggleproxy");
+            trace!("{}", err);
+            Err(anyhow!("Failed to enable toggleproxy"))
+        }
+    }
+}
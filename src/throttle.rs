@@ -0,0 +1,173 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+use crate::config::Config;
+
+/// Bytes sent up by the client
+const UP: usize = 0;
+/// Bytes sent down to the client
+const DOWN: usize = 1;
+
+/// A token bucket holding up to a second's worth of bytes. Reads and writes
+/// spend what they moved, possibly going into debt, and the next one waits
+/// until the debt is paid off
+struct Bucket {
+    /// Bytes per second
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(kbps: u64) -> Self {
+        let rate = (kbps * 1000 / 8).max(1) as f64;
+        Bucket {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, kbps: u64) {
+        self.rate = (kbps * 1000 / 8).max(1) as f64;
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    /// How long until bytes may move again
+    fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.updated = now;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn spend(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+lazy_static! {
+    /// Shared by every connection, for `max_kbps_total`
+    static ref TOTAL: Mutex<[Option<Bucket>; 2]> = Mutex::new([None, None]);
+}
+
+/// Limits a client connection to `max_kbps_per_conn` or the simulated link's
+/// rate each way, and all of them together to `max_kbps_total`
+pub struct ThrottledStream<S> {
+    inner: S,
+    buckets: [Option<Bucket>; 2],
+    total: bool,
+    sleeps: [Option<Pin<Box<Sleep>>>; 2],
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, config: &Config) -> Self {
+        let mut total = TOTAL.lock().unwrap();
+        for bucket in total.iter_mut() {
+            match (bucket.as_mut(), config.max_kbps_total) {
+                (Some(bucket), Some(kbps)) => bucket.set_rate(kbps),
+                (None, Some(kbps)) => *bucket = Some(Bucket::new(kbps)),
+                (_, None) => *bucket = None,
+            }
+        }
+
+        let per_conn = match (
+            config.max_kbps_per_conn,
+            config.simulate.and_then(|simulate| simulate.kbps),
+        ) {
+            (Some(kbps), Some(simulated)) => Some(kbps.min(simulated)),
+            (kbps, simulated) => kbps.or(simulated),
+        };
+        ThrottledStream {
+            inner,
+            buckets: [per_conn.map(Bucket::new), per_conn.map(Bucket::new)],
+            total: config.max_kbps_total.is_some(),
+            sleeps: [None, None],
+        }
+    }
+
+    fn poll_allowed(&mut self, direction: usize, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleeping) = self.sleeps[direction].as_mut() {
+                ready!(sleeping.as_mut().poll(cx));
+                self.sleeps[direction] = None;
+            }
+
+            let mut wait = self.buckets[direction].as_mut().and_then(Bucket::wait);
+            if self.total {
+                let total = TOTAL.lock().unwrap()[direction]
+                    .as_mut()
+                    .and_then(Bucket::wait);
+                wait = wait.max(total);
+            }
+            match wait {
+                Some(wait) => self.sleeps[direction] = Some(Box::pin(sleep(wait))),
+                None => return Poll::Ready(()),
+            }
+        }
+    }
+
+    fn spend(&mut self, direction: usize, bytes: usize) {
+        if let Some(bucket) = self.buckets[direction].as_mut() {
+            bucket.spend(bytes);
+        }
+        if self.total {
+            if let Some(bucket) = TOTAL.lock().unwrap()[direction].as_mut() {
+                bucket.spend(bytes);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_allowed(UP, cx));
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.spend(UP, buf.filled().len() - filled);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_allowed(DOWN, cx));
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.spend(DOWN, written);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
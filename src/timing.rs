@@ -0,0 +1,325 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{watch, Notify},
+};
+
+use crate::proto::Address;
+
+use crate::{
+    aggregate,
+    events::{emit, EventKind},
+    socks5_async::relay::{Drain, Steering},
+};
+
+/// How many finished traces are kept for the `trace` command
+const RECENT_TRACES: usize = 100;
+
+/// Points in a connection's life, in the order they happen
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Auth,
+    Command,
+    Resolve,
+    Dial,
+    FirstByte,
+    Close,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mark {
+    pub phase: Phase,
+    /// Microseconds since the connection was accepted
+    pub at_us: u64,
+}
+
+/// The timing breakdown of one connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionTrace {
+    pub id: u64,
+    pub client: String,
+    pub target: Option<String>,
+    pub marks: Vec<Mark>,
+    /// Whether the relay is held with [`set_paused`]
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Records when a live connection reaches each phase
+pub struct Timing {
+    accepted: Instant,
+    sampled: AtomicBool,
+    trace: Mutex<ConnectionTrace>,
+    cancel: Notify,
+    steering: watch::Sender<Steering>,
+    /// The destination as requested, once known
+    address: Mutex<Option<Address>>,
+}
+
+lazy_static! {
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    static ref LIVE: Mutex<HashMap<u64, Arc<Timing>>> = Mutex::new(HashMap::new());
+    static ref RECENT: Mutex<VecDeque<ConnectionTrace>> = Mutex::new(VecDeque::new());
+}
+
+impl Timing {
+    /// Starts timing a connection accepted from `client`, keeping its trace
+    /// when it is picked by `sample_rate`
+    pub fn start(client: SocketAddr, sample_rate: f64) -> Arc<Timing> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let timing = Arc::new(Timing {
+            accepted: Instant::now(),
+            sampled: AtomicBool::new(sample_rate > 0.0 && rand::random::<f64>() < sample_rate),
+            trace: Mutex::new(ConnectionTrace {
+                id,
+                client: client.to_string(),
+                target: None,
+                marks: Vec::new(),
+                paused: false,
+            }),
+            cancel: Notify::new(),
+            steering: watch::channel(Steering::default()).0,
+            address: Mutex::new(None),
+        });
+        LIVE.lock().unwrap().insert(id, timing.clone());
+        timing
+    }
+
+    /// What the control socket and logs know the connection by
+    pub fn id(&self) -> u64 {
+        self.trace.lock().unwrap().id
+    }
+
+    pub fn mark(&self, phase: Phase) {
+        let at_us = self.accepted.elapsed().as_micros() as u64;
+        let mut trace = self.trace.lock().unwrap();
+        // Only the first time counts, e.g. the first byte
+        if !trace.marks.iter().any(|mark| mark.phase == phase) {
+            trace.marks.push(Mark { phase, at_us });
+        }
+    }
+
+    /// Keeps the destination for reroutes and drains, traces only show its
+    /// top-level domain when `aggregate_only`
+    pub fn set_target(&self, addr: &Address, aggregate_only: bool) {
+        self.trace.lock().unwrap().target = Some(aggregate::shown(aggregate_only, addr));
+        *self.address.lock().unwrap() = Some(addr.clone());
+    }
+
+    /// Completes once the connection is closed with [`cancel`]
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+
+    /// Whether the connection is paused or draining, as it changes
+    pub fn steering(&self) -> watch::Receiver<Steering> {
+        self.steering.subscribe()
+    }
+
+    /// Marks the connection closed and keeps its trace when it was sampled
+    pub fn finish(&self) {
+        self.mark(Phase::Close);
+        let trace = self.trace.lock().unwrap().clone();
+        LIVE.lock().unwrap().remove(&trace.id);
+
+        if self.sampled.load(Ordering::Relaxed) {
+            emit(EventKind::Trace {
+                trace: trace.clone(),
+            });
+
+            let mut recent = RECENT.lock().unwrap();
+            if recent.len() == RECENT_TRACES {
+                recent.pop_front();
+            }
+            recent.push_back(trace);
+        }
+    }
+}
+
+/// Marks the first byte read from the target
+pub struct TimedStream<S> {
+    inner: S,
+    timing: Arc<Timing>,
+    seen: bool,
+}
+
+impl<S> TimedStream<S> {
+    pub fn new(inner: S, timing: Arc<Timing>) -> Self {
+        TimedStream {
+            inner,
+            timing,
+            seen: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if !this.seen && buf.filled().len() > filled {
+            this.seen = true;
+            this.timing.mark(Phase::FirstByte);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The traces so far of every open connection
+pub fn live() -> Vec<ConnectionTrace> {
+    let mut live = LIVE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|timing| timing.trace.lock().unwrap().clone())
+        .collect::<Vec<_>>();
+    live.sort_by_key(|trace| trace.id);
+    live
+}
+
+/// Keeps the trace of an open connection once it closes, returning it so far
+pub fn sample(id: u64) -> Option<ConnectionTrace> {
+    let live = LIVE.lock().unwrap();
+    let timing = live.get(&id)?;
+    timing.sampled.store(true, Ordering::Relaxed);
+    let trace = timing.trace.lock().unwrap().clone();
+    Some(trace)
+}
+
+/// The trace so far of open connection `id`
+pub fn trace(id: u64) -> Option<ConnectionTrace> {
+    let live = LIVE.lock().unwrap();
+    let trace = live.get(&id)?.trace.lock().unwrap().clone();
+    Some(trace)
+}
+
+/// The requested destination of open connection `id`, once known
+pub fn address(id: u64) -> Option<Address> {
+    let live = LIVE.lock().unwrap();
+    let address = live.get(&id)?.address.lock().unwrap().clone();
+    address
+}
+
+/// Closes open connection `id`, returning whether it was open
+pub fn cancel(id: u64) -> bool {
+    match LIVE.lock().unwrap().get(&id) {
+        Some(timing) => {
+            // Kept until the connection starts relaying when it hasn't yet
+            timing.cancel.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stops or restarts relaying open connection `id` without closing it,
+/// returning its trace so far
+pub fn set_paused(id: u64, paused: bool) -> Option<ConnectionTrace> {
+    let live = LIVE.lock().unwrap();
+    let timing = live.get(&id)?;
+    timing
+        .steering
+        .send_modify(|steering| steering.paused = paused);
+    let mut trace = timing.trace.lock().unwrap();
+    trace.paused = paused;
+    Some(trace.clone())
+}
+
+/// The requested destination of every open connection that has one
+pub fn live_addresses() -> Vec<(u64, Address)> {
+    LIVE.lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(id, timing)| Some((*id, timing.address.lock().unwrap().clone()?)))
+        .collect()
+}
+
+/// Has open connection `id` close once idle or by the drain deadline,
+/// whichever comes first. An earlier drain already set is kept
+pub fn drain(id: u64, drain: Drain) -> bool {
+    match LIVE.lock().unwrap().get(&id) {
+        Some(timing) => {
+            timing
+                .steering
+                .send_if_modified(|steering| match steering.drain {
+                    Some(current) if current.deadline <= drain.deadline => false,
+                    _ => {
+                        steering.drain = Some(drain);
+                        true
+                    }
+                });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sampled connections that have closed, oldest first
+pub fn recent() -> Vec<ConnectionTrace> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn print_traces(traces: &[ConnectionTrace]) {
+    for trace in traces {
+        let mut line = format!(
+            "#{} {} -> {}",
+            trace.id,
+            trace.client,
+            trace.target.as_deref().unwrap_or("?")
+        );
+        // Show how long each phase took after the one before it
+        let mut previous = 0;
+        for mark in &trace.marks {
+            line.push_str(&format!(
+                "  {:?} +{:.1}ms",
+                mark.phase,
+                (mark.at_us - previous) as f64 / 1000.0
+            ));
+            previous = mark.at_us;
+        }
+        if trace.paused {
+            line.push_str("  (paused)");
+        }
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use lazy_static::lazy_static;
+
+use log::{error, info, trace, warn};
+
+use rustls::{
+    client::{
+        ClientSessionMemoryCache, ClientSessionStore, Resumption, ServerCertVerified,
+        ServerCertVerifier, WebPkiVerifier,
+    },
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    ServerName,
+};
+
+use rustls_pemfile::Item;
+
+use sha2::{Digest, Sha256};
+
+use tokio::net::TcpStream;
+
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::transport::BoxStream;
+
+/// How many target proxy names TLS sessions are kept for
+const SESSION_CACHE: usize = 256;
+
+lazy_static! {
+    /// Shared by every TLS connection to a target proxy, so repeated tunnels
+    /// resume the session instead of doing a full handshake. Only in memory,
+    /// tickets aren't worth keeping secret on disk for one handshake a restart
+    static ref SESSIONS: Arc<dyn ClientSessionStore> =
+        Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE));
+
+    /// What was loaded for the listener, loaded again once a reload points
+    /// elsewhere or the files change
+    static ref ACCEPTOR: Mutex<Option<LoadedAcceptor>> = Mutex::new(None);
+}
+
+/// The listener's certificate and key, as of when they were last modified
+struct LoadedAcceptor {
+    cert: PathBuf,
+    key: PathBuf,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    acceptor: TlsAcceptor,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn root_store() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    roots
+}
+
+/// Base64 SHA-256 of the certificate's SubjectPublicKeyInfo, the same format
+/// as HPKP and `openssl ... | openssl dgst -sha256 -binary | base64`
+pub fn spki_sha256(certificate: &Certificate) -> Option<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&certificate.0).ok()?;
+    let hash = Sha256::digest(parsed.tbs_certificate.subject_pki.raw);
+    Some(STANDARD.encode(hash))
+}
+
+/// Accepts the target's certificate only if its public key is pinned
+struct PinnedVerifier {
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match spki_sha256(end_entity) {
+            Some(hash) if self.pins.contains(&hash) => Ok(ServerCertVerified::assertion()),
+            Some(hash) => {
+                error!(
+                    "Target proxy certificate is not pinned, its key hash is {}",
+                    hash
+                );
+                Err(rustls::Error::General(
+                    "Certificate does not match any pin".to_string(),
+                ))
+            }
+            None => Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::BadEncoding,
+            )),
+        }
+    }
+}
+
+pub fn tls_config(pins: &[String]) -> Arc<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match pins.is_empty() {
+        true => builder
+            .with_custom_certificate_verifier(Arc::new(WebPkiVerifier::new(root_store(), None))),
+        false => builder.with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            pins: pins.to_vec(),
+        })),
+    };
+    let mut config = config.with_no_client_auth();
+    config.resumption = Resumption::store(SESSIONS.clone());
+    Arc::new(config)
+}
+
+pub(crate) async fn tls_connect(
+    stream: BoxStream,
+    server_name: &str,
+    pins: &[String],
+) -> io::Result<BoxStream> {
+    let server_name = match ServerName::try_from(server_name) {
+        Ok(server_name) => server_name,
+        Err(err) => {
+            trace!("{}", err);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid TLS server name {}", server_name),
+            ));
+        }
+    };
+
+    let stream = TlsConnector::from(tls_config(pins))
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(stream))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the listener's certificate chain and private key from PEM files
+pub fn load_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert)?))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificate in {}", cert.display())));
+    }
+    let private_key = rustls_pemfile::read_all(&mut BufReader::new(std::fs::File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("No private key in {}", key.display())))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(private_key),
+        )
+        .map_err(|err| invalid(format!("Invalid certificate or key: {}", err)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The acceptor for `cert` and `key`, loaded again when either was replaced,
+/// so renewed certificates are served without a restart
+fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let mut loaded = ACCEPTOR.lock().unwrap();
+    let modified = (modified(cert), modified(key));
+    let current = loaded
+        .as_ref()
+        .filter(|loaded| loaded.cert == cert && loaded.key == key);
+    if let Some(current) = current {
+        if current.modified == modified {
+            return Ok(current.acceptor.clone());
+        }
+    }
+    let acceptor = match (load_acceptor(cert, key), current) {
+        (Ok(acceptor), Some(_)) => {
+            info!("Loaded the renewed certificate {}", cert.display());
+            acceptor
+        }
+        (Ok(acceptor), None) => acceptor,
+        // A renewal may be half written, keep serving the old certificate
+        // until the files change again
+        (Err(err), Some(current)) => {
+            warn!("Failed to load the renewed {}: {}", cert.display(), err);
+            current.acceptor.clone()
+        }
+        (Err(err), None) => return Err(err),
+    };
+    *loaded = Some(LoadedAcceptor {
+        cert: cert.to_path_buf(),
+        key: key.to_path_buf(),
+        modified,
+        acceptor: acceptor.clone(),
+    });
+    Ok(acceptor)
+}
+
+/// Terminates TLS from a client of the listener
+pub(crate) async fn tls_accept(
+    stream: TcpStream,
+    cert: &Path,
+    key: &Path,
+) -> io::Result<BoxStream> {
+    let stream = acceptor(cert, key)?.accept(stream).await?;
+    Ok(Box::new(stream))
+}
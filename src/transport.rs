@@ -0,0 +1,214 @@
+use std::{
+    future::Future,
+    io,
+    time::{Duration, Instant},
+};
+
+use log::{info, trace};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::{
+    compress,
+    config::{Config, Protocol, Transport},
+    obfs::obfuscator,
+    standby,
+    upstream::{record_failure, record_latency},
+};
+
+#[cfg(feature = "tls")]
+pub(crate) use crate::tls::tls_accept;
+#[cfg(feature = "tls")]
+use crate::tls::tls_connect;
+
+#[cfg(feature = "websocket")]
+use crate::websocket::ws_connect;
+
+/// Any stream a connection can be relayed over
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+/// The host part of a host:port target
+pub fn target_host(target: &str) -> &str {
+    match target.rsplit_once(':') {
+        Some((host, _)) => host.trim_matches(['[', ']']),
+        None => target,
+    }
+}
+
+#[cfg(not(all(feature = "tls", feature = "websocket")))]
+fn not_built(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("This build has no {} support", what),
+    )
+}
+
+#[cfg(not(feature = "tls"))]
+async fn tls_connect(
+    _stream: BoxStream,
+    _server_name: &str,
+    _pins: &[String],
+) -> io::Result<BoxStream> {
+    Err(not_built("TLS"))
+}
+
+#[cfg(not(feature = "tls"))]
+pub(crate) async fn tls_accept(
+    _stream: TcpStream,
+    _cert: &std::path::Path,
+    _key: &std::path::Path,
+) -> io::Result<BoxStream> {
+    Err(not_built("TLS"))
+}
+
+#[cfg(not(feature = "websocket"))]
+async fn ws_connect(
+    _stream: BoxStream,
+    _host: &str,
+    _path: &str,
+    _pad_to: Option<usize>,
+) -> io::Result<BoxStream> {
+    Err(not_built("WebSocket"))
+}
+
+/// A target proxy with its scheme split off
+pub struct TargetProxy<'a> {
+    pub protocol: Protocol,
+    /// Given as `https://`, so reached over TLS even with a plain transport
+    pub tls: bool,
+    pub addr: &'a str,
+}
+
+pub fn parse_target<'a>(config: &Config, target: &'a str) -> TargetProxy<'a> {
+    let (protocol, tls, addr) = match target.split_once("://") {
+        Some(("http", addr)) => (Protocol::Http, false, addr),
+        Some(("https", addr)) => (Protocol::Http, true, addr),
+        Some(("socks5" | "socks5h", addr)) => (Protocol::Socks5, false, addr),
+        _ => (config.target_protocol, false, target),
+    };
+    TargetProxy {
+        protocol,
+        tls,
+        addr: addr.trim_end_matches('/'),
+    }
+}
+
+/// Opens the connection to a target proxy, wrapped in its configured transport
+pub async fn connect_upstream(config: &Config, target: &str) -> io::Result<BoxStream> {
+    let target = parse_target(config, target);
+    let mut stream: BoxStream = Box::new(TcpStream::connect(target.addr).await?);
+    if let Some(obfs) = &config.target_obfs {
+        stream = obfuscator(obfs).wrap(stream).await?;
+    }
+
+    let host = target_host(target.addr);
+    let stream: io::Result<BoxStream> = match &config.target_transport {
+        Transport::Tcp if target.tls => tls_connect(stream, host, &[]).await,
+        Transport::Tcp => Ok(stream),
+        Transport::Tls {
+            server_name,
+            pin_sha256,
+        } => {
+            let server_name = server_name.as_deref().unwrap_or(host);
+            tls_connect(stream, server_name, pin_sha256).await
+        }
+        Transport::Ws {
+            host: ws_host,
+            path,
+            pad_to,
+        } => {
+            let ws_host = ws_host.as_deref().unwrap_or(host);
+            Ok(Box::new(ws_connect(stream, ws_host, path, *pad_to).await?))
+        }
+        Transport::Wss {
+            server_name,
+            host: ws_host,
+            path,
+            pin_sha256,
+            pad_to,
+        } => {
+            let server_name = server_name.as_deref().unwrap_or(host);
+            let stream = tls_connect(stream, server_name, pin_sha256).await?;
+            let ws_host = ws_host.as_deref().unwrap_or(server_name);
+            Ok(Box::new(ws_connect(stream, ws_host, path, *pad_to).await?))
+        }
+    };
+
+    // Inside any TLS, encrypted bytes don't compress
+    match config.target_compression {
+        true => compress::offer(stream?).await,
+        false => stream,
+    }
+}
+
+/// Gives up on `connect` once `config.connect_timeout_ms` has passed
+pub async fn with_connect_timeout<T>(
+    config: &Config,
+    connect: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match config.connect_timeout() {
+        Some(limit) => tokio::time::timeout(limit, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connect timed out"))?,
+        None => connect.await,
+    }
+}
+
+/// Tries each of `targets` in order until `handshake` succeeds through one
+/// with the protocol the target speaks,
+/// going round the list again after a backoff as `config.failover` allows.
+/// Also returns the target proxy that was used
+pub async fn connect_with_failover<T, F, Fut>(
+    config: &Config,
+    targets: &[String],
+    mut handshake: F,
+) -> io::Result<(T, String)>
+where
+    F: FnMut(BoxStream, Protocol) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let failover = &config.failover;
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No target proxy is configured");
+    for round in 0..failover.rounds.max(1) {
+        if round > 0 {
+            let backoff = failover
+                .backoff_ms
+                .saturating_mul(1 << (round - 1).min(16))
+                .min(failover.max_backoff_ms);
+            tokio::time::sleep(Duration::from_millis(backoff)).await;
+        }
+
+        for (index, target) in targets.iter().enumerate() {
+            let started = Instant::now();
+            let result = with_connect_timeout(config, async {
+                let stream = match standby::take(config, target) {
+                    Some(stream) => stream,
+                    None => connect_upstream(config, target).await?,
+                };
+                handshake(stream, parse_target(config, target).protocol).await
+            })
+            .await;
+            match result {
+                Ok(value) => {
+                    record_latency(target, started.elapsed());
+                    if index > 0 || round > 0 {
+                        info!("Failed over to target proxy {}", target);
+                    }
+                    return Ok((value, target.clone()));
+                }
+                Err(err) => {
+                    trace!("Target proxy {} failed: {}", target, err);
+                    record_failure(target);
+                    last_err = err;
+                }
+            }
+        }
+    }
+    Err(last_err)
+}
@@ -0,0 +1,896 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use async_trait::async_trait;
+
+use log::{error, trace};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::proto::{Address, Reply};
+
+#[cfg(not(feature = "vendored-socks"))]
+use socks5_server::{connection::associate::state as associate, Associate};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{lookup_host, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    acl,
+    config::{self, Config, DnsMode, QuicPolicy, RuleAction},
+    dns,
+    health::direct_allowed,
+    policy,
+    resolve::{resolve, resolve_locally},
+    rules,
+    socks5_async::lib::udp_associate_with_stream,
+    transport::{connect_with_failover, parse_target, BoxStream},
+    upstream,
+};
+
+/// Largest datagram that can be relayed
+const BUF_SIZE: usize = 65535;
+
+const DNS_PORT: u16 = 53;
+
+/// Browsers speak QUIC to the HTTPS port
+const QUIC_PORT: u16 = 443;
+
+/// How long to wait for an answer to a query sent around the relay
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds a UDP socket that sends and receives both IPv4 and IPv6, falling back
+/// to IPv4 only on hosts without IPv6
+pub fn bind_dual_stack() -> io::Result<UdpSocket> {
+    // IPv6 sockets can open even where IPv6 is disabled, and fail to bind
+    let socket = match bind_v6_any() {
+        Ok(socket) => socket,
+        Err(err) => {
+            trace!("Binding UDP to IPv4 only, IPv6 failed: {}", err);
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_v6_any() -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    // Windows and some BSDs default to IPv6 only
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    Ok(socket)
+}
+
+/// Converts `addr` to the family `socket` is bound with, IPv4 addresses have
+/// to be sent as v4-mapped addresses from a dual-stack socket
+fn for_socket(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(addr)) => {
+            SocketAddr::new(IpAddr::V6(addr.ip().to_ipv6_mapped()), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Undoes v4-mapped addresses, so clients see the address they sent to
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Appends a SOCKS5 UDP request header for `addr`
+fn encode_udp_header(addr: &Address, buf: &mut Vec<u8>) {
+    // RSV, RSV, FRAG
+    buf.extend_from_slice(&[0, 0, 0]);
+    match addr {
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            buf.push(0x04);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::DomainAddress(domain, port) => {
+            buf.push(0x03);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain);
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+}
+
+/// Parses a SOCKS5 UDP request header, returning the address and the header
+/// length. Fragments are not supported
+fn decode_udp_header(buf: &[u8]) -> Option<(Address, usize)> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+
+    match buf[3] {
+        0x01 => {
+            let addr = buf.get(4..10)?;
+            let ip: [u8; 4] = addr[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([addr[4], addr[5]]);
+            Some((Address::SocketAddress((ip, port).into()), 10))
+        }
+        0x04 => {
+            let addr = buf.get(4..22)?;
+            let ip: [u8; 16] = addr[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([addr[16], addr[17]]);
+            Some((Address::SocketAddress((ip, port).into()), 22))
+        }
+        0x03 => {
+            let len = *buf.get(4)? as usize;
+            let addr = buf.get(5..7 + len)?;
+            let port = u16::from_be_bytes([addr[len], addr[len + 1]]);
+            Some((Address::DomainAddress(addr[..len].to_vec(), port), 7 + len))
+        }
+        _ => None,
+    }
+}
+
+/// The relay's socket facing the client, every datagram carries a SOCKS5
+/// UDP header
+struct ClientSocket(UdpSocket);
+
+impl ClientSocket {
+    /// Receives the next datagram, returning where it is for, where its
+    /// payload is in `buf` and who sent it. Fragments are dropped
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(Address, Range<usize>, SocketAddr)> {
+        loop {
+            let (len, from) = self.0.recv_from(buf).await?;
+            match decode_udp_header(&buf[..len]) {
+                Some((addr, header)) => return Ok((addr, header..len, from)),
+                None => trace!("Dropped malformed datagram from {}", from),
+            }
+        }
+    }
+
+    /// Sends `pkt` to the client as if it came from `from`
+    async fn send_to(&self, pkt: &[u8], from: &Address, client: SocketAddr) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(pkt.len() + 22);
+        encode_udp_header(from, &mut datagram);
+        datagram.extend_from_slice(pkt);
+        self.0
+            .send_to(&datagram, for_socket(&self.0, client))
+            .await?;
+        Ok(())
+    }
+}
+
+/// A UDP ASSOCIATE request waiting for its reply, from either SOCKS stack
+#[async_trait]
+pub(crate) trait AssociateRequest: Send {
+    /// The connection the association lasts as long as
+    type Control: Send;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    async fn reply(self, reply: Reply, addr: Address) -> Result<Self::Control>;
+    /// Resolves once the client closes the connection
+    async fn closed(control: &mut Self::Control);
+}
+
+#[cfg(not(feature = "vendored-socks"))]
+#[async_trait]
+impl AssociateRequest for Associate<associate::NeedReply> {
+    type Control = Associate<associate::Ready>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Associate::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Associate::local_addr(self)
+    }
+
+    async fn reply(self, reply: Reply, addr: Address) -> Result<Self::Control> {
+        match Associate::reply(self, reply, addr).await {
+            Ok(associate) => Ok(associate),
+            Err((err, _)) => Err(err.into()),
+        }
+    }
+
+    async fn closed(control: &mut Self::Control) {
+        let _ = control.wait_close().await;
+    }
+}
+
+/// Where datagrams from the client are sent
+enum Outbound {
+    Direct(UdpSocket),
+    /// Datagrams go to the target proxy's relay
+    Upstream {
+        socket: UdpSocket,
+        relay: SocketAddr,
+    },
+}
+
+impl Outbound {
+    fn direct() -> io::Result<(Outbound, Option<BoxStream>)> {
+        Ok((Outbound::Direct(bind_dual_stack()?), None))
+    }
+
+    /// Also returns the connection to the target proxy, which keeps the
+    /// association only while it stays open
+    async fn upstream(config: &Config) -> io::Result<(Outbound, Option<BoxStream>)> {
+        let targets = upstream::order(config);
+        let ((control, relay), target) =
+            connect_with_failover(config, &targets, |mut control, protocol| {
+                let credentials = config.target_credentials();
+                async move {
+                    if protocol == config::Protocol::Http {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "HTTP target proxies can't relay UDP",
+                        ));
+                    }
+                    let relay = udp_associate_with_stream(&mut control, credentials)
+                        .await
+                        .map_err(|err| io::Error::other(err.to_string()))?;
+                    Ok((control, relay))
+                }
+            })
+            .await?;
+
+        // Relays usually answer with an unspecified address, meaning the
+        // address we reached the proxy on
+        let relay = match relay.ip().is_unspecified() {
+            true => match lookup_host(parse_target(config, &target).addr)
+                .await?
+                .next()
+            {
+                Some(target) => SocketAddr::new(target.ip(), relay.port()),
+                None => relay,
+            },
+            false => relay,
+        };
+
+        Ok((
+            Outbound::Upstream {
+                socket: bind_dual_stack()?,
+                relay,
+            },
+            Some(control),
+        ))
+    }
+
+    async fn new(config: &Config) -> io::Result<(Outbound, Option<BoxStream>)> {
+        match config.status {
+            true => Outbound::upstream(config).await,
+            false => Outbound::direct(),
+        }
+    }
+
+    async fn send(&self, config: &Config, pkt: &[u8], target: &Address) -> io::Result<()> {
+        match self {
+            Outbound::Direct(socket) => {
+                let addrs = resolve(config, target).await?;
+                acl::check_resolved(&config.blocked_destinations, &addrs)?;
+                let addr = match addrs.first() {
+                    Some(addr) => *addr,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "Domain did not resolve",
+                        ))
+                    }
+                };
+                socket.send_to(pkt, for_socket(socket, addr)).await?;
+            }
+            Outbound::Upstream { socket, relay } => {
+                // Checked the way connections through the target proxy are
+                if config.dns_mode == DnsMode::Remote
+                    && acl::has_ranges(&config.blocked_destinations)
+                    && matches!(target, Address::DomainAddress(..))
+                {
+                    if let Ok(addrs) = resolve_locally(target).await {
+                        acl::check_resolved(&config.blocked_destinations, &addrs)?;
+                    }
+                }
+                let mut datagram = Vec::with_capacity(pkt.len() + 22);
+                encode_udp_header(target, &mut datagram);
+                datagram.extend_from_slice(pkt);
+                socket
+                    .send_to(&datagram, for_socket(socket, *relay))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives the next reply, returning who sent it and where its payload is in `buf`
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(Address, Range<usize>)> {
+        match self {
+            Outbound::Direct(socket) => {
+                let (len, from) = socket.recv_from(buf).await?;
+                Ok((Address::SocketAddress(canonical(from)), 0..len))
+            }
+            Outbound::Upstream { socket, relay } => loop {
+                let (len, from) = socket.recv_from(buf).await?;
+                if canonical(from) != canonical(*relay) {
+                    continue;
+                }
+                match decode_udp_header(&buf[..len]) {
+                    Some((addr, header)) => return Ok((addr, header..len)),
+                    None => trace!("Dropped malformed datagram from relay"),
+                }
+            },
+        }
+    }
+}
+
+/// Sends a DNS query the rules route differently from the relay and passes
+/// the answer back to the client
+async fn resolve_elsewhere(
+    listener: Arc<ClientSocket>,
+    config: Config,
+    direct: bool,
+    pkt: Vec<u8>,
+    server: Address,
+    client: SocketAddr,
+) -> io::Result<()> {
+    let (outbound, _control) = match direct {
+        true => Outbound::direct()?,
+        false => Outbound::upstream(&config).await?,
+    };
+    outbound.send(&config, &pkt, &server).await?;
+
+    let mut buf = vec![0; BUF_SIZE];
+    let (from, payload) = timeout(DNS_TIMEOUT, outbound.recv(&mut buf)).await??;
+    listener.send_to(&buf[payload], &from, client).await?;
+    Ok(())
+}
+
+/// Applies the client policies and rules to a DNS query from the client,
+/// returns whether it was dealt with and shouldn't be relayed
+async fn intercept_dns(
+    listener: &Arc<ClientSocket>,
+    config: &Config,
+    pkt: &[u8],
+    server: &Address,
+    client: SocketAddr,
+) -> io::Result<bool> {
+    let port = match server {
+        Address::SocketAddress(addr) => addr.port(),
+        Address::DomainAddress(_, port) => *port,
+    };
+    if port != DNS_PORT {
+        return Ok(false);
+    }
+    let query = match dns::parse_query(pkt) {
+        Some(query) => query,
+        None => return Ok(false),
+    };
+
+    let name = Address::DomainAddress(query.name.as_bytes().to_vec(), port);
+    if let Some(policy) =
+        policy::blocking(&config.client_policies, client.ip().to_canonical(), &name)
+    {
+        trace!("Client policy {} applies to {}", policy.name, query.name);
+        let res = dns::nxdomain(pkt, &query);
+        listener.send_to(&res, server, client).await?;
+        return Ok(true);
+    }
+
+    match rules::match_domain(&config.rules, &query.name) {
+        Some(RuleAction::Block) => {
+            let res = dns::nxdomain(pkt, &query);
+            listener.send_to(&res, server, client).await?;
+        }
+        Some(RuleAction::Redirect { to }) => {
+            let res = dns::answer(pkt, &query, *to);
+            listener.send_to(&res, server, client).await?;
+        }
+        // Only queries that have to leave another way than the relay's
+        Some(action @ (RuleAction::Direct | RuleAction::Proxy))
+            if (*action == RuleAction::Proxy) != config.status =>
+        {
+            let direct = *action == RuleAction::Direct;
+            // Dropped by the kill switch, the client times out
+            if direct && !direct_allowed(config) {
+                return Ok(true);
+            }
+            let listener = listener.clone();
+            let config = config.clone();
+            let pkt = pkt.to_vec();
+            let server = server.clone();
+            tokio::spawn(async move {
+                match resolve_elsewhere(listener, config, direct, pkt, server, client).await {
+                    Ok(_) => {}
+                    Err(err) => trace!("Failed to resolve {}: {}", query.name, err),
+                }
+            });
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Whether `client` may send datagrams to `target` at all
+fn datagram_allowed(config: &Config, client: IpAddr, target: &Address) -> bool {
+    if acl::is_blocked(&config.blocked_destinations, target) {
+        return false;
+    }
+    if let Some(policy) = policy::blocking(&config.client_policies, client, target) {
+        trace!("Client policy {} applies to {}", policy.name, client);
+        return false;
+    }
+    true
+}
+
+/// Whether a datagram from `client` to `target` goes through the target
+/// proxy, `None` drops it
+fn datagram_route(config: &Config, client: IpAddr, target: &Address) -> Option<bool> {
+    if !datagram_allowed(config, client, target) {
+        return None;
+    }
+
+    let port = match target {
+        Address::SocketAddress(addr) => addr.port(),
+        Address::DomainAddress(_, port) => *port,
+    };
+
+    let rule = rules::match_rule(&config.rules, target);
+    let quic = match port {
+        QUIC_PORT => rule.and_then(|rule| rule.quic),
+        _ => None,
+    };
+    let upstream = match (quic, rule.map(|rule| &rule.action)) {
+        (Some(QuicPolicy::Block), _) => None,
+        (Some(QuicPolicy::Proxy), _) => Some(true),
+        (None, Some(RuleAction::Block)) => None,
+        (None, Some(RuleAction::Direct)) => Some(false),
+        (None, Some(RuleAction::Proxy)) => Some(true),
+        _ => Some(config.status),
+    };
+    match upstream {
+        Some(false) if config.status && !direct_allowed(config) => None,
+        upstream => upstream,
+    }
+}
+
+/// Resolves once the other end closes `stream`, reading past anything it
+/// sends
+pub(crate) async fn read_until_closed<S: AsyncRead + Unpin>(stream: &mut S) {
+    let mut buf = [0u8; 64];
+    while let Ok(1..) = stream.read(&mut buf).await {}
+}
+
+/// Resolves once the connection is closed, never when there isn't one
+async fn wait_until_closed(control: &mut Option<BoxStream>) {
+    match control {
+        Some(control) => read_until_closed(control).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_optional(
+    outbound: &Option<Outbound>,
+    buf: &mut [u8],
+) -> io::Result<(Address, Range<usize>)> {
+    match outbound {
+        Some(outbound) => outbound.recv(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Relays UDP for a client until it closes the associate connection
+pub(crate) async fn associate<R: AssociateRequest>(associate: R, config: &Config) -> Result<()> {
+    let client_ip = associate.peer_addr()?.ip().to_canonical();
+    let listener = bind_dual_stack()?;
+    let reply_addr = SocketAddr::new(
+        associate.local_addr()?.ip().to_canonical(),
+        listener.local_addr()?.port(),
+    );
+
+    let (outbound, mut control) = match Outbound::new(config).await {
+        Ok(outbound) => outbound,
+        Err(err) => {
+            error!("Failed to set up UDP relay: {:?}", err);
+            // Dropping the connection closes it
+            associate
+                .reply(Reply::HostUnreachable, Address::unspecified())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut associated = associate
+        .reply(Reply::Succeeded, Address::SocketAddress(reply_addr))
+        .await?;
+
+    let listener = Arc::new(ClientSocket(listener));
+    let mut client: Option<SocketAddr> = None;
+    let mut client_buf = vec![0; BUF_SIZE];
+    let mut buf = vec![0; BUF_SIZE];
+    // The route the config doesn't default to, opened once a rule needs it
+    let mut alternate: Option<Outbound> = None;
+    let mut _alternate_control: Option<BoxStream> = None;
+    let mut alternate_buf = vec![0; BUF_SIZE];
+    loop {
+        tokio::select! {
+            _ = R::closed(&mut associated) => break,
+            // The target proxy's relay is gone once it drops the connection
+            _ = wait_until_closed(&mut control) => {
+                trace!("Target proxy ended the UDP association");
+                break;
+            }
+            received = listener.recv_from(&mut client_buf) => {
+                let (target, payload, from) = received?;
+                // Only the client that asked for the relay may use it
+                if from.ip().to_canonical() != client_ip {
+                    continue;
+                }
+                client = Some(from);
+                let pkt = &client_buf[payload];
+
+                // Before DNS is answered, the server is a destination like any other
+                if !datagram_allowed(config, client_ip, &target) {
+                    continue;
+                }
+                if intercept_dns(&listener, config, pkt, &target, from).await? {
+                    continue;
+                }
+
+                let upstream = match datagram_route(config, client_ip, &target) {
+                    Some(upstream) => upstream,
+                    None => continue,
+                };
+                let sent = match upstream == config.status {
+                    true => outbound.send(config, pkt, &target).await,
+                    false => {
+                        if alternate.is_none() {
+                            let opened = match upstream {
+                                true => Outbound::upstream(config).await,
+                                false => Outbound::direct(),
+                            };
+                            match opened {
+                                Ok((opened, control)) => {
+                                    alternate = Some(opened);
+                                    _alternate_control = control;
+                                }
+                                Err(err) => trace!("Failed to open UDP route: {}", err),
+                            }
+                        }
+                        match &alternate {
+                            Some(alternate) => alternate.send(config, pkt, &target).await,
+                            None => continue,
+                        }
+                    }
+                };
+                match sent {
+                    Ok(_) => {}
+                    Err(err) => trace!("Failed to relay datagram: {}", err),
+                }
+            }
+            received = outbound.recv(&mut buf) => {
+                let (from, payload) = received?;
+                if let Some(client) = client {
+                    listener.send_to(&buf[payload], &from, client).await?;
+                }
+            }
+            received = recv_optional(&alternate, &mut alternate_buf) => {
+                let (from, payload) = received?;
+                if let Some(client) = client {
+                    listener.send_to(&alternate_buf[payload], &from, client).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// An associate request from `peer`, made on a connection to `local`
+    struct TestAssociate {
+        peer: SocketAddr,
+        local: SocketAddr,
+        replied: oneshot::Sender<(Reply, Address)>,
+        closed: oneshot::Receiver<()>,
+    }
+
+    #[async_trait]
+    impl AssociateRequest for TestAssociate {
+        type Control = oneshot::Receiver<()>;
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.local)
+        }
+
+        async fn reply(self, reply: Reply, addr: Address) -> Result<Self::Control> {
+            let _ = self.replied.send((reply, addr));
+            Ok(self.closed)
+        }
+
+        async fn closed(control: &mut Self::Control) {
+            let _ = control.await;
+        }
+    }
+
+    /// Relays a datagram from a client on `loopback` to an echo server on
+    /// it, checking the reply comes back from the relay address with the
+    /// echo server's address in the header as the client wrote it
+    async fn relay_over(loopback: IpAddr) {
+        let client = UdpSocket::bind((loopback, 0)).await.unwrap();
+        let echo = UdpSocket::bind((loopback, 0)).await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let (replied, reply) = oneshot::channel();
+        let (close, closed) = oneshot::channel();
+        let request = TestAssociate {
+            peer: client.local_addr().unwrap(),
+            local: SocketAddr::new(loopback, 1080),
+            replied,
+            closed,
+        };
+        let relay = tokio::spawn(async move { associate(request, &Config::default()).await });
+        let relay_addr = match reply.await.unwrap() {
+            (Reply::Succeeded, Address::SocketAddress(addr)) => addr,
+            other => panic!("Associate replied {:?}", other),
+        };
+        assert_eq!(relay_addr.ip(), loopback);
+
+        let mut datagram = Vec::new();
+        encode_udp_header(&Address::SocketAddress(target), &mut datagram);
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        let mut buf = [0; 64];
+        let (len, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, relay_addr);
+        assert_eq!(&buf[..len], &datagram[..]);
+        assert_eq!(
+            decode_udp_header(&buf[..len]),
+            Some((Address::SocketAddress(target), len - 4))
+        );
+
+        drop(close);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_over_ipv4() {
+        relay_over(Ipv4Addr::LOCALHOST.into()).await;
+    }
+
+    #[tokio::test]
+    async fn relays_over_ipv6() {
+        // Hosts without IPv6 have nothing to test
+        if std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+            return;
+        }
+        relay_over(Ipv6Addr::LOCALHOST.into()).await;
+    }
+
+    fn quic_rule(action: RuleAction, quic: QuicPolicy) -> Config {
+        let mut config = Config::default();
+        config.rules.push(config::Rule {
+            pattern: "example.com".to_string(),
+            action,
+            quic: Some(quic),
+            race: false,
+        });
+        config
+    }
+
+    #[test]
+    fn quic_policy_blocks_only_udp_443() {
+        let config = quic_rule(RuleAction::Proxy, QuicPolicy::Block);
+        let client = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let quic = Address::DomainAddress(b"www.example.com".to_vec(), 443);
+        let dns = Address::DomainAddress(b"www.example.com".to_vec(), 53);
+        let other = Address::DomainAddress(b"example.org".to_vec(), 443);
+
+        assert_eq!(datagram_route(&config, client, &quic), None);
+        assert_eq!(datagram_route(&config, client, &dns), Some(true));
+        assert_eq!(datagram_route(&config, client, &other), Some(config.status));
+    }
+
+    #[test]
+    fn quic_policy_proxies_only_udp_443() {
+        let config = quic_rule(RuleAction::Direct, QuicPolicy::Proxy);
+        let client = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let quic = Address::DomainAddress(b"example.com".to_vec(), 443);
+        let dns = Address::DomainAddress(b"example.com".to_vec(), 53);
+
+        assert_eq!(datagram_route(&config, client, &quic), Some(true));
+        assert_eq!(datagram_route(&config, client, &dns), Some(false));
+    }
+
+    #[test]
+    fn datagrams_follow_client_policies() {
+        let config = Config {
+            client_policies: vec![config::ClientPolicy {
+                name: "kids".to_string(),
+                clients: vec!["192.0.2.0/24".parse().unwrap()],
+                blocked: vec!["*:443".to_string().try_into().unwrap()],
+                active: Vec::new(),
+            }],
+            ..Config::default()
+        };
+        let quic = Address::DomainAddress(b"example.com".to_vec(), 443);
+        let dns = Address::DomainAddress(b"example.com".to_vec(), 53);
+        let kid = IpAddr::from([192, 0, 2, 7]);
+        let parent = IpAddr::from([198, 51, 100, 7]);
+
+        assert_eq!(datagram_route(&config, kid, &quic), None);
+        assert_eq!(datagram_route(&config, kid, &dns), Some(config.status));
+        assert_eq!(datagram_route(&config, parent, &quic), Some(config.status));
+    }
+
+    #[tokio::test]
+    async fn dns_queries_follow_client_policies() {
+        let config = Config {
+            client_policies: vec![config::ClientPolicy {
+                name: "kids".to_string(),
+                clients: vec!["127.0.0.0/8".parse().unwrap()],
+                blocked: vec!["*.example.com".to_string().try_into().unwrap()],
+                active: Vec::new(),
+            }],
+            ..Config::default()
+        };
+        let listener = Arc::new(ClientSocket(
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+        ));
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let from = client.local_addr().unwrap();
+        let server = Address::SocketAddress(([192, 0, 2, 1], 53).into());
+
+        // Answered here, the server never sees the blocked name
+        let pkt = dns::query(7, "ads.example.com", 1).unwrap();
+        assert!(intercept_dns(&listener, &config, &pkt, &server, from)
+            .await
+            .unwrap());
+        let mut buf = [0; 512];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let (answered_for, header) = decode_udp_header(&buf[..len]).unwrap();
+        assert_eq!(answered_for, server);
+        assert_eq!(buf[header + 3] & 0x0f, 3);
+
+        let pkt = dns::query(8, "example.org", 1).unwrap();
+        assert!(!intercept_dns(&listener, &config, &pkt, &server, from)
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn dns_servers_follow_client_policies() {
+        let config = Config {
+            client_policies: vec![config::ClientPolicy {
+                name: "kids".to_string(),
+                clients: vec!["192.0.2.0/24".parse().unwrap()],
+                blocked: vec!["*:53".to_string().try_into().unwrap()],
+                active: Vec::new(),
+            }],
+            ..Config::default()
+        };
+        let server = Address::SocketAddress(([198, 51, 100, 1], 53).into());
+
+        assert!(!datagram_allowed(
+            &config,
+            IpAddr::from([192, 0, 2, 7]),
+            &server
+        ));
+        assert!(datagram_allowed(
+            &config,
+            IpAddr::from([198, 51, 100, 7]),
+            &server
+        ));
+    }
+
+    #[test]
+    fn datagrams_to_blocked_destinations_are_dropped() {
+        let config = Config {
+            blocked_destinations: vec!["10.0.0.0/8".to_string().try_into().unwrap()],
+            ..Config::default()
+        };
+        let client = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let private = Address::SocketAddress(([10, 0, 0, 1], 53).into());
+        let public = Address::SocketAddress(([192, 0, 2, 1], 53).into());
+
+        assert_eq!(datagram_route(&config, client, &private), None);
+        assert_eq!(
+            datagram_route(&config, client, &public),
+            Some(config.status)
+        );
+    }
+
+    #[test]
+    fn v4_mapped_addresses_are_undone() {
+        let mapped = SocketAddr::new(Ipv4Addr::LOCALHOST.to_ipv6_mapped().into(), 53);
+        assert_eq!(
+            canonical(mapped),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 53))
+        );
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 53));
+        assert_eq!(canonical(v6), v6);
+    }
+
+    fn headers() -> Vec<(Address, Vec<u8>)> {
+        [
+            Address::SocketAddress(SocketAddrV4::new([192, 0, 2, 1].into(), 53).into()),
+            Address::SocketAddress((Ipv6Addr::LOCALHOST, 443).into()),
+            Address::DomainAddress(b"example.com".to_vec(), 80),
+            Address::DomainAddress(Vec::new(), 80),
+        ]
+        .into_iter()
+        .map(|addr| {
+            let mut header = Vec::new();
+            encode_udp_header(&addr, &mut header);
+            (addr, header)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn udp_header_round_trips() {
+        for (addr, mut header) in headers() {
+            let len = header.len();
+            header.extend_from_slice(b"payload");
+            assert_eq!(decode_udp_header(&header), Some((addr, len)));
+        }
+    }
+
+    #[test]
+    fn truncated_udp_header_is_dropped() {
+        for (_, header) in headers() {
+            for len in 0..header.len() {
+                assert_eq!(decode_udp_header(&header[..len]), None);
+            }
+        }
+    }
+
+    #[test]
+    fn fragmented_udp_header_is_dropped() {
+        for (_, mut header) in headers() {
+            header[2] = 1;
+            assert_eq!(decode_udp_header(&header), None);
+        }
+    }
+
+    #[test]
+    fn unknown_address_type_is_dropped() {
+        for addr_type in [0x00, 0x02, 0x05] {
+            assert_eq!(decode_udp_header(&[0, 0, 0, addr_type, 0, 0]), None);
+        }
+    }
+}
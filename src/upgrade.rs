@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+
+use serde_json::{Map, Value};
+
+/// The config version this build reads and writes
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Brings a config from the version before it to the next, in order
+const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [v0_to_v1];
+
+/// Moves `from` to `to` unless the config already has `to`
+fn rename(config: &mut Map<String, Value>, from: &str, to: &str) {
+    if let Some(value) = config.remove(from) {
+        config.entry(to).or_insert(value);
+    }
+}
+
+/// Files from before versions were kept
+fn v0_to_v1(config: &mut Map<String, Value>) {
+    rename(config, "set_system_proxy", "system_proxy");
+}
+
+/// Upgrades a config file's settings to [`CONFIG_VERSION`] in place,
+/// returning the version it had. Settings added since are left out, so they
+/// take their defaults
+pub fn upgrade(tree: &mut Value) -> Result<u32> {
+    let config = tree
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("The config is not a map of settings"))?;
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow!("version: {} is not a config version", version))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(anyhow!(
+            "version: {} is newer than this toggleproxy understands, which is up to {}",
+            version,
+            CONFIG_VERSION
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(config);
+    }
+    config.insert("version".to_string(), CONFIG_VERSION.into());
+    Ok(version)
+}
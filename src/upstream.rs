@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::config::{Balance, Config};
+
+/// How much a new connect time moves a target's average latency
+const LATENCY_WEIGHT: f64 = 0.3;
+
+#[derive(Default)]
+struct Upstream {
+    active: u64,
+    /// Moving average of how long connects through the target take
+    latency: Option<Duration>,
+    failed: Option<Instant>,
+}
+
+lazy_static! {
+    static ref UPSTREAMS: Mutex<HashMap<String, Upstream>> = Mutex::new(HashMap::new());
+    static ref NEXT: AtomicUsize = AtomicUsize::new(0);
+    static ref LAST_USED: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Counts an open tunnel through a target proxy until dropped
+pub struct Lease {
+    target: String,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(upstream) = UPSTREAMS.lock().unwrap().get_mut(&self.target) {
+            upstream.active = upstream.active.saturating_sub(1);
+        }
+    }
+}
+
+pub fn lease(target: &str) -> Lease {
+    UPSTREAMS
+        .lock()
+        .unwrap()
+        .entry(target.to_string())
+        .or_default()
+        .active += 1;
+    Lease {
+        target: target.to_string(),
+    }
+}
+
+/// Records how long a successful connect through `target` took, which makes
+/// it the one in use
+pub fn record_latency(target: &str, latency: Duration) {
+    *LAST_USED.lock().unwrap() = Some(target.to_string());
+    let mut upstreams = UPSTREAMS.lock().unwrap();
+    let upstream = upstreams.entry(target.to_string()).or_default();
+    upstream.failed = None;
+    upstream.latency = Some(match upstream.latency {
+        Some(average) => average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT),
+        None => latency,
+    });
+}
+
+pub fn record_failure(target: &str) {
+    let mut upstreams = UPSTREAMS.lock().unwrap();
+    upstreams.entry(target.to_string()).or_default().failed = Some(Instant::now());
+}
+
+/// The target proxy the latest connection went through
+pub fn last_used() -> Option<String> {
+    LAST_USED.lock().unwrap().clone()
+}
+
+/// The target proxies in the order a new connection should try them
+pub fn order(config: &Config) -> Vec<String> {
+    let targets = config.target.0.clone();
+    if targets.len() < 2 {
+        return targets;
+    }
+    let mut targets = balanced(config, targets);
+
+    // New connections switch to the warm targets instead of waiting for a
+    // failed one to time out again
+    if let Some(standby) = &config.warm_standby {
+        let failed_for = Duration::from_secs(standby.failed_secs);
+        let upstreams = UPSTREAMS.lock().unwrap();
+        targets.sort_by_key(|target| {
+            upstreams
+                .get(target)
+                .and_then(|upstream| upstream.failed)
+                .is_some_and(|failed| failed.elapsed() < failed_for)
+        });
+    }
+    targets
+}
+
+fn balanced(config: &Config, mut targets: Vec<String>) -> Vec<String> {
+    match config.balance {
+        Balance::Ordered => {}
+        Balance::RoundRobin => {
+            let first = NEXT.fetch_add(1, Ordering::Relaxed) % targets.len();
+            targets.rotate_left(first);
+        }
+        Balance::LeastConnections => {
+            let upstreams = UPSTREAMS.lock().unwrap();
+            // Stable, so ties keep the configured order
+            targets.sort_by_key(|target| upstreams.get(target).map_or(0, |u| u.active));
+        }
+        Balance::Latency => {
+            let latencies = {
+                let upstreams = UPSTREAMS.lock().unwrap();
+                targets
+                    .iter()
+                    .map(|target| upstreams.get(target).and_then(|u| u.latency))
+                    .collect::<Vec<_>>()
+            };
+            // Targets that have not connected yet count as the fastest so
+            // they get measured
+            let fastest = latencies.iter().flatten().min().copied();
+            let millis = latencies
+                .iter()
+                .map(|latency| match latency.or(fastest) {
+                    Some(latency) => (latency.as_secs_f64() * 1000.0).max(1.0),
+                    None => 1.0,
+                })
+                .collect::<Vec<_>>();
+
+            let mut pick = rand::random::<f64>() * millis.iter().map(|ms| 1.0 / ms).sum::<f64>();
+            let mut first = millis.len() - 1;
+            for (index, ms) in millis.iter().enumerate() {
+                pick -= 1.0 / ms;
+                if pick <= 0.0 {
+                    first = index;
+                    break;
+                }
+            }
+
+            // The rest are tried fastest first
+            let mut rest = (0..targets.len())
+                .filter(|index| *index != first)
+                .collect::<Vec<_>>();
+            rest.sort_by(|a, b| millis[*a].total_cmp(&millis[*b]));
+            targets = std::iter::once(first)
+                .chain(rest)
+                .map(|index| targets[index].clone())
+                .collect();
+        }
+    }
+    targets
+}
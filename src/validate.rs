@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use ipnet::IpNet;
+
+use crate::{config::Config, transport::parse_target};
+
+/// A setting that can't work as written, and which one it is, like
+/// `rules[2].pattern`
+pub struct Problem {
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Why `addr` isn't a host:port, if it isn't
+fn check_host_port(addr: &str) -> Option<String> {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return Some(format!("{} has no port", addr)),
+    };
+    if host.trim_matches(['[', ']']).is_empty() {
+        return Some(format!("{} has no host", addr));
+    }
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => Some(format!("{} is not a valid port in {}", port, addr)),
+        Ok(_) => None,
+    }
+}
+
+/// Why `target` isn't a usable target proxy, if it isn't
+fn check_target(config: &Config, target: &str) -> Option<String> {
+    if target.contains("://")
+        && !["http://", "https://", "socks5://", "socks5h://"]
+            .iter()
+            .any(|scheme| target.starts_with(scheme))
+    {
+        return Some(format!(
+            "{} uses a scheme other than http, https or socks5",
+            target
+        ));
+    }
+    check_host_port(parse_target(config, target).addr)
+}
+
+/// The domain a rule pattern covers, without its `*.` or `.` prefix, or why
+/// it isn't one
+fn rule_domain(pattern: &str) -> Result<String, String> {
+    let domain = pattern
+        .trim_start_matches("*.")
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if domain.is_empty() {
+        return Err("The pattern is empty".to_string());
+    }
+    if domain.contains('*') {
+        return Err(format!(
+            "{} uses *, only a leading *. is supported, which covers every subdomain",
+            pattern
+        ));
+    }
+    match domain
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        Some(c) => Err(format!("{:?} can't be part of a domain in {}", c, pattern)),
+        None => Ok(domain),
+    }
+}
+
+/// Checks what parsing the config can't: ports, addresses, credentials and
+/// whether every rule can ever match. All problems are returned at once
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |field: String, message: String| problems.push(Problem { field, message });
+
+    if config.port == 0 {
+        problem("port".to_string(), "The port must be 1-65535".to_string());
+    }
+    match config.pac_port {
+        Some(0) => problem(
+            "pac_port".to_string(),
+            "The port must be 1-65535".to_string(),
+        ),
+        Some(port) if port == config.port => problem(
+            "pac_port".to_string(),
+            format!("Port {} is already the proxy's port", port),
+        ),
+        _ => {}
+    }
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(_), None) => problem(
+            "tls_key".to_string(),
+            "tls_cert is set, but not the key".to_string(),
+        ),
+        (None, Some(_)) => problem(
+            "tls_cert".to_string(),
+            "tls_key is set, but not the certificate".to_string(),
+        ),
+        _ => {}
+    }
+
+    let mut names = HashMap::new();
+    for (index, profile) in config.profiles.iter().enumerate() {
+        let field = format!("profiles[{}]", index);
+        match names.insert(profile.name.as_str(), index) {
+            _ if profile.name.is_empty() => problem(
+                format!("{}.name", field),
+                "The profile needs a name".to_string(),
+            ),
+            Some(first) => problem(
+                format!("{}.name", field),
+                format!("profiles[{}] is already named {}", first, profile.name),
+            ),
+            None => {}
+        }
+        if profile.target.is_empty() {
+            problem(
+                format!("{}.target", field),
+                "At least one target proxy is needed".to_string(),
+            );
+        }
+        for target in profile.target.iter() {
+            if let Some(message) = check_target(config, target) {
+                problem(format!("{}.target", field), message);
+            }
+        }
+    }
+
+    let mut names = HashMap::new();
+    let mut ports = HashMap::new();
+    for (index, listener) in config.listeners.iter().enumerate() {
+        let field = format!("listeners[{}]", index);
+        match names.insert(listener.name.as_str(), index) {
+            _ if listener.name.is_empty() => problem(
+                format!("{}.name", field),
+                "The listener needs a name".to_string(),
+            ),
+            Some(first) => problem(
+                format!("{}.name", field),
+                format!("listeners[{}] is already named {}", first, listener.name),
+            ),
+            None => {}
+        }
+        match ports.insert(listener.port, index) {
+            _ if listener.port == 0 => problem(
+                format!("{}.port", field),
+                "The port must be 1-65535".to_string(),
+            ),
+            _ if listener.port == config.port || Some(listener.port) == config.pac_port => problem(
+                format!("{}.port", field),
+                format!("Port {} is already used by the proxy", listener.port),
+            ),
+            Some(first) => problem(
+                format!("{}.port", field),
+                format!("listeners[{}] already uses port {}", first, listener.port),
+            ),
+            None => {}
+        }
+        if listener.target.is_empty() {
+            problem(
+                format!("{}.target", field),
+                "At least one target proxy is needed".to_string(),
+            );
+        }
+        for target in listener.target.iter() {
+            if let Some(message) = check_target(config, target) {
+                problem(format!("{}.target", field), message);
+            }
+        }
+        if listener.target_username.is_none() && listener.target_password.is_some() {
+            problem(
+                format!("{}.target_username", field),
+                "target_password is set, but not the username".to_string(),
+            );
+        }
+    }
+
+    if config.target.is_empty() {
+        problem(
+            "target".to_string(),
+            "At least one target proxy is needed".to_string(),
+        );
+    }
+    for (index, target) in config.target.iter().enumerate() {
+        let field = match config.target.0.len() {
+            1 => "target".to_string(),
+            _ => format!("target[{}]", index),
+        };
+        if let Some(message) = check_target(config, target) {
+            problem(field, message);
+        }
+    }
+
+    // A username alone is sent with an empty password
+    if config.target_username.is_none() && config.target_password.is_some() {
+        problem(
+            "target_username".to_string(),
+            "target_password is set, but not the username".to_string(),
+        );
+    }
+
+    // Anything else is taken as the path of a Unix socket
+    match config.control.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() == config.port => problem(
+            "control".to_string(),
+            format!("Port {} is already the proxy's port", addr.port()),
+        ),
+        Ok(_) => {}
+        Err(_) if config.control.contains(':') && !config.control.contains('/') => problem(
+            "control".to_string(),
+            format!(
+                "{} is neither an IP:port nor the path of a socket",
+                config.control
+            ),
+        ),
+        Err(_) => {}
+    }
+    for (index, token) in config.control_tokens.iter().enumerate() {
+        if token.token.is_empty() {
+            problem(
+                format!("control_tokens[{}].token", index),
+                "The token is empty, so it would match any request without one".to_string(),
+            );
+        }
+    }
+
+    // The first rule covering a destination wins, so a later rule with the
+    // same pattern never applies
+    let mut seen = HashMap::new();
+    for (index, rule) in config.rules.iter().enumerate() {
+        let field = format!("rules[{}].pattern", index);
+        let pattern = rule.pattern.trim();
+        let key = match (pattern.parse::<IpNet>(), pattern.parse::<IpAddr>()) {
+            (Ok(net), _) => net.trunc().to_string(),
+            (_, Ok(ip)) => IpNet::from(ip).to_string(),
+            _ => match rule_domain(pattern) {
+                Ok(domain) => domain,
+                Err(message) => {
+                    problem(field, message);
+                    continue;
+                }
+            },
+        };
+        match seen.get(&key) {
+            Some(first) => problem(
+                field,
+                format!(
+                    "{} never applies, rules[{}] already covers it",
+                    rule.pattern, first
+                ),
+            ),
+            None => {
+                seen.insert(key, index);
+            }
+        }
+    }
+
+    problems
+}
@@ -0,0 +1,178 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{Sink, Stream as _};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{handshake::client::generate_key, http::Request, Message},
+    WebSocketStream,
+};
+
+use crate::{stats::STATS, transport::BoxStream};
+
+/// Smallest and largest client ping frame, 2 header and 4 mask bytes plus up
+/// to 125 bytes of payload
+const MIN_PING_FRAME: usize = 6;
+const MAX_PING_FRAME: usize = MIN_PING_FRAME + 125;
+
+/// Carries a byte stream in binary websocket messages
+pub struct WsStream {
+    inner: WebSocketStream<BoxStream>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    pad_to: Option<usize>,
+    pending_padding: usize,
+}
+
+/// Size of a masked client frame carrying `len` bytes
+fn frame_size(len: usize) -> usize {
+    let header = match len {
+        0..=125 => 2,
+        126..=65535 => 4,
+        _ => 10,
+    };
+    header + 4 + len
+}
+
+/// Bytes of ping frames needed to round `written` up to a multiple of `pad_to`
+fn padding_for(written: usize, pad_to: usize) -> usize {
+    let mut padding = (pad_to - written % pad_to) % pad_to;
+    while padding > 0 && padding < MIN_PING_FRAME {
+        padding += pad_to;
+    }
+    padding
+}
+
+fn ws_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Performs the websocket upgrade over an established stream. `host` is sent as
+/// the Host header, independently of the address and SNI the stream was
+/// opened with
+///
+/// With `pad_to`, each write is followed by ping frames so the bytes on the wire
+/// add up to a multiple of `pad_to`. Peers answer pings with pongs of the same
+/// size and otherwise ignore them, so no cooperation from the server is needed
+pub async fn ws_connect(
+    stream: BoxStream,
+    host: &str,
+    path: &str,
+    pad_to: Option<usize>,
+) -> io::Result<WsStream> {
+    let request = Request::builder()
+        .uri(format!("ws://{}{}", host, path))
+        .header("Host", host)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", generate_key())
+        .body(())
+        .map_err(io::Error::other)?;
+
+    let (inner, _) = client_async(request, stream).await.map_err(ws_error)?;
+    Ok(WsStream {
+        inner,
+        read_buf: Vec::new(),
+        read_pos: 0,
+        pad_to: pad_to.filter(|pad_to| *pad_to >= MIN_PING_FRAME),
+        pending_padding: 0,
+    })
+}
+
+impl WsStream {
+    /// Sends queued padding, one ping frame at a time
+    fn poll_padding(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_padding > 0 {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_error)?;
+
+            let mut frame = self.pending_padding.min(MAX_PING_FRAME);
+            let left = self.pending_padding - frame;
+            // Leave enough for one more ping frame
+            if left > 0 && left < MIN_PING_FRAME {
+                frame -= MIN_PING_FRAME - left;
+            }
+
+            let payload = (0..frame - MIN_PING_FRAME)
+                .map(|_| rand::random::<u8>())
+                .collect();
+            Pin::new(&mut self.inner)
+                .start_send(Message::Ping(payload))
+                .map_err(ws_error)?;
+            self.pending_padding -= frame;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_pos == this.read_buf.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Pings are answered by tungstenite, text frames aren't part of the tunnel
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(ws_error(err))),
+            }
+        }
+
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_padding(cx))?;
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(ws_error)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+
+        if let Some(pad_to) = this.pad_to {
+            this.pending_padding = padding_for(frame_size(buf.len()), pad_to);
+            STATS.padded(buf.len() as u64, this.pending_padding as u64);
+            // Whatever doesn't fit now goes out before the next write or flush
+            let _ = this.poll_padding(cx)?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_padding(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(ws_error)
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error, Result};
+
+use lazy_static::lazy_static;
+
+use log::{info, trace};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{net::UdpSocket, time::sleep_until};
+
+use crate::config::WakeOnLan;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(pub [u8; 6]);
+
+impl TryFrom<String> for MacAddress {
+    type Error = Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        let octets = text
+            .split([':', '-'])
+            .map(|octet| u8::from_str_radix(octet, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .and_then(|octets| <[u8; 6]>::try_from(octets).ok())
+            .ok_or_else(|| anyhow!("Invalid MAC address {}", text))?;
+        Ok(MacAddress(octets))
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> Self {
+        mac.0
+            .iter()
+            .map(|octet| format!("{:02x}", octet))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+lazy_static! {
+    /// Until when the last wake is still being waited out
+    static ref WAKING: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Six 0xff bytes, then the MAC sixteen times
+fn magic_packet(mac: &MacAddress) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac.0);
+    }
+    packet
+}
+
+async fn send(wake: &WakeOnLan) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&magic_packet(&wake.mac), &wake.broadcast)
+        .await?;
+    Ok(())
+}
+
+/// Sends the magic packet and waits `wait_secs` for the machine to come up.
+/// Connections failing while a wake is under way only wait for it to finish,
+/// so a burst of them sends a single packet
+pub async fn wake(wake: &WakeOnLan) -> Result<()> {
+    let now = Instant::now();
+    let (deadline, sent) = {
+        let mut waking = WAKING.lock().unwrap();
+        match *waking {
+            Some(deadline) if deadline > now => (deadline, false),
+            _ => {
+                let deadline = now + Duration::from_secs(wake.wait_secs);
+                *waking = Some(deadline);
+                (deadline, true)
+            }
+        }
+    };
+    if sent {
+        info!(
+            "Waking {} through {}",
+            String::from(wake.mac),
+            wake.broadcast
+        );
+        if let Err(err) = send(wake).await {
+            *WAKING.lock().unwrap() = None;
+            return Err(err);
+        }
+    } else {
+        trace!("Waiting for {} to wake", String::from(wake.mac));
+    }
+    sleep_until(deadline.into()).await;
+    Ok(())
+}